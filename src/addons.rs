@@ -0,0 +1,158 @@
+use {
+    crate::{
+        wine_bin,
+        wine_inf_path,
+        Arch,
+        CommandRun,
+    },
+    aargvark::Aargvark,
+    directories::BaseDirs,
+    loga::{
+        ea,
+        StandardFlag,
+        StandardLog,
+    },
+    std::{
+        collections::HashMap,
+        ffi::OsString,
+        fs,
+        path::{
+            Path,
+            PathBuf,
+        },
+        process::Command,
+    },
+};
+
+/// Wine add-ons that wine offers to download with a dialog when a program needs
+/// them and they aren't installed.
+pub struct Addon {
+    /// Ex: `wine-mono`, also the prefix of the installer file name
+    pub name: &'static str,
+    /// The DLL that triggers the download dialog
+    pub dll: &'static str,
+    /// Where the add-on is installed in a prefix, relative to the prefix
+    pub prefix_dirs: &'static [&'static str],
+    pub uses: &'static str,
+}
+
+pub const ADDONS: &[Addon] = &[Addon {
+    name: "wine-mono",
+    dll: "mscoree",
+    prefix_dirs: &["drive_c/windows/mono"],
+    uses: ".NET programs",
+}, Addon {
+    name: "wine-gecko",
+    dll: "mshtml",
+    prefix_dirs: &["drive_c/windows/system32/gecko", "drive_c/windows/syswow64/gecko"],
+    uses: "programs showing web pages",
+}];
+
+impl Addon {
+    /// Where distribution packages install the add-on for all prefixes, ex:
+    /// `/usr/share/wine/mono`.
+    pub fn shared_dir(&self, env: &HashMap<OsString, OsString>) -> PathBuf {
+        let shared = wine_inf_path(env).parent().map(|p| p.to_path_buf()).unwrap_or_default();
+        return shared.join(self.name.trim_start_matches("wine-"));
+    }
+
+    /// Installed for all prefixes, or in this prefix.
+    pub fn installed(&self, prefix: &Path, env: &HashMap<OsString, OsString>) -> bool {
+        return self.shared_dir(env).exists() || self.prefix_dirs.iter().any(|d| prefix.join(d).exists());
+    }
+
+    /// Installers wine previously downloaded for the add-on, in wine's download cache
+    /// (ex: `~/.cache/wine/wine-gecko-2.47.4-x86.msi`). Gecko has separate installers
+    /// for 32 and 64 bit.
+    fn cached_installers(&self, arch: Arch) -> Vec<PathBuf> {
+        let Some(base) = BaseDirs::new() else {
+            return vec![];
+        };
+        let Ok(entries) = fs::read_dir(base.cache_dir().join("wine")) else {
+            return vec![];
+        };
+        let mut found = vec![];
+        for entry in entries.flatten() {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if file_name.starts_with(&format!("{}-", self.name)) && file_name.ends_with(".msi") {
+                found.push(entry.path());
+            }
+        }
+
+        // Newest version sorts last, keep the newest per architecture
+        found.sort();
+        let mut out = vec![];
+        for suffix in ["-x86.msi", "-x86_64.msi", ""] {
+            if suffix == "-x86_64.msi" && matches!(arch, Arch::Win32) {
+                continue;
+            }
+            let matching = found.iter().rfind(|p| {
+                let name = p.to_string_lossy();
+                if suffix.is_empty() {
+                    return !name.ends_with("-x86.msi") && !name.ends_with("-x86_64.msi");
+                } else {
+                    return name.ends_with(suffix);
+                }
+            });
+            if let Some(path) = matching {
+                out.push(path.clone());
+            }
+        }
+        return out;
+    }
+}
+
+/// What to do when a program needs a wine add-on that isn't installed, instead of
+/// showing wine's download dialog.
+#[derive(Aargvark, Clone, Copy)]
+pub enum AssumeAddons {
+    /// Install missing add-ons from wine's download cache before running
+    Yes,
+    /// Disable the DLLs that trigger the dialogs, so programs run without the add-ons
+    No,
+}
+
+/// Add DLL overrides disabling the add-on DLLs, after any existing overrides so
+/// they take precedence.
+pub fn decline_addons(env: &mut HashMap<OsString, OsString>) {
+    let mut overrides = vec![];
+    if let Some(existing) = env.get(&OsString::from("WINEDLLOVERRIDES")) {
+        overrides.push(existing.to_string_lossy().to_string());
+    }
+    overrides.push(format!("{}=", ADDONS.iter().map(|a| a.dll).collect::<Vec<_>>().join(",")));
+    env.insert(OsString::from("WINEDLLOVERRIDES"), OsString::from(overrides.join(";")));
+}
+
+/// Silently install missing add-ons in the (mounted) prefix from wine's download
+/// cache. Add-ons that aren't cached are skipped with a warning, wine will show
+/// the download dialog if they're needed.
+pub fn install_cached_addons(
+    log: &StandardLog,
+    env: &HashMap<OsString, OsString>,
+    prefix: &Path,
+    arch: Arch,
+) -> Result<(), loga::Error> {
+    for addon in ADDONS {
+        if addon.installed(prefix, env) {
+            continue;
+        }
+        let installers = addon.cached_installers(arch);
+        if installers.is_empty() {
+            log.log_with(
+                StandardFlag::Warning,
+                "Add-on isn't installed or in wine's download cache, wine may ask to download it",
+                ea!(addon = addon.name),
+            );
+            continue;
+        }
+        for installer in installers {
+            log.log_with(
+                StandardFlag::Info,
+                "Installing wine add-on",
+                ea!(addon = addon.name, installer = installer.to_string_lossy()),
+            );
+            Command::new(wine_bin(env)).envs(env).arg("msiexec").arg("/i").arg(&installer).arg("/qn").run()?;
+        }
+    }
+    return Ok(());
+}
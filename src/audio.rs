@@ -0,0 +1,69 @@
+use {
+    crate::{
+        wine_bin,
+        CommandRun,
+    },
+    aargvark::Aargvark,
+    loga::{
+        ea,
+        ResultContext,
+    },
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+    std::{
+        collections::HashMap,
+        ffi::OsString,
+        fs,
+        path::Path,
+        process::Command,
+    },
+};
+
+/// Which audio driver wine uses.
+#[derive(Aargvark, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AudioDriver {
+    /// Let wine pick the first one that works
+    #[default]
+    Auto,
+    /// PulseAudio, also for PipeWire (via pipewire-pulse)
+    Pulse,
+    Alsa,
+    Oss,
+    /// No audio, for programs that crash or hang trying to use it
+    Disabled,
+}
+
+impl AudioDriver {
+    /// The `Audio` value in wine's `Drivers` key, `None` to remove it.
+    fn registry_value(&self) -> Option<&'static str> {
+        match self {
+            AudioDriver::Auto => return None,
+            AudioDriver::Pulse => return Some("pulse"),
+            AudioDriver::Alsa => return Some("alsa"),
+            AudioDriver::Oss => return Some("oss"),
+            AudioDriver::Disabled => return Some(""),
+        }
+    }
+}
+
+/// Make wine in the prefix use the audio driver, via the registry.
+pub fn set_audio_driver(
+    env: &HashMap<OsString, OsString>,
+    prefix: &Path,
+    driver: AudioDriver,
+) -> Result<(), loga::Error> {
+    let value = match driver.registry_value() {
+        Some(v) => format!("\"{}\"", v),
+        None => "-".to_string(),
+    };
+    let import_path = prefix.join("audio-driver.reg");
+    fs::write(
+        &import_path,
+        format!("REGEDIT4\n\n[HKEY_CURRENT_USER\\Software\\Wine\\Drivers]\n\"Audio\"={}\n", value),
+    ).context_with("Error writing audio driver setting to import", ea!(path = import_path.to_string_lossy()))?;
+    let res = Command::new(wine_bin(env)).envs(env).arg("regedit").arg("/S").arg(&import_path).run();
+    _ = fs::remove_file(&import_path);
+    return res;
+}
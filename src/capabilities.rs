@@ -0,0 +1,192 @@
+use {
+    crate::{
+        find_bin,
+        landlock::landlock_abi,
+        mount::fusermount_bin,
+    },
+    loga::ea,
+    std::{
+        env,
+        fs,
+        path::Path,
+        sync::OnceLock,
+    },
+};
+
+/// Something on the host that some features need.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// Kernel overlay filesystem
+    Overlayfs,
+    Sudo,
+    /// `fuse-overlayfs` and the FUSE device
+    Fuse,
+    /// A systemd user session, for services
+    Systemd,
+    /// An X11 or Wayland desktop to open windows on
+    Display,
+    /// A GPU render device, for hardware accelerated graphics
+    Gpu,
+    /// `xdotool`, for tagging windows and the escape supervisor
+    Xdotool,
+    /// Feral GameMode's `gamemoderun`
+    Gamemode,
+    /// Bubblewrap (`bwrap`), for sandboxing
+    Bwrap,
+    /// Kernel Landlock support, for confining programs without extra tools
+    Landlock,
+}
+
+impl Capability {
+    pub const ALL: &'static [Capability] =
+        &[
+            Capability::Overlayfs,
+            Capability::Sudo,
+            Capability::Fuse,
+            Capability::Systemd,
+            Capability::Display,
+            Capability::Gpu,
+            Capability::Xdotool,
+            Capability::Gamemode,
+            Capability::Bwrap,
+            Capability::Landlock,
+        ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::Overlayfs => "overlayfs",
+            Capability::Sudo => "sudo",
+            Capability::Fuse => "fuse",
+            Capability::Systemd => "systemd",
+            Capability::Display => "display",
+            Capability::Gpu => "gpu",
+            Capability::Xdotool => "xdotool",
+            Capability::Gamemode => "gamemode",
+            Capability::Bwrap => "bwrap",
+            Capability::Landlock => "landlock",
+        }
+    }
+
+    /// Check the host, returning what's missing if unavailable.
+    fn probe(&self) -> Result<(), String> {
+        match self {
+            Capability::Overlayfs => {
+                // Simulated by the test stubs
+                #[cfg(feature = "test-mode")]
+                if crate::test_mode::test_dir().is_some() {
+                    return Ok(());
+                }
+                let filesystems = fs::read_to_string("/proc/filesystems").unwrap_or_default();
+                if !filesystems.lines().any(|l| l.split_whitespace().last() == Some("overlay")) {
+                    return Err(
+                        "`overlay` not listed in /proc/filesystems (may be available as a module once loaded)".to_string(),
+                    );
+                }
+            },
+            Capability::Sudo => {
+                if find_bin("sudo").is_none() {
+                    return Err("`sudo` not found in PATH".to_string());
+                }
+            },
+            Capability::Fuse => {
+                if find_bin("fuse-overlayfs").is_none() {
+                    return Err("`fuse-overlayfs` not found in PATH".to_string());
+                }
+                #[cfg(feature = "test-mode")]
+                let have_device = crate::test_mode::test_dir().is_some() || Path::new("/dev/fuse").exists();
+                #[cfg(not(feature = "test-mode"))]
+                let have_device = Path::new("/dev/fuse").exists();
+                if !have_device {
+                    return Err("/dev/fuse doesn't exist".to_string());
+                }
+                if fusermount_bin().is_none() {
+                    return Err("`fusermount3` or `fusermount` not found in PATH".to_string());
+                }
+            },
+            Capability::Systemd => {
+                if find_bin("systemctl").is_none() {
+                    return Err("`systemctl` not found in PATH".to_string());
+                }
+                if !Path::new("/run/systemd/system").exists() {
+                    return Err("the system wasn't booted with systemd".to_string());
+                }
+                let Some(runtime_dir) = env::var_os("XDG_RUNTIME_DIR") else {
+                    return Err("`XDG_RUNTIME_DIR` isn't set, there's no user session".to_string());
+                };
+                if !Path::new(&runtime_dir).join("bus").exists() {
+                    return Err("no user session bus in `XDG_RUNTIME_DIR`".to_string());
+                }
+            },
+            Capability::Display => {
+                if env::var_os("DISPLAY").is_none() && env::var_os("WAYLAND_DISPLAY").is_none() {
+                    return Err("neither `DISPLAY` nor `WAYLAND_DISPLAY` is set".to_string());
+                }
+            },
+            Capability::Gpu => {
+                let has_render_node = fs::read_dir("/dev/dri").map(|entries| {
+                    return entries.flatten().any(|e| e.file_name().to_string_lossy().starts_with("renderD"));
+                }).unwrap_or(false);
+                if !has_render_node {
+                    return Err("no render device in /dev/dri, graphics will be software rendered".to_string());
+                }
+            },
+            Capability::Xdotool => {
+                if find_bin("xdotool").is_none() {
+                    return Err("`xdotool` not found in PATH".to_string());
+                }
+            },
+            Capability::Gamemode => {
+                if find_bin("gamemoderun").is_none() {
+                    return Err("`gamemoderun` not found in PATH, install Feral GameMode".to_string());
+                }
+            },
+            Capability::Bwrap => {
+                if find_bin("bwrap").is_none() {
+                    return Err("`bwrap` not found in PATH, install bubblewrap".to_string());
+                }
+            },
+            Capability::Landlock => {
+                landlock_abi()?;
+            },
+        }
+        return Ok(());
+    }
+}
+
+/// What's available on this host, checked once.
+pub struct Capabilities {
+    results: Vec<(Capability, Result<(), String>)>,
+}
+
+impl Capabilities {
+    /// Ok, or what's missing.
+    pub fn check(&self, capability: Capability) -> Result<(), &str> {
+        let (_, res) = self.results.iter().find(|(c, _)| *c == capability).unwrap();
+        return res.as_ref().map(|_| ()).map_err(|e| e.as_str());
+    }
+
+    pub fn all(&self) -> &[(Capability, Result<(), String>)] {
+        return &self.results;
+    }
+}
+
+pub fn capabilities() -> &'static Capabilities {
+    static CAPABILITIES: OnceLock<Capabilities> = OnceLock::new();
+    return CAPABILITIES.get_or_init(
+        || Capabilities { results: Capability::ALL.iter().map(|c| (*c, c.probe())).collect() },
+    );
+}
+
+/// Fail early with what's missing if the host doesn't have what a feature needs,
+/// ex: `require(Capability::Systemd, "`system service`")`.
+pub fn require(capability: Capability, feature: &str) -> Result<(), loga::Error> {
+    if let Err(missing) = capabilities().check(capability) {
+        return Err(
+            loga::err_with(
+                format!("{} needs {}, but {}", feature, capability.name(), missing),
+                ea!(capability = capability.name()),
+            ),
+        );
+    }
+    return Ok(());
+}
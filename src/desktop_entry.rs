@@ -0,0 +1,98 @@
+use {
+    crate::root_dir,
+    directories::BaseDirs,
+    loga::{
+        ea,
+        ResultContext,
+    },
+    std::{
+        env::current_exe,
+        path::{
+            Path,
+            PathBuf,
+        },
+    },
+};
+
+/// The desktop file (and icon) name for a program in a system, ex:
+/// `winebasin-games-Setup`.
+pub fn desktop_entry_id(system_name: &str, exe: &str) -> String {
+    let stem = Path::new(exe).file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let stem = stem.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+        c
+    } else {
+        '_'
+    }).collect::<String>();
+    return format!("winebasin-{}-{}", system_name, stem);
+}
+
+/// Where desktop environments look for the user's application menu entries, ex:
+/// `~/.local/share/applications/`.
+pub fn desktop_entry_path(system_name: &str, exe: &str) -> Result<PathBuf, loga::Error> {
+    let base = BaseDirs::new().context("Unable to determine user data directory")?;
+    return Ok(
+        base.data_dir().join("applications").join(format!("{}.desktop", desktop_entry_id(system_name, exe))),
+    );
+}
+
+/// Quote an `Exec` argument, per the desktop entry spec.
+pub fn quote_exec_arg(arg: &str) -> String {
+    let plain = !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./:=+,@".contains(c));
+    let out = if plain {
+        arg.to_string()
+    } else {
+        let mut out = String::from("\"");
+        for c in arg.chars() {
+            if "\"`$\\".contains(c) {
+                out.push('\\');
+            }
+            out.push(c);
+        }
+        out.push('"');
+        out
+    };
+
+    // Escape field codes and, since it's a string value, backslashes
+    return out.replace('\\', "\\\\").replace('%', "%%");
+}
+
+/// The command line that starts `exe` (relative to `drive_c`) in the system with
+/// `system run`, for launchers.
+pub fn system_run_command_line(system_name: &str, exe: &str) -> Result<Vec<String>, loga::Error> {
+    let winebasin = current_exe().context("Error determining path to winebasin executable")?;
+    let root = root_dir()?;
+    return Ok(
+        vec![
+            winebasin
+                .to_str()
+                .context_with("Winebasin executable path isn't utf-8", ea!(path = winebasin.to_string_lossy()))?
+                .to_string(),
+            "--root".to_string(),
+            root
+                .to_str()
+                .context_with("Winebasin data root path isn't utf-8", ea!(path = root.to_string_lossy()))?
+                .to_string(),
+            "system".to_string(),
+            "run".to_string(),
+            system_name.to_string(),
+            exe.to_string()
+        ],
+    );
+}
+
+/// Build a desktop entry that starts `exe` (relative to `drive_c`) in the system
+/// with `system run`. `icon` is an icon name from the theme or a path.
+pub fn desktop_entry(system_name: &str, exe: &str, title: &str, icon: Option<&str>) -> Result<String, loga::Error> {
+    let args = system_run_command_line(system_name, exe)?;
+    let mut out =
+        format!(
+            "[Desktop Entry]\nType=Application\nName={}\nComment=Run in winebasin system {}\nExec={}\nTerminal=false\n",
+            title.replace('\n', " "),
+            system_name,
+            args.iter().map(|a| quote_exec_arg(a)).collect::<Vec<_>>().join(" ")
+        );
+    if let Some(icon) = icon {
+        out.push_str(&format!("Icon={}\n", icon));
+    }
+    return Ok(out);
+}
@@ -0,0 +1,121 @@
+use {
+    loga::{
+        ea,
+        ResultContext,
+    },
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+    std::{
+        collections::BTreeMap,
+        fs,
+        io,
+        os::unix::fs::symlink,
+        path::{
+            Path,
+            PathBuf,
+        },
+    },
+};
+
+/// What the `z:` drive maps to. Wine maps it to the whole host filesystem.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ZDrive {
+    /// The whole host filesystem, like wine does (restores it if it was changed)
+    Host,
+    /// No `z:` drive
+    Remove,
+    /// Only this host directory
+    Path(PathBuf),
+}
+
+/// Replace a drive's link in the prefix's `dosdevices`, or remove it if `target`
+/// is `None`.
+fn set_drive_link(prefix: &Path, drive: &str, target: Option<&Path>) -> Result<(), loga::Error> {
+    let dosdevices = prefix.join("dosdevices");
+    let link = dosdevices.join(drive);
+    if let Ok(current) = fs::read_link(&link) {
+        if Some(current.as_path()) == target {
+            return Ok(());
+        }
+    }
+    match fs::remove_file(&link) {
+        Ok(_) => { },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => { },
+        Err(e) => return Err(
+            loga::err_with("Error removing drive link", ea!(path = link.to_string_lossy(), err = e)),
+        ),
+    }
+    if let Some(target) = target {
+        fs::create_dir_all(
+            &dosdevices,
+        ).context_with("Error creating dosdevices dir", ea!(path = dosdevices.to_string_lossy()))?;
+        symlink(target, &link).context_with("Error creating drive link", ea!(path = link.to_string_lossy()))?;
+    }
+    return Ok(());
+}
+
+/// The host path of a Windows path in the prefix (ex: `C:\Games\game.exe`),
+/// through the drive's `dosdevices` link.
+pub fn unix_path(prefix: &Path, path: &str) -> Result<PathBuf, loga::Error> {
+    let path = path.replace('\\', "/");
+    let Some(
+        (drive, rest)
+    ) = path.split_once(':').filter(|(d, _)| d.len() == 1 && d.chars().all(|c| c.is_ascii_alphabetic())) else {
+        return Err(loga::err_with("Not an absolute Windows path", ea!(path = path)));
+    };
+    let drive = drive.to_ascii_lowercase();
+    let root = if drive == "c" {
+        prefix.join("drive_c")
+    } else {
+        prefix.join("dosdevices").join(format!("{}:", drive))
+    };
+    return Ok(root.join(rest.trim_start_matches('/')));
+}
+
+/// Check a drive name from the config (ex: `d:`), returning the `dosdevices` link
+/// name. `c:` is the prefix's `drive_c` and `z:` is set with `z_drive`.
+fn drive_link_name(drive: &str) -> Result<String, loga::Error> {
+    let name = drive.to_ascii_lowercase();
+    match name.as_bytes() {
+        [letter @ b'a' ..= b'y', b':'] if *letter != b'c' => return Ok(name),
+        _ => return Err(
+            loga::err_with(
+                "Invalid drive, expected a letter and colon (ex: `d:`), other than `c:` and `z:`",
+                ea!(drive = drive),
+            ),
+        ),
+    }
+}
+
+/// Link the extra drives (drive to host path) into the mounted prefix's
+/// `dosdevices`.
+pub fn apply_drives(prefix: &Path, drives: &BTreeMap<String, PathBuf>) -> Result<(), loga::Error> {
+    for (drive, target) in drives {
+        set_drive_link(prefix, &drive_link_name(drive)?, Some(target))?;
+    }
+    return Ok(());
+}
+
+/// Remove the extra drives' links from the system's prefix (the upper layer, while
+/// unmounted). Links that were changed to point elsewhere are kept.
+pub fn remove_drives(prefix: &Path, drives: &BTreeMap<String, PathBuf>) -> Result<(), loga::Error> {
+    for (drive, target) in drives {
+        let name = drive_link_name(drive)?;
+        if fs::read_link(prefix.join("dosdevices").join(&name)).ok().as_ref() == Some(target) {
+            set_drive_link(prefix, &name, None)?;
+        }
+    }
+    return Ok(());
+}
+
+/// Make the mounted prefix's `z:` drive match the setting.
+pub fn apply_z_drive(prefix: &Path, z_drive: &ZDrive) -> Result<(), loga::Error> {
+    match z_drive {
+        ZDrive::Host => return set_drive_link(prefix, "z:", Some(Path::new("/"))),
+        ZDrive::Remove => return set_drive_link(prefix, "z:", None),
+        ZDrive::Path(path) => return set_drive_link(prefix, "z:", Some(path)),
+    }
+}
@@ -0,0 +1,179 @@
+use {
+    crate::{
+        root_dir,
+        wine_bin,
+        winetricks::{
+            download,
+            sha256_file,
+        },
+        Arch,
+        CommandRun,
+    },
+    loga::{
+        ea,
+        ResultContext,
+        StandardFlag,
+        StandardLog,
+    },
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+    std::{
+        collections::HashMap,
+        ffi::OsString,
+        fs,
+        path::{
+            Path,
+            PathBuf,
+        },
+        process::Command,
+    },
+};
+
+/// Installed by `basis dxvk install` when no version is specified.
+pub const DEFAULT_DXVK_VERSION: &str = "2.4";
+
+/// DLLs DXVK replaces. Older releases don't have all of them.
+const DXVK_DLLS: &[&str] = &["d3d8", "d3d9", "d3d10core", "d3d11", "dxgi"];
+
+/// The DXVK release installed in a basis, recorded in the basis config.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BasisDxvk {
+    /// Release version, ex: `2.4`.
+    pub version: String,
+    /// SHA-256 of the release archive, hex, so recreating the basis installs the same
+    /// files.
+    pub sha256: String,
+}
+
+fn dxvk_archive_path(version: &str) -> Result<PathBuf, loga::Error> {
+    return Ok(root_dir()?.join("cache").join("dxvk").join(format!("dxvk-{}.tar.gz", version)));
+}
+
+/// Download the release archive to the cache if it isn't already there, and check
+/// it against `sha256` if specified. Returns the archive's checksum.
+pub fn fetch_dxvk(log: &StandardLog, version: &str, sha256: Option<&str>) -> Result<String, loga::Error> {
+    let path = dxvk_archive_path(version)?;
+    let log = log.fork(ea!(version = version, path = path.to_string_lossy()));
+    if path.exists() {
+        let have = sha256_file(&path)?;
+        match sha256 {
+            Some(want) if !have.eq_ignore_ascii_case(want) => {
+                log.log(
+                    StandardFlag::Warning,
+                    "Cached DXVK release doesn't match its checksum, downloading it again",
+                );
+            },
+            _ => return Ok(have),
+        }
+    }
+    let dir = path.parent().unwrap();
+    fs::create_dir_all(dir).context_with("Error creating DXVK cache dir", ea!(path = dir.to_string_lossy()))?;
+    let download_path = path.with_extension("download");
+    let url = format!("https://github.com/doitsujin/dxvk/releases/download/v{0}/dxvk-{0}.tar.gz", version);
+    log.log_with(StandardFlag::Info, "Downloading DXVK", ea!(url = url));
+    download(&url, &download_path)?;
+    let have = sha256_file(&download_path)?;
+    if let Some(want) = sha256 {
+        if !have.eq_ignore_ascii_case(want) {
+            _ = fs::remove_file(&download_path);
+            return Err(
+                log.err_with("Downloaded DXVK release doesn't match the checksum", ea!(want = want, have = have)),
+            );
+        }
+    }
+    fs::rename(
+        &download_path,
+        &path,
+    ).context_with("Error moving downloaded DXVK into place", ea!(path = path.to_string_lossy()))?;
+    return Ok(have);
+}
+
+/// Where the DLLs for each architecture in the release go in the prefix.
+fn dll_dirs(arch: Arch) -> &'static [(&'static str, &'static str)] {
+    match arch {
+        Arch::Win32 => return &[("x32", "drive_c/windows/system32")],
+        Arch::Win64 | Arch::Wow64 => {
+            return &[("x64", "drive_c/windows/system32"), ("x32", "drive_c/windows/syswow64")];
+        },
+    }
+}
+
+fn import_overrides(env: &HashMap<OsString, OsString>, prefix: &Path, value: &str) -> Result<(), loga::Error> {
+    let mut reg = "REGEDIT4\n\n[HKEY_CURRENT_USER\\Software\\Wine\\DllOverrides]\n".to_string();
+    for dll in DXVK_DLLS {
+        reg.push_str(&format!("\"{}\"={}\n", dll, value));
+    }
+    let import_path = prefix.join("dxvk-overrides.reg");
+    fs::write(
+        &import_path,
+        reg,
+    ).context_with("Error writing DXVK overrides to import", ea!(path = import_path.to_string_lossy()))?;
+    let res = Command::new(wine_bin(env)).envs(env).arg("regedit").arg("/S").arg(&import_path).run();
+    _ = fs::remove_file(&import_path);
+    return res;
+}
+
+/// Copy the DXVK DLLs from the cached release (see `fetch_dxvk`, which must have
+/// been called) into the prefix and make wine use them instead of its own.
+pub fn install_dxvk(
+    env: &HashMap<OsString, OsString>,
+    prefix: &Path,
+    arch: Arch,
+    dxvk: &BasisDxvk,
+) -> Result<(), loga::Error> {
+    let archive = dxvk_archive_path(&dxvk.version)?;
+    let have = sha256_file(&archive)?;
+    if !have.eq_ignore_ascii_case(&dxvk.sha256) {
+        return Err(
+            loga::err_with(
+                "Cached DXVK release doesn't match the checksum in the basis config",
+                ea!(path = archive.to_string_lossy(), want = dxvk.sha256, have = have),
+            ),
+        );
+    }
+    let unpack_dir = tempfile::tempdir().context("Error creating temporary dir to unpack DXVK in")?;
+    Command::new("tar")
+        .arg("--extract")
+        .arg("--file")
+        .arg(&archive)
+        .arg("--directory")
+        .arg(unpack_dir.path())
+        .arg("--strip-components=1")
+        .run()?;
+    for (source, dest) in dll_dirs(arch) {
+        let source = unpack_dir.path().join(source);
+        let dest = prefix.join(dest);
+        fs::create_dir_all(
+            &dest,
+        ).context_with("Error creating DLL dir in prefix", ea!(path = dest.to_string_lossy()))?;
+        for dll in DXVK_DLLS {
+            let file = format!("{}.dll", dll);
+            if !source.join(&file).exists() {
+                continue;
+            }
+            fs::copy(
+                source.join(&file),
+                dest.join(&file),
+            ).context_with("Error copying DXVK DLL into prefix", ea!(dll = file, path = dest.to_string_lossy()))?;
+        }
+    }
+    import_overrides(env, prefix, "\"native\"")?;
+    return Ok(());
+}
+
+/// Remove the overrides and the DXVK DLLs. Wine's own DLLs have to be restored
+/// afterwards by updating the prefix.
+pub fn remove_dxvk(env: &HashMap<OsString, OsString>, prefix: &Path, arch: Arch) -> Result<(), loga::Error> {
+    import_overrides(env, prefix, "-")?;
+    for (_, dest) in dll_dirs(arch) {
+        for dll in DXVK_DLLS {
+            let path = prefix.join(dest).join(format!("{}.dll", dll));
+            if path.exists() {
+                fs::remove_file(&path).context_with("Error removing DXVK DLL", ea!(path = path.to_string_lossy()))?;
+            }
+        }
+    }
+    return Ok(());
+}
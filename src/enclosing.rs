@@ -0,0 +1,103 @@
+use {
+    crate::{
+        basis_path,
+        check_system,
+        read_basis_config,
+        root_dir,
+        system_path,
+    },
+    loga::{
+        ea,
+        StandardFlag,
+        StandardLog,
+    },
+    std::{
+        env,
+        fs,
+        path::{
+            Component,
+            Path,
+        },
+        sync::OnceLock,
+    },
+};
+
+/// The basis or system prefix this process is running in (ex: started from a
+/// `system shell`), found from `WINEPREFIX`. The process that started the shell
+/// holds shared locks on them until the shell exits.
+pub struct Enclosing {
+    pub basis_name: String,
+    pub system_name: Option<String>,
+}
+
+static ENCLOSING: OnceLock<Option<Enclosing>> = OnceLock::new();
+
+pub fn enclosing() -> Option<&'static Enclosing> {
+    return ENCLOSING.get().and_then(|e| e.as_ref());
+}
+
+/// `basis/NAME/prefix/...` or `system/NAME/mount/...` relative to the root.
+fn parse_prefix(root: &Path, prefix: &Path) -> Option<(String, String)> {
+    let rel = prefix.strip_prefix(root).ok()?;
+    let mut components = rel.components().filter_map(|c| match c {
+        Component::Normal(c) => Some(c.to_string_lossy().to_string()),
+        _ => None,
+    });
+    let kind = components.next()?;
+    let name = components.next()?;
+    let dir = components.next()?;
+    match (kind.as_str(), dir.as_str()) {
+        ("basis", "prefix") | ("system", "mount") => return Some((kind, name)),
+        _ => return None,
+    }
+}
+
+fn find_enclosing(root: &Path, prefix: &Path) -> Option<Enclosing> {
+    let (kind, name) = parse_prefix(root, prefix).or_else(|| {
+        // Either may be reached through a symlink
+        return parse_prefix(&fs::canonicalize(root).ok()?, &fs::canonicalize(prefix).ok()?);
+    })?;
+    if kind == "basis" {
+        return Some(Enclosing {
+            basis_name: name,
+            system_name: None,
+        });
+    }
+    let basis_name = check_system(&system_path(&name).ok()?).ok()?.basis_name;
+    return Some(Enclosing {
+        basis_name: basis_name,
+        system_name: Some(name),
+    });
+}
+
+/// If `WINEPREFIX` points into a basis or system, warn and remove the wine
+/// environment variables inherited from its shell so they don't leak into other
+/// prefixes. Call once at startup, before starting threads that use the
+/// environment.
+pub fn detect_enclosing(log: &StandardLog) -> Result<(), loga::Error> {
+    let found = match env::var_os("WINEPREFIX") {
+        Some(prefix) => find_enclosing(&root_dir()?, Path::new(&prefix)),
+        None => None,
+    };
+    if let Some(found) = &found {
+        log.log_with(
+            StandardFlag::Warning,
+            "Running inside a winebasin prefix (ex: from `system shell`), ignoring the wine environment inherited from it. The basis and system can't be modified until the shell exits.",
+            ea!(basis = found.basis_name, system = found.system_name.as_deref().unwrap_or("-")),
+        );
+        let mut inherited = vec!["WINEPREFIX".to_string(), "WINEARCH".to_string(), "WINEDLLOVERRIDES".to_string()];
+        if let Ok(config) = basis_path(&found.basis_name).and_then(|p| read_basis_config(&p)) {
+            inherited.extend(config.env.into_keys());
+        }
+        if let Some(system_name) = &found.system_name {
+            if let Ok(config) = system_path(system_name).and_then(|p| check_system(&p)) {
+                inherited.extend(config.env.into_keys());
+            }
+        }
+        for key in inherited {
+            env::remove_var(key);
+        }
+    }
+    _ = ENCLOSING.set(found);
+    return Ok(());
+}
@@ -0,0 +1,321 @@
+use {
+    crate::{
+        capabilities::{
+            require,
+            Capability,
+        },
+        runtime::{
+            mounted_systems,
+            prefix_processes,
+        },
+        signals,
+        system_mount_path,
+        system_path,
+        CommandRun,
+    },
+    aargvark::{
+        AargvarkFromStr,
+        HelpPattern,
+        HelpPatternElement,
+        HelpState,
+    },
+    loga::{
+        ea,
+        ResultContext,
+        StandardFlag,
+        StandardLog,
+    },
+    std::{
+        collections::HashSet,
+        fs::{
+            self,
+            File,
+        },
+        io::Read,
+        mem::size_of,
+        path::PathBuf,
+        process::Command,
+        sync::mpsc,
+        thread,
+    },
+};
+
+const EV_KEY: u16 = 1;
+const KEY_PRESS: i32 = 1;
+
+/// Linux key codes (`linux/input-event-codes.h`) for both sides of each modifier.
+const MODIFIERS: &[(&str, [u16; 2])] =
+    &[("ctrl", [29, 97]), ("shift", [42, 54]), ("alt", [56, 100]), ("super", [125, 126])];
+
+/// Key codes for the keys that can be used with the modifiers.
+const KEYS: &[(&str, u16)] =
+    &[
+        ("escape", 1),
+        ("backspace", 14),
+        ("f1", 59),
+        ("f2", 60),
+        ("f3", 61),
+        ("f4", 62),
+        ("f5", 63),
+        ("f6", 64),
+        ("f7", 65),
+        ("f8", 66),
+        ("f9", 67),
+        ("f10", 68),
+        ("scrolllock", 70),
+        ("f11", 87),
+        ("f12", 88),
+        ("sysrq", 99),
+        ("home", 102),
+        ("end", 107),
+        ("insert", 110),
+        ("delete", 111),
+        ("pause", 119),
+    ];
+
+/// A key with modifiers, ex: `ctrl+alt+end`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Hotkey {
+    /// Indexes into `MODIFIERS`
+    modifiers: Vec<usize>,
+    key: u16,
+}
+
+impl AargvarkFromStr for Hotkey {
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut modifiers = vec![];
+        let mut key = None;
+        for part in s.to_ascii_lowercase().split('+') {
+            if let Some(i) = MODIFIERS.iter().position(|(name, _)| *name == part) {
+                modifiers.push(i);
+            } else if let Some((_, code)) = KEYS.iter().find(|(name, _)| *name == part) {
+                if key.replace(*code).is_some() {
+                    return Err(format!("Hotkey `{}` has more than one non-modifier key", s));
+                }
+            } else {
+                return Err(
+                    format!(
+                        "Unknown key `{}` in hotkey `{}`, known keys are {}",
+                        part,
+                        s,
+                        MODIFIERS
+                            .iter()
+                            .map(|(name, _)| *name)
+                            .chain(KEYS.iter().map(|(name, _)| *name))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                );
+            }
+        }
+        let Some(key) = key else {
+            return Err(format!("Hotkey `{}` has no non-modifier key", s));
+        };
+        modifiers.sort();
+        modifiers.dedup();
+        return Ok(Hotkey {
+            modifiers: modifiers,
+            key: key,
+        });
+    }
+
+    fn build_help_pattern(_state: &mut HelpState) -> HelpPattern {
+        return HelpPattern(vec![HelpPatternElement::Type("KEY+KEY...".to_string())]);
+    }
+}
+
+impl Hotkey {
+    /// Whether pressing `code` with the keys in `held` down triggers the hotkey. The
+    /// modifiers must match exactly, so `ctrl+alt+end` isn't triggered by
+    /// `ctrl+alt+shift+end`.
+    fn matches(&self, code: u16, held: &HashSet<u16>) -> bool {
+        if code != self.key {
+            return false;
+        }
+        return MODIFIERS
+            .iter()
+            .enumerate()
+            .all(|(i, (_, codes))| self.modifiers.contains(&i) == codes.iter().any(|c| held.contains(c)));
+    }
+}
+
+fn input_devices_dir() -> PathBuf {
+    // Events are written to fifos here by the tests
+    #[cfg(feature = "test-mode")]
+    if let Some(dir) = crate::test_mode::test_dir() {
+        return dir.join("input");
+    }
+    return PathBuf::from("/dev/input");
+}
+
+/// Read key events from an input device, sending `(code, value)` for each until
+/// the device can't be read.
+fn read_keys(path: PathBuf, events: mpsc::Sender<Result<(u16, i32), loga::Error>>) {
+    let mut device = match File::open(&path) {
+        Ok(d) => d,
+        Err(e) => {
+            _ =
+                events.send(
+                    Err(loga::err_with("Error opening input device", ea!(path = path.to_string_lossy(), err = e))),
+                );
+            return;
+        },
+    };
+
+    // The timestamp comes first and differs in size by architecture
+    let mut event = vec![
+        0u8;
+        size_of::<libc::input_event>()
+    ];
+    let at = event.len() - 8;
+    loop {
+        if let Err(e) = device.read_exact(&mut event) {
+            _ =
+                events.send(
+                    Err(loga::err_with("Error reading input device", ea!(path = path.to_string_lossy(), err = e))),
+                );
+            return;
+        }
+        let type_ = u16::from_ne_bytes([event[at], event[at + 1]]);
+        let code = u16::from_ne_bytes([event[at + 2], event[at + 3]]);
+        let value = i32::from_ne_bytes([event[at + 4], event[at + 5], event[at + 6], event[at + 7]]);
+        if type_ != EV_KEY {
+            continue;
+        }
+        if events.send(Ok((code, value))).is_err() {
+            return;
+        }
+    }
+}
+
+/// Break all pointer and keyboard grabs using the X server's `XF86Ungrab` action,
+/// which works even while another program holds the grab.
+fn release_grabs() -> Result<(), loga::Error> {
+    return Command::new("xdotool").arg("key").arg("XF86Ungrab").run();
+}
+
+/// Kill the program owning the focused window, if it's running in a mounted system.
+fn kill_focused(log: &StandardLog) -> Result<(), loga::Error> {
+    let output =
+        Command::new("xdotool")
+            .arg("getactivewindow")
+            .arg("getwindowpid")
+            .output()
+            .context("Error running `xdotool` to find the focused window")?;
+    let Some(pid) = String::from_utf8_lossy(&output.stdout).trim().parse::<u32>().ok() else {
+        log.log(StandardFlag::Warning, "Couldn't determine which program owns the focused window");
+        return Ok(());
+    };
+    for (system_name, _) in mounted_systems()? {
+        let processes = prefix_processes(&system_mount_path(&system_path(&system_name)?))?;
+        let Some(process) = processes.into_iter().find(|p| p.pid == pid) else {
+            continue;
+        };
+        signals::kill(pid, libc::SIGKILL);
+        log.log_with(
+            StandardFlag::Info,
+            "Killed focused program",
+            ea!(system = system_name, pid = pid, command = process.cmdline.join(" ")),
+        );
+        return Ok(());
+    }
+    log.log_with(StandardFlag::Warning, "Focused window isn't a program in a mounted system", ea!(pid = pid));
+    return Ok(());
+}
+
+/// Watch the keyboards for the hotkeys until terminated. `release` breaks input
+/// grabs, `kill` also kills the focused program if it's running in a mounted
+/// system. Keys are read from the input devices rather than registered with the
+/// desktop, so they work while a program is grabbing the keyboard.
+pub fn supervise(log: &StandardLog, release: &Hotkey, kill: &Hotkey) -> Result<(), loga::Error> {
+    require(Capability::Xdotool, "The escape supervisor")?;
+
+    // Enables the `XF86Ungrab` action
+    Command::new("setxkbmap").arg("-option").arg("grab:break_actions").run()?;
+    let devices_dir = input_devices_dir();
+    let (events_send, events) = mpsc::channel();
+    let mut devices = 0;
+    for entry in fs::read_dir(
+        &devices_dir,
+    ).context_with("Error listing input devices", ea!(path = devices_dir.to_string_lossy()))? {
+        let entry = entry.context("Error reading input device entry")?;
+        if !entry.file_name().to_string_lossy().starts_with("event") {
+            continue;
+        }
+        let events_send = events_send.clone();
+        thread::spawn(move || read_keys(entry.path(), events_send));
+        devices += 1;
+    }
+    drop(events_send);
+    if devices == 0 {
+        return Err(loga::err_with("No input devices found", ea!(path = devices_dir.to_string_lossy())));
+    }
+    log.log(StandardFlag::Info, "Watching for hotkeys");
+    let mut held = HashSet::new();
+    let mut failed = vec![];
+    for event in events {
+        let (code, value) = match event {
+            Ok(e) => e,
+            Err(e) => {
+                failed.push(e);
+                continue;
+            },
+        };
+        if value == 0 {
+            held.remove(&code);
+            continue;
+        }
+        let pressed = value == KEY_PRESS && held.insert(code);
+        if !pressed {
+            // Key repeat
+            continue;
+        }
+        if release.matches(code, &held) {
+            if let Err(e) = release_grabs() {
+                log.log_err(StandardFlag::Warning, e.context("Error releasing input grabs"));
+            } else {
+                log.log(StandardFlag::Info, "Released input grabs");
+            }
+        } else if kill.matches(code, &held) {
+            if let Err(e) = release_grabs() {
+                log.log_err(StandardFlag::Warning, e.context("Error releasing input grabs"));
+            }
+            if let Err(e) = kill_focused(log) {
+                log.log_err(StandardFlag::Warning, e.context("Error killing focused program"));
+            }
+        }
+    }
+
+    // Every device reader stopped
+    return Err(
+        loga::agg_err(
+            "Can't read any input devices, the supervisor needs to be in the `input` group (or run as root)",
+            failed,
+        ),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_hotkeys() {
+        let hotkey = Hotkey::from_str("Ctrl+Alt+End").unwrap();
+        assert_eq!(hotkey.key, 107);
+        assert_eq!(hotkey.modifiers, vec![0, 2]);
+        assert!(Hotkey::from_str("ctrl+alt").is_err());
+        assert!(Hotkey::from_str("ctrl+end+home").is_err());
+        assert!(Hotkey::from_str("ctrl+q").is_err());
+    }
+
+    #[test]
+    fn exact_modifiers() {
+        let hotkey = Hotkey::from_str("ctrl+alt+end").unwrap();
+        assert!(hotkey.matches(107, &HashSet::from([29, 56, 107])));
+        assert!(hotkey.matches(107, &HashSet::from([97, 100, 107])));
+        assert!(!hotkey.matches(107, &HashSet::from([29, 107])));
+        assert!(!hotkey.matches(107, &HashSet::from([29, 42, 56, 107])));
+        assert!(!hotkey.matches(102, &HashSet::from([29, 56, 102])));
+    }
+}
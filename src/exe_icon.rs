@@ -0,0 +1,474 @@
+use {
+    crate::health::PrefixView,
+    directories::BaseDirs,
+    loga::{
+        ea,
+        ResultContext,
+        StandardFlag,
+        StandardLog,
+    },
+    std::{
+        collections::BTreeMap,
+        fs,
+        path::Path,
+    },
+};
+
+// Resource types, from `winuser.h`
+const RT_ICON: u32 = 3;
+const RT_GROUP_ICON: u32 = 14;
+
+/// Icon sizes in the hicolor theme's directories.
+const HICOLOR_SIZES: &[u32] = &[16, 22, 24, 32, 48, 64, 96, 128, 256];
+
+/// One image of a program's icon.
+pub struct IconImage {
+    pub size: u32,
+    pub png: Vec<u8>,
+}
+
+fn read_u16(data: &[u8], at: usize) -> Result<u16, String> {
+    return at
+        .checked_add(2)
+        .and_then(|end| data.get(at .. end))
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| format!("Truncated executable (reading offset {})", at));
+}
+
+fn read_u32(data: &[u8], at: usize) -> Result<u32, String> {
+    return at
+        .checked_add(4)
+        .and_then(|end| data.get(at .. end))
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| format!("Truncated executable (reading offset {})", at));
+}
+
+/// `base + add`, for offsets read from the file.
+fn add_offset(base: usize, add: usize) -> Result<usize, String> {
+    return base.checked_add(add).ok_or_else(|| "Executable offset out of range".to_string());
+}
+
+/// The resources of a PE executable (`.exe`, `.dll`).
+struct Resources<'a> {
+    data: &'a [u8],
+    /// File offset of the resource directory
+    root: usize,
+    /// Section mappings, (virtual address, size, file offset)
+    sections: Vec<(u32, u32, u32)>,
+}
+
+impl<'a> Resources<'a> {
+    fn parse(data: &'a [u8]) -> Result<Option<Resources<'a>>, String> {
+        if data.get(0 .. 2) != Some(b"MZ") {
+            return Err("Not a Windows executable (missing `MZ` header)".to_string());
+        }
+        let pe = read_u32(data, 0x3c)? as usize;
+        if data.get(pe .. pe + 4) != Some(b"PE\0\0") {
+            return Err("Not a PE executable (missing `PE` header)".to_string());
+        }
+        let section_count = read_u16(data, pe + 6)? as usize;
+        let optional = pe + 24;
+        let optional_size = read_u16(data, pe + 20)? as usize;
+        let (dir_count_at, dirs_at) = match read_u16(data, optional)? {
+            0x10b => (optional + 92, optional + 96),
+            0x20b => (optional + 108, optional + 112),
+            magic => return Err(format!("Unknown PE optional header type {:#x}", magic)),
+        };
+        if read_u32(data, dir_count_at)? <= 2 {
+            return Ok(None);
+        }
+        let resource_rva = read_u32(data, dirs_at + 2 * 8)?;
+        if resource_rva == 0 {
+            return Ok(None);
+        }
+        let mut sections = vec![];
+        for i in 0 .. section_count {
+            let at = optional + optional_size + i * 40;
+            let virtual_size = read_u32(data, at + 8)?;
+            let raw_size = read_u32(data, at + 16)?;
+            sections.push((read_u32(data, at + 12)?, virtual_size.max(raw_size), read_u32(data, at + 20)?));
+        }
+        let mut out = Resources {
+            data: data,
+            root: 0,
+            sections: sections,
+        };
+        out.root = out.rva_offset(resource_rva)?;
+        return Ok(Some(out));
+    }
+
+    fn rva_offset(&self, rva: u32) -> Result<usize, String> {
+        for (address, size, offset) in &self.sections {
+            let Some(within) = rva.checked_sub(*address) else {
+                continue;
+            };
+            if within < *size {
+                return offset
+                    .checked_add(within)
+                    .map(|o| o as usize)
+                    .ok_or_else(|| format!("Executable address {:#x} maps past the end of the file", rva));
+            }
+        }
+        return Err(format!("Executable address {:#x} isn't in any section", rva));
+    }
+
+    /// Entries of a resource directory, (name or ID, offset and subdirectory flag).
+    /// Named entries come first and are given as `None`.
+    fn entries(&self, dir: usize) -> Result<Vec<(Option<u32>, u32)>, String> {
+        let dir = add_offset(self.root, dir)?;
+        let named = read_u16(self.data, add_offset(dir, 12)?)? as usize;
+        let ids = read_u16(self.data, add_offset(dir, 14)?)? as usize;
+        let mut out = vec![];
+        for i in 0 .. named + ids {
+            let at = add_offset(dir, 16 + i * 8)?;
+            let name = read_u32(self.data, at)?;
+            out.push((if name & 0x8000_0000 == 0 {
+                Some(name)
+            } else {
+                None
+            }, read_u32(self.data, add_offset(at, 4)?)?));
+        }
+        return Ok(out);
+    }
+
+    /// The data of the first resource of the type (and ID, if given), in any language.
+    fn find(&self, type_: u32, id: Option<u32>) -> Result<Option<&'a [u8]>, String> {
+        let mut dir = 0usize;
+        for want in [Some(type_), id] {
+            let entries = self.entries(dir)?;
+            let Some((_, offset)) = entries.into_iter().find(|(name, _)| want.is_none() || *name == want) else {
+                return Ok(None);
+            };
+            if offset & 0x8000_0000 == 0 {
+                return Err("Malformed executable resource directory".to_string());
+            }
+            dir = (offset & 0x7fff_ffff) as usize;
+        }
+
+        // Language directory, first language
+        let Some((_, offset)) = self.entries(dir)?.into_iter().next() else {
+            return Ok(None);
+        };
+        let entry = add_offset(self.root, offset as usize)?;
+        let start = self.rva_offset(read_u32(self.data, entry)?)?;
+        let size = read_u32(self.data, entry + 4)? as usize;
+        return Ok(
+            Some(
+                start
+                    .checked_add(size)
+                    .and_then(|end| self.data.get(start .. end))
+                    .ok_or_else(|| "Truncated executable resource".to_string())?,
+            ),
+        );
+    }
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0 .. 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    return !crc;
+}
+
+/// Encode RGBA pixels (rows top to bottom) as a PNG, without compression.
+fn encode_png(width: u32, height: u32, rgba: &[u8]) -> Vec<u8> {
+    let mut raw = vec![];
+    for row in rgba.chunks(width as usize * 4) {
+        // Filter type none
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    let mut zlib = vec![0x78, 0x01];
+    let blocks = raw.chunks(0xffff).collect::<Vec<_>>();
+    for (i, block) in blocks.iter().enumerate() {
+        zlib.push(if i == blocks.len() - 1 {
+            1
+        } else {
+            0
+        });
+        zlib.extend((block.len() as u16).to_le_bytes());
+        zlib.extend((!(block.len() as u16)).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in &raw {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    zlib.extend(((b << 16) | a).to_be_bytes());
+    let mut out = b"\x89PNG\r\n\x1a\n".to_vec();
+    let mut chunk = |type_: &[u8], body: &[u8]| {
+        out.extend((body.len() as u32).to_be_bytes());
+        let mut crc_data = type_.to_vec();
+        crc_data.extend_from_slice(body);
+        out.extend_from_slice(&crc_data);
+        out.extend(crc32(&crc_data).to_be_bytes());
+    };
+    let mut header = vec![];
+    header.extend(width.to_be_bytes());
+    header.extend(height.to_be_bytes());
+
+    // 8 bit RGBA, default compression, filtering and no interlacing
+    header.extend([8, 6, 0, 0, 0]);
+    chunk(b"IHDR", &header);
+    chunk(b"IDAT", &zlib);
+    chunk(b"IEND", &[]);
+    return out;
+}
+
+/// Convert an icon image stored as a DIB (`BITMAPINFOHEADER`, pixels, then the
+/// transparency mask) to a PNG.
+fn dib_to_png(data: &[u8]) -> Result<(u32, Vec<u8>), String> {
+    let header_size = read_u32(data, 0)? as usize;
+    let width = read_u32(data, 4)?;
+
+    // Includes the mask
+    let height = read_u32(data, 8)? / 2;
+    let bit_count = read_u16(data, 14)? as u32;
+    if width == 0 || width > 1024 || height == 0 || height > 1024 {
+        return Err(format!("Unsupported icon size {}x{}", width, height));
+    }
+    if read_u32(data, 16)? != 0 {
+        return Err("Compressed icon bitmaps aren't supported".to_string());
+    }
+    let mut palette = vec![];
+    if bit_count <= 8 {
+        let colors = match read_u32(data, 32)? {
+            0 => 1 << bit_count,
+            n => n,
+        };
+        for i in 0 .. colors as usize {
+            let at = header_size + i * 4;
+            let bgr = data.get(at .. at + 3).ok_or_else(|| "Truncated icon palette".to_string())?;
+            palette.push([bgr[2], bgr[1], bgr[0], 255]);
+        }
+    }
+    let pixels = header_size + palette.len() * 4;
+    let stride = (width * bit_count).div_ceil(32) as usize * 4;
+    let mask = pixels + stride * height as usize;
+    let mask_stride = width.div_ceil(32) as usize * 4;
+    let mut rgba = vec![
+        0u8;
+        (width * height * 4) as usize
+    ];
+    let mut any_alpha = false;
+    for y in 0 .. height as usize {
+        // Rows are stored bottom to top
+        let row = pixels + (height as usize - 1 - y) * stride;
+        for x in 0 .. width as usize {
+            let pixel = match bit_count {
+                32 | 24 => {
+                    let at = row + x * (bit_count as usize / 8);
+                    let b =
+                        data
+                            .get(at .. at + bit_count as usize / 8)
+                            .ok_or_else(|| "Truncated icon bitmap".to_string())?;
+                    [b[2], b[1], b[0], if bit_count == 32 {
+                        b[3]
+                    } else {
+                        255
+                    }]
+                },
+                8 | 4 | 1 => {
+                    let bit = x * bit_count as usize;
+                    let byte = *data.get(row + bit / 8).ok_or_else(|| "Truncated icon bitmap".to_string())?;
+                    let index = (byte >> (8 - bit_count as usize - bit % 8)) & ((1u16 << bit_count) - 1) as u8;
+                    *palette.get(index as usize).ok_or_else(|| "Icon color isn't in the palette".to_string())?
+                },
+                _ => return Err(format!("Unsupported icon bit depth {}", bit_count)),
+            };
+            any_alpha |= bit_count == 32 && pixel[3] != 0;
+            rgba[(y * width as usize + x) * 4 .. (y * width as usize + x + 1) * 4].copy_from_slice(&pixel);
+        }
+    }
+
+    // Use the mask unless the image has its own transparency
+    if !any_alpha {
+        for y in 0 .. height as usize {
+            let row = mask + (height as usize - 1 - y) * mask_stride;
+            for x in 0 .. width as usize {
+                let transparent = data.get(row + x / 8).map(|b| b & (0x80 >> (x % 8)) != 0).unwrap_or(false);
+                rgba[(y * width as usize + x) * 4 + 3] = if transparent {
+                    0
+                } else {
+                    255
+                };
+            }
+        }
+    }
+    return Ok((width, encode_png(width, height, &rgba)));
+}
+
+/// Extract the images of a Windows executable's main (first) icon, as PNGs. Empty
+/// if it has no icon.
+pub fn exe_icon(data: &[u8]) -> Result<Vec<IconImage>, String> {
+    let Some(resources) = Resources::parse(data)? else {
+        return Ok(vec![]);
+    };
+    let Some(group) = resources.find(RT_GROUP_ICON, None)? else {
+        return Ok(vec![]);
+    };
+    let mut out = vec![];
+    for i in 0 .. read_u16(group, 4)? as usize {
+        let entry = 6 + i * 14;
+        let id = read_u16(group, entry + 12)? as u32;
+        let Some(image) = resources.find(RT_ICON, Some(id))? else {
+            continue;
+        };
+        if image.starts_with(b"\x89PNG") {
+            out.push(IconImage {
+                size: u32::from_be_bytes(
+                    image.get(16 .. 20).ok_or_else(|| "Truncated PNG icon".to_string())?.try_into().unwrap(),
+                ),
+                png: image.to_vec(),
+            });
+        } else {
+            let (size, png) = dib_to_png(image)?;
+            out.push(IconImage {
+                size: size,
+                png: png,
+            });
+        }
+    }
+    return Ok(out);
+}
+
+/// Install the icon's images into the user's hicolor icon theme as `name`. Images
+/// in sizes the theme doesn't have are skipped, unless there are no others, in
+/// which case the largest is installed outside the theme.
+pub fn install_icon(name: &str, images: Vec<IconImage>) -> Result<(), loga::Error> {
+    let base = BaseDirs::new().context("Unable to determine user data directory")?;
+    let icons = base.data_dir().join("icons");
+
+    // The largest file (most colors) of each size
+    let mut by_size = BTreeMap::new();
+    for image in images {
+        if by_size.get(&image.size).is_none_or(|i: &IconImage| i.png.len() < image.png.len()) {
+            by_size.insert(image.size, image);
+        }
+    }
+    let mut writes = vec![];
+    for (size, image) in &by_size {
+        if HICOLOR_SIZES.contains(size) {
+            writes.push(
+                (icons.join(format!("hicolor/{0}x{0}/apps", size)).join(format!("{}.png", name)), &image.png),
+            );
+        }
+    }
+    if writes.is_empty() {
+        if let Some((_, image)) = by_size.iter().next_back() {
+            writes.push((icons.join(format!("{}.png", name)), &image.png));
+        }
+    }
+    for (path, png) in writes {
+        let dir = path.parent().unwrap();
+        fs::create_dir_all(dir).context_with("Error creating icon dir", ea!(path = dir.to_string_lossy()))?;
+        fs::write(&path, png).context_with("Error writing icon", ea!(path = path.to_string_lossy()))?;
+    }
+    return Ok(());
+}
+
+/// Install the icon of a program in a prefix (`exe` relative to `drive_c`) as
+/// `name`, returning the icon name. Programs without a usable icon are skipped
+/// with a warning.
+pub fn install_program_icon(
+    log: &StandardLog,
+    view: &PrefixView,
+    exe: &str,
+    name: &str,
+) -> Result<Option<String>, loga::Error> {
+    let Some(path) = view.resolve(&Path::new("drive_c").join(exe)) else {
+        log.log_with(StandardFlag::Warning, "Program not found in the system, not adding an icon", ea!(exe = exe));
+        return Ok(None);
+    };
+    let data = fs::read(&path).context_with("Error reading program", ea!(path = path.to_string_lossy()))?;
+    match exe_icon(&data) {
+        Ok(images) if !images.is_empty() => {
+            install_icon(name, images)?;
+            return Ok(Some(name.to_string()));
+        },
+        Ok(_) => {
+            log.log_with(StandardFlag::Warning, "Program has no icon", ea!(exe = exe));
+            return Ok(None);
+        },
+        Err(e) => {
+            log.log_with(StandardFlag::Warning, "Couldn't extract the program's icon", ea!(exe = exe, err = e));
+            return Ok(None);
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::exe_icon;
+
+    /// A PE32 executable header with these sections (virtual address, size, file
+    /// offset) and resource directory address, padded to `len`.
+    fn pe(sections: &[(u32, u32, u32)], resource_rva: u32, len: usize) -> Vec<u8> {
+        let mut data = vec![
+            0u8;
+            len
+        ];
+        let mut put = |at: usize, bytes: &[u8]| data[at .. at + bytes.len()].copy_from_slice(bytes);
+        let pe = 0x40usize;
+        let optional = pe + 24;
+        let optional_size = 96 + 3 * 8;
+        put(0, b"MZ");
+        put(0x3c, &(pe as u32).to_le_bytes());
+        put(pe, b"PE\0\0");
+        put(pe + 6, &(sections.len() as u16).to_le_bytes());
+        put(pe + 20, &(optional_size as u16).to_le_bytes());
+        put(optional, &0x10bu16.to_le_bytes());
+        put(optional + 92, &3u32.to_le_bytes());
+        put(optional + 96 + 2 * 8, &resource_rva.to_le_bytes());
+        for (i, (address, size, offset)) in sections.iter().enumerate() {
+            let at = optional + optional_size + i * 40;
+            put(at + 8, &size.to_le_bytes());
+            put(at + 12, &address.to_le_bytes());
+            put(at + 20, &offset.to_le_bytes());
+        }
+        return data;
+    }
+
+    #[test]
+    fn not_executables() {
+        assert!(exe_icon(b"").is_err());
+        assert!(exe_icon(b"MZ").is_err());
+        let mut data = pe(&[], 0, 0x200);
+        data[0x40] = b'X';
+        assert!(exe_icon(&data).is_err());
+    }
+
+    #[test]
+    fn no_resources() {
+        assert!(exe_icon(&pe(&[], 0, 0x200)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncated() {
+        // Header cut off before the sections
+        assert!(exe_icon(&pe(&[(0x1000, 0x1000, 0x200)], 0x1000, 0x200)[..0x90]).is_err());
+
+        // Resource directory past the end of the file
+        assert!(exe_icon(&pe(&[(0x1000, 0x1000, 0x200)], 0x1000, 0x200)).is_err());
+        assert!(exe_icon(&pe(&[(0x1000, 0x1000, 0xffff_0000)], 0x1000, 0x200)).is_err());
+    }
+
+    #[test]
+    fn overflowing_offsets() {
+        // Section offset plus the address within the section doesn't fit
+        assert!(exe_icon(&pe(&[(0x1000, 0x1000, 0xffff_ff00)], 0x1800, 0x200)).is_err());
+        assert!(exe_icon(&pe(&[(0xffff_f000, 0xffff_ffff, 0xffff_ffff)], 0xffff_ffff, 0x200)).is_err());
+
+        // Address before every section
+        assert!(exe_icon(&pe(&[(0x1000, 0x1000, 0x200)], 0x10, 0x200)).is_err());
+    }
+}
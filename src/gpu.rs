@@ -0,0 +1,112 @@
+use {
+    aargvark::{
+        AargvarkFromStr,
+        HelpPattern,
+        HelpPatternElement,
+        HelpState,
+    },
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+    std::{
+        collections::HashMap,
+        ffi::OsString,
+        path::Path,
+    },
+};
+
+/// Which GPU to render with, on machines with more than one (ex: laptops with
+/// integrated and discrete graphics).
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum GpuChoice {
+    Discrete,
+    Integrated,
+    /// A Mesa device index (`DRI_PRIME=N`)
+    Index(u32),
+}
+
+impl TryFrom<String> for GpuChoice {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "discrete" => return Ok(GpuChoice::Discrete),
+            "integrated" => return Ok(GpuChoice::Integrated),
+            s => match s.parse::<u32>() {
+                Ok(i) => return Ok(GpuChoice::Index(i)),
+                Err(_) => return Err(
+                    format!("Expected a GPU (`discrete`, `integrated`, or a device index), got `{}`", s),
+                ),
+            },
+        }
+    }
+}
+
+impl From<GpuChoice> for String {
+    fn from(value: GpuChoice) -> Self {
+        match value {
+            GpuChoice::Discrete => return "discrete".to_string(),
+            GpuChoice::Integrated => return "integrated".to_string(),
+            GpuChoice::Index(i) => return i.to_string(),
+        }
+    }
+}
+
+impl AargvarkFromStr for GpuChoice {
+    fn from_str(s: &str) -> Result<Self, String> {
+        return GpuChoice::try_from(s.to_string());
+    }
+
+    fn build_help_pattern(_state: &mut HelpState) -> HelpPattern {
+        return HelpPattern(vec![HelpPatternElement::Type("discrete|integrated|INDEX".to_string())]);
+    }
+}
+
+/// Whether the NVIDIA proprietary driver is loaded, which uses its own PRIME
+/// render offload variables instead of Mesa's `DRI_PRIME`.
+fn nvidia_driver_loaded() -> bool {
+    return Path::new("/proc/driver/nvidia/version").exists();
+}
+
+/// The NVIDIA Vulkan ICD, to restrict Vulkan to the NVIDIA GPU.
+fn nvidia_vulkan_icd() -> Option<&'static Path> {
+    return ["/usr/share/vulkan/icd.d/nvidia_icd.json", "/etc/vulkan/icd.d/nvidia_icd.json"]
+        .into_iter()
+        .map(Path::new)
+        .find(|p| p.exists());
+}
+
+impl GpuChoice {
+    /// Set the render offload variables for Mesa, and for NVIDIA's driver if it's
+    /// loaded.
+    pub fn apply(&self, env: &mut HashMap<OsString, OsString>) {
+        let mut set = |k: &str, v: &str| {
+            env.insert(OsString::from(k), OsString::from(v));
+        };
+        match self {
+            GpuChoice::Discrete => {
+                set("DRI_PRIME", "1");
+                if nvidia_driver_loaded() {
+                    set("__NV_PRIME_RENDER_OFFLOAD", "1");
+                    set("__GLX_VENDOR_LIBRARY_NAME", "nvidia");
+                    set("__VK_LAYER_NV_optimus", "NVIDIA_only");
+                    if let Some(icd) = nvidia_vulkan_icd() {
+                        set("VK_ICD_FILENAMES", &icd.to_string_lossy());
+                    }
+                }
+            },
+            GpuChoice::Integrated => {
+                set("DRI_PRIME", "0");
+                if nvidia_driver_loaded() {
+                    set("__GLX_VENDOR_LIBRARY_NAME", "mesa");
+                    set("__VK_LAYER_NV_optimus", "non_NVIDIA_only");
+                }
+            },
+            GpuChoice::Index(i) => {
+                set("DRI_PRIME", &i.to_string());
+            },
+        }
+    }
+}
@@ -0,0 +1,291 @@
+use {
+    crate::{
+        basis_path,
+        check_system,
+        echo_command,
+        mount::MountBackend,
+        read_basis_config,
+        root_dir,
+        runtime::{
+            format_size,
+            mounted_systems,
+        },
+        system_path,
+        system_prefix_path,
+        Arch,
+    },
+    loga::{
+        ea,
+        DebugDisplay,
+        ResultContext,
+        StandardLog,
+    },
+    serde_json::json,
+    std::{
+        fs,
+        path::Path,
+        process::{
+            Command,
+            Stdio,
+        },
+    },
+};
+
+pub struct GraphBasis {
+    name: String,
+    /// False if systems refer to a basis that doesn't exist (ex: it was deleted).
+    exists: bool,
+    /// None if the basis config can't be read.
+    arch: Option<Arch>,
+    disk_bytes: Option<u64>,
+}
+
+pub struct GraphSystem {
+    name: String,
+    /// Ok(basis name), or the error reading the system config.
+    basis: Result<String, loga::Error>,
+    disk_bytes: Option<u64>,
+    mounted: Option<MountBackend>,
+}
+
+pub struct Graph {
+    bases: Vec<GraphBasis>,
+    systems: Vec<GraphSystem>,
+}
+
+/// Disk space used by the directory, not counting anything mounted inside it (ex:
+/// a system's overlay mount).
+pub fn disk_usage(path: &Path) -> Result<u64, loga::Error> {
+    let mut command = Command::new("du");
+    command.arg("-s").arg("-x").arg("-B1").arg(path).stderr(Stdio::null());
+    echo_command(&command);
+    let log = StandardLog::new().fork(ea!(command = command.dbg_str()));
+
+    // Exits with an error if some files are unreadable (ex: the root-owned overlay
+    // work dir) but still prints the total of everything else
+    let output = command.output().stack_context(&log, "Failed to run du")?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    return stdout
+        .split_whitespace()
+        .next()
+        .and_then(|x| x.parse::<u64>().ok())
+        .stack_context_with(&log, "Couldn't read disk usage from du output", ea!(status = output.status));
+}
+
+fn list_dir(path: &Path) -> Result<Vec<String>, loga::Error> {
+    let mut out = vec![];
+    if !path.exists() {
+        return Ok(out);
+    }
+    for entry in fs::read_dir(path).context_with("Error listing directory", ea!(path = path.to_string_lossy()))? {
+        let entry = entry.context_with("Error reading directory entry", ea!(path = path.to_string_lossy()))?;
+        out.push(entry.file_name().to_string_lossy().to_string());
+    }
+    out.sort();
+    return Ok(out);
+}
+
+/// Find all bases and systems, which bases systems use, how much disk they use,
+/// and whether systems are mounted.
+pub fn build_graph() -> Result<Graph, loga::Error> {
+    let root = root_dir()?;
+    let mounted = mounted_systems()?;
+    let mut bases = vec![];
+    for name in list_dir(&root.join("basis"))? {
+        let path = basis_path(&name)?;
+        bases.push(GraphBasis {
+            arch: read_basis_config(&path).ok().map(|c| c.arch),
+            disk_bytes: disk_usage(&path).ok(),
+            name: name,
+            exists: true,
+        });
+    }
+    let mut systems = vec![];
+    for name in list_dir(&root.join("system"))? {
+        let path = system_path(&name)?;
+        let basis = check_system(&path).map(|c| c.basis_name);
+        if let Ok(basis_name) = &basis {
+            if !bases.iter().any(|b| b.name == *basis_name) {
+                bases.push(GraphBasis {
+                    name: basis_name.clone(),
+                    exists: false,
+                    arch: None,
+                    disk_bytes: None,
+                });
+            }
+        }
+        systems.push(GraphSystem {
+            disk_bytes: disk_usage(&system_prefix_path(&path)).ok(),
+            mounted: mounted.iter().find(|(n, _)| *n == name).map(|(_, b)| *b),
+            name: name,
+            basis: basis,
+        });
+    }
+    return Ok(Graph {
+        bases: bases,
+        systems: systems,
+    });
+}
+
+fn basis_details(basis: &GraphBasis, graph: &Graph) -> Vec<String> {
+    let mut details = vec![];
+    if !basis.exists {
+        details.push("missing".to_string());
+        return details;
+    }
+    match basis.arch {
+        Some(arch) => details.push(arch.name().to_string()),
+        None => details.push("unreadable config".to_string()),
+    }
+    if let Some(bytes) = basis.disk_bytes {
+        details.push(format_size(bytes));
+    }
+    let mounted =
+        graph
+            .systems
+            .iter()
+            .filter(|s| s.mounted.is_some() && s.basis.as_ref().is_ok_and(|b| *b == basis.name))
+            .count();
+    if mounted > 0 {
+        details.push(format!("in use by {} mounted system{}", mounted, if mounted == 1 {
+            ""
+        } else {
+            "s"
+        }));
+    }
+    return details;
+}
+
+fn system_details(system: &GraphSystem) -> Vec<String> {
+    let mut details = vec![];
+    if let Some(bytes) = system.disk_bytes {
+        details.push(format_size(bytes));
+    }
+    if let Some(backend) = system.mounted {
+        details.push(format!("mounted with {}", backend.name()));
+    }
+    return details;
+}
+
+fn with_details(name: &str, details: &[String]) -> String {
+    if details.is_empty() {
+        return name.to_string();
+    } else {
+        return format!("{} ({})", name, details.join(", "));
+    }
+}
+
+/// Print bases with the systems using them underneath.
+pub fn print_tree(graph: &Graph) {
+    if graph.bases.is_empty() && graph.systems.is_empty() {
+        println!("No bases or systems");
+        return;
+    }
+    for basis in &graph.bases {
+        println!("{}", with_details(&basis.name, &basis_details(basis, graph)));
+        let dependents =
+            graph
+                .systems
+                .iter()
+                .filter(|s| s.basis.as_ref().is_ok_and(|b| *b == basis.name))
+                .collect::<Vec<_>>();
+        for (i, system) in dependents.iter().enumerate() {
+            let branch = if i + 1 == dependents.len() {
+                "└─"
+            } else {
+                "├─"
+            };
+            println!("{} {}", branch, with_details(&system.name, &system_details(system)));
+        }
+    }
+    let broken = graph.systems.iter().filter(|s| s.basis.is_err()).collect::<Vec<_>>();
+    if !broken.is_empty() {
+        println!("Broken systems (fix with `winebasin system repair NAME BASIS`):");
+        for system in broken {
+            if let Err(e) = &system.basis {
+                println!("  {}: {}", with_details(&system.name, &system_details(system)), e);
+            }
+        }
+    }
+}
+
+fn dot_quote(text: &str) -> String {
+    return format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n"));
+}
+
+/// Print the graph in Graphviz format, with edges from each system to its basis.
+pub fn print_dot(graph: &Graph) {
+    println!("digraph winebasin {{");
+    for basis in &graph.bases {
+        let mut label = basis.name.clone();
+        for detail in basis_details(basis, graph) {
+            label.push('\n');
+            label.push_str(&detail);
+        }
+        let style = if basis.exists {
+            ""
+        } else {
+            ", style=dashed"
+        };
+        println!(
+            "  {} [shape=box, label={}{}];",
+            dot_quote(&format!("basis/{}", basis.name)),
+            dot_quote(&label),
+            style
+        );
+    }
+    for system in &graph.systems {
+        let id = dot_quote(&format!("system/{}", system.name));
+        let mut label = system.name.clone();
+        for detail in system_details(system) {
+            label.push('\n');
+            label.push_str(&detail);
+        }
+        match &system.basis {
+            Ok(basis_name) => {
+                let style = if system.mounted.is_some() {
+                    ", style=bold"
+                } else {
+                    ""
+                };
+                println!("  {} [label={}{}];", id, dot_quote(&label), style);
+                println!("  {} -> {};", id, dot_quote(&format!("basis/{}", basis_name)));
+            },
+            Err(_) => {
+                label.push_str("\nbroken config");
+                println!("  {} [label={}, color=red];", id, dot_quote(&label));
+            },
+        }
+    }
+    println!("}}");
+}
+
+pub fn graph_json(graph: &Graph) -> serde_json::Value {
+    let mut bases = vec![];
+    for basis in &graph.bases {
+        bases.push(json!({
+            "name": basis.name,
+            "exists": basis.exists,
+            "arch": basis.arch,
+            "disk_bytes": basis.disk_bytes
+        }));
+    }
+    let mut systems = vec![];
+    for system in &graph.systems {
+        let basis = system.basis.as_ref().ok();
+        let broken = system.basis.as_ref().err().map(|e| e.to_string());
+        let backend = system.mounted.map(|b| b.name());
+        systems.push(json!({
+            "name": system.name,
+            "basis": basis,
+            "broken": broken,
+            "disk_bytes": system.disk_bytes,
+            "mounted": backend.is_some(),
+            "backend": backend
+        }));
+    }
+    return json!({
+        "bases": bases,
+        "systems": systems
+    });
+}
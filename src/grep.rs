@@ -0,0 +1,216 @@
+use {
+    crate::{
+        basis_prefix_path,
+        health::PrefixView,
+        registry::{
+            decode_registry_value,
+            decode_utf16,
+            parse_registry,
+            RegistryLine,
+        },
+        system_prefix_path,
+    },
+    loga::{
+        ea,
+        ResultContext,
+        StandardFlag,
+        StandardLog,
+    },
+    serde::Serialize,
+    std::{
+        fs,
+        path::{
+            Path,
+            PathBuf,
+        },
+    },
+};
+
+/// Files bigger than this (installers, game data) aren't searched, except registry
+/// files.
+const MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Matched lines longer than this are shortened when printed.
+const MAX_TEXT_CHARS: usize = 300;
+
+pub struct Matcher {
+    pub pattern: String,
+    pub ignore_case: bool,
+}
+
+impl Matcher {
+    fn matches(&self, text: &str) -> bool {
+        if self.ignore_case {
+            return text.to_lowercase().contains(&self.pattern.to_lowercase());
+        } else {
+            return text.contains(&self.pattern);
+        }
+    }
+
+    /// Look for the pattern in a binary file as UTF-8 and UTF-16 (how Windows programs
+    /// usually store text). Case is only ignored for ASCII letters.
+    fn matches_bytes(&self, data: &[u8]) -> bool {
+        let utf8 = self.pattern.as_bytes().to_vec();
+        let utf16 = self.pattern.encode_utf16().flat_map(|u| u.to_le_bytes()).collect::<Vec<_>>();
+        for needle in [utf8, utf16] {
+            if needle.is_empty() {
+                return true;
+            }
+            let found = data.windows(needle.len()).any(|w| {
+                if self.ignore_case {
+                    return w.eq_ignore_ascii_case(&needle);
+                } else {
+                    return w == needle;
+                }
+            });
+            if found {
+                return true;
+            }
+        }
+        return false;
+    }
+}
+
+#[derive(Serialize)]
+pub struct GrepMatch {
+    /// Relative to the prefix
+    pub path: PathBuf,
+    /// Starting at 1, none for binary files
+    pub line: Option<usize>,
+    /// The decoded registry value or line of text, none for binary files
+    pub text: Option<String>,
+}
+
+fn shorten(text: &str) -> String {
+    let text = text.trim();
+    if text.chars().count() <= MAX_TEXT_CHARS {
+        return text.to_string();
+    }
+    return format!("{}...", text.chars().take(MAX_TEXT_CHARS).collect::<String>());
+}
+
+/// Search a `.reg` file (wine's `system.reg`/`user.reg` or a `regedit` export),
+/// matching against `[KEY] NAME = VALUE` with escapes and hex-encoded strings
+/// decoded.
+fn grep_registry(matcher: &Matcher, rel: &Path, text: &str, out: &mut Vec<GrepMatch>) {
+    let mut key = String::new();
+    for (line_number, line) in parse_registry(text) {
+        let text = match line {
+            RegistryLine::Key(k) => {
+                key = k;
+                format!("[{}]", key)
+            },
+            RegistryLine::Value { name, value } => format!("[{}] {} = {}", key, name, decode_registry_value(&value)),
+        };
+        if matcher.matches(&text) {
+            out.push(GrepMatch {
+                path: rel.to_path_buf(),
+                line: Some(line_number),
+                text: Some(shorten(&text)),
+            });
+        }
+    }
+}
+
+fn grep_file(log: &StandardLog, matcher: &Matcher, rel: &Path, path: &Path, out: &mut Vec<GrepMatch>) {
+    let is_registry = rel.extension().is_some_and(|e| e.eq_ignore_ascii_case("reg"));
+    let Ok(meta) = path.metadata() else {
+        return;
+    };
+    if !is_registry && meta.len() > MAX_FILE_SIZE {
+        log.log_with(StandardFlag::Debug, "Skipping large file", ea!(path = path.to_string_lossy()));
+        return;
+    }
+    let data = match fs::read(path) {
+        Ok(d) => d,
+        Err(e) => {
+            log.log_with(
+                StandardFlag::Warning,
+                "Error reading file, skipping",
+                ea!(path = path.to_string_lossy(), err = e),
+            );
+            return;
+        },
+    };
+    let text = if let Some(utf16) = data.strip_prefix(&[0xff, 0xfe]) {
+        decode_utf16(utf16)
+    } else if data.contains(&0) {
+        if matcher.matches_bytes(&data) {
+            out.push(GrepMatch {
+                path: rel.to_path_buf(),
+                line: None,
+                text: None,
+            });
+        }
+        return;
+    } else {
+        String::from_utf8_lossy(&data).to_string()
+    };
+    if is_registry {
+        grep_registry(matcher, rel, &text, out);
+        return;
+    }
+    for (i, line) in text.lines().enumerate() {
+        if matcher.matches(line) {
+            out.push(GrepMatch {
+                path: rel.to_path_buf(),
+                line: Some(i + 1),
+                text: Some(shorten(line)),
+            });
+        }
+    }
+}
+
+fn grep_dir(log: &StandardLog, matcher: &Matcher, view: &PrefixView, rel: &Path, out: &mut Vec<GrepMatch>) {
+    for name in view.list_dir(rel) {
+        let rel = rel.join(name);
+        let Some(path) = view.resolve(&rel) else {
+            continue;
+        };
+        let Ok(meta) = path.symlink_metadata() else {
+            continue;
+        };
+
+        // Links aren't followed, `dosdevices` links to the whole host filesystem
+        if meta.is_dir() {
+            grep_dir(log, matcher, view, &rel, out);
+        } else if meta.is_file() {
+            grep_file(log, matcher, &rel, &path, out);
+        }
+    }
+}
+
+/// Search the system's prefix as it appears when mounted (the system layer over
+/// the basis), without mounting it.
+pub fn grep_system(
+    log: &StandardLog,
+    matcher: &Matcher,
+    system_path: &Path,
+    basis_path: &Path,
+) -> Result<Vec<GrepMatch>, loga::Error> {
+    let view = PrefixView {
+        upper: system_prefix_path(system_path),
+        lower: basis_prefix_path(basis_path),
+    };
+    if !view.lower.exists() {
+        return Err(loga::err_with("Basis prefix doesn't exist", ea!(path = view.lower.to_string_lossy())));
+    }
+    let mut out = vec![];
+    grep_dir(log, matcher, &view, Path::new(""), &mut out);
+    return Ok(out);
+}
+
+/// Names of the entries in a directory (ex: all systems in `system/`), sorted.
+/// Empty if the directory doesn't exist.
+pub fn dir_names(dir: &Path) -> Result<Vec<String>, loga::Error> {
+    let mut out = vec![];
+    if !dir.exists() {
+        return Ok(out);
+    }
+    for entry in fs::read_dir(dir).context_with("Error listing directory", ea!(path = dir.to_string_lossy()))? {
+        let entry = entry.context("Error reading directory entry")?;
+        out.push(entry.file_name().to_string_lossy().to_string());
+    }
+    out.sort();
+    return Ok(out);
+}
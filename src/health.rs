@@ -0,0 +1,368 @@
+use {
+    crate::{
+        addons::ADDONS,
+        basis_needs_update,
+        basis_prefix_path,
+        read_basis_config,
+        wine_envs,
+        runtime::stale_systems,
+        system_prefix_path,
+        Arch,
+    },
+    loga::{
+        ea,
+        ResultContext,
+    },
+    serde::Serialize,
+    std::{
+        collections::{
+            BTreeSet,
+            HashMap,
+        },
+        ffi::OsString,
+        fs,
+        io::Read,
+        os::{
+            linux::fs::MetadataExt,
+            unix::fs::{
+                symlink,
+                FileTypeExt,
+            },
+        },
+        path::{
+            Component,
+            Path,
+            PathBuf,
+        },
+    },
+};
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// Only a problem for some programs
+    Info,
+    Warning,
+    Error,
+}
+
+/// A repair `system health --fix` can make itself.
+pub enum Fix {
+    /// Replace whatever is at `path` (in the system layer) with a symlink
+    Symlink {
+        path: PathBuf,
+        target: PathBuf,
+    },
+}
+
+#[derive(Serialize)]
+pub struct Issue {
+    pub severity: Severity,
+    pub problem: String,
+    pub suggestion: String,
+    #[serde(skip)]
+    pub fix: Option<Fix>,
+}
+
+/// Read-only view of a system's prefix as it appears when mounted, without
+/// mounting it: files in the system layer hide files in the basis.
+pub struct PrefixView {
+    pub upper: PathBuf,
+    pub lower: PathBuf,
+}
+
+impl PrefixView {
+    pub fn resolve(&self, rel: &Path) -> Option<PathBuf> {
+        let upper = self.upper.join(rel);
+        if let Ok(meta) = upper.symlink_metadata() {
+            // Overlayfs marks deleted files with a 0/0 character device
+            if meta.file_type().is_char_device() && meta.st_rdev() == 0 {
+                return None;
+            }
+            return Some(upper);
+        }
+        let lower = self.lower.join(rel);
+        if lower.symlink_metadata().is_ok() {
+            return Some(lower);
+        }
+        return None;
+    }
+
+    pub fn list_dir(&self, rel: &Path) -> BTreeSet<String> {
+        let mut out = BTreeSet::new();
+        for base in [&self.lower, &self.upper] {
+            let Ok(entries) = fs::read_dir(base.join(rel)) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                out.insert(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+        return out.into_iter().filter(|name| self.resolve(&rel.join(name)).is_some()).collect();
+    }
+}
+
+/// Resolve `..` in a prefix-relative path. None if it leaves the prefix.
+fn normalize_rel(path: &Path) -> Option<PathBuf> {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(c) => out.push(c),
+            Component::CurDir => { },
+            Component::ParentDir => {
+                if !out.pop() {
+                    return None;
+                }
+            },
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    return Some(out);
+}
+
+fn check_dosdevices(view: &PrefixView, system_prefix: &Path, issues: &mut Vec<Issue>) {
+    let dosdevices = Path::new("dosdevices");
+    let names = view.list_dir(dosdevices);
+    if !names.contains("c:") {
+        issues.push(Issue {
+            severity: Severity::Error,
+            problem: "Drive `c:` is missing from `dosdevices`".to_string(),
+            suggestion: "Recreate the `dosdevices/c:` link to `../drive_c`".to_string(),
+            fix: Some(Fix::Symlink {
+                path: system_prefix.join("dosdevices/c:"),
+                target: PathBuf::from("../drive_c"),
+            }),
+        });
+    }
+    for name in names {
+        let rel = dosdevices.join(&name);
+        let Some(path) = view.resolve(&rel) else {
+            continue;
+        };
+        let Ok(target) = fs::read_link(&path) else {
+            continue;
+        };
+        let exists = if target.is_absolute() {
+            target.exists()
+        } else {
+            normalize_rel(&dosdevices.join(&target)).and_then(|t| view.resolve(&t)).is_some()
+        };
+        if exists {
+            continue;
+        }
+        let fix_target = match name.as_str() {
+            "c:" => Some(PathBuf::from("../drive_c")),
+            "z:" => Some(PathBuf::from("/")),
+            _ => None,
+        };
+        issues.push(Issue {
+            severity: if fix_target.is_some() {
+                Severity::Error
+            } else {
+                Severity::Warning
+            },
+            problem: format!("Drive `{}` links to `{}`, which doesn't exist", name, target.to_string_lossy()),
+            suggestion: match &fix_target {
+                Some(fix_target) => format!("Relink `dosdevices/{}` to `{}`", name, fix_target.to_string_lossy()),
+                None => format!(
+                    "Remove the drive with `winecfg` or reconnect whatever `{}` was on",
+                    target.to_string_lossy()
+                ),
+            },
+            fix: fix_target.map(|fix_target| Fix::Symlink {
+                path: system_prefix.join(&rel),
+                target: fix_target,
+            }),
+        });
+    }
+}
+
+/// Check the registry file header, and the architecture marker in `system.reg`.
+fn check_registry(view: &PrefixView, arch: Option<Arch>, issues: &mut Vec<Issue>) {
+    for name in ["system.reg", "user.reg", "userdef.reg"] {
+        let Some(path) = view.resolve(Path::new(name)) else {
+            issues.push(Issue {
+                severity: Severity::Error,
+                problem: format!("Registry file `{}` is missing", name),
+                suggestion: "Recreate the system, or restore the file from a backup".to_string(),
+                fix: None,
+            });
+            continue;
+        };
+        let mut head = vec![
+            0u8;
+            4096
+        ];
+        let head_len = fs::File::open(&path).and_then(|mut f| f.read(&mut head)).unwrap_or(0);
+        let head = String::from_utf8_lossy(&head[..head_len]);
+        let layer = if path.starts_with(&view.upper) {
+            "system"
+        } else {
+            "basis"
+        };
+        if !head.starts_with("WINE REGISTRY Version 2") {
+            issues.push(Issue {
+                severity: Severity::Error,
+                problem: format!("Registry file `{}` (in the {}) is corrupted or empty", name, layer),
+                suggestion: if layer == "system" {
+                    format!(
+                        "Restore `{}` from a backup, or delete the system's copy to fall back to the basis' (losing settings changed in the system)",
+                        path.to_string_lossy()
+                    )
+                } else {
+                    format!("Restore `{}` from a backup, or recreate the basis", path.to_string_lossy())
+                },
+                fix: None,
+            });
+            continue;
+        }
+        if name != "system.reg" {
+            continue;
+        }
+        let Some(arch) = arch else {
+            continue;
+        };
+        let want = arch.winearch();
+        let have = head.lines().find_map(|l| l.strip_prefix("#arch=")).map(|x| x.trim().to_string());
+        if let Some(have) = have {
+            if have != want {
+                issues.push(Issue {
+                    severity: Severity::Error,
+                    problem: format!("The prefix is `{}` but the basis config says `{}`", have, want),
+                    suggestion: "Fix `arch` in the basis config, or recreate the basis with the right `--arch`".to_string(),
+                    fix: None,
+                });
+            }
+        }
+    }
+}
+
+/// Check wine-mono and wine-gecko are installed in the prefix or shared by the
+/// wine installation. Without them wine asks to download them when a program needs
+/// them.
+fn check_addons(view: &PrefixView, env: &HashMap<OsString, OsString>, issues: &mut Vec<Issue>) {
+    for addon in ADDONS {
+        let shared_dir = addon.shared_dir(env);
+        if shared_dir.exists() || addon.prefix_dirs.iter().any(|d| view.resolve(Path::new(d)).is_some()) {
+            continue;
+        }
+        issues.push(Issue {
+            severity: Severity::Info,
+            problem: format!("{} isn't installed, wine will prompt to download it for {}", addon.name, addon.uses),
+            suggestion: format!(
+                "Install your distribution's {} package (shared, in `{}`), or install it in the basis",
+                addon.name,
+                shared_dir.to_string_lossy()
+            ),
+            fix: None,
+        });
+    }
+}
+
+/// Look for common ways a system's prefix breaks. `basis_path` is from the system
+/// config.
+pub fn check_health(
+    system_name: &str,
+    system_path: &Path,
+    basis_name: &str,
+    basis_path: &Path,
+) -> Result<Vec<Issue>, loga::Error> {
+    let mut issues = vec![];
+    if !basis_path.exists() {
+        issues.push(Issue {
+            severity: Severity::Error,
+            problem: format!("The system's basis `{}` doesn't exist", basis_name),
+            suggestion: format!(
+                "Recreate the basis with `winebasin basis create {}`, or point the system at another basis in its config",
+                basis_name
+            ),
+            fix: None,
+        });
+        return Ok(issues);
+    }
+    let config = match read_basis_config(basis_path) {
+        Ok(c) => Some(c),
+        Err(e) => {
+            issues.push(Issue {
+                severity: Severity::Error,
+                problem: format!("The basis config can't be read: {}", e),
+                suggestion: "Fix the basis' `config.json`".to_string(),
+                fix: None,
+            });
+            None
+        },
+    };
+    let arch = config.as_ref().map(|c| c.arch);
+
+    // An unregistered wine is reported by the update check
+    let env = config.and_then(|c| wine_envs(&c, &basis_prefix_path(basis_path)).ok()).unwrap_or_default();
+    match basis_needs_update(basis_path) {
+        Ok(true) => issues.push(Issue {
+            severity: Severity::Warning,
+            problem: "Wine was updated since the basis was last updated".to_string(),
+            suggestion: format!("Run `winebasin basis update {}` (or just start the system)", basis_name),
+            fix: None,
+        }),
+        Ok(false) => { },
+        Err(e) => issues.push(Issue {
+            severity: Severity::Warning,
+            problem: format!("Can't tell if the basis needs an update: {}", e),
+            suggestion: format!("Run `winebasin basis update {}`", basis_name),
+            fix: None,
+        }),
+    }
+    if stale_systems()?.iter().any(|(name, _)| name == system_name) {
+        issues.push(Issue {
+            severity: Severity::Warning,
+            problem: "A winebasin process using the system exited without cleaning up".to_string(),
+            suggestion: "Run `winebasin cleanup`".to_string(),
+            fix: None,
+        });
+    }
+    let system_prefix = system_prefix_path(system_path);
+    let view = PrefixView {
+        upper: system_prefix.clone(),
+        lower: basis_prefix_path(basis_path),
+    };
+    check_registry(&view, arch, &mut issues);
+    check_dosdevices(&view, &system_prefix, &mut issues);
+    check_addons(&view, &env, &mut issues);
+    return Ok(issues);
+}
+
+/// 100 for no problems, minus 30 per error and 10 per warning.
+pub fn health_score(issues: &[Issue]) -> u32 {
+    let mut penalty = 0;
+    for issue in issues {
+        penalty += match issue.severity {
+            Severity::Info => 0,
+            Severity::Warning => 10,
+            Severity::Error => 30,
+        };
+    }
+    return 100u32.saturating_sub(penalty);
+}
+
+pub fn apply_fix(fix: &Fix) -> Result<(), loga::Error> {
+    match fix {
+        Fix::Symlink { path, target } => {
+            if path.symlink_metadata().is_ok() {
+                fs::remove_file(
+                    path,
+                ).context_with("Error removing broken link", ea!(path = path.to_string_lossy()))?;
+            }
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(
+                    parent,
+                ).context_with("Error creating directory", ea!(path = parent.to_string_lossy()))?;
+            }
+            symlink(
+                target,
+                path,
+            ).context_with(
+                "Error creating link",
+                ea!(path = path.to_string_lossy(), target = target.to_string_lossy()),
+            )?;
+        },
+    }
+    return Ok(());
+}
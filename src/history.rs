@@ -0,0 +1,128 @@
+use {
+    crate::wine_bin,
+    loga::{
+        ea,
+        ResultContext,
+    },
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+    std::{
+        collections::HashMap,
+        ffi::OsString,
+        fs::{
+            self,
+            OpenOptions,
+        },
+        io::Write,
+        path::{
+            Path,
+            PathBuf,
+        },
+        process::{
+            Command,
+            Stdio,
+        },
+    },
+};
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HistoryEntryV1 {
+    /// Command and arguments, relative to `drive_c`.
+    pub command: Vec<String>,
+    /// Unix time, seconds
+    pub started: u64,
+    /// Unix time, seconds
+    pub ended: u64,
+    /// None if the program was killed by a signal.
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    /// As reported by `wine --version`.
+    pub wine_version: Option<String>,
+}
+
+pub type HistoryLatestEntry = HistoryEntryV1;
+
+#[derive(Serialize, Deserialize)]
+enum HistoryEntry {
+    V1(HistoryEntryV1),
+}
+
+pub fn history_path(system_path: &Path) -> PathBuf {
+    return system_path.join("history.jsonl");
+}
+
+pub fn wine_version(env: &HashMap<OsString, OsString>) -> Option<String> {
+    let output = Command::new(wine_bin(env)).envs(env).arg("--version").stderr(Stdio::null()).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    return Some(String::from_utf8_lossy(&output.stdout).trim().to_string());
+}
+
+/// Add an entry to the end of the system's run history. The history is
+/// append-only, one JSON entry per line.
+pub fn append_history(system_path: &Path, entry: HistoryLatestEntry) -> Result<(), loga::Error> {
+    let path = history_path(system_path);
+    let mut line = serde_json::to_vec(&HistoryEntry::V1(entry)).unwrap();
+    line.push(b'\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .context_with("Error opening system run history", ea!(path = path.to_string_lossy()))?
+        .write_all(&line)
+        .context_with("Error writing system run history", ea!(path = path.to_string_lossy()))?;
+    return Ok(());
+}
+
+/// Read the system's run history, oldest first. Unreadable lines (ex: cut short by
+/// a crash) are skipped.
+pub fn read_history(system_path: &Path) -> Result<Vec<HistoryLatestEntry>, loga::Error> {
+    let path = history_path(system_path);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let data = fs::read(&path).context_with("Error reading system run history", ea!(path = path.to_string_lossy()))?;
+    let mut out = vec![];
+    for line in data.split(|c| *c == b'\n') {
+        match serde_json::from_slice::<HistoryEntry>(line) {
+            Ok(HistoryEntry::V1(entry)) => out.push(entry),
+            Err(_) => { },
+        }
+    }
+    return Ok(out);
+}
+
+/// Format unix time as `YYYY-MM-DD HH:MM:SS` UTC.
+pub fn format_time(secs: u64) -> String {
+    // Civil from days, see https://howardhinnant.github.io/date_algorithms.html
+    let days = (secs / 86400) as i64;
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 {
+        mp + 3
+    } else {
+        mp - 9
+    };
+    let year = yoe + era * 400 + if month <= 2 {
+        1
+    } else {
+        0
+    };
+    return format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60
+    );
+}
@@ -0,0 +1,175 @@
+use {
+    crate::{
+        desktop_entry::quote_exec_arg,
+        find_bin,
+        root_dir,
+    },
+    directories::BaseDirs,
+    loga::{
+        ea,
+        ResultContext,
+    },
+    std::{
+        env::current_exe,
+        path::{
+            Path,
+            PathBuf,
+        },
+        process::Command,
+    },
+};
+
+/// Windows program and installer types `winebasin open` handles.
+const OPEN_MIME_TYPES: &[&str] =
+    &[
+        "application/x-ms-dos-executable",
+        "application/x-msdownload",
+        "application/vnd.microsoft.portable-executable",
+        "application/x-msi",
+        "application/x-ms-shortcut",
+    ];
+
+/// The desktop file `winebasin://` links open with.
+pub const URL_ENTRY_NAME: &str = "winebasin-url.desktop";
+
+fn applications_dir() -> Result<PathBuf, loga::Error> {
+    let base = BaseDirs::new().context("Unable to determine user data directory")?;
+    return Ok(base.data_dir().join("applications"));
+}
+
+/// Where the "Run with winebasin" desktop entry is installed.
+pub fn open_entry_path() -> Result<PathBuf, loga::Error> {
+    return Ok(applications_dir()?.join("winebasin-open.desktop"));
+}
+
+/// Where the `winebasin://` URL handler desktop entry is installed.
+pub fn url_entry_path() -> Result<PathBuf, loga::Error> {
+    return Ok(applications_dir()?.join(URL_ENTRY_NAME));
+}
+
+/// A desktop entry that offers to open Windows programs and installers with
+/// `winebasin open`.
+pub fn open_entry() -> Result<String, loga::Error> {
+    let winebasin = current_exe().context("Error determining path to winebasin executable")?;
+    let root = root_dir()?;
+    return Ok(
+        format!(
+            "[Desktop Entry]\nType=Application\nName=Run with winebasin\nComment=Run a Windows program in a winebasin system\nExec={} --root {} open %f\nMimeType={};\nNoDisplay=true\nTerminal=false\n",
+            quote_exec_arg(&winebasin.to_string_lossy()),
+            quote_exec_arg(&root.to_string_lossy()),
+            OPEN_MIME_TYPES.join(";")
+        ),
+    );
+}
+
+/// A desktop entry that handles `winebasin://` links with `winebasin handle-url`.
+pub fn url_entry() -> Result<String, loga::Error> {
+    let winebasin = current_exe().context("Error determining path to winebasin executable")?;
+    let root = root_dir()?;
+    return Ok(
+        format!(
+            "[Desktop Entry]\nType=Application\nName=winebasin\nComment=Open winebasin links\nExec={} --root {} handle-url %u\nMimeType=x-scheme-handler/winebasin;\nNoDisplay=true\nTerminal=false\n",
+            quote_exec_arg(&winebasin.to_string_lossy()),
+            quote_exec_arg(&root.to_string_lossy())
+        ),
+    );
+}
+
+/// Make the URL handler entry the default for `winebasin://` links, if `xdg-mime`
+/// is installed.
+pub fn set_url_handler_default() {
+    if find_bin("xdg-mime").is_some() {
+        _ = Command::new("xdg-mime").args(["default", URL_ENTRY_NAME, "x-scheme-handler/winebasin"]).status();
+    }
+}
+
+fn percent_decode(s: &str) -> Result<String, loga::Error> {
+    let bytes = s.as_bytes();
+    let mut out = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let byte =
+                s
+                    .get(i + 1 .. i + 3)
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                    .context_with("Invalid percent escape in URL", ea!(text = s))?;
+            out.push(byte);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    return String::from_utf8(out).context_with("URL path isn't utf-8", ea!(text = s));
+}
+
+/// A parsed `winebasin://` link.
+pub enum WinebasinUrl {
+    /// `winebasin://run/SYSTEM/APP`, where `APP` is a name from `system app add`.
+    Run {
+        system_name: String,
+        app: String,
+    },
+}
+
+/// Parse a `winebasin://` link. Since links can come from web pages, they can only
+/// name registered apps, not paths (which could be a download).
+pub fn parse_url(url: &str) -> Result<WinebasinUrl, loga::Error> {
+    let rest = url.strip_prefix("winebasin://").context_with("Not a winebasin:// URL", ea!(url = url))?;
+
+    // Query and fragment aren't used
+    let rest = rest.split(['?', '#']).next().unwrap();
+    let mut parts = rest.split('/');
+    match parts.next() {
+        Some("run") => { },
+        _ => return Err(loga::err_with("Unknown winebasin:// URL action, expected `run`", ea!(url = url))),
+    }
+    let parts = parts.filter(|p| !p.is_empty()).map(percent_decode).collect::<Result<Vec<_>, _>>()?;
+    let [system_name, app] = <[String; 2]>::try_from(parts).map_err(|_| {
+        loga::err_with(
+            "Invalid winebasin:// URL, expected `winebasin://run/SYSTEM/APP` with an app name from `system app add`",
+            ea!(url = url),
+        )
+    })?;
+    return Ok(WinebasinUrl::Run {
+        system_name: system_name,
+        app: app,
+    });
+}
+
+/// Refresh the desktop's MIME type to application cache, if the tool is installed.
+pub fn update_desktop_database(dir: &Path) {
+    if find_bin("update-desktop-database").is_some() {
+        _ = Command::new("update-desktop-database").arg(dir).status();
+    }
+}
+
+/// Ask which system to open `file` in, with zenity or kdialog. `None` if canceled.
+pub fn choose_system(file: &Path, systems: &[String]) -> Result<Option<String>, loga::Error> {
+    if systems.is_empty() {
+        return Err(loga::err("There are no systems, create one with `winebasin system create`"));
+    }
+    let text = format!("Run {} in which system?", file.file_name().unwrap_or_default().to_string_lossy());
+    let mut command = if find_bin("zenity").is_some() {
+        let mut command = Command::new("zenity");
+        command.args(["--list", "--title=Run with winebasin", "--column=System"]).arg(format!("--text={}", text));
+        command.args(systems);
+        command
+    } else if find_bin("kdialog").is_some() {
+        let mut command = Command::new("kdialog");
+        command.args(["--title", "Run with winebasin", "--combobox"]).arg(text).args(systems);
+        command
+    } else {
+        return Err(loga::err("Choosing a system needs `zenity` or `kdialog`, or pick one with `--system`"));
+    };
+    let output = command.output().context("Error asking for a system")?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    let choice = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if !systems.contains(&choice) {
+        return Err(loga::err_with("Unknown system chosen", ea!(system = choice)));
+    }
+    return Ok(Some(choice));
+}
@@ -0,0 +1,232 @@
+use {
+    crate::sandbox::{
+        install_dir,
+        SandboxConfig,
+        READ_ONLY_PATH_VARS,
+        SYSTEM_DIRS,
+        WRITABLE_PATH_VARS,
+    },
+    loga::{
+        ea,
+        ResultContext,
+    },
+    std::{
+        collections::HashMap,
+        env,
+        ffi::{
+            CString,
+            OsStr,
+            OsString,
+        },
+        fs,
+        io,
+        os::{
+            fd::{
+                AsRawFd,
+                FromRawFd,
+                OwnedFd,
+            },
+            unix::{
+                ffi::OsStrExt,
+                fs::DirBuilderExt,
+                process::CommandExt,
+            },
+        },
+        path::{
+            Path,
+            PathBuf,
+        },
+        process::Command,
+    },
+};
+
+// From `linux/landlock.h`
+const CREATE_RULESET_VERSION: libc::c_uint = 1 << 0;
+const RULE_PATH_BENEATH: libc::c_uint = 1;
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+
+/// All the rights in the first version, through `MAKE_SYM`.
+const ACCESS_FS_V1: u64 = (1 << 13) - 1;
+const ACCESS_FS_REFER: u64 = 1 << 13;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+/// Rights that apply to files, rather than directories' contents.
+const ACCESS_FILE: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE | ACCESS_FS_TRUNCATE;
+const ACCESS_READ: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// The Landlock ABI version the kernel supports, or why it can't be used.
+pub fn landlock_abi() -> Result<i64, String> {
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0usize,
+            CREATE_RULESET_VERSION,
+        )
+    };
+    if res < 0 {
+        return Err(match io::Error::last_os_error().raw_os_error() {
+            Some(libc::ENOSYS) => "the kernel doesn't support Landlock".to_string(),
+            Some(
+                libc::EOPNOTSUPP,
+            ) => "Landlock is disabled in the kernel (see `lsm=` in the boot options)".to_string(),
+            _ => format!("checking for Landlock failed: {}", io::Error::last_os_error()),
+        });
+    }
+    return Ok(res);
+}
+
+/// Rules restricting a process tree's filesystem access to the mounted prefix, the
+/// host's system directories, devices, caches and allowed paths. Like the
+/// bubblewrap sandbox, but needs no extra programs. The home directory is
+/// inaccessible.
+pub struct Landlock {
+    ruleset: OwnedFd,
+}
+
+impl Landlock {
+    pub fn new(
+        config: &SandboxConfig,
+        env: &HashMap<OsString, OsString>,
+        command: &Command,
+    ) -> Result<Landlock, loga::Error> {
+        let abi = landlock_abi().map_err(loga::err)?;
+        let mut handled = ACCESS_FS_V1;
+        if abi >= 2 {
+            handled |= ACCESS_FS_REFER;
+        }
+        if abi >= 3 {
+            handled |= ACCESS_FS_TRUNCATE;
+        }
+        let attr = RulesetAttr { handled_access_fs: handled };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                std::mem::size_of::<RulesetAttr>(),
+                0 as libc::c_uint,
+            )
+        };
+        if fd < 0 {
+            return Err(loga::err_with("Error creating Landlock ruleset", ea!(err = io::Error::last_os_error())));
+        }
+        let out = Landlock { ruleset: unsafe {
+            OwnedFd::from_raw_fd(fd as i32)
+        } };
+
+        // Wine's server socket dir. Created here so the directory containing it doesn't
+        // need to be writable.
+        let wine_tmp = PathBuf::from(format!("/tmp/.wine-{}", unsafe {
+            libc::getuid()
+        }));
+        if !wine_tmp.exists() {
+            fs::DirBuilder::new()
+                .mode(0o700)
+                .create(&wine_tmp)
+                .context_with("Error creating wine's temporary dir", ea!(path = wine_tmp.to_string_lossy()))?;
+        }
+        let mut read = vec![PathBuf::from("/proc"), PathBuf::from("/sys"), PathBuf::from("/tmp/.X11-unix")];
+        read.extend(SYSTEM_DIRS.iter().map(PathBuf::from));
+        for program in [
+            command.get_program(),
+            env.get(OsStr::new("WINE")).map(|p| p.as_os_str()).unwrap_or_default(),
+        ] {
+            let program = Path::new(program);
+            if program.is_absolute() {
+                read.extend(install_dir(program).map(|d| d.to_path_buf()));
+            }
+        }
+        for key in READ_ONLY_PATH_VARS {
+            read.extend(env.get(OsStr::new(key)).map(PathBuf::from));
+        }
+        read.extend(config.allow_read_only.iter().cloned());
+        let mut write = vec![PathBuf::from("/dev"), wine_tmp];
+        if let Some(runtime_dir) = env::var_os("XDG_RUNTIME_DIR") {
+            // Wayland, PulseAudio/PipeWire and the session bus
+            write.push(PathBuf::from(runtime_dir));
+        }
+        for key in WRITABLE_PATH_VARS {
+            write.extend(env.get(OsStr::new(key)).map(PathBuf::from));
+        }
+        write.extend(config.allow.iter().cloned());
+        for path in read {
+            out.allow(&path, ACCESS_READ & handled)?;
+        }
+        for path in write {
+            out.allow(&path, handled)?;
+        }
+        return Ok(out);
+    }
+
+    /// Allow access to everything under a path. Missing paths are skipped.
+    fn allow(&self, path: &Path, mut access: u64) -> Result<(), loga::Error> {
+        let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) else {
+            return Ok(());
+        };
+        let fd = unsafe {
+            libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC)
+        };
+        if fd < 0 {
+            return Ok(());
+        }
+        let fd = unsafe {
+            OwnedFd::from_raw_fd(fd)
+        };
+        if !path.is_dir() {
+            access &= ACCESS_FILE;
+        }
+        let attr = PathBeneathAttr {
+            allowed_access: access,
+            parent_fd: fd.as_raw_fd(),
+        };
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                self.ruleset.as_raw_fd(),
+                RULE_PATH_BENEATH,
+                &attr as *const PathBeneathAttr,
+                0 as libc::c_uint,
+            )
+        };
+        if res < 0 {
+            return Err(
+                loga::err_with(
+                    "Error adding path to Landlock ruleset",
+                    ea!(path = path.to_string_lossy(), err = io::Error::last_os_error()),
+                ),
+            );
+        }
+        return Ok(());
+    }
+
+    /// Confine the command's process (and everything it starts) when it's spawned.
+    /// `self` must be kept until then. Wrapping the command afterwards drops this.
+    pub fn restrict(&self, command: &mut Command) {
+        let ruleset = self.ruleset.as_raw_fd();
+        unsafe {
+            command.pre_exec(move || {
+                if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                if libc::syscall(libc::SYS_landlock_restrict_self, ruleset, 0 as libc::c_uint) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                return Ok(());
+            });
+        }
+    }
+}
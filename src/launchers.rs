@@ -0,0 +1,113 @@
+use {
+    crate::{
+        desktop_entry::system_run_command_line,
+        quote_subcommand,
+    },
+    aargvark::Aargvark,
+    loga::ResultContext,
+    serde::Serialize,
+    std::{
+        collections::BTreeMap,
+        path::Path,
+    },
+};
+
+/// Lowercase with runs of other characters replaced by `-`, ex: `my-game`.
+fn slug(name: &str) -> String {
+    let mut out = String::new();
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            out.push(c.to_ascii_lowercase());
+        } else if !out.is_empty() && !out.ends_with('-') {
+            out.push('-');
+        }
+    }
+    return out.trim_end_matches('-').to_string();
+}
+
+/// A YAML string (JSON strings are valid YAML).
+fn yaml_str(s: &str) -> String {
+    return serde_json::to_string(s).unwrap();
+}
+
+/// A Lutris installer (for `lutris -i FILE`) adding a game that starts `exe`
+/// (relative to `drive_c`) in the system with `system run`. Lutris runs it as a
+/// native program (the `linux` runner), since winebasin mounts the prefix and
+/// picks the wine.
+pub fn lutris_installer(system_name: &str, exe: &str, title: &str) -> Result<String, loga::Error> {
+    let command_line = system_run_command_line(system_name, exe)?;
+    let game_slug = slug(title);
+    return Ok(
+        format!(
+            "name: {}\ngame_slug: {}\nversion: {}\nslug: {}\nrunner: linux\nscript:\n  game:\n    exe: {}\n    args: {}\n    working_dir: {}\n",
+            yaml_str(title),
+            yaml_str(&game_slug),
+            yaml_str(&format!("winebasin {}", system_name)),
+            yaml_str(&format!("{}-winebasin-{}", game_slug, slug(system_name))),
+            yaml_str(&command_line[0]),
+            yaml_str(
+                &String::from_utf8(quote_subcommand(command_line[1..].iter().map(|a| a.as_bytes()))?).unwrap(),
+            ),
+            yaml_str(&Path::new(&command_line[0]).parent().unwrap_or(Path::new("/")).to_string_lossy())
+        ),
+    );
+}
+
+/// How to print a launcher entry.
+#[derive(Aargvark, Clone, Copy, Default)]
+pub enum LauncherFormat {
+    #[default]
+    Json,
+    Toml,
+}
+
+/// A launcher-neutral description of how to start a program in a system, for
+/// adding it as a custom game in Heroic and similar launchers.
+#[derive(Serialize)]
+pub struct LauncherEntry {
+    pub title: String,
+    /// The wrapper to start (winebasin).
+    pub executable: String,
+    pub args: Vec<String>,
+    /// `executable` and `args` as a shell command line.
+    pub command: String,
+    /// `args` as a shell command line, for launchers that take launch options.
+    pub launch_options: String,
+    pub working_dir: String,
+    /// Environment variables from the basis and system configs. `system run` sets them
+    /// itself, so launchers don't need to.
+    pub env: BTreeMap<String, String>,
+}
+
+/// Describe starting `exe` (relative to `drive_c`) in the system with `system run`.
+pub fn launcher_entry(
+    system_name: &str,
+    exe: &str,
+    title: &str,
+    env: BTreeMap<String, String>,
+) -> Result<LauncherEntry, loga::Error> {
+    let command_line = system_run_command_line(system_name, exe)?;
+    let quote = |args: &[String]| {
+        return String::from_utf8(
+            quote_subcommand(args.iter().map(|a| a.as_bytes()))?,
+        ).context("Quoted command isn't utf-8");
+    };
+    return Ok(LauncherEntry {
+        title: title.to_string(),
+        executable: command_line[0].clone(),
+        args: command_line[1..].to_vec(),
+        command: quote(&command_line)?,
+        launch_options: quote(&command_line[1..])?,
+        working_dir: Path::new(&command_line[0]).parent().unwrap_or(Path::new("/")).to_string_lossy().to_string(),
+        env: env,
+    });
+}
+
+impl LauncherEntry {
+    pub fn format(&self, format: LauncherFormat) -> Result<String, loga::Error> {
+        match format {
+            LauncherFormat::Json => return Ok(format!("{}\n", serde_json::to_string_pretty(self).unwrap())),
+            LauncherFormat::Toml => return toml::to_string(self).context("Error formatting launcher entry as TOML"),
+        }
+    }
+}
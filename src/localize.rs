@@ -0,0 +1,218 @@
+use {
+    crate::{
+        basis_path,
+        root_dir,
+        runtime::format_size,
+    },
+    loga::{
+        ea,
+        ErrContext,
+        ResultContext,
+        StandardFlag,
+        StandardLog,
+    },
+    std::{
+        collections::hash_map::DefaultHasher,
+        fs::{
+            self,
+            File,
+        },
+        hash::Hasher,
+        io::{
+            stderr,
+            IsTerminal,
+            Read,
+            Write,
+        },
+        os::unix::fs::{
+            symlink,
+            PermissionsExt,
+        },
+        path::Path,
+        time::{
+            Duration,
+            Instant,
+        },
+    },
+};
+
+struct Progress {
+    total: u64,
+    done: u64,
+    last_print: Instant,
+    show: bool,
+}
+
+impl Progress {
+    fn add(&mut self, bytes: u64) {
+        self.done += bytes;
+        if self.show && self.last_print.elapsed() >= Duration::from_millis(500) {
+            self.last_print = Instant::now();
+            self.print();
+        }
+    }
+
+    fn print(&self) {
+        let percent = (self.done * 100).checked_div(self.total).unwrap_or(100);
+        eprint!("\rCopying basis: {}% ({} / {})   ", percent, format_size(self.done), format_size(self.total));
+    }
+}
+
+fn tree_size(path: &Path) -> Result<u64, loga::Error> {
+    let meta =
+        path.symlink_metadata().context_with("Error reading file metadata", ea!(path = path.to_string_lossy()))?;
+    if meta.is_dir() {
+        let mut total = 0;
+        for entry in fs::read_dir(path).context_with("Error listing directory", ea!(path = path.to_string_lossy()))? {
+            let entry = entry.context("Error reading directory entry")?;
+            total += tree_size(&entry.path())?;
+        }
+        return Ok(total);
+    } else if meta.is_file() {
+        return Ok(meta.len());
+    } else {
+        return Ok(0);
+    }
+}
+
+fn hash_file(path: &Path) -> Result<u64, loga::Error> {
+    let mut file = File::open(path).context_with("Error opening file", ea!(path = path.to_string_lossy()))?;
+    let mut hasher = DefaultHasher::new();
+    let mut buf = vec![
+        0u8;
+        1024 * 1024
+    ];
+    loop {
+        let len = file.read(&mut buf).context_with("Error reading file", ea!(path = path.to_string_lossy()))?;
+        if len == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.write(&buf[..len]);
+    }
+}
+
+/// Copy a file, then check the copy has the same contents as what was read.
+fn copy_file(source: &Path, dest: &Path, progress: &mut Progress) -> Result<(), loga::Error> {
+    let mut source_file =
+        File::open(source).context_with("Error opening file to copy", ea!(path = source.to_string_lossy()))?;
+    let mut dest_file = File::create(dest).context_with("Error creating file", ea!(path = dest.to_string_lossy()))?;
+    let mut hasher = DefaultHasher::new();
+    let mut buf = vec![
+        0u8;
+        1024 * 1024
+    ];
+    loop {
+        let len =
+            source_file
+                .read(&mut buf)
+                .context_with("Error reading file to copy", ea!(path = source.to_string_lossy()))?;
+        if len == 0 {
+            break;
+        }
+        hasher.write(&buf[..len]);
+        dest_file.write_all(&buf[..len]).context_with("Error writing file", ea!(path = dest.to_string_lossy()))?;
+        progress.add(len as u64);
+    }
+    dest_file.sync_all().context_with("Error flushing file", ea!(path = dest.to_string_lossy()))?;
+    if hash_file(dest)? != hasher.finish() {
+        return Err(
+            loga::err_with(
+                "Copied file doesn't match the original",
+                ea!(source = source.to_string_lossy(), dest = dest.to_string_lossy()),
+            ),
+        );
+    }
+    return Ok(());
+}
+
+/// Copy a directory tree keeping symlinks and permissions. Other special files are
+/// skipped (a prefix shouldn't have any).
+fn copy_tree(source: &Path, dest: &Path, progress: &mut Progress) -> Result<(), loga::Error> {
+    let meta =
+        source.symlink_metadata().context_with("Error reading file metadata", ea!(path = source.to_string_lossy()))?;
+    if meta.is_symlink() {
+        let target =
+            fs::read_link(source).context_with("Error reading link", ea!(path = source.to_string_lossy()))?;
+        symlink(&target, dest).context_with("Error creating link", ea!(path = dest.to_string_lossy()))?;
+        return Ok(());
+    } else if meta.is_dir() {
+        fs::create_dir(dest).context_with("Error creating directory", ea!(path = dest.to_string_lossy()))?;
+        for entry in fs::read_dir(
+            source,
+        ).context_with("Error listing directory", ea!(path = source.to_string_lossy()))? {
+            let entry = entry.context("Error reading directory entry")?;
+            copy_tree(&entry.path(), &dest.join(entry.file_name()), progress)?;
+        }
+    } else if meta.is_file() {
+        copy_file(source, dest, progress)?;
+    } else {
+        return Ok(());
+    }
+
+    // After copying contents, in case the permissions don't allow writing. Keep it
+    // writable by the owner (the original may be read-only) so the basis can be
+    // updated.
+    fs::set_permissions(
+        dest,
+        fs::Permissions::from_mode(meta.permissions().mode() | 0o200),
+    ).context_with("Error setting permissions", ea!(path = dest.to_string_lossy()))?;
+    return Ok(());
+}
+
+/// If the basis is only in a (read-only) extra root, copy it to the main root so
+/// it no longer depends on the extra root. The copy is checked against what was
+/// read, and only appears in the main root once it's complete.
+pub fn localize_basis(log: &StandardLog, basis_name: &str) -> Result<(), loga::Error> {
+    let source = basis_path(basis_name)?;
+    let bases_dir = root_dir()?.join("basis");
+    let dest = bases_dir.join(basis_name);
+    if source == dest || !source.exists() {
+        return Ok(());
+    }
+    let log = log.fork(ea!(basis = basis_name, from = source.to_string_lossy()));
+    fs::create_dir_all(
+        &bases_dir,
+    ).context_with("Error creating bases dir", ea!(path = bases_dir.to_string_lossy()))?;
+    let total = tree_size(&source)?;
+    log.log_with(
+        StandardFlag::Info,
+        "Copying basis from read-only extra root to the main root",
+        ea!(to = dest.to_string_lossy(), size = format_size(total)),
+    );
+    let temp = bases_dir.join(format!(".{}.localizing-{}", basis_name, std::process::id()));
+    let mut progress = Progress {
+        total: total,
+        done: 0,
+        last_print: Instant::now(),
+        show: stderr().is_terminal(),
+    };
+    let res = copy_tree(&source, &temp, &mut progress).and_then(|_| {
+        if progress.done != total {
+            return Err(
+                loga::err_with(
+                    "Basis changed while being copied",
+                    ea!(expected = format_size(total), copied = format_size(progress.done)),
+                ),
+            );
+        }
+        return Ok(());
+    });
+    if progress.show {
+        progress.print();
+        eprintln!();
+    }
+    if let Err(e) = res {
+        _ = fs::remove_dir_all(&temp);
+        return Err(e);
+    }
+    if let Err(e) = fs::rename(&temp, &dest) {
+        _ = fs::remove_dir_all(&temp);
+        if dest.exists() {
+            // Another winebasin process copied it first
+            return Ok(());
+        }
+        return Err(e.context_with("Error moving copied basis into place", ea!(path = dest.to_string_lossy())));
+    }
+    log.log(StandardFlag::Info, "Copied basis");
+    return Ok(());
+}
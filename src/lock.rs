@@ -0,0 +1,217 @@
+use {
+    crate::{
+        enclosing::enclosing,
+        exe_icon::crc32,
+        runtime::runtime_dir,
+    },
+    loga::{
+        ea,
+        ResultContext,
+    },
+    std::{
+        fs::{
+            create_dir_all,
+            File,
+            OpenOptions,
+            read_dir,
+            TryLockError,
+        },
+        os::unix::ffi::OsStrExt,
+        path::{
+            Path,
+            PathBuf,
+        },
+    },
+};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// Using, ex: running a program in a system
+    Shared,
+    /// Modifying, ex: updating or deleting
+    Exclusive,
+}
+
+/// An advisory (`flock`) lock on a basis or system, released when dropped.
+pub struct Lock {
+    file: File,
+    path: PathBuf,
+    /// Human readable name of what's locked, for errors
+    desc: String,
+    mode: LockMode,
+    wait: bool,
+    /// Held by the winebasin process whose shell this is running in, see `enclosing`
+    enclosing: bool,
+}
+
+impl Lock {
+    /// Change the lock mode, returning `false` if not waiting and it's held by someone
+    /// else.
+    fn change_mode(&mut self, mode: LockMode, wait: bool) -> Result<bool, loga::Error> {
+        if mode == self.mode {
+            return Ok(true);
+        }
+        let res = match (mode, wait) {
+            (LockMode::Shared, true) => self.file.lock_shared().map_err(TryLockError::Error),
+            (LockMode::Shared, false) => self.file.try_lock_shared(),
+            (LockMode::Exclusive, true) => self.file.lock().map_err(TryLockError::Error),
+            (LockMode::Exclusive, false) => self.file.try_lock(),
+        };
+        match res {
+            Ok(_) => { },
+            Err(TryLockError::WouldBlock) => {
+                return Ok(false);
+            },
+            Err(TryLockError::Error(e)) => {
+                return Err(e).context_with("Error locking", ea!(lock = self.path.to_string_lossy()));
+            },
+        }
+        self.mode = mode;
+        return Ok(true);
+    }
+
+    /// Change the lock mode (upgrade to exclusive or downgrade to shared), waiting if
+    /// `--wait` was specified. Per `flock`, conversion isn't atomic - another process
+    /// may acquire the lock in-between.
+    pub fn set_mode(&mut self, mode: LockMode) -> Result<(), loga::Error> {
+        self.check_enclosing(mode)?;
+        if !self.change_mode(mode, self.wait)? {
+            return Err(
+                loga::err_with(
+                    format!(
+                        "The {} is in use by another winebasin process, stop it or use `--wait` to wait for it to finish",
+                        self.desc
+                    ),
+                    ea!(lock = self.path.to_string_lossy()),
+                ),
+            );
+        }
+        return Ok(());
+    }
+
+    /// Like `set_mode` but never waits, returning `false` if the lock is held
+    /// elsewhere.
+    pub fn try_set_mode(&mut self, mode: LockMode) -> Result<bool, loga::Error> {
+        if self.check_enclosing(mode).is_err() {
+            return Ok(false);
+        }
+        return self.change_mode(mode, false);
+    }
+
+    /// Like `set_mode` but always waits.
+    pub fn wait_set_mode(&mut self, mode: LockMode) -> Result<(), loga::Error> {
+        self.check_enclosing(mode)?;
+        self.change_mode(mode, true)?;
+        return Ok(());
+    }
+
+    /// Getting an exclusive lock held by the enclosing shell's process would fail (or
+    /// wait forever) since that process won't exit until this one does.
+    fn check_enclosing(&self, mode: LockMode) -> Result<(), loga::Error> {
+        if mode == LockMode::Exclusive && self.enclosing {
+            return Err(
+                loga::err_with(
+                    format!("The {} is in use by the shell this is running in, exit the shell first", self.desc),
+                    ea!(lock = self.path.to_string_lossy()),
+                ),
+            );
+        }
+        return Ok(());
+    }
+
+    pub fn mode(&self) -> LockMode {
+        return self.mode;
+    }
+}
+
+fn locks_dir() -> Result<PathBuf, loga::Error> {
+    let dir = runtime_dir()?.join("locks");
+    create_dir_all(&dir).context_with("Error creating lock dir", ea!(path = dir.to_string_lossy()))?;
+    return Ok(dir);
+}
+
+fn open_lock_file(path: &Path) -> Result<File, loga::Error> {
+    return OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .context_with("Error opening lock file", ea!(path = path.to_string_lossy()));
+}
+
+fn lock(kind: &str, name: &str, mode: LockMode, wait: bool) -> Result<Lock, loga::Error> {
+    let path = locks_dir()?.join(format!("{}-{}.lock", kind, name));
+    let file = open_lock_file(&path)?;
+    let mut out = Lock {
+        file: file,
+        path: path,
+        desc: format!("{} `{}`", kind, name),
+        // Placeholder to force `set_mode` to lock
+        mode: match mode {
+            LockMode::Shared => LockMode::Exclusive,
+            LockMode::Exclusive => LockMode::Shared,
+        },
+        wait: wait,
+        enclosing: enclosing().is_some_and(|e| match kind {
+            "basis" => e.basis_name == name,
+            "system" => e.system_name.as_deref() == Some(name),
+            _ => false,
+        }),
+    };
+    out.set_mode(mode)?;
+    return Ok(out);
+}
+
+pub fn lock_basis(name: &str, mode: LockMode, wait: bool) -> Result<Lock, loga::Error> {
+    return lock("basis", name, mode, wait);
+}
+
+pub fn lock_system(name: &str, mode: LockMode, wait: bool) -> Result<Lock, loga::Error> {
+    return lock("system", name, mode, wait);
+}
+
+/// Short-lived lock held while deciding whether to mount or unmount a system.
+pub fn lock_system_mount(name: &str) -> Result<Lock, loga::Error> {
+    return lock("system-mount", name, LockMode::Exclusive, true);
+}
+
+/// Lock a host directory mapped as a drive in a system, failing if another system
+/// has it locked. Processes using the same system share the lock.
+pub fn lock_drive_target(system_name: &str, target: &Path) -> Result<Lock, loga::Error> {
+    let target = target.canonicalize().unwrap_or_else(|_| target.to_path_buf());
+    let kind = format!("drive-{:08x}", crc32(target.as_os_str().as_bytes()));
+
+    // Each system using the directory holds a shared lock on its own file, checked
+    // while holding this
+    let _check_lock = lock(&kind, "check", LockMode::Exclusive, true)?;
+    let dir = locks_dir()?;
+    let prefix = format!("{}-system-", kind);
+    for entry in read_dir(&dir).context_with("Error listing lock dir", ea!(path = dir.to_string_lossy()))? {
+        let entry = entry.context("Error reading lock dir entry")?;
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let Some(other) = file_name.strip_prefix(&prefix).and_then(|n| n.strip_suffix(".lock")) else {
+            continue;
+        };
+        if other == system_name {
+            continue;
+        }
+        match open_lock_file(&entry.path())?.try_lock() {
+            Ok(_) => { },
+            Err(TryLockError::WouldBlock) => {
+                return Err(
+                    loga::err_with(
+                        format!(
+                            "A drive directory is in use by system `{}`, stop it first so they don't write to it at the same time",
+                            other
+                        ),
+                        ea!(path = target.to_string_lossy()),
+                    ),
+                );
+            },
+            Err(TryLockError::Error(e)) => {
+                return Err(e).context_with("Error checking lock", ea!(lock = entry.path().to_string_lossy()));
+            },
+        }
+    }
+    return lock(&format!("{}-system", kind), system_name, LockMode::Shared, false);
+}
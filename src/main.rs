@@ -1,35 +1,278 @@
+mod addons;
+mod audio;
+mod capabilities;
+mod desktop_entry;
+mod drives;
+mod dxvk;
+mod enclosing;
+mod escape;
+mod exe_icon;
+mod graph;
+mod gpu;
+mod grep;
+mod health;
+mod history;
+mod integrate;
+mod landlock;
+mod launchers;
+mod localize;
+mod lock;
+mod menus;
+mod manifest;
+mod mount;
+mod nested_x;
+mod priority;
+mod registry;
+mod runtime;
+mod sandbox;
+mod secrets;
+mod server;
+#[cfg(feature = "systemd")]
+mod service;
+mod shell_link;
+mod signals;
+mod steam;
+mod template;
+#[cfg(feature = "test-mode")]
+mod test_mode;
+mod window_tag;
+mod wines;
+mod winetricks;
+
 use {
     aargvark::{
-        vark,
+        vark_explicit,
         Aargvark,
-    },
-    command_fds::{
-        CommandFdExt,
-        FdMapping,
+        AargvarkFromStr,
+        HelpPattern,
+        HelpPatternElement,
+        HelpState,
     },
     defer::defer,
-    directories::ProjectDirs,
-    format_bytes::format_bytes,
+    directories::{
+        BaseDirs,
+        ProjectDirs,
+    },
     loga::{
         ea,
-        fatal,
         DebugDisplay,
+        ErrContext,
         ResultContext,
         StandardFlag,
         StandardLog,
     },
-    os_pipe::pipe,
     serde::{
         Deserialize,
         Serialize,
     },
+    serde_json::json,
     shlex::bytes::try_quote,
+    runtime::{
+        runtime_dir,
+        format_duration,
+        format_size,
+        now_secs,
+        prefix_processes,
+        read_mount_state,
+        remove_mount_state,
+        list_mount_states,
+        list_export_states,
+        remove_export_state,
+        write_export_state,
+        is_mounted,
+        ExportState,
+        mounted_systems,
+        stale_systems,
+        Staleness,
+        list_run_states,
+        broken_state_files,
+        remove_run_state,
+        run_states_dir,
+        write_run_state,
+        pid_alive,
+        RunState,
+    },
+    signals::Handler,
+    capabilities::{
+        capabilities,
+        require,
+        Capability,
+    },
+    addons::{
+        decline_addons,
+        install_cached_addons,
+        AssumeAddons,
+    },
+    enclosing::detect_enclosing,
+    escape::Hotkey,
+    grep::{
+        dir_names,
+        grep_system,
+        Matcher,
+    },
+    registry::{
+        diff_registry,
+        installed_programs,
+        read_registry_file,
+        split_windows_command_line,
+        windows_program_path,
+        InstalledProgram,
+        read_registry_tree,
+        RegistryChangeKind,
+        REGISTRY_FILES,
+    },
+    health::{
+        apply_fix,
+        check_health,
+        health_score,
+        PrefixView,
+        Severity,
+    },
+    window_tag::WindowTagger,
+    gpu::GpuChoice,
+    desktop_entry::{
+        desktop_entry,
+        desktop_entry_id,
+        desktop_entry_path,
+        system_run_command_line,
+    },
+    exe_icon::install_program_icon,
+    integrate::{
+        choose_system,
+        open_entry,
+        open_entry_path,
+        parse_url,
+        set_url_handler_default,
+        update_desktop_database,
+        url_entry,
+        url_entry_path,
+        WinebasinUrl,
+    },
+    launchers::{
+        launcher_entry,
+        lutris_installer,
+        LauncherFormat,
+    },
+    steam::{
+        add_shortcut,
+        shortcuts_path,
+        steam_running,
+        Shortcut,
+    },
+    menus::{
+        disable_menus,
+        remove_menu_entries,
+    },
+    drives::{
+        apply_drives,
+        apply_z_drive,
+        remove_drives,
+        ZDrive,
+    },
+    priority::{
+        IoPriority,
+        ProcessPriority,
+    },
+    sandbox::{
+        bwrap_wrapper,
+        SandboxConfig,
+    },
+    landlock::Landlock,
+    shell_link::shell_link_command,
+    audio::{
+        set_audio_driver,
+        AudioDriver,
+    },
+    dxvk::{
+        fetch_dxvk,
+        install_dxvk,
+        remove_dxvk,
+        BasisDxvk,
+        DEFAULT_DXVK_VERSION,
+    },
+    localize::localize_basis,
+    manifest::{
+        apply_manifest,
+        read_manifest,
+    },
+    history::{
+        append_history,
+        format_time,
+        read_history,
+        wine_version,
+        HistoryLatestEntry,
+    },
+    nested_x::{
+        check_resolution,
+        nested_x_command,
+        NestedX,
+        NestedXServer,
+    },
+    graph::{
+        build_graph,
+        disk_usage,
+        graph_json,
+        print_dot,
+        print_tree,
+    },
+    secrets::{
+        mask_secrets,
+        SecretEnv,
+        SecretRef,
+    },
+    server::{
+        run_server,
+        ServerConfig,
+    },
+    template::expand_template,
+    lock::{
+        lock_basis,
+        lock_drive_target,
+        lock_system,
+        Lock,
+        LockMode,
+    },
+    mount::{
+        mount_command,
+        mount_prefix,
+        mount_readonly,
+        select_backend,
+        unmount_command,
+        BackendChoice,
+        MountBackend,
+    },
+    wines::{
+        add_flatpak_wine,
+        check_wine_name,
+        detect_wine_install,
+        install_wine,
+        installed_wines_dir,
+        read_wines,
+        registered_wine,
+        write_wines,
+    },
+    winetricks::{
+        load_preset,
+        prefetch_winetricks,
+        run_winetricks,
+        winetricks_bin,
+        sha256_file,
+        WinetricksPin,
+        WINETRICKS_PIN,
+        WINETRICKS_CACHE,
+        winetricks_cache_dir,
+    },
     std::{
         cell::OnceCell,
-        collections::HashMap,
+        collections::{
+            BTreeMap,
+            HashMap,
+            HashSet,
+        },
         env::{
             self,
             current_dir,
+            current_exe,
         },
         ffi::{
             OsStr,
@@ -38,17 +281,25 @@ use {
         fs::{
             self,
             create_dir_all,
+            File,
         },
         io::{
-            BufRead,
-            BufReader,
+            stdin,
+            stderr,
+            Read,
             Write,
         },
         os::{
             linux::fs::MetadataExt,
-            unix::ffi::{
-                OsStrExt,
-                OsStringExt,
+            unix::{
+                ffi::{
+                    OsStrExt,
+                    OsStringExt,
+                },
+                process::{
+                    CommandExt,
+                    ExitStatusExt,
+                },
             },
         },
         path::{
@@ -56,17 +307,60 @@ use {
             PathBuf,
         },
         process::{
+            exit,
             Command,
+            ExitStatus,
             Stdio,
         },
         rc::Rc,
+        sync::OnceLock,
+        sync::atomic::{
+            AtomicBool,
+            Ordering,
+        },
+        thread::{
+            self,
+            sleep,
+        },
+        time::{
+            Duration,
+            Instant,
+        },
     },
 };
+#[cfg(feature = "systemd")]
+use service::{
+    service_name,
+    service_path,
+    service_unit,
+};
 
-#[derive(Aargvark, Serialize, Deserialize, Clone, Copy)]
+#[derive(Aargvark, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 enum Arch {
     Win32,
     Win64,
+    /// A 64 bit prefix for wine built in the new WoW64 mode (wine 9+), which runs 32
+    /// bit programs without 32 bit host libraries. There's no separate `wine64`.
+    Wow64,
+}
+
+impl Arch {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Arch::Win32 => "win32",
+            Arch::Win64 => "win64",
+            Arch::Wow64 => "wow64",
+        }
+    }
+
+    /// The `WINEARCH` for the prefix, also what's in `#arch=` in `system.reg`. WoW64
+    /// prefixes are ordinary 64 bit prefixes.
+    pub fn winearch(&self) -> &'static str {
+        match self {
+            Arch::Win32 => "win32",
+            Arch::Win64 | Arch::Wow64 => "win64",
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -74,20 +368,368 @@ struct BasisConfigV1 {
     arch: Arch,
 }
 
-type BasisLatestConfig = BasisConfigV1;
+#[derive(Serialize, Deserialize, Clone)]
+struct BasisConfigV2 {
+    arch: Arch,
+    /// Environment variables (ex: `WINEESYNC`, `WINEDLLOVERRIDES`) set for everything
+    /// run with the basis or its systems.
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    /// Winetricks verbs installed in the prefix, in order, from the preset,
+    /// `--winetricks`, and `basis winetricks`.
+    #[serde(default)]
+    winetricks: Vec<String>,
+    /// Registry files (contents) imported after the winetricks verbs, from
+    /// `--registry`.
+    #[serde(default)]
+    registry: Vec<String>,
+    /// Shell script run in the prefix last, from `--winetricks-script`.
+    #[serde(default)]
+    script: Option<String>,
+    /// Wine program and arguments that create the prefix and update it after wine is
+    /// upgraded. Defaults to `wineboot --update`.
+    #[serde(default)]
+    update_command: Option<Vec<String>>,
+    /// Name of a wine installation added with `wine add` (or `wine install`) to use
+    /// instead of the wine in `PATH`. May be Proton.
+    #[serde(default)]
+    wine: Option<String>,
+    /// DXVK release installed with `basis dxvk install`, installed again after the
+    /// registry files when the basis is recreated.
+    #[serde(default)]
+    dxvk: Option<BasisDxvk>,
+    /// Keep DXVK and VKD3D-Proton shader/state caches for all systems using the basis
+    /// in `cache/shaders/BASIS` in the root, instead of in each system's prefix, so
+    /// new and recreated systems start with warm caches.
+    #[serde(default)]
+    shared_shader_cache: bool,
+    /// Which faster wine synchronization implementations to use.
+    #[serde(default)]
+    sync: SyncConfig,
+    /// The audio driver wine uses, set in the prefix when it's built.
+    #[serde(default)]
+    audio: AudioDriver,
+}
+
+type BasisLatestConfig = BasisConfigV2;
+
+impl BasisLatestConfig {
+    /// The config for a new basis with the arch, with everything else default.
+    fn new(arch: Arch) -> BasisLatestConfig {
+        return BasisLatestConfig {
+            arch: arch,
+            env: BTreeMap::new(),
+            winetricks: vec![],
+            registry: vec![],
+            script: None,
+            update_command: None,
+            wine: None,
+            dxvk: None,
+            shared_shader_cache: false,
+            sync: SyncConfig::default(),
+            audio: AudioDriver::Auto,
+        };
+    }
+}
 
 #[derive(Serialize, Deserialize)]
 enum BasisConfig {
     V1(BasisConfigV1),
+    V2(BasisConfigV2),
+}
+
+#[derive(Aargvark, Serialize, Deserialize, Clone, Copy)]
+enum UpdatePolicy {
+    /// Skip the update with a warning and use the basis as-is (`system run` then also
+    /// needs `--allow-wine-mismatch` if the wine version changed)
+    Defer,
+    /// Wait for the systems to be unmounted, then update
+    Wait,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct GlobalConfigV1 {
+    /// What to do when starting a system whose basis needs an update while other
+    /// systems using the basis are mounted. Defaults to `Defer`.
+    update_policy: Option<UpdatePolicy>,
+    /// How long `system run` waits for programs still running in the prefix after the
+    /// command exits before killing them. Defaults to waiting forever.
+    wineserver_timeout_secs: Option<u64>,
+    /// Where to store bases and systems instead of the default data directory. `~` and
+    /// `$VAR`/`${VAR}` are expanded. Overridden by `WINEBASIN_ROOT` and `--root`.
+    root: Option<String>,
+    /// Additional roots (ex: a shared network directory) to look for bases in, in
+    /// order, when a basis isn't in the main root. These are used read-only. Expanded
+    /// like `root`.
+    extra_roots: Vec<String>,
+    /// Copy bases from extra roots to the main root the first time a system using them
+    /// is started, so they keep working without the extra root.
+    localize_extra_bases: bool,
+    /// Download and use this winetricks release for installing verbs, rather than the
+    /// `winetricks` in `PATH`.
+    winetricks_pin: Option<WinetricksPin>,
+    /// Where winetricks keeps downloads, shared by all bases and systems. Defaults to
+    /// `cache/winetricks` in the root. Expanded like `root`. Overridden by `W_CACHE`.
+    winetricks_cache: Option<String>,
+    /// Default `WINEDEBUG` for `system run` if not already set in the environment or
+    /// the basis or system `env`. Overridden by `system run --wine-debug`.
+    wine_debug: Option<String>,
+    /// Short names for longer commands, see `expand_alias`.
+    aliases: BTreeMap<String, String>,
+    /// Name for systems created by `system shell`/`system run` when given a basis
+    /// name. `{basis}` is the basis name, `{date}` today's date (UTC, `YYYY-MM-DD`),
+    /// and `{n}` the lowest number that makes the name unused. `{{` and `}}` are
+    /// literal braces. Defaults to `{basis}-{date}-{n}`.
+    system_name_template: Option<String>,
+}
+
+type GlobalLatestConfig = GlobalConfigV1;
+
+#[derive(Serialize, Deserialize)]
+enum GlobalConfig {
+    V1(GlobalConfigV1),
 }
 
 #[derive(Serialize, Deserialize)]
 struct SystemConfigV1 {
     basis_name: String,
+    /// If set, `system run` only runs these executables (paths relative to `drive_c`)
+    /// and `system shell` is disabled. Make the config file read-only to users for
+    /// this to be meaningful.
+    #[serde(default)]
+    allowed_commands: Option<Vec<PathBuf>>,
+    /// How to run the system unattended with `system server`.
+    #[serde(default)]
+    server: Option<ServerConfig>,
+    /// Environment variables for everything run in the system, overriding the basis'
+    /// `env`.
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    /// Environment variables like `env`, but with values looked up when a program
+    /// starts (ex: a license server token in `pass`) so they aren't written in the
+    /// config. They're masked in `--dry-run` output and logged commands.
+    #[serde(default)]
+    secret_env: BTreeMap<String, SecretRef>,
+    /// DLL overrides (ex: `d3d11` -> `n,b`), added to `WINEDLLOVERRIDES` after any
+    /// from the basis or system `env`.
+    #[serde(default)]
+    dll_overrides: BTreeMap<String, String>,
+    /// Set the WM_CLASS class of windows opened with `system run` to
+    /// `winebasin-SYSTEM`, for window manager rules. Requires `xdotool`.
+    #[serde(default)]
+    tag_windows: bool,
+    /// Start programs with umu-launcher (`umu-run`) instead of wine, for Proton and
+    /// the game's protonfixes.
+    #[serde(default)]
+    umu: Option<UmuConfig>,
+    /// Keep the Mesa and NVIDIA driver shader caches in `shader_cache` in the system
+    /// directory instead of the user's shared cache, so they're deleted with the
+    /// system.
+    #[serde(default)]
+    isolate_shader_cache: bool,
+    /// Overrides the basis' `sync` settings that are set.
+    #[serde(default)]
+    sync: SyncConfig,
+    /// Run programs with wine's native Wayland driver (wine 9+) instead of X11 when
+    /// there's a Wayland session.
+    #[serde(default)]
+    wayland: bool,
+    /// Run `system run` programs in a wine virtual desktop with this resolution (ex:
+    /// `1024x768`) unless `--virtual-desktop` is specified.
+    #[serde(default)]
+    virtual_desktop: Option<String>,
+    /// Run `system run` programs with Feral GameMode (`gamemoderun`).
+    #[serde(default)]
+    gamemode: bool,
+    /// Show the MangoHud performance overlay in Vulkan programs (including DXVK and
+    /// VKD3D-Proton).
+    #[serde(default)]
+    mangohud: bool,
+    /// Apply vkBasalt post-processing shaders to Vulkan programs.
+    #[serde(default)]
+    vkbasalt: Option<VkbasaltConfig>,
+    /// Which GPU to render with, `discrete`, `integrated`, or a Mesa device index (as
+    /// a string).
+    #[serde(default)]
+    gpu: Option<GpuChoice>,
+    /// CPUs and scheduling priority for programs run with `system run` and
+    /// `system shell`.
+    #[serde(default)]
+    priority: ProcessPriority,
+    /// Run `system run` programs in a transient systemd user scope with these resource
+    /// limits.
+    #[serde(default)]
+    systemd_scope: Option<SystemdScopeConfig>,
+    /// Run `system run` programs in a bubblewrap sandbox that only exposes the prefix,
+    /// the host's system directories and devices, and these allowed paths.
+    #[serde(default)]
+    sandbox: Option<SandboxConfig>,
+    /// Confine `system run` programs' filesystem access with Landlock, like `sandbox`
+    /// but without extra programs.
+    #[serde(default)]
+    landlock: Option<SandboxConfig>,
+    /// The audio driver set in the system with `system set-audio`, overriding the
+    /// basis'.
+    #[serde(default)]
+    audio: AudioDriver,
+    /// Remove the `z:` drive (the whole host filesystem) or point it somewhere else,
+    /// applied each time the system is mounted. Left as wine made it if unset.
+    #[serde(default)]
+    z_drive: Option<ZDrive>,
+    /// Extra drives mapped to host paths, ex: `{"d:": "/mnt/games/IsoCache"}`. Linked
+    /// in the prefix's `dosdevices` each time the system is mounted.
+    #[serde(default)]
+    drives: BTreeMap<String, PathBuf>,
+    /// Remove the `drives` links from the system's prefix when it's unmounted, so they
+    /// only exist while the system is in use.
+    #[serde(default)]
+    remove_drives_on_unmount: bool,
+    /// Let wine add menu entries and file associations for installed programs to the
+    /// host. Otherwise `winemenubuilder` is disabled and entries it added before for
+    /// the system are removed when it's mounted.
+    #[serde(default)]
+    allow_menus: bool,
+    /// Short names for programs in the system, for `system app run`.
+    #[serde(default)]
+    apps: BTreeMap<String, AppConfig>,
+}
+
+/// A program registered with `system app add`.
+#[derive(Serialize, Deserialize, Clone)]
+struct AppConfig {
+    /// The program, relative to `drive_c`.
+    exe: String,
+    /// Arguments always passed to the program.
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+/// Resource limits for the systemd scope programs run in, see
+/// `systemd.resource-control(5)`.
+#[derive(Serialize, Deserialize, Clone)]
+struct SystemdScopeConfig {
+    /// Memory limit (`MemoryMax`), ex: `8G`.
+    #[serde(default)]
+    memory_max: Option<String>,
+    /// CPU time limit (`CPUQuota`), ex: `200%` for two cores' worth.
+    #[serde(default)]
+    cpu_quota: Option<String>,
+}
+
+/// vkBasalt settings for a system.
+#[derive(Serialize, Deserialize, Clone)]
+struct VkbasaltConfig {
+    /// The vkBasalt config file, relative to the system directory. Defaults to
+    /// vkBasalt's own search (ex: `~/.config/vkBasalt/vkBasalt.conf`).
+    #[serde(default)]
+    config_file: Option<PathBuf>,
+}
+
+/// Turn wine's faster synchronization implementations on or off. Unset leaves it
+/// to the wine build's default. Set for both wine (`WINEESYNC` etc.) and Proton
+/// (`PROTON_NO_ESYNC` etc.).
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+struct SyncConfig {
+    #[serde(default)]
+    esync: Option<bool>,
+    #[serde(default)]
+    fsync: Option<bool>,
+    /// The ntsync kernel driver (`/dev/ntsync`), for builds that support it.
+    #[serde(default)]
+    ntsync: Option<bool>,
+}
+
+impl SyncConfig {
+    /// Everything off, for `--no-fsync`.
+    fn disabled() -> SyncConfig {
+        return SyncConfig {
+            esync: Some(false),
+            fsync: Some(false),
+            ntsync: Some(false),
+        };
+    }
+
+    /// Set the environment variables for the settings that are set, unless `keep` (ex:
+    /// the config's `env`) sets them explicitly.
+    fn apply(&self, env: &mut HashMap<OsString, OsString>, keep: &BTreeMap<String, String>) {
+        let flag = |on: bool| if on {
+            "1"
+        } else {
+            "0"
+        };
+        let mut vars = vec![];
+        if let Some(on) = self.esync {
+            vars.push(("WINEESYNC", flag(on)));
+            vars.push(("PROTON_NO_ESYNC", flag(!on)));
+        }
+        if let Some(on) = self.fsync {
+            vars.push(("WINEFSYNC", flag(on)));
+            vars.push(("PROTON_NO_FSYNC", flag(!on)));
+        }
+        if let Some(on) = self.ntsync {
+            vars.push(("WINENTSYNC", flag(on)));
+            vars.push(("PROTON_USE_NTSYNC", flag(on)));
+        }
+        for (k, v) in vars {
+            if !keep.contains_key(k) {
+                env.insert(OsString::from(k), OsString::from(v));
+            }
+        }
+    }
 }
 
 type SystemLatestConfig = SystemConfigV1;
 
+impl SystemLatestConfig {
+    /// The config for a new system using the basis, with everything else default.
+    fn new(basis_name: &str) -> SystemLatestConfig {
+        return SystemLatestConfig {
+            basis_name: basis_name.to_string(),
+            allowed_commands: None,
+            server: None,
+            env: BTreeMap::new(),
+            secret_env: BTreeMap::new(),
+            dll_overrides: BTreeMap::new(),
+            tag_windows: false,
+            umu: None,
+            isolate_shader_cache: false,
+            wayland: false,
+            virtual_desktop: None,
+            gamemode: false,
+            mangohud: false,
+            vkbasalt: None,
+            gpu: None,
+            priority: ProcessPriority::default(),
+            systemd_scope: None,
+            sandbox: None,
+            landlock: None,
+            audio: AudioDriver::Auto,
+            z_drive: None,
+            drives: BTreeMap::new(),
+            remove_drives_on_unmount: false,
+            allow_menus: false,
+            apps: BTreeMap::new(),
+            sync: SyncConfig::default(),
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct UmuConfig {
+    /// `GAMEID`, which selects the protonfixes to apply (ex: `umu-starcitizen`, see
+    /// the umu database). `0` applies none.
+    game_id: String,
+    /// `PROTONPATH`, the Proton to use: a path or `GE-Proton` for the latest
+    /// GE-Proton. Defaults to the basis' wine if it's Proton, otherwise umu's default
+    /// (the latest UMU-Proton).
+    #[serde(default)]
+    proton_path: Option<String>,
+    /// `STORE` (ex: `gog`, `egs`), if the game id is the store's.
+    #[serde(default)]
+    store: Option<String>,
+}
+
 #[derive(Serialize, Deserialize)]
 enum SystemConfig {
     V1(SystemConfigV1),
@@ -96,10 +738,110 @@ enum SystemConfig {
 #[derive(Aargvark)]
 struct BasisCreateArgs {
     basis_name: String,
-    /// Defaults to `win64`.
+    /// Defaults to `win64`. `wow-64` is for wine built in the new WoW64 mode.
     arch: Option<Arch>,
-    /// Install a recommended 5Gb of winetricks dlls to the prefix
-    recommended_winetricks: Option<()>,
+    /// Install a preset collection of winetricks verbs to the prefix. Built-in presets
+    /// are `recommended` (a billion, 5+GB), `gaming`, `office`, and `minimal`. Presets
+    /// can be added or overridden by placing `NAME.json` in `presets/` in the
+    /// winebasin config directory (ex: `~/.config/winebasin/presets/`).
+    preset: Option<String>,
+    /// Don't ask for confirmation before installing a preset.
+    yes: Option<()>,
+    /// Install these winetricks verbs (ex: `--winetricks vcrun2019 corefonts`), after
+    /// any preset.
+    winetricks: Option<Vec<WinetricksVerb>>,
+    /// Import this registry file (`.reg`, ex: exported with `regedit`) into the new
+    /// prefix, after installing any winetricks verbs.
+    registry: Option<PathBuf>,
+    /// Run this script in a shell in the new prefix (with the wine environment set),
+    /// after installing any winetricks verbs and importing any registry file.
+    winetricks_script: Option<PathBuf>,
+    /// Use this wine installation (added with `wine add`) instead of the wine in
+    /// `PATH`.
+    wine: Option<String>,
+    /// Make wine use this audio driver instead of picking one itself.
+    audio: Option<AudioDriver>,
+}
+
+/// A winetricks verb from the command line. Verbs never start with `-`, so a list
+/// of verbs ends at the next flag.
+struct WinetricksVerb(String);
+
+impl AargvarkFromStr for WinetricksVerb {
+    fn from_str(s: &str) -> Result<Self, String> {
+        if s.is_empty() || s.starts_with('-') {
+            return Err(format!("Expected a winetricks verb, got `{}`", s));
+        }
+        return Ok(WinetricksVerb(s.to_string()));
+    }
+
+    fn build_help_pattern(_state: &mut HelpState) -> HelpPattern {
+        return HelpPattern(vec![HelpPatternElement::Type("VERB".to_string())]);
+    }
+}
+
+/// An environment variable from the command line, `KEY=VALUE`.
+struct EnvArg {
+    key: String,
+    value: String,
+}
+
+impl AargvarkFromStr for EnvArg {
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.split_once('=') {
+            Some((key, value)) if !key.is_empty() => {
+                return Ok(EnvArg {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                });
+            },
+            _ => return Err(format!("Expected an environment variable like `KEY=VALUE`, got `{}`", s)),
+        }
+    }
+
+    fn build_help_pattern(_state: &mut HelpState) -> HelpPattern {
+        return HelpPattern(vec![HelpPatternElement::Type("KEY=VALUE".to_string())]);
+    }
+}
+
+/// Set `WINEDEBUG` from `--wine-debug`, or the global config default if neither
+/// the configs nor the calling environment set it.
+fn apply_wine_debug(env: &mut HashMap<OsString, OsString>, arg: &Option<String>, default: &Option<String>) {
+    if let Some(channels) = arg {
+        env.insert(OsString::from("WINEDEBUG"), OsString::from(channels));
+    } else if let Some(channels) = default {
+        if env::var_os("WINEDEBUG").is_none() {
+            env.entry(OsString::from("WINEDEBUG")).or_insert_with(|| OsString::from(channels));
+        }
+    }
+}
+
+/// Copy wine's output to stderr and a log file.
+fn tee_to_log(log: &StandardLog, mut source: impl Read, mut file: File) {
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = match source.read(&mut buf) {
+            Ok(0) => return,
+            Ok(n) => n,
+            Err(e) => {
+                log.log_err(StandardFlag::Warning, e.context("Error reading wine output"));
+                return;
+            },
+        };
+        _ = stderr().write_all(&buf[..n]);
+        if let Err(e) = file.write_all(&buf[..n]) {
+            log.log_err(StandardFlag::Warning, e.context("Error writing wine output to log"));
+            return;
+        }
+    }
+}
+
+/// Add environment variables from `--env` to the environment built from the
+/// configs.
+fn add_arg_envs(env: &mut HashMap<OsString, OsString>, args: &Option<Vec<EnvArg>>) {
+    for arg in args.iter().flatten() {
+        env.insert(OsString::from(&arg.key), OsString::from(&arg.value));
+    }
 }
 
 #[derive(Aargvark)]
@@ -107,6 +849,9 @@ struct BasisShellArgs {
     basis_name: String,
     /// Command to run in the shell, such as a script. If empty, interactive shell.
     command: Vec<String>,
+    /// Additional environment variables for this shell, ex:
+    /// `--env WINEDEBUG=+loaddll DXVK_HUD=1`. These override the basis config.
+    env: Option<Vec<EnvArg>>,
 }
 
 #[derive(Aargvark)]
@@ -123,29 +868,448 @@ enum BasisArgs {
     Update {
         basis_name: String,
     },
+    /// Install more winetricks verbs into an existing basis, adding them to the verbs
+    /// recorded in the basis config. Systems using the basis must not be running.
+    Winetricks {
+        basis_name: String,
+        verbs: Vec<WinetricksVerb>,
+    },
+    /// Delete the basis prefix and build it again from scratch following the recipe in
+    /// the basis config (architecture, winetricks verbs, registry files, script), ex:
+    /// after a wine upgrade breaks it. The old prefix is restored if rebuilding fails.
+    /// Systems using the basis must not be running.
+    Recreate {
+        basis_name: String,
+    },
+    /// Manage the DXVK (Direct3D 8-11 on Vulkan) release installed in the basis.
+    Dxvk(BasisDxvkArgs),
+    /// Change the audio driver wine uses in the basis (`auto` lets wine pick) and
+    /// record it in the basis config. Systems using the basis must not be running.
+    SetAudio {
+        basis_name: String,
+        driver: AudioDriver,
+    },
     /// Open a shell inside the basis `drive_c` dir
     Shell(BasisShellArgs),
+    /// Copy a basis from an extra root to the main root, so it keeps working without
+    /// the extra root and can be modified. See `localize_extra_bases` in the global
+    /// config to do this automatically.
+    Localize {
+        basis_name: String,
+    },
+    /// List bases in the main root and any `extra_roots`, with the root each is in.
+    /// Bases hidden by a basis with the same name in an earlier root are marked
+    /// shadowed.
+    List,
     /// Print the path to the basis directory (contains basis prefix and other config).
     Path {
         basis_name: String,
     },
 }
 
+#[derive(Aargvark)]
+#[vark(break)]
+enum BasisDxvkArgs {
+    /// Download a DXVK release (cached in `cache/dxvk` in the root), copy its DLLs
+    /// into the basis prefix, set the DLL overrides, and record the version in the
+    /// basis config. Replaces any DXVK already installed. Systems using the basis must
+    /// not be running.
+    Install {
+        basis_name: String,
+        /// Defaults to a recent release.
+        version: Option<String>,
+        /// SHA-256 of the release archive, hex. The checksum of what was downloaded is
+        /// recorded either way, so recreating the basis uses the same files.
+        #[vark(literal = "sha256")]
+        sha256: Option<String>,
+    },
+    /// Remove DXVK's DLLs and overrides from the basis prefix, restoring wine's own.
+    Remove {
+        basis_name: String,
+    },
+}
+
 #[derive(Aargvark)]
 struct SystemShellArgs {
     system_name: String,
+    /// If `SYSTEM-NAME` is a basis, use the system with this name, creating it from
+    /// the basis if it doesn't exist.
+    name: Option<String>,
     /// Command to run in the shell, such as a script. If empty, interactive shell.
     command: Vec<String>,
+    /// Additional environment variables for this shell, ex:
+    /// `--env WINEDEBUG=+loaddll DXVK_HUD=1`. These override the basis and system
+    /// configs.
+    env: Option<Vec<EnvArg>>,
+    /// Turn off fsync, esync and ntsync, overriding the configs, for debugging crashes
+    /// related to synchronization.
+    no_fsync: Option<()>,
+    /// Use wine's Wayland driver, as if `wayland` were set in the system config.
+    wayland: Option<()>,
+    /// Use X11 even if `wayland` is set in the system config.
+    no_wayland: Option<()>,
+    /// Let installers add menu entries and file associations to the host, as if
+    /// `allow_menus` were set in the system config.
+    allow_menus: Option<()>,
+    /// Set `WINEBASIN_VIRTUAL_DESKTOP` for running programs in a wine virtual desktop
+    /// with this resolution (ex: `1024x768`), with
+    /// `wine explorer /desktop=$WINEBASIN_VIRTUAL_DESKTOP PROGRAM`. Overrides the
+    /// system config.
+    virtual_desktop: Option<String>,
+    /// Pin the programs to these CPUs (ex: `0-3` or `0,2`) with `taskset`, for old
+    /// games that misbehave with many cores. Overrides the system config.
+    cpus: Option<String>,
+    /// Run with this niceness (-20 to 19, higher is lower priority). Overrides the
+    /// system config.
+    nice: Option<i32>,
+    /// Run with this IO priority, `idle` or a best-effort level from 0 (highest) to 7.
+    /// Overrides the system config.
+    ionice: Option<IoPriority>,
 }
 
 #[derive(Aargvark)]
 struct SystemRunArgs {
     system_name: String,
-    /// Command and arguments, relative to `drive_c`.
+    /// If `SYSTEM-NAME` is a basis, use the system with this name, creating it from
+    /// the basis if it doesn't exist.
+    name: Option<String>,
+    /// Command and arguments, relative to `drive_c`. `.msi` packages are installed
+    /// with `msiexec /i`, passing the arguments (ex: `/quiet`) to msiexec. Shortcuts
+    /// (`.lnk`) start the program they point to.
     command: Vec<String>,
     /// Working directory of command - defaults to parent directory of specified
     /// command.
     working_dir: Option<PathBuf>,
+    /// After the command exits, wait at most this many seconds for other programs
+    /// running in the prefix (ex: launched by a launcher) to exit before killing them
+    /// and unmounting. Overrides the global config.
+    wineserver_timeout: Option<u64>,
+    /// Start the system and program in the background and return immediately. Use
+    /// `system stop` to stop it.
+    detach: Option<()>,
+    /// Print how long each step of starting the program took (to stderr), to find
+    /// what's making startup slow.
+    profile_startup: Option<()>,
+    /// Additional environment variables for this run, ex:
+    /// `--env WINEDEBUG=+loaddll DXVK_HUD=1`. These override the basis and system
+    /// configs. Not allowed for systems with a list of allowed commands.
+    env: Option<Vec<EnvArg>>,
+    /// Run the program in a nested X server window with this resolution (ex:
+    /// `1920x1080`), so fullscreen mode changes and input grabs don't affect the
+    /// desktop.
+    nested_x: Option<String>,
+    /// Which nested X server to use with `--nested-x`. Defaults to `xwayland` under
+    /// Wayland and `xephyr` otherwise.
+    nested_x_server: Option<NestedXServer>,
+    /// Set `WINEDEBUG` to these channels (ex: `+loaddll,-fixme`). Overrides the global
+    /// config and basis and system `env`.
+    wine_debug: Option<String>,
+    /// Also write wine's stderr to a timestamped file in `run_logs` in the system
+    /// directory.
+    log: Option<()>,
+    /// Answer wine's wine-mono/wine-gecko download dialogs ahead of time, for
+    /// unattended installs. `yes` installs missing add-ons from wine's download cache
+    /// before running, `no` disables the DLLs that need them.
+    assume_addons: Option<AssumeAddons>,
+    /// Run even if the basis was last updated with a different wine version than the
+    /// current one (ex: the update was deferred because other systems are running).
+    allow_wine_mismatch: Option<()>,
+    /// Turn off fsync, esync and ntsync, overriding the configs, for debugging crashes
+    /// related to synchronization.
+    no_fsync: Option<()>,
+    /// Use wine's Wayland driver, as if `wayland` were set in the system config.
+    wayland: Option<()>,
+    /// Use X11 even if `wayland` is set in the system config.
+    no_wayland: Option<()>,
+    /// Run the program in a wine virtual desktop with this resolution (ex:
+    /// `1024x768`), so fullscreen mode changes don't affect the real display.
+    /// Overrides the system config.
+    virtual_desktop: Option<String>,
+    /// Run the program with Feral GameMode (`gamemoderun`), which tunes the CPU
+    /// governor and scheduling for games while it runs.
+    gamemode: Option<()>,
+    /// Let the program add menu entries and file associations to the host, as if
+    /// `allow_menus` were set in the system config.
+    allow_menus: Option<()>,
+    /// Show the MangoHud performance overlay (sets `MANGOHUD=1`).
+    mangohud: Option<()>,
+    /// Apply vkBasalt post-processing shaders (sets `ENABLE_VKBASALT=1`).
+    vkbasalt: Option<()>,
+    /// Use this vkBasalt config file (sets `VKBASALT_CONFIG_FILE`). Implies
+    /// `--vkbasalt`.
+    vkbasalt_config: Option<PathBuf>,
+    /// Run the program in a transient systemd user scope
+    /// (`systemd-run --user --scope`), with the limits from `systemd_scope` in the
+    /// system config, so everything it starts can be limited and is stopped together.
+    systemd_scope: Option<()>,
+    /// Run the program in a bubblewrap (`bwrap`) sandbox with the paths allowed by
+    /// `sandbox` in the system config, hiding the home directory.
+    sandbox: Option<()>,
+    /// Confine the program's filesystem access with Landlock (kernel 5.13+) to the
+    /// paths allowed by `landlock` in the system config, hiding the home directory.
+    landlock: Option<()>,
+    /// Render with this GPU: `discrete`, `integrated`, or a Mesa device index (ex:
+    /// `1`). Sets the PRIME render offload variables. Overrides the system config.
+    gpu: Option<GpuChoice>,
+    /// Pin the programs to these CPUs (ex: `0-3` or `0,2`) with `taskset`, for old
+    /// games that misbehave with many cores. Overrides the system config.
+    cpus: Option<String>,
+    /// Run with this niceness (-20 to 19, higher is lower priority). Overrides the
+    /// system config.
+    nice: Option<i32>,
+    /// Run with this IO priority, `idle` or a best-effort level from 0 (highest) to 7.
+    /// Overrides the system config.
+    ionice: Option<IoPriority>,
+}
+
+impl SystemRunArgs {
+    /// Run `command` in the system with the settings from the configs.
+    fn new(system_name: String, command: Vec<String>) -> SystemRunArgs {
+        return SystemRunArgs {
+            system_name: system_name,
+            name: None,
+            command: command,
+            working_dir: None,
+            wineserver_timeout: None,
+            detach: None,
+            allow_menus: None,
+            profile_startup: None,
+            env: None,
+            nested_x: None,
+            nested_x_server: None,
+            wine_debug: None,
+            log: None,
+            assume_addons: None,
+            allow_wine_mismatch: None,
+            no_fsync: None,
+            wayland: None,
+            no_wayland: None,
+            virtual_desktop: None,
+            gamemode: None,
+            mangohud: None,
+            vkbasalt: None,
+            vkbasalt_config: None,
+            gpu: None,
+            systemd_scope: None,
+            sandbox: None,
+            landlock: None,
+            cpus: None,
+            nice: None,
+            ionice: None,
+        };
+    }
+}
+
+#[derive(Aargvark)]
+#[vark(break)]
+enum WineArgs {
+    /// Register a wine installation under a name, for `basis create --wine NAME` or
+    /// `wine` in the basis config. `PATH` is the directory containing `bin/wine` (ex:
+    /// `/opt/wine-staging`), or a Proton install containing the `proton` launcher.
+    Add {
+        name: String,
+        path: PathBuf,
+    },
+    /// Register wine from a Flatpak app, for systems without a native wine (ex:
+    /// immutable distros). Wrapper scripts in `wine/NAME` in the root run it with
+    /// access to the prefix.
+    AddFlatpak {
+        name: String,
+        /// The Flatpak app id. Defaults to `org.winehq.Wine`.
+        app: Option<String>,
+    },
+    /// Download a prebuilt wine build, check its checksum, unpack it into `wine/NAME`
+    /// in the root and register it as `NAME`. Known builds are `wine-ge-VERSION` (ex:
+    /// `wine-ge-8-26`), `lutris-VERSION` (ex: `lutris-7.2-2`) and `tkg-VERSION` (ex:
+    /// `tkg-9.0`), other builds can be installed with `--url`.
+    Install {
+        name: String,
+        /// Download the build archive from here instead of the known location for the name.
+        url: Option<String>,
+        /// SHA-256 of the archive, hex. Required unless the build publishes a checksum
+        /// (wine-ge).
+        #[vark(literal = "sha256")]
+        sha256: Option<String>,
+    },
+    /// List registered wine installations and their versions.
+    List,
+    /// Unregister a wine installation. Bases in the main root using it must be changed
+    /// first. Builds installed with `wine install` are deleted, other installations
+    /// are left in place.
+    Remove {
+        name: String,
+    },
+}
+
+#[derive(Aargvark)]
+#[vark(break)]
+enum CacheArgs {
+    /// Show where the winetricks download cache is and how much space each verb's
+    /// downloads use.
+    Stats,
+    /// Delete cached winetricks downloads, for all verbs or just the specified ones.
+    /// They'll be downloaded again when needed.
+    Clear {
+        verbs: Vec<WinetricksVerb>,
+    },
+    /// Download everything the winetricks verbs need into the cache, so bases using
+    /// them can be created later without network access. Nothing is installed and wine
+    /// isn't run. Verbs whose files must be downloaded manually still need that done
+    /// by hand.
+    Prefetch {
+        /// Download the files needed for this architecture. Defaults to `win-64`, which
+        /// usually needs both 32 and 64 bit installers.
+        arch: Option<Arch>,
+        verbs: Vec<WinetricksVerb>,
+    },
+}
+
+#[derive(Aargvark)]
+struct ManifestRunArgs {
+    /// Read this manifest instead of `winebasin.toml` in the current directory.
+    manifest: Option<PathBuf>,
+    /// Start the program in the background, see `system run --detach`.
+    detach: Option<()>,
+    /// Additional arguments for the command in the manifest.
+    args: Vec<String>,
+}
+
+#[cfg(feature = "systemd")]
+#[derive(Aargvark)]
+struct ServiceInstallArgs {
+    system_name: String,
+    /// Command and arguments, relative to `drive_c`. If empty, runs `system server`
+    /// using the server settings in the system config.
+    command: Vec<String>,
+}
+
+#[cfg(feature = "systemd")]
+#[derive(Aargvark)]
+#[vark(break)]
+enum ServiceArgs {
+    /// Write and enable a systemd user service that runs a command in the system at
+    /// login (ex: a dedicated server). The service runs `system run` so the system is
+    /// mounted while the service runs.
+    Install(ServiceInstallArgs),
+    /// Show the status of the system's service.
+    Status {
+        system_name: String,
+    },
+    /// Stop, disable, and delete the system's service.
+    Remove {
+        system_name: String,
+    },
+}
+
+#[derive(Aargvark)]
+struct SystemAppAddArgs {
+    system_name: String,
+    alias: String,
+    /// The program, relative to `drive_c`.
+    exe: String,
+    /// Arguments to always pass to the program.
+    args: Option<Vec<String>>,
+}
+
+#[derive(Aargvark)]
+struct SystemAppRunArgs {
+    system_name: String,
+    alias: String,
+    /// Start the program in the background, see `system run --detach`.
+    detach: Option<()>,
+    /// Additional arguments, after the registered ones.
+    args: Vec<String>,
+}
+
+#[derive(Aargvark)]
+#[vark(break)]
+enum SystemAppArgs {
+    /// Register a program in the system under a short name, replacing any program
+    /// already registered with the name.
+    Add(SystemAppAddArgs),
+    /// Forget a registered program.
+    Remove {
+        system_name: String,
+        alias: String,
+    },
+    /// List the system's registered programs.
+    List {
+        system_name: String,
+    },
+    /// Run a registered program with `system run`.
+    Run(SystemAppRunArgs),
+}
+
+#[derive(Aargvark)]
+struct SystemDesktopEntryArgs {
+    system_name: String,
+    /// The program to start, relative to `drive_c` (required).
+    exe: Option<String>,
+    /// Name shown in the menu. Defaults to the program's file name.
+    title: Option<String>,
+    /// Icon name from the icon theme, or path to an image. Defaults to the program's
+    /// icon, extracted and installed in the user's icon theme.
+    icon: Option<String>,
+}
+
+#[derive(Aargvark)]
+struct SystemSteamShortcutArgs {
+    system_name: String,
+    /// The program to start, relative to `drive_c` (required).
+    exe: Option<String>,
+    /// Name shown in Steam. Defaults to the program's file name.
+    title: Option<String>,
+    /// Path to an image for the shortcut's icon.
+    icon: Option<PathBuf>,
+    /// The Steam account ID (a directory in Steam's `userdata`) to add the shortcut
+    /// to, if there's more than one.
+    steam_user: Option<String>,
+}
+
+#[derive(Aargvark)]
+struct SystemExportLutrisArgs {
+    system_name: String,
+    /// The program to start, relative to `drive_c` (required).
+    exe: Option<String>,
+    /// Name shown in Lutris. Defaults to the program's file name.
+    title: Option<String>,
+}
+
+#[derive(Aargvark)]
+struct SystemExportLauncherArgs {
+    system_name: String,
+    /// The program to start, relative to `drive_c` (required).
+    exe: Option<String>,
+    /// Name for the launcher to show. Defaults to the program's file name.
+    title: Option<String>,
+    /// Defaults to JSON.
+    format: Option<LauncherFormat>,
+}
+
+#[derive(Aargvark)]
+struct SystemMountArgs {
+    system_name: String,
+    /// Mount read-only. Required, since writable mounts are only supported within
+    /// `shell` and `run`.
+    ro: Option<()>,
+    /// Where to mount the merged view (required).
+    at: Option<PathBuf>,
+}
+
+/// What to compare a system's registry to.
+#[derive(Aargvark)]
+enum RegdiffAgainst {
+    /// The system's basis
+    Basis,
+    /// Another system (as it appears when mounted)
+    System(String),
+}
+
+#[derive(Aargvark)]
+struct SystemUnmountArgs {
+    system_name: String,
+    /// Only unmount the view mounted here, rather than all of the system's views.
+    at: Option<PathBuf>,
 }
 
 #[derive(Aargvark)]
@@ -157,46 +1321,266 @@ enum SystemArgs {
         system_name: String,
     },
     /// Start a system (mount merged prefix) and open a shell inside the system's
-    /// `drive_c` dir. The system prefix will be unmounted when the shell exits.
-    /// Creates the system if it doesn't already exist.
+    /// `drive_c` dir. The system prefix will be unmounted when the shell exits. Given
+    /// a basis name instead, creates a new system using the basis (named by
+    /// `system_name_template` in the global config, or `--name`).
     Shell(SystemShellArgs),
     /// Start a system (mount merged prefix) and run a program inside the system's
-    /// `drive_c` dir. Creates the system if it doesn't already exist.
+    /// `drive_c` dir. Given a basis name instead, creates a new system using the basis
+    /// (named by `system_name_template` in the global config, or `--name`).
     Run(SystemRunArgs),
+    /// Stop programs started with `system run --detach` and unmount the system. Runs
+    /// that haven't exited after 30 seconds are killed.
+    Stop {
+        system_name: String,
+    },
+    /// Install winetricks verbs into the system only (ex: dependencies of one
+    /// program), leaving the basis unchanged. Mounts the system while installing.
+    Winetricks {
+        system_name: String,
+        verbs: Vec<WinetricksVerb>,
+    },
+    /// Change the audio driver wine uses in the system only (`auto` goes back to wine
+    /// picking one) and record it in the system config. Mounts the system while
+    /// changing it.
+    SetAudio {
+        system_name: String,
+        driver: AudioDriver,
+    },
+    /// Run the server configured in the system config in the foreground, without a
+    /// display by default, restarting it if it crashes. Output is written to rotating
+    /// logs in `server_logs` in the system directory.
+    Server {
+        system_name: String,
+    },
+    /// List wine processes running in the system.
+    Ps {
+        system_name: String,
+    },
+    /// Kill all wine processes in a running system (ex: if something is stuck), using
+    /// `wineserver -k`. Works with the system started by any winebasin process.
+    Kill {
+        system_name: String,
+        /// Kill with SIGKILL rather than letting wine shut down.
+        hard: Option<()>,
+    },
+    /// Show commands previously run in the system with `system run`, when, and how
+    /// they exited.
+    History {
+        system_name: String,
+        /// Only show the most recent runs.
+        last: Option<usize>,
+    },
+    /// Check the system's prefix for common problems (broken drive links, corrupted
+    /// registry, architecture mismatch, missing wine-mono/gecko, ...) and suggest
+    /// repairs.
+    Health {
+        system_name: String,
+        /// Make the repairs that can be made automatically. The system must not be mounted.
+        fix: Option<()>,
+    },
+    /// Search the files in one or all systems' prefixes for text (ex: to find which
+    /// system has a program's license key or config), without mounting them. Registry
+    /// files are decoded first, so values are matched as `[KEY] NAME = VALUE`. Binary
+    /// files are searched for the text as UTF-8 and UTF-16, and files over 16 MiB
+    /// other than registry files are skipped.
+    Grep {
+        pattern: String,
+        /// Only search this system.
+        system: Option<String>,
+        ignore_case: Option<()>,
+    },
+    /// Show registry keys and values the system added, removed, or changed compared to
+    /// its basis (or another system), ex: to find out what settings a workaround
+    /// changed.
+    Regdiff {
+        system_name: String,
+        /// Defaults to the system's basis.
+        against: Option<RegdiffAgainst>,
+    },
+    /// List the Windows programs installed in the system (and its basis), from the
+    /// registry's Uninstall keys, with where they're installed and how to uninstall
+    /// them. Doesn't mount the system.
+    Apps {
+        system_name: String,
+    },
+    /// Run the uninstaller of a program installed in the system (see `system apps`)
+    /// with `system run`. `PROGRAM` is the program's name, part of its name, or its ID.
+    Uninstall {
+        system_name: String,
+        program: String,
+    },
+    /// Manage systemd user services for long-running programs.
+    #[cfg(feature = "systemd")]
+    Service(ServiceArgs),
+    /// Register programs in the system under short names, so they can be run without
+    /// their full paths.
+    App(SystemAppArgs),
+    /// Add a program in the system to the desktop's application menu, with a desktop
+    /// entry in `~/.local/share/applications` that starts it with `system run`.
+    DesktopEntry(SystemDesktopEntryArgs),
+    /// Add a program in the system to Steam as a non-Steam game that starts it with
+    /// `system run`, so it can use Steam Input and the overlay. Steam must not be
+    /// running.
+    SteamShortcut(SystemSteamShortcutArgs),
+    /// Print a Lutris installer that adds a program in the system to Lutris, started
+    /// with `system run`. Install it with `lutris -i FILE`.
+    ExportLutris(SystemExportLutrisArgs),
+    /// Print how to start a program in the system (the `system run` command line,
+    /// working dir and the configs' environment) as JSON or TOML, for adding it as a
+    /// custom game in Heroic or other launchers.
+    ExportLauncher(SystemExportLauncherArgs),
+    /// List systems and their bases. Systems with unreadable configs are listed as
+    /// broken.
+    List,
+    /// Replace a system's broken config with a new one using the specified basis. The
+    /// old config is kept as `config.json.broken`.
+    Repair {
+        system_name: String,
+        basis_name: String,
+    },
     /// Print the path to the system directory (prefix layer containing files not in
     /// the basis). Creates the system if it doesn't already exist.
     Path {
         system_name: String,
     },
+    /// Mount a read-only merged view of the system at a chosen location (ex: for
+    /// backup tools) which stays mounted after winebasin exits. Changes made by
+    /// running the system while this is mounted may not be visible in the view.
+    Mount(SystemMountArgs),
+    /// Unmount read-only views created with `system mount`.
+    Unmount(SystemUnmountArgs),
 }
 
 #[derive(Aargvark)]
 #[vark(break)]
-enum Args {
+enum ArgsCommand {
     System(SystemArgs),
     Basis(BasisArgs),
+    /// Show mounted systems, how long they've been mounted, and wine processes still
+    /// using them.
+    Status,
+    /// Unmount leftover system overlays and remove runtime state left by winebasin
+    /// processes that exited without cleaning up (ex: were killed).
+    Cleanup {
+        /// Also unmount all read-only views created with `system mount`.
+        exports: Option<()>,
+    },
+    /// Check for required tools and show which mount backend would be used and why.
+    Doctor,
+    /// Immediately kill (SIGKILL) the programs in a mounted system, which releases any
+    /// keyboard and mouse grabs they hold (ex: a fullscreen program that hung).
+    Escape {
+        system: String,
+    },
+    /// Watch the keyboards for hotkeys that get control back from fullscreen programs
+    /// that hang while grabbing input, until terminated (ex: start it with your
+    /// desktop session). Keys are read from the input devices, so this needs to be in
+    /// the `input` group, and grabs are released through the X server (including
+    /// Xwayland) with `xdotool`.
+    EscapeSupervisor {
+        /// Break keyboard and mouse grabs. Defaults to `ctrl+alt+end`.
+        release_hotkey: Option<Hotkey>,
+        /// Break grabs and kill the program owning the focused window, if it's running in
+        /// a mounted system. Defaults to `ctrl+alt+shift+end`.
+        kill_hotkey: Option<Hotkey>,
+    },
+    /// Show bases and the systems that use them, with disk usage and which systems are
+    /// mounted, ex: to see what would be affected before deleting or updating a basis.
+    Graph {
+        /// Print the graph in Graphviz format (ex: pipe to `dot -Tsvg`).
+        dot: Option<()>,
+        /// Print the graph as a tree (the default).
+        tree: Option<()>,
+    },
+    /// Create or update the basis and system described by `winebasin.toml` in the
+    /// current directory (ex: checked into a project) so they match it.
+    Apply {
+        /// Read this manifest instead of `winebasin.toml` in the current directory.
+        manifest: Option<PathBuf>,
+    },
+    /// Apply `winebasin.toml` in the current directory, then run the system's
+    /// `command` from it with `system run`.
+    Run(ManifestRunArgs),
+    /// Run a Windows program or installer (`.exe`, `.msi`, `.lnk`) from anywhere in a
+    /// system with `system run`, asking which system with a dialog (zenity or kdialog)
+    /// unless `--system` is given. Used by `integrate`.
+    Open {
+        file: PathBuf,
+        system: Option<String>,
+    },
+    /// Open a `winebasin://` link. `winebasin://run/SYSTEM/APP` runs `APP`, a program
+    /// registered with `system app add`, in the system with `system run`. Links can't
+    /// run other programs, since any web page can open them. Used by `integrate`.
+    HandleUrl {
+        url: String,
+    },
+    /// Add "Run with winebasin" to the file manager for Windows programs and
+    /// installers, which runs them with `open`, and register winebasin as the handler
+    /// for `winebasin://` links.
+    Integrate {
+        /// Remove it instead.
+        remove: Option<()>,
+    },
+    /// Manage the winetricks download cache shared by all bases and systems.
+    Cache(CacheArgs),
+    /// Manage wine installations (ex: wine-staging, custom builds) that bases can use
+    /// instead of the wine in `PATH`.
+    Wine(WineArgs),
 }
 
-trait ToOsString {
-    fn to_os_str(&self) -> OsString;
+#[derive(Aargvark)]
+struct Args {
+    /// Where to store bases and systems. Overrides `WINEBASIN_ROOT` and the global
+    /// config.
+    root: Option<PathBuf>,
+    /// Use this mount backend instead of selecting one automatically.
+    backend: Option<MountBackend>,
+    /// If a basis or system is in use by another winebasin process, wait for it to
+    /// finish rather than exiting with an error.
+    wait: Option<()>,
+    /// What to do when starting a system whose basis needs an update while other
+    /// systems using the basis are mounted. Overrides the global config.
+    update_policy: Option<UpdatePolicy>,
+    /// Print informational output (`basis check`, `basis path`, `basis list`,
+    /// `system path`, `system ps`, `system history`, `system health`, `system grep`,
+    /// `system regdiff`, `status`, `doctor`, `graph`, `cache stats`, `wine list`) as
+    /// JSON. Logs still go to stderr.
+    json: Option<()>,
+    /// Only log errors.
+    quiet: Option<()>,
+    /// Log debug messages too.
+    verbose: Option<()>,
+    /// Log debug messages and every command run (including those run as root) before
+    /// running it.
+    debug: Option<()>,
+    /// For `system run` and `system shell`, print the commands (mount, unmount, wine,
+    /// and the environment) that would be run instead of running them.
+    dry_run: Option<()>,
+    /// Use stub wine, winetricks, sudo and mount commands written to `.test` in the
+    /// root, for the integration tests. Requires `--root` or `WINEBASIN_ROOT`.
+    #[cfg(feature = "test-mode")]
+    test_mode: Option<()>,
+    command: ArgsCommand,
 }
 
-impl ToOsString for [u8] {
-    fn to_os_str(&self) -> OsString {
-        return OsStr::from_bytes(self).to_os_string();
-    }
+/// Log commands before running them (`--debug`).
+static ECHO_COMMANDS: AtomicBool = AtomicBool::new(false);
+
+fn echo_commands() -> bool {
+    return ECHO_COMMANDS.load(Ordering::Relaxed);
 }
 
-impl ToOsString for str {
-    fn to_os_str(&self) -> OsString {
-        return self.as_bytes().to_os_str();
+fn echo_command(command: &Command) {
+    if echo_commands() {
+        StandardLog::new()
+            .with_flags(&[StandardFlag::Debug])
+            .log_with(StandardFlag::Debug, "Running command", ea!(command = mask_secrets(command.dbg_str())));
     }
 }
 
-impl ToOsString for String {
-    fn to_os_str(&self) -> OsString {
-        return self.as_bytes().to_os_str();
-    }
+fn print_json(value: serde_json::Value) {
+    println!("{}", serde_json::to_string_pretty(&value).unwrap());
 }
 
 trait CommandRun {
@@ -206,7 +1590,8 @@ trait CommandRun {
 
 impl CommandRun for Command {
     fn run(&mut self) -> Result<(), loga::Error> {
-        let log = StandardLog::new().fork(ea!(command = self.dbg_str()));
+        echo_command(self);
+        let log = StandardLog::new().fork(ea!(command = mask_secrets(self.dbg_str())));
         let res =
             self
                 .spawn()
@@ -220,7 +1605,8 @@ impl CommandRun for Command {
     }
 
     fn run_stdin(&mut self, stdin: &[u8]) -> Result<(), loga::Error> {
-        let log = StandardLog::new().fork(ea!(command = self.dbg_str()));
+        echo_command(self);
+        let log = StandardLog::new().fork(ea!(command = mask_secrets(self.dbg_str())));
         self.stdin(Stdio::piped());
         let mut child = self.spawn().stack_context(&log, "Error starting shell")?;
         let mut child_stdin = child.stdin.take().unwrap();
@@ -234,10 +1620,25 @@ impl CommandRun for Command {
     }
 }
 
-fn quote_subcommand<'a>(subcommand: impl IntoIterator<Item = &'a [u8]>) -> Result<Vec<u8>, loga::Error> {
-    let mut out: Vec<u8> = vec![];
-    for (i, arg) in subcommand.into_iter().enumerate() {
-        if i > 0 {
+/// Find an executable in `PATH`, or check that it exists if it's a path.
+fn find_bin(name: &str) -> Option<PathBuf> {
+    if name.contains('/') {
+        let path = PathBuf::from(name);
+        return path.exists().then_some(path);
+    }
+    for dir in env::split_paths(&env::var_os("PATH")?) {
+        let path = dir.join(name);
+        if path.is_file() {
+            return Some(path);
+        }
+    }
+    return None;
+}
+
+fn quote_subcommand<'a>(subcommand: impl IntoIterator<Item = &'a [u8]>) -> Result<Vec<u8>, loga::Error> {
+    let mut out: Vec<u8> = vec![];
+    for (i, arg) in subcommand.into_iter().enumerate() {
+        if i > 0 {
             out.extend(b" ");
         }
         out.extend(
@@ -252,111 +1653,60 @@ fn quote_subcommand<'a>(subcommand: impl IntoIterator<Item = &'a [u8]>) -> Resul
     return Ok(out);
 }
 
-#[allow(dyn_drop)]
-fn mount_prefix(
-    log: &StandardLog,
-    basis_path: &Path,
-    system_path: &Path,
-) -> Result<(Box<dyn Drop>, PathBuf), loga::Error> {
-    let root_dir = root_dir()?;
-    let tempdirs_path = root_dir.join("temp");
-    create_dir_all(
-        &tempdirs_path,
-    ).context_with("Error creating temp dir", ea!(path = tempdirs_path.to_string_lossy()))?;
-
-    // Launch background sudo process (keep it open so don't need reauth at exit)
-    let (sudo_read, sudo_read_child) = pipe().context("Error creating sudo read pipe pair")?;
-    let mut sudo_read = BufReader::new(sudo_read).lines();
-    let mut sudo =
-        Command::new("sudo")
-            .arg("--close-from")
-            .arg("4")
-            .arg("bash")
-            .arg("-eu")
-            .stdin(Stdio::piped())
-            .fd_mappings(vec![FdMapping {
-                parent_fd: sudo_read_child.into(),
-                child_fd: 3,
-            }])
-            .context("Error attaching pipes to sudo child")?
-            .spawn()
-            .context("Error starting cleanup bash process")?;
-    let mut sudo_write = sudo.stdin.take().unwrap();
-    let mut sudo_exec = {
-        let mut i = 0;
-        move |line: &[u8]| {
-            sudo_write.write_all(line)?;
-            sudo_write.write_all(b";\n")?;
-            let want_i = i.to_string();
-            i += 1;
-            sudo_write.write_all(&format_bytes!(b"echo {} >&3;\n", want_i.as_bytes()))?;
-            sudo_write.flush()?;
-            while let Some(line) = sudo_read.next() {
-                let line = line.context("Error reading ipc line")?;
-                let line = line.trim();
-                if line == want_i {
-                    break;
-                }
-            }
-            return Ok(()) as Result<_, loga::Error>;
-        }
-    };
+fn shell_commandline(env: &HashMap<OsString, OsString>, prefix_path: &Path) -> Command {
+    let mut commandline =
+        Command::new(PathBuf::from(env::var("SHELL").as_ref().map(|x| x.as_str()).unwrap_or("/bin/bash")));
+    commandline.envs(env).current_dir(prefix_path.join("drive_c"));
+    return commandline;
+}
 
-    // Mount
-    let mount_path = system_mount_path(&system_path);
-    sudo_exec(
-        &quote_subcommand(
-            [
-                b"mount" as &[u8],
-                b"--types",
-                b"overlay",
-                b"overlay",
-                b"--options",
-                &format_bytes!(
-                    b"lowerdir={},upperdir={},workdir={},metacopy=off,index=off",
-                    basis_prefix_path(&basis_path).as_os_str().as_bytes(),
-                    system_prefix_path(&system_path).as_os_str().as_bytes(),
-                    system_overlay_work_path(&system_path).as_os_str().as_bytes()
-                ),
-                mount_path.as_os_str().as_bytes(),
-            ],
-        )?,
-    )?;
-    return Ok((
-        // Unmount when dropped
-        Box::new(defer({
-            let log = log.clone();
-            let mount_path = mount_path.clone();
-            move || {
-                (|| {
-                    sudo_exec(&quote_subcommand([b"umount", mount_path.as_os_str().as_bytes()]).unwrap())?;
-                    drop(sudo_exec);
-                    let res = sudo.wait_with_output()?;
-                    if !res.status.success() {
-                        log.log_with(
-                            StandardFlag::Warning,
-                            "Cleanup sudo process exited with error",
-                            ea!(output = res.dbg_str()),
-                        );
-                    }
-                    return Ok(()) as Result<_, loga::Error>;
-                })().log(&log, StandardFlag::Warning, "Error completing cleanup");
-            }
-        })),
-        // Useful return
-        mount_path,
-    ));
+/// Environment variables for `system run` flags (overlays, GPU). These override
+/// the configs.
+fn add_run_flag_envs(env: &mut HashMap<OsString, OsString>, args: &SystemRunArgs) -> Result<(), loga::Error> {
+    if args.mangohud.is_some() {
+        env.insert(OsString::from("MANGOHUD"), OsString::from("1"));
+    }
+    if args.vkbasalt.is_some() || args.vkbasalt_config.is_some() {
+        env.insert(OsString::from("ENABLE_VKBASALT"), OsString::from("1"));
+    }
+    if let Some(gpu) = args.gpu {
+        gpu.apply(env);
+    }
+    if let Some(config_file) = &args.vkbasalt_config {
+        // Programs run in `drive_c`
+        let config_file = current_dir().context("Can't determine current dir")?.join(config_file);
+        env.insert(OsString::from("VKBASALT_CONFIG_FILE"), config_file.into_os_string());
+    }
+    return Ok(());
 }
 
-fn shell_commandline(basis_config: &BasisLatestConfig, prefix_path: &Path) -> Command {
-    let mut commandline =
-        Command::new(&PathBuf::from(env::var("SHELL").as_ref().map(|x| x.as_str()).unwrap_or("/bin/bash")));
-    commandline.envs(wine_envs(&basis_config, &prefix_path)).current_dir(&prefix_path.join("drive_c"));
-    return commandline;
+/// Whether to use wine's Wayland driver (by hiding `DISPLAY`, so wine doesn't pick
+/// X11) for a system run or shell. The flags override the system config. Falls
+/// back to X11 if there's no Wayland session.
+fn use_wayland(
+    log: &StandardLog,
+    system_config: &SystemLatestConfig,
+    wayland: &Option<()>,
+    no_wayland: &Option<()>,
+) -> bool {
+    let want = if no_wayland.is_some() {
+        false
+    } else if wayland.is_some() {
+        true
+    } else {
+        system_config.wayland
+    };
+    if !want {
+        return false;
+    }
+    if env::var_os("WAYLAND_DISPLAY").is_none() {
+        log.log(StandardFlag::Warning, "Wayland driver requested but `WAYLAND_DISPLAY` isn't set, using X11");
+        return false;
+    }
+    return true;
 }
 
-fn run_shell(basis_config: &BasisLatestConfig, prefix_path: &Path, command: Vec<String>) -> Result<(), loga::Error> {
-    let mut commandline = shell_commandline(basis_config, prefix_path);
+fn run_shell(mut commandline: Command, command: Vec<String>) -> Result<(), loga::Error> {
     if command.is_empty() {
         commandline.run()?;
     } else {
@@ -373,19 +1723,135 @@ fn run_shell(basis_config: &BasisLatestConfig, prefix_path: &Path, command: Vec<
     return Ok(());
 }
 
-fn root_dir() -> Result<Rc<PathBuf>, loga::Error> {
-    static mut PROJECT_DIRS: OnceCell<Result<Rc<PathBuf>, loga::Error>> = OnceCell::new();
-    return unsafe {
-        PROJECT_DIRS.get_or_init(
-            || ProjectDirs::from("", "", "winebasin")
-                .context("Could not determine system directories")
-                .map(|x| Rc::new(x.data_dir().to_path_buf())),
-        )
-    }.clone();
+fn project_dirs() -> Result<Rc<ProjectDirs>, loga::Error> {
+    thread_local!{
+        static PROJECT_DIRS: OnceCell<Result<Rc<ProjectDirs>, loga::Error>> = const {
+            OnceCell::new()
+        };
+    }
+
+    return PROJECT_DIRS.with(
+        |x| x
+            .get_or_init(
+                || ProjectDirs::from("", "", "winebasin")
+                    .context("Could not determine system directories")
+                    .map(Rc::new),
+            )
+            .clone(),
+    );
+}
+
+/// Data root chosen with `--root`, `WINEBASIN_ROOT`, or the global config.
+static ROOT_OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
+static EXTRA_ROOTS: OnceLock<Vec<PathBuf>> = OnceLock::new();
+static LOCALIZE_EXTRA_BASES: AtomicBool = AtomicBool::new(false);
+
+fn root_dir() -> Result<PathBuf, loga::Error> {
+    if let Some(root) = ROOT_OVERRIDE.get() {
+        return Ok(root.clone());
+    }
+    return Ok(project_dirs()?.data_dir().to_path_buf());
+}
+
+/// Expand a leading `~` and `$VAR`/`${VAR}` environment variables, and make the
+/// path absolute.
+fn expand_path(path: &str) -> Result<PathBuf, loga::Error> {
+    let mut out = String::new();
+    let mut rest = path;
+    if rest == "~" || rest.starts_with("~/") {
+        let base = BaseDirs::new().context("Unable to determine home directory")?;
+        out.push_str(&base.home_dir().to_string_lossy());
+        rest = &rest[1..];
+    }
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        let name;
+        if let Some(braced) = rest.strip_prefix('{') {
+            let end = braced.find('}').context_with("Unclosed `${` in path", ea!(path = path))?;
+            name = &braced[..end];
+            rest = &braced[end + 1..];
+        } else {
+            let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            name = &rest[..end];
+            rest = &rest[end..];
+        }
+        if name.is_empty() {
+            return Err(loga::err_with("Empty environment variable name in path", ea!(path = path)));
+        }
+        out.push_str(
+            &env::var(name).context_with("Environment variable in path isn't set", ea!(path = path, name = name))?,
+        );
+    }
+    out.push_str(rest);
+    return std::path::absolute(&out).context_with("Error making path absolute", ea!(path = out));
+}
+
+fn config_dir() -> Result<PathBuf, loga::Error> {
+    return Ok(project_dirs()?.config_dir().to_path_buf());
+}
+
+fn global_config_path() -> Result<PathBuf, loga::Error> {
+    return Ok(config_dir()?.join("config.json"));
+}
+
+/// Load the global config, using defaults if there's no config file.
+fn global_config() -> Result<GlobalLatestConfig, loga::Error> {
+    let config_path = global_config_path()?;
+    if !config_path.exists() {
+        return Ok(GlobalLatestConfig::default());
+    }
+    let config =
+        match serde_json::from_slice::<GlobalConfig>(
+            &fs::read(
+                &config_path,
+            ).context_with("Error reading global config", ea!(path = config_path.to_string_lossy()))?,
+        ).context_with("Error parsing global config as json", ea!(path = config_path.to_string_lossy()))? {
+            GlobalConfig::V1(config) => config,
+        };
+    return Ok(config);
+}
+
+/// Ask the user a yes/no question on the terminal, defaulting to no.
+fn confirm(question: &str) -> Result<bool, loga::Error> {
+    eprint!("{} [y/N] ", question);
+    stderr().flush().context("Error flushing prompt")?;
+    let mut answer = String::new();
+    stdin().read_line(&mut answer).context("Error reading confirmation")?;
+    return Ok(matches!(answer.trim(), "y" | "Y" | "yes"));
+}
+
+fn extra_roots() -> &'static [PathBuf] {
+    return EXTRA_ROOTS.get().map(|r| r.as_slice()).unwrap_or(&[]);
 }
 
+/// The basis in the main root, or if it's not there, the first extra root that has
+/// it. If no root has it, the (nonexistent) path in the main root.
 fn basis_path(name: &str) -> Result<PathBuf, loga::Error> {
-    return Ok(root_dir()?.join("basis").join(name));
+    let local = root_dir()?.join("basis").join(name);
+    if local.exists() {
+        return Ok(local);
+    }
+    for root in extra_roots() {
+        let path = root.join("basis").join(name);
+        if path.exists() {
+            return Ok(path);
+        }
+    }
+    return Ok(local);
+}
+
+/// Bases found in extra roots can't be modified.
+fn check_basis_writable(basis_name: &str, basis_path: &Path) -> Result<(), loga::Error> {
+    if !basis_path.starts_with(root_dir()?) {
+        return Err(
+            loga::err_with(
+                "The basis is in a read-only extra root and can't be modified",
+                ea!(basis = basis_name, path = basis_path.to_string_lossy()),
+            ),
+        );
+    }
+    return Ok(());
 }
 
 fn basis_config_path(basis_path: &Path) -> PathBuf {
@@ -396,11 +1862,110 @@ fn basis_prefix_path(basis_path: &Path) -> PathBuf {
     return basis_path.join("prefix");
 }
 
+/// Look for `share/wine/wine.inf` in the installation containing the wine binary,
+/// at each step of following its symlinks. The binary may be in `bin/`,
+/// `lib/wine/`, or `lib/wine/x86_64-unix/` of the installation, and on Nix `bin/`
+/// in the profile links to `bin/` in the store.
+fn find_wine_inf(env: &HashMap<OsString, OsString>) -> Option<PathBuf> {
+    let mut path = find_bin(&wine_bin(env).to_string_lossy())?;
+    for _ in 0 .. 40 {
+        for dir in path.ancestors().skip(1).take(4) {
+            let inf = dir.join("share/wine/wine.inf");
+            if inf.is_file() {
+                return Some(inf.canonicalize().unwrap_or(inf));
+            }
+        }
+        let Ok(target) = fs::read_link(&path) else {
+            break;
+        };
+        path = path.parent().map(|p| p.join(&target)).unwrap_or(target);
+    }
+    return None;
+}
+
+/// `wine.inf` of the wine used with the environment from `wine_envs`.
+fn wine_inf_path(env: &HashMap<OsString, OsString>) -> PathBuf {
+    // Wine itself has the location built in at compile time
+    // (https://github.com/wine-mirror/wine/blob/951e0e27a743e52c75c7fedc0b1eaa9eb77e6bb6/programs/wineboot/wineboot.c#L93),
+    // so it has to be found from the wine installation.
+    if let Some(wine) = env.get(OsStr::new("WINE")) {
+        // A registered wine, `WINE_INF_DIR` is for the default wine
+        return find_wine_inf(
+            env,
+        ).unwrap_or_else(
+            || Path::new(wine)
+                .parent()
+                .and_then(|p| p.parent())
+                .unwrap_or(Path::new("/"))
+                .join("share/wine/wine.inf"),
+        );
+    }
+    static FOUND: OnceLock<PathBuf> = OnceLock::new();
+    return FOUND.get_or_init(|| {
+        if let Ok(d) = env::var("WINE_INF_DIR") {
+            return PathBuf::from(d);
+        }
+        if let Some(found) = find_wine_inf(env) {
+            return found;
+        }
+        return PathBuf::from("/usr/share/wine/wine.inf");
+    }).clone();
+}
+
+/// The wine a basis prefix was last created or updated with.
+#[derive(Serialize, Deserialize)]
+struct BasisWine {
+    /// As reported by `wine --version`.
+    wine_version: Option<String>,
+    /// SHA-256 of `wine.inf`, which describes what wine sets up in prefixes.
+    wine_inf_sha256: Option<String>,
+}
+
+fn basis_wine_path(basis_path: &Path) -> PathBuf {
+    return basis_path.join("wine.json");
+}
+
+fn current_basis_wine(env: &HashMap<OsString, OsString>) -> BasisWine {
+    return BasisWine {
+        wine_version: wine_version(env),
+        wine_inf_sha256: sha256_file(&wine_inf_path(env)).ok(),
+    };
+}
+
+/// The wine the basis was last created or updated with, if recorded (bases last
+/// updated by older versions of winebasin don't have it).
+fn read_basis_wine(basis_path: &Path) -> Result<Option<BasisWine>, loga::Error> {
+    let wine_path = basis_wine_path(basis_path);
+    if !wine_path.exists() {
+        return Ok(None);
+    }
+    let log = StandardLog::new().fork(ea!(path = wine_path.to_string_lossy()));
+    return Ok(
+        Some(
+            serde_json::from_slice::<BasisWine>(
+                &fs::read(&wine_path).stack_context(&log, "Error reading basis wine version file")?,
+            ).stack_context(&log, "Error parsing basis wine version file")?,
+        ),
+    );
+}
+
 fn basis_needs_update(basis_path: &Path) -> Result<bool, loga::Error> {
     let log = StandardLog::new().fork(ea!(path = basis_path.to_string_lossy()));
     if !basis_path.exists() {
         return Err(log.err("Basis doesn't exist"));
     }
+    let env = wine_envs(&read_basis_config(basis_path)?, &basis_prefix_path(basis_path))?;
+    if let Some(have) = read_basis_wine(basis_path)? {
+        let current = current_basis_wine(&env);
+
+        // Ignore what can't be determined now (ex: `wine.inf` isn't where expected)
+        let version_changed = current.wine_version.is_some() && current.wine_version != have.wine_version;
+        let inf_changed = current.wine_inf_sha256.is_some() && current.wine_inf_sha256 != have.wine_inf_sha256;
+        return Ok(version_changed || inf_changed);
+    }
+
+    // Bases last updated before the wine version was recorded use wine's own check,
+    // which doesn't work where file times are fixed (ex: Nix)
     let have_time_string =
         String::from_utf8_lossy(
             &fs::read(
@@ -410,231 +1975,3627 @@ fn basis_needs_update(basis_path: &Path) -> Result<bool, loga::Error> {
             .trim()
             .to_string();
     let have_time =
-        i64::from_str_radix(
-            &have_time_string,
-            10,
-        ).stack_context_with(
-            &log,
-            "Error parsing timestamp in prefix update timestamp file",
-            ea!(timestamp = have_time_string),
-        )?;
-
-    // From
-    // https://github.com/wine-mirror/wine/blob/951e0e27a743e52c75c7fedc0b1eaa9eb77e6bb6/programs/wineboot/wineboot.c#L93
-    // except... that's set by another binary, where it's built in as a compile-time
-    // define. Just hard code and nix users will need to set an env var.
-    let inf_path = if let Some(d) = env::var("WINE_INF_DIR").ok() {
-        PathBuf::from(d)
-    } else {
-        PathBuf::from("/usr/share/wine/wine.inf")
-    };
-    let inf_meta = inf_path.metadata().context("Error getting metadata of wine.inf")?;
+        have_time_string
+            .parse::<i64>()
+            .stack_context_with(
+                &log,
+                "Error parsing timestamp in prefix update timestamp file",
+                ea!(timestamp = have_time_string),
+            )?;
+    let inf_meta = wine_inf_path(&env).metadata().context("Error getting metadata of wine.inf")?;
     return Ok(have_time < inf_meta.st_mtime());
 }
 
-fn wine_envs(config: &BasisLatestConfig, prefix: &Path) -> HashMap<&'static OsStr, OsString> {
+/// Check the basis was last updated with the current wine version. Running an old
+/// prefix with a newer wine is a common source of breakage, so a mismatch (ex: the
+/// update was deferred) is an error unless `allow` (`--allow-wine-mismatch`).
+fn check_basis_wine(
+    log: &StandardLog,
+    basis_name: &str,
+    basis_path: &Path,
+    env: &HashMap<OsString, OsString>,
+    allow: bool,
+) -> Result<(), loga::Error> {
+    let Some(have) = read_basis_wine(basis_path)?.and_then(|w| w.wine_version) else {
+        return Ok(());
+    };
+    let Some(current) = wine_version(env) else {
+        return Ok(());
+    };
+    if have == current {
+        return Ok(());
+    }
+    if allow {
+        log.log_with(
+            StandardFlag::Warning,
+            "Basis was last updated with a different wine version, programs may not work correctly",
+            ea!(basis = basis_name, basis_wine = have, current_wine = current),
+        );
+        return Ok(());
+    }
+    return Err(
+        loga::err_with(
+            "Basis was last updated with a different wine version. Update it with `basis update` once other systems using it are stopped, or run anyway with `--allow-wine-mismatch`",
+            ea!(basis = basis_name, basis_wine = have, current_wine = current),
+        ),
+    );
+}
+
+/// The environment for running things in the basis prefix (or a prefix using it).
+/// If the basis uses a registered wine, `WINE` and `WINESERVER` point to it (used
+/// by winebasin and winetricks) and its `bin` is first in `PATH`. For Proton,
+/// `WINEBASIN_PROTON` is the launcher and `STEAM_COMPAT_DATA_PATH` is `proton`
+/// next to the prefix (see `wine_program_command`).
+fn wine_envs(config: &BasisLatestConfig, prefix: &Path) -> Result<HashMap<OsString, OsString>, loga::Error> {
     let mut out = HashMap::new();
-    out.insert(OsStr::from_bytes("WINEPREFIX".as_bytes()), prefix.as_os_str().to_os_string());
-    out.insert(OsStr::from_bytes("WINEARCH".as_bytes()), OsStr::from_bytes(match config.arch {
-        Arch::Win32 => "win32",
-        Arch::Win64 => "win64",
-    }.as_bytes()).to_os_string());
-    return out;
+    if let Some(name) = &config.wine {
+        let wine = registered_wine(name)?;
+        let mut paths = vec![wine.bin_dir()];
+        if let Some(path) = env::var_os("PATH") {
+            paths.extend(env::split_paths(&path));
+        }
+        out.insert(
+            OsString::from("PATH"),
+            env::join_paths(paths).context_with("Error adding wine to PATH", ea!(wine = name))?,
+        );
+        out.insert(OsString::from("WINE"), wine.wine_bin().into_os_string());
+        out.insert(OsString::from("WINESERVER"), wine.wineserver_bin().into_os_string());
+        if wine.proton {
+            out.insert(OsString::from("WINEBASIN_PROTON"), wine.proton_bin().into_os_string());
+            out.insert(OsString::from("STEAM_COMPAT_DATA_PATH"), proton_compat_path(prefix).into_os_string());
+            if env::var_os("STEAM_COMPAT_CLIENT_INSTALL_PATH").is_none() {
+                // Proton needs it set, but only uses it to find the Steam client libraries for
+                // games
+                let steam =
+                    BaseDirs::new()
+                        .map(|d| d.home_dir().join(".steam").join("steam"))
+                        .unwrap_or_else(|| PathBuf::from("/nonexistent"));
+                out.insert(OsString::from("STEAM_COMPAT_CLIENT_INSTALL_PATH"), steam.into_os_string());
+            }
+        }
+    }
+    config.sync.apply(&mut out, &config.env);
+    for (k, v) in &config.env {
+        out.insert(OsString::from(k), OsString::from(v));
+    }
+    out.insert(OsString::from("WINEPREFIX"), prefix.as_os_str().to_os_string());
+    out.insert(OsString::from("WINEARCH"), OsString::from(config.arch.winearch()));
+    return Ok(out);
 }
 
-fn wine_bin() -> String {
-    return env::var("WINE").ok().unwrap_or_else(|| "wine".to_string());
+/// Proton keeps its prefix in `pfx` in its compat data dir, along with its own
+/// bookkeeping. Each basis and system gets a compat data dir next to its prefix
+/// with `pfx` linking to the prefix.
+fn proton_compat_path(prefix: &Path) -> PathBuf {
+    return prefix.parent().unwrap_or(prefix).join("proton");
 }
 
-fn wineserver_bin() -> String {
-    return env::var("WINESERVER").ok().unwrap_or_else(|| "wineserver".to_string());
+/// A command to run a wine program (followed by its arguments) with the
+/// environment from `wine_envs`: `wine`, or for Proton runners the `proton`
+/// launcher's `run`, after linking the prefix into its compat data dir, or
+/// `umu-run` for systems using umu.
+fn wine_program_command(env: &HashMap<OsString, OsString>) -> Result<Command, loga::Error> {
+    if let Some(umu) = env.get(OsStr::new("WINEBASIN_UMU")) {
+        let Some(umu) = find_bin(&umu.to_string_lossy()) else {
+            return Err(
+                loga::err(
+                    "The system config has `umu` set but `umu-run` wasn't found in `PATH`, install umu-launcher",
+                ),
+            );
+        };
+        return Ok(Command::new(umu));
+    }
+    let Some(proton) = env.get(OsStr::new("WINEBASIN_PROTON")) else {
+        return Ok(Command::new(wine_bin(env)));
+    };
+    let compat_path = PathBuf::from(env.get(OsStr::new("STEAM_COMPAT_DATA_PATH")).unwrap());
+    let prefix = PathBuf::from(env.get(OsStr::new("WINEPREFIX")).unwrap());
+    let pfx_path = compat_path.join("pfx");
+    if fs::read_link(&pfx_path).ok().as_ref() != Some(&prefix) {
+        // Proton doesn't follow a dangling link when creating the prefix
+        create_dir_all(&prefix).context_with("Error creating prefix", ea!(path = prefix.to_string_lossy()))?;
+        create_dir_all(
+            &compat_path,
+        ).context_with("Error creating Proton compat data dir", ea!(path = compat_path.to_string_lossy()))?;
+        _ = fs::remove_file(&pfx_path);
+        std::os::unix::fs::symlink(
+            &prefix,
+            &pfx_path,
+        ).context_with("Error linking prefix into Proton compat data dir", ea!(path = pfx_path.to_string_lossy()))?;
+    }
+    let mut out = Command::new(proton);
+    out.arg("run");
+    return Ok(out);
 }
 
-fn wine_hostname(config: &BasisLatestConfig, prefix_path: &Path) -> Result<(), loga::Error> {
-    Command::new(wine_bin()).arg("hostname").envs(wine_envs(config, prefix_path)).stdout(Stdio::null()).run()?;
-    return Ok(());
+/// The value for `explorer /desktop=` to run a program in a virtual desktop.
+fn virtual_desktop_name(resolution: &str) -> String {
+    return format!("winebasin,{}", resolution);
 }
 
-fn update_basis(basis_path: &Path) -> Result<BasisLatestConfig, loga::Error> {
-    let config_path = basis_config_path(basis_path);
-    let config =
-        match serde_json::from_slice::<BasisConfig>(
-            &fs::read(
-                &config_path,
-            ).context_with("Error reading basis config", ea!(path = config_path.to_string_lossy()))?,
-        ).context_with("Error parsing basis config as json", ea!(path = config_path.to_string_lossy()))? {
-            BasisConfig::V1(config) => config,
-        };
-    if !basis_needs_update(basis_path)? {
-        return Ok(config);
+/// The virtual desktop resolution for a system run or shell: the flag, otherwise
+/// the system config.
+fn virtual_desktop<
+    'a,
+>(arg: &'a Option<String>, system_config: &'a SystemLatestConfig) -> Result<Option<&'a str>, loga::Error> {
+    let Some(resolution) = arg.as_ref().or(system_config.virtual_desktop.as_ref()) else {
+        return Ok(None);
+    };
+    check_resolution(resolution)?;
+    return Ok(Some(resolution));
+}
+
+/// `gamemoderun`, if GameMode was requested with `--gamemode` or the system config.
+fn gamemode_wrapper(arg: &Option<()>, system_config: &SystemLatestConfig) -> Result<Option<PathBuf>, loga::Error> {
+    if arg.is_none() && !system_config.gamemode {
+        return Ok(None);
     }
-    let prefix_path = basis_prefix_path(basis_path);
-    wine_hostname(&config, &prefix_path)?;
-    return Ok(config);
+    require(Capability::Gamemode, "GameMode (`--gamemode` or `gamemode` in the system config)")?;
+    return Ok(find_bin("gamemoderun"));
 }
 
-fn system_path(name: &str) -> Result<PathBuf, loga::Error> {
-    return Ok(root_dir()?.join("system").join(name));
+/// The `taskset`/`nice`/`ionice` wrapper for a system run or shell, from the flags
+/// and the system config.
+fn priority_wrapper(
+    system_config: &SystemLatestConfig,
+    cpus: &Option<String>,
+    nice: Option<i32>,
+    ionice: Option<IoPriority>,
+) -> Result<Vec<OsString>, loga::Error> {
+    return system_config.priority.merge(&ProcessPriority {
+        cpus: cpus.clone(),
+        nice: nice,
+        ionice: ionice,
+    }).wrapper();
 }
 
-fn system_config_path(system_path: &Path) -> PathBuf {
-    return system_path.join("config.json");
+/// `systemd-run` to run in a transient user scope, if requested with
+/// `--systemd-scope` or the system config, with the limits from the config. All
+/// processes in the scope are stopped with it.
+fn systemd_scope_wrapper(arg: &Option<()>, system_config: &SystemLatestConfig) -> Vec<OsString> {
+    if arg.is_none() && system_config.systemd_scope.is_none() {
+        return vec![];
+    }
+    let mut out: Vec<OsString> = vec!["systemd-run".into(), "--user".into(), "--scope".into(), "--quiet".into()];
+    if let Some(scope) = &system_config.systemd_scope {
+        if let Some(memory_max) = &scope.memory_max {
+            out.extend(["--property".into(), format!("MemoryMax={}", memory_max).into()]);
+        }
+        if let Some(cpu_quota) = &scope.cpu_quota {
+            out.extend(["--property".into(), format!("CPUQuota={}", cpu_quota).into()]);
+        }
+    }
+    out.push("--".into());
+    return out;
 }
 
-fn system_prefix_path(system_path: &Path) -> PathBuf {
-    return system_path.join("prefix");
+/// The sandbox settings if the program should run in a sandbox, requested with
+/// `--sandbox` or the system config.
+fn sandbox_config(arg: &Option<()>, system_config: &SystemLatestConfig) -> Option<SandboxConfig> {
+    if arg.is_none() && system_config.sandbox.is_none() {
+        return None;
+    }
+    return Some(system_config.sandbox.clone().unwrap_or_default());
 }
 
-fn system_overlay_work_path(system_path: &Path) -> PathBuf {
-    return system_path.join("overlay_work");
+/// The Landlock settings if the program should be confined with Landlock,
+/// requested with `--landlock` or the system config.
+fn landlock_config(arg: &Option<()>, system_config: &SystemLatestConfig) -> Option<SandboxConfig> {
+    if arg.is_none() && system_config.landlock.is_none() {
+        return None;
+    }
+    return Some(system_config.landlock.clone().unwrap_or_default());
 }
 
-fn system_mount_path(system_path: &Path) -> PathBuf {
-    return system_path.join("mount");
+/// Make a command run through a wrapper command line (ex: `gamemoderun`), which
+/// runs the command passed as its arguments. An empty wrapper leaves the command
+/// as is.
+fn wrap_command(wrapper: &[OsString], command: Command) -> Command {
+    let Some((program, args)) = wrapper.split_first() else {
+        return command;
+    };
+    let mut out = Command::new(program);
+    out.args(args).arg(command.get_program()).args(command.get_args());
+    for (k, v) in command.get_envs() {
+        match v {
+            Some(v) => out.env(k, v),
+            None => out.env_remove(k),
+        };
+    }
+    if let Some(dir) = command.get_current_dir() {
+        out.current_dir(dir);
+    }
+    return out;
 }
 
-fn check_system(system_path: &Path) -> Result<SystemLatestConfig, loga::Error> {
-    let config_path = basis_config_path(system_path);
-    let config =
-        match serde_json::from_slice::<SystemConfig>(
-            &fs::read(
-                &config_path,
-            ).context_with("Error reading system config", ea!(path = config_path.to_string_lossy()))?,
-        ).context_with("Error parsing system config as json", ea!(path = config_path.to_string_lossy()))? {
-            SystemConfig::V1(config) => config,
+/// The command for `system run`. `command` is the executable (relative to
+/// `drive_c`) followed by its arguments.
+fn wine_run_command(
+    env: &HashMap<OsString, OsString>,
+    drive_c_path: &Path,
+    working_dir: Option<&Path>,
+    virtual_desktop: Option<&str>,
+    mut command: Vec<String>,
+) -> Result<Command, loga::Error> {
+    if command.is_empty() {
+        return Err(loga::err("Command line to run in system is empty"));
+    }
+    let mut command_args = command.split_off(1);
+    let mut command_command = drive_c_path.join(command.pop().unwrap());
+    let mut working_dir = working_dir.map(|d| d.to_path_buf());
+
+    // Start what shortcuts point to, with the shortcut's arguments before any given
+    if command_command.extension().is_some_and(|e| e.eq_ignore_ascii_case("lnk")) {
+        let link = shell_link_command(drive_c_path.parent().unwrap(), &command_command)?;
+        command_command = link.program;
+        command_args.splice(0 .. 0, link.args);
+        working_dir = working_dir.or(link.working_dir);
+    }
+    let mut out = wine_program_command(env)?;
+    out.envs(env).current_dir(working_dir.as_deref().or(command_command.parent()).unwrap_or(drive_c_path));
+    if let Some(resolution) = virtual_desktop {
+        out.arg("explorer").arg(format!("/desktop={}", virtual_desktop_name(resolution)));
+    }
+
+    // Wine only runs installer packages through msiexec
+    if command_command.extension().is_some_and(|e| e.eq_ignore_ascii_case("msi")) {
+        out.arg("msiexec").arg("/i");
+    }
+    out.arg(&command_command).args(command_args);
+    return Ok(out);
+}
+
+/// The environment for `system run`: the system's, with the run's flags applied.
+fn run_envs(
+    global_config: &GlobalLatestConfig,
+    args: &SystemRunArgs,
+    basis_config: &BasisLatestConfig,
+    system_config: &SystemLatestConfig,
+    system_path: &Path,
+    mount_path: &Path,
+    secrets: SecretEnv,
+) -> Result<HashMap<OsString, OsString>, loga::Error> {
+    let mut env = system_wine_envs(basis_config, system_config, system_path, mount_path, secrets)?;
+    apply_wine_debug(&mut env, &args.wine_debug, &global_config.wine_debug);
+    if let Some(AssumeAddons::No) = args.assume_addons {
+        decline_addons(&mut env);
+    }
+    if args.no_fsync.is_some() {
+        SyncConfig::disabled().apply(&mut env, &BTreeMap::new());
+    }
+    add_run_flag_envs(&mut env, args)?;
+    add_arg_envs(&mut env, &args.env);
+    return Ok(env);
+}
+
+/// What `system run` wraps the program with, from the flags and system config.
+struct RunWrappers {
+    gamemode: Option<PathBuf>,
+    priority: Vec<OsString>,
+    systemd_scope: Vec<OsString>,
+    sandbox: Option<SandboxConfig>,
+    landlock: Option<SandboxConfig>,
+}
+
+impl RunWrappers {
+    fn new(args: &SystemRunArgs, system_config: &SystemLatestConfig) -> Result<RunWrappers, loga::Error> {
+        return Ok(RunWrappers {
+            gamemode: gamemode_wrapper(&args.gamemode, system_config)?,
+            priority: priority_wrapper(system_config, &args.cpus, args.nice, args.ionice)?,
+            systemd_scope: systemd_scope_wrapper(&args.systemd_scope, system_config),
+            sandbox: sandbox_config(&args.sandbox, system_config),
+            landlock: landlock_config(&args.landlock, system_config),
+        });
+    }
+
+    /// Check that the host has what the wrappers need, before mounting so it fails
+    /// early.
+    fn check(&self) -> Result<(), loga::Error> {
+        if !self.systemd_scope.is_empty() {
+            require(Capability::Systemd, "Running in a systemd scope")?;
+        }
+        if self.sandbox.is_some() {
+            require(Capability::Bwrap, "The sandbox (`--sandbox` or `sandbox` in the system config)")?;
+        }
+        if self.landlock.is_some() {
+            require(Capability::Landlock, "Landlock confinement (`--landlock` or `landlock` in the system config)")?;
+        }
+        return Ok(());
+    }
+
+    /// Wrap the wine command (from `wine_run_command`). With `confine`, the Landlock
+    /// rules (if enabled) are applied too and must be kept until the command is
+    /// spawned; dry runs don't create them.
+    fn wrap(
+        &self,
+        env: &HashMap<OsString, OsString>,
+        mut command: Command,
+        confine: bool,
+    ) -> Result<(Command, Option<Landlock>), loga::Error> {
+        let bwrap = self.sandbox.as_ref().map(|s| bwrap_wrapper(s, env, &command));
+        let landlock = match &self.landlock {
+            Some(config) if confine => Some(Landlock::new(config, env, &command)?),
+            _ => None,
         };
-    return Ok(config);
+        if let Some(wrapper) = &self.gamemode {
+            command = wrap_command(&[wrapper.clone().into_os_string()], command);
+        }
+        if let Some(bwrap) = &bwrap {
+            command = wrap_command(bwrap, command);
+        }
+        command = wrap_command(&self.priority, command);
+        command = wrap_command(&self.systemd_scope, command);
+        if let Some(landlock) = &landlock {
+            // Last, wrapping drops it
+            landlock.restrict(&mut command);
+        }
+        return Ok((command, landlock));
+    }
 }
 
-fn main() {
-    match (|| {
-        let args = vark::<Args>();
-        let log = StandardLog::new().with_flags(&[StandardFlag::Error, StandardFlag::Warning, StandardFlag::Info]);
-        match args {
-            Args::Basis(args) => match args {
-                BasisArgs::Create(args) => {
-                    let basis_path = basis_path(&args.basis_name)?;
-                    let log = log.fork(ea!(path = basis_path.to_string_lossy()));
-                    if basis_path.exists() {
-                        return Err(
-                            log.err("Basis already exists. Delete the directory first if you want to re-create it"),
-                        );
-                    }
-                    create_dir_all(&basis_path).context("Failed to ensure basis directory")?;
-                    let arch = args.arch.unwrap_or(Arch::Win64);
-                    let config = BasisLatestConfig { arch: arch };
-                    let config_path = basis_config_path(&basis_path);
-                    fs::write(
-                        &config_path,
-                        &serde_json::to_vec_pretty(&BasisConfig::V1(config.clone())).unwrap(),
-                    ).stack_context_with(
-                        &log,
-                        "Error writing basis config",
-                        ea!(config = config_path.to_string_lossy()),
-                    )?;
-                    let prefix_path = basis_prefix_path(&basis_path);
-                    wine_hostname(&config, &prefix_path)?;
-                    if args.recommended_winetricks.is_some() {
-                        let mut commandline = shell_commandline(&config, &prefix_path);
-                        match arch {
-                            Arch::Win32 => {
-                                commandline.run_stdin(include_bytes!("../winetricks32.sh"))?;
-                            },
-                            Arch::Win64 => {
-                                commandline.run_stdin(include_bytes!("../winetricks64.sh"))?;
-                            },
-                        }
-                    }
-                },
-                BasisArgs::Check { basis_name } => {
-                    let basis_path = basis_path(&basis_name)?;
-                    print!("{}", basis_needs_update(&basis_path)?);
-                },
-                BasisArgs::Update { basis_name } => {
-                    let basis_path = basis_path(&basis_name)?;
-                    update_basis(&basis_path)?;
-                },
-                BasisArgs::Shell(args) => {
-                    let basis_path = basis_path(&args.basis_name)?;
-                    let basis_config = update_basis(&basis_path)?;
-                    let log = log.fork(ea!(path = basis_path.to_string_lossy()));
-                    if !basis_path.exists() {
-                        return Err(log.err("Basis doesn't exist"));
-                    }
-                    run_shell(&basis_config, &basis_prefix_path(&basis_path), args.command)?;
-                },
-                BasisArgs::Path { basis_name } => {
-                    print!("{}", basis_path(&basis_name)?.to_string_lossy());
-                },
-            },
-            Args::System(args) => match args {
-                SystemArgs::Create { basis_name, system_name } => {
-                    let system_path = system_path(&system_name)?;
-                    create_dir_all(
-                        &system_path,
-                    ).context_with("Error creating system path", ea!(path = system_path.to_string_lossy()))?;
-                    create_dir_all(
-                        &system_prefix_path(&system_path),
-                    ).context("Failed to ensure system prefix directory")?;
-                    create_dir_all(
-                        &system_overlay_work_path(&system_path),
-                    ).context("Failed to ensure system overlay work directory")?;
-                    create_dir_all(
-                        &system_mount_path(&system_path),
-                    ).context("Failed to ensure system overlay mount directory")?;
-                    let config_path = system_config_path(&system_path);
-                    fs::write(
-                        &config_path,
-                        serde_json::to_vec_pretty(
-                            &SystemConfig::V1(SystemLatestConfig { basis_name: basis_name }),
-                        ).unwrap(),
-                    ).context_with("Error writing config to system dir", ea!(path = config_path.to_string_lossy()))?;
-                },
-                SystemArgs::Shell(args) => {
-                    let system_path = system_path(&args.system_name)?;
+/// Format a command as a shell command line, including the working directory and
+/// environment, for `--dry-run`.
+fn command_shell_line(command: &Command) -> Result<String, loga::Error> {
+    let mut out = vec![];
+    let removed = command.get_envs().filter(|(_, v)| v.is_none()).map(|(k, _)| k).collect::<Vec<_>>();
+    if !removed.is_empty() {
+        out.extend(b"env ");
+        for k in removed {
+            out.extend(b"-u ");
+            out.extend(quote_subcommand([k.as_bytes()])?);
+            out.push(b' ');
+        }
+    }
+    for (k, v) in command.get_envs() {
+        let Some(v) = v else {
+            continue;
+        };
+
+        // Only quote the value, a quoted assignment would be treated as a command
+        out.extend(k.as_bytes());
+        out.push(b'=');
+        out.extend(quote_subcommand([v.as_bytes()])?);
+        out.push(b' ');
+    }
+    out.extend(
+        quote_subcommand([command.get_program()].into_iter().chain(command.get_args()).map(|a| a.as_bytes()))?,
+    );
+    let mut out = String::from_utf8_lossy(&out).to_string();
+    if let Some(dir) = command.get_current_dir() {
+        out =
+            format!("cd {} && {}", String::from_utf8_lossy(&quote_subcommand([dir.as_os_str().as_bytes()])?), out);
+    }
+    return Ok(out);
+}
+
+/// Print what starting, using, and stopping a system would run, for `--dry-run`.
+fn print_dry_run(
+    backend: Option<MountBackend>,
+    system_name: &str,
+    system_path: &Path,
+    basis_name: &str,
+    commands: &[Command],
+    notes: &[String],
+) -> Result<(), loga::Error> {
+    let basis_path = basis_path(basis_name)?;
+    if basis_needs_update(&basis_path)? {
+        if check_basis_writable(basis_name, &basis_path).is_ok() {
+            let update_command = basis_update_command(&read_basis_config(&basis_path)?);
+            println!(
+                "# Basis `{}` needs an update, it would be updated first with `wine {}`",
+                basis_name,
+                update_command.join(" ")
+            );
+        } else {
+            println!(
+                "# Basis `{}` needs an update but is in a read-only extra root, it would be used as-is",
+                basis_name
+            );
+        }
+    }
+    let mount_path = system_mount_path(system_path);
+    let choice = select_backend(backend);
+    if is_mounted(&mount_path)? {
+        println!("# System `{}` is already mounted, the existing mount would be shared", system_name);
+    } else {
+        println!("# Mount ({}: {})", choice.backend.name(), choice.reason);
+        if choice.backend == MountBackend::Copy {
+            println!("# (after copying the basis prefix into the system's, the first time)");
+        }
+        println!("{}", command_shell_line(&mount_command(choice.backend, &basis_path, system_path, &mount_path))?);
+    }
+    println!("# Run");
+    for note in notes {
+        for line in note.lines() {
+            println!("# {}", line);
+        }
+    }
+    for command in commands {
+        println!("{}", command_shell_line(command)?);
+    }
+    println!("# Unmount (if no other winebasin process is using the system)");
+    match unmount_command(choice.backend, &mount_path) {
+        Ok(command) => println!("{}", command_shell_line(&command)?),
+        Err(e) => println!("# Unavailable: {}", e),
+    }
+    return Ok(());
+}
+
+/// Mount the system (see `mount_prefix`) and set up its drives.
+#[allow(dyn_drop)]
+fn mount_system(
+    log: &StandardLog,
+    backend: &BackendChoice,
+    basis_path: &Path,
+    system_name: &str,
+    system_path: &Path,
+    system_config: &SystemLatestConfig,
+) -> Result<(Box<dyn Drop>, PathBuf), loga::Error> {
+    // Two systems writing to the same host directory (ex: shared saves) at once can
+    // corrupt it
+    let mut drive_locks = vec![];
+    for target in system_config.drives.values() {
+        drive_locks.push(lock_drive_target(system_name, target)?);
+    }
+    let (mount, mount_path) = mount_prefix(log, backend, basis_path, system_name, system_path)?;
+    if let Some(z_drive) = &system_config.z_drive {
+        apply_z_drive(&mount_path, z_drive)?;
+    }
+    apply_drives(&mount_path, &system_config.drives)?;
+    if !system_config.allow_menus {
+        remove_menu_entries(log, &mount_path)?;
+    }
+    if system_config.drives.is_empty() {
+        return Ok((mount, mount_path));
+    }
+    let remove_drives_on_unmount = system_config.remove_drives_on_unmount;
+    return Ok((Box::new(defer({
+        let log = log.clone();
+        let drives = system_config.drives.clone();
+        let system_path = system_path.to_path_buf();
+        let mount_path = mount_path.clone();
+        move || {
+            drop(mount);
+
+            // Still in use by another process
+            if remove_drives_on_unmount && !is_mounted(&mount_path).unwrap_or(true) {
+                remove_drives(
+                    &system_prefix_path(&system_path),
+                    &drives,
+                ).log(&log, StandardFlag::Warning, "Error removing drive links from the system");
+            }
+            drop(drive_locks);
+        }
+    })), mount_path));
+}
+
+fn read_basis_config(basis_path: &Path) -> Result<BasisLatestConfig, loga::Error> {
+    let config_path = basis_config_path(basis_path);
+    match serde_json::from_slice::<BasisConfig>(
+        &fs::read(
+            &config_path,
+        ).context_with("Error reading basis config", ea!(path = config_path.to_string_lossy()))?,
+    ).context_with("Error parsing basis config as json", ea!(path = config_path.to_string_lossy()))? {
+        BasisConfig::V1(config) => return Ok(BasisLatestConfig::new(config.arch)),
+        BasisConfig::V2(config) => return Ok(config),
+    }
+}
+
+fn write_basis_config(basis_path: &Path, config: &BasisLatestConfig) -> Result<(), loga::Error> {
+    let config_path = basis_config_path(basis_path);
+    fs::write(
+        &config_path,
+        serde_json::to_vec_pretty(&BasisConfig::V2(config.clone())).unwrap(),
+    ).context_with("Error writing basis config", ea!(path = config_path.to_string_lossy()))?;
+    return Ok(());
+}
+
+/// Environment for running things in a system: the basis environment with the
+/// system's environment and DLL overrides layered on top. `prefix` is where the
+/// system is mounted.
+fn system_wine_envs(
+    basis_config: &BasisLatestConfig,
+    system_config: &SystemLatestConfig,
+    system_path: &Path,
+    prefix: &Path,
+    secrets: SecretEnv,
+) -> Result<HashMap<OsString, OsString>, loga::Error> {
+    let mut out = wine_envs(basis_config, prefix)?;
+    system_config.sync.apply(&mut out, &system_config.env);
+    if let Some(gpu) = system_config.gpu {
+        gpu.apply(&mut out);
+    }
+    for (k, v) in &system_config.env {
+        out.insert(OsString::from(k), OsString::from(v));
+    }
+    for (k, secret) in &system_config.secret_env {
+        match secrets {
+            SecretEnv::Resolve => {
+                out.insert(
+                    OsString::from(k),
+                    OsString::from(secret.resolve().context_with("Error looking up secret", ea!(var = k))?),
+                );
+            },
+            SecretEnv::Mask => {
+                out.insert(OsString::from(k), OsString::from(secret.describe()));
+            },
+            SecretEnv::Omit => { },
+        }
+    }
+    if !system_config.allow_menus {
+        disable_menus(&mut out);
+    }
+    if !system_config.dll_overrides.is_empty() {
+        // Later entries take precedence, so the system's come last
+        let mut overrides = vec![];
+        if let Some(existing) = out.get(OsStr::new("WINEDLLOVERRIDES")) {
+            overrides.push(existing.to_string_lossy().to_string());
+        }
+        for (dll, mode) in &system_config.dll_overrides {
+            overrides.push(format!("{}={}", dll, mode));
+        }
+        out.insert(OsString::from("WINEDLLOVERRIDES"), OsString::from(overrides.join(";")));
+    }
+    out.insert(OsString::from("WINEPREFIX"), prefix.as_os_str().to_os_string());
+    if basis_config.shared_shader_cache {
+        let cache_dir = shared_shader_cache_dir(&system_config.basis_name)?;
+        create_dir_all(
+            &cache_dir,
+        ).context_with("Error creating shared shader cache dir", ea!(path = cache_dir.to_string_lossy()))?;
+
+        // Unless set in the basis or system `env`
+        for key in ["DXVK_STATE_CACHE_PATH", "VKD3D_SHADER_CACHE_PATH"] {
+            if !basis_config.env.contains_key(key) && !system_config.env.contains_key(key) {
+                out.insert(OsString::from(key), cache_dir.clone().into_os_string());
+            }
+        }
+    }
+    if system_config.isolate_shader_cache {
+        let cache_dir = system_shader_cache_path(system_path);
+        create_dir_all(
+            &cache_dir,
+        ).context_with("Error creating system shader cache dir", ea!(path = cache_dir.to_string_lossy()))?;
+        for key in ["MESA_SHADER_CACHE_DIR", "__GL_SHADER_DISK_CACHE_PATH"] {
+            if !basis_config.env.contains_key(key) && !system_config.env.contains_key(key) {
+                out.insert(OsString::from(key), cache_dir.clone().into_os_string());
+            }
+        }
+    }
+    if system_config.mangohud && !basis_config.env.contains_key("MANGOHUD") &&
+        !system_config.env.contains_key("MANGOHUD") {
+        out.insert(OsString::from("MANGOHUD"), OsString::from("1"));
+    }
+    if let Some(vkbasalt) = &system_config.vkbasalt {
+        out.insert(OsString::from("ENABLE_VKBASALT"), OsString::from("1"));
+        if let Some(config_file) = &vkbasalt.config_file {
+            out.insert(OsString::from("VKBASALT_CONFIG_FILE"), system_path.join(config_file).into_os_string());
+        }
+    }
+    if let Some(umu) = &system_config.umu {
+        // umu runs Proton with the prefix directly, not through a compat data dir
+        let basis_proton = out.remove(OsStr::new("WINEBASIN_PROTON"));
+        out.remove(OsStr::new("STEAM_COMPAT_DATA_PATH"));
+        out.insert(OsString::from("WINEBASIN_UMU"), OsString::from("umu-run"));
+        out.insert(OsString::from("GAMEID"), OsString::from(&umu.game_id));
+        if let Some(proton_path) = &umu.proton_path {
+            out.insert(OsString::from("PROTONPATH"), OsString::from(proton_path));
+        } else if let Some(dir) = basis_proton.as_ref().and_then(|p| Path::new(p).parent()) {
+            out.insert(OsString::from("PROTONPATH"), dir.as_os_str().to_os_string());
+        }
+        if let Some(store) = &umu.store {
+            out.insert(OsString::from("STORE"), OsString::from(store));
+        }
+    }
+    return Ok(out);
+}
+
+/// Where systems using the basis keep shader caches if the basis has
+/// `shared_shader_cache`. In the main root, since the basis may be read-only.
+fn shared_shader_cache_dir(basis_name: &str) -> Result<PathBuf, loga::Error> {
+    return Ok(root_dir()?.join("cache").join("shaders").join(basis_name));
+}
+
+/// The environment for a system's mounted prefix, from its configs.
+fn mounted_system_envs(system_path: &Path) -> Result<HashMap<OsString, OsString>, loga::Error> {
+    let system_config = check_system(system_path)?;
+    let basis_config = read_basis_config(&basis_path(&system_config.basis_name)?)?;
+    return system_wine_envs(
+        &basis_config,
+        &system_config,
+        system_path,
+        &system_mount_path(system_path),
+        SecretEnv::Omit,
+    );
+}
+
+/// The wine to run with the environment from `wine_envs`: the basis' registered
+/// wine, otherwise `WINE`, otherwise `wine` from `PATH`.
+fn wine_bin(env: &HashMap<OsString, OsString>) -> OsString {
+    if let Some(bin) = env.get(OsStr::new("WINE")) {
+        return bin.clone();
+    }
+    return env::var_os("WINE").unwrap_or_else(|| OsString::from("wine"));
+}
+
+/// Like `wine_bin`, for `wineserver`.
+fn wineserver_bin(env: &HashMap<OsString, OsString>) -> OsString {
+    if let Some(bin) = env.get(OsStr::new("WINESERVER")) {
+        return bin.clone();
+    }
+    return env::var_os("WINESERVER").unwrap_or_else(|| OsString::from("wineserver"));
+}
+
+/// Wait for all programs in the prefix to exit, killing them if they don't exit
+/// within `timeout`.
+fn wait_wineserver(
+    log: &StandardLog,
+    env: &HashMap<OsString, OsString>,
+    timeout: Option<Duration>,
+) -> Result<(), loga::Error> {
+    let kill = {
+        let wineserver = wineserver_bin(env);
+        let env = env.iter().map(|(k, v)| (k.to_os_string(), v.clone())).collect::<Vec<(OsString, OsString)>>();
+        move || {
+            _ = Command::new(&wineserver).envs(env.clone()).arg("-k").status();
+        }
+    };
+    signals::set_handler(Handler::Call(Box::new({
+        let kill = kill.clone();
+        move |_| kill()
+    })));
+    let res = (|| {
+        let mut command = Command::new(wineserver_bin(env));
+        command.envs(env).arg("-w");
+        let command_log = log.fork(ea!(command = mask_secrets(command.dbg_str())));
+        echo_command(&command);
+        let mut child = command.spawn().stack_context(&command_log, "Failed to spawn command")?;
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            if let Some(status) = child.try_wait().stack_context(&command_log, "Error waiting for wineserver")? {
+                if !status.success() {
+                    return Err(command_log.err_with("Command exited with error", ea!(status = status)));
+                }
+                return Ok(());
+            }
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                command_log.log_with(
+                    StandardFlag::Warning,
+                    "Programs in the prefix are still running after the timeout, killing them",
+                    ea!(timeout_secs = timeout.unwrap().as_secs()),
+                );
+                kill();
+                child.wait().stack_context(&command_log, "Error waiting for wineserver")?;
+                return Ok(());
+            }
+            sleep(Duration::from_millis(200));
+        }
+    })();
+    signals::set_handler(Handler::Record);
+    return res;
+}
+
+fn basis_update_log_path(basis_path: &Path) -> PathBuf {
+    return basis_path.join("update.log");
+}
+
+/// The basis' update command, as `wine` arguments.
+fn basis_update_command(config: &BasisLatestConfig) -> Vec<String> {
+    return config.update_command.clone().unwrap_or_else(|| vec!["wineboot".to_string(), "--update".to_string()]);
+}
+
+/// Create the prefix or update it for the current wine version using the basis'
+/// update command. Output goes to `update.log` in the basis directory, the end of
+/// which is included in the error if it fails.
+fn update_prefix(basis_path: &Path, config: &BasisLatestConfig) -> Result<(), loga::Error> {
+    let prefix_path = basis_prefix_path(basis_path);
+    let log_path = basis_update_log_path(basis_path);
+    let log_file =
+        File::create(&log_path).context_with("Error creating update log", ea!(path = log_path.to_string_lossy()))?;
+    let env = wine_envs(config, &prefix_path)?;
+    let res =
+        wine_program_command(&env)?
+            .args(basis_update_command(config))
+            .envs(&env)
+            .stdin(Stdio::null())
+            .stdout(log_file.try_clone().context("Error duplicating update log file handle")?)
+            .stderr(log_file)
+            .run();
+    if let Err(e) = res {
+        let output = fs::read_to_string(&log_path).unwrap_or_default();
+        let mut tail = output.lines().rev().take(20).collect::<Vec<_>>();
+        tail.reverse();
+        return Err(
+            loga::agg_err_with(
+                "Error updating the basis prefix",
+                vec![e],
+                ea!(log = log_path.to_string_lossy(), output = tail.join("\n")),
+            ),
+        );
+    }
+    let wine_path = basis_wine_path(basis_path);
+    fs::write(
+        &wine_path,
+        serde_json::to_vec_pretty(&current_basis_wine(&env)).unwrap(),
+    ).context_with("Error recording basis wine version", ea!(path = wine_path.to_string_lossy()))?;
+    return Ok(());
+}
+
+/// Set up a new prefix following the recipe in the basis config.
+fn build_basis_prefix(log: &StandardLog, basis_path: &Path, config: &BasisLatestConfig) -> Result<(), loga::Error> {
+    let prefix_path = basis_prefix_path(basis_path);
+    let env = wine_envs(config, &prefix_path)?;
+    update_prefix(basis_path, config)?;
+    if !config.winetricks.is_empty() {
+        run_winetricks(log, &env, &config.winetricks)?;
+    }
+    for (i, registry) in config.registry.iter().enumerate() {
+        let import_path = basis_path.join(format!("import-{}.reg", i));
+        fs::write(
+            &import_path,
+            registry,
+        ).context_with("Error writing registry file to import", ea!(path = import_path.to_string_lossy()))?;
+        let res = Command::new(wine_bin(&env)).envs(&env).arg("regedit").arg("/S").arg(&import_path).run();
+        _ = fs::remove_file(&import_path);
+        res?;
+    }
+    if let Some(dxvk) = &config.dxvk {
+        fetch_dxvk(log, &dxvk.version, Some(&dxvk.sha256))?;
+        install_dxvk(&env, &prefix_path, config.arch, dxvk)?;
+    }
+    if config.audio != AudioDriver::Auto {
+        set_audio_driver(&env, &prefix_path, config.audio)?;
+    }
+    if let Some(script) = &config.script {
+        shell_commandline(&env, &prefix_path).run_stdin(script.as_bytes())?;
+    }
+    return Ok(());
+}
+
+/// Create the basis directory, record the recipe, and build the prefix.
+fn create_basis(log: &StandardLog, basis_path: &Path, config: &BasisLatestConfig) -> Result<(), loga::Error> {
+    create_dir_all(basis_path).context("Failed to ensure basis directory")?;
+
+    // Record the recipe first so a failed setup can be retried with `basis recreate`
+    write_basis_config(basis_path, config)?;
+    build_basis_prefix(log, basis_path, config)?;
+    return Ok(());
+}
+
+/// Check an existing basis can be changed (writable, up to date, and no systems
+/// using it running) before modifying its prefix. The lock must be exclusive.
+/// Returns the basis path and config.
+fn prepare_basis_change(
+    log: &StandardLog,
+    basis_name: &str,
+    lock: &mut Lock,
+    policy: UpdatePolicy,
+) -> Result<(PathBuf, BasisLatestConfig), loga::Error> {
+    let basis_path = basis_path(basis_name)?;
+    check_basis_writable(basis_name, &basis_path)?;
+    let config = update_basis(log, basis_name, lock, policy)?;
+
+    // Changing the lower layer of a mounted overlay is undefined behavior
+    let mounted = mounted_dependents(basis_name)?;
+    if !mounted.is_empty() {
+        return Err(
+            loga::err_with(
+                "Systems using the basis are running, stop them first",
+                ea!(basis = basis_name, systems = mounted.join(", ")),
+            ),
+        );
+    }
+    return Ok((basis_path, config));
+}
+
+/// Install winetricks verbs in an existing basis and add them to the recipe. The
+/// lock must be exclusive.
+fn install_basis_verbs(
+    log: &StandardLog,
+    basis_name: &str,
+    lock: &mut Lock,
+    policy: UpdatePolicy,
+    verbs: Vec<String>,
+) -> Result<(), loga::Error> {
+    let (basis_path, mut config) = prepare_basis_change(log, basis_name, lock, policy)?;
+    run_winetricks(log, &wine_envs(&config, &basis_prefix_path(&basis_path))?, &verbs)?;
+    config.winetricks.extend(verbs);
+    write_basis_config(&basis_path, &config)?;
+
+    // New files show through to systems, but registry changes don't if the system has
+    // its own copy of the registry
+    let shadowed = registry_shadowed_dependents(basis_name)?;
+    if !shadowed.is_empty() {
+        log.log_with(
+            StandardFlag::Warning,
+            "These systems have their own registry, so registry changes made by the verbs won't be visible in them. Install the verbs in them with `system winetricks` instead.",
+            ea!(systems = shadowed.join(", ")),
+        );
+    }
+    return Ok(());
+}
+
+/// Systems using the basis with their own copy of the registry, which hides
+/// registry changes made in the basis.
+fn registry_shadowed_dependents(basis_name: &str) -> Result<Vec<String>, loga::Error> {
+    let mut out = vec![];
+    for name in basis_dependents(basis_name)? {
+        let prefix_path = system_prefix_path(&system_path(&name)?);
+        if ["system.reg", "user.reg"].iter().any(|r| prefix_path.join(r).exists()) {
+            out.push(name);
+        }
+    }
+    return Ok(out);
+}
+
+/// Names of all systems using the basis. Systems with broken configs are skipped.
+fn basis_dependents(basis_name: &str) -> Result<Vec<String>, loga::Error> {
+    let systems_dir = root_dir()?.join("system");
+    let mut out = vec![];
+    if !systems_dir.exists() {
+        return Ok(out);
+    }
+    for entry in fs::read_dir(
+        &systems_dir,
+    ).context_with("Error listing systems", ea!(path = systems_dir.to_string_lossy()))? {
+        let entry = entry.context("Error reading systems dir entry")?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let Ok(config) = check_system(&system_path(&name)?) else {
+            continue;
+        };
+        if config.basis_name == basis_name {
+            out.push(name);
+        }
+    }
+    out.sort();
+    return Ok(out);
+}
+
+/// Names of mounted systems using the basis.
+fn mounted_dependents(basis_name: &str) -> Result<Vec<String>, loga::Error> {
+    let mut out = vec![];
+    for (name, _) in mounted_systems()? {
+        let Ok(config) = check_system(&system_path(&name)?) else {
+            continue;
+        };
+        if config.basis_name == basis_name {
+            out.push(name);
+        }
+    }
+    return Ok(out);
+}
+
+/// Update the basis if required. If an update is needed, `lock` is temporarily
+/// upgraded to exclusive. If the lock is shared (i.e. the basis is being used to
+/// start a system) and other systems using the basis are mounted, the update is
+/// deferred or waits for them according to `policy`.
+fn update_basis(
+    log: &StandardLog,
+    basis_name: &str,
+    lock: &mut Lock,
+    policy: UpdatePolicy,
+) -> Result<BasisLatestConfig, loga::Error> {
+    if LOCALIZE_EXTRA_BASES.load(Ordering::Relaxed) {
+        localize_basis(log, basis_name)?;
+    }
+    let basis_path = basis_path(basis_name)?;
+    let config = read_basis_config(&basis_path)?;
+    if !basis_needs_update(&basis_path)? {
+        return Ok(config);
+    }
+    if check_basis_writable(basis_name, &basis_path).is_err() {
+        log.log_with(
+            StandardFlag::Warning,
+            "Basis needs an update but is in a read-only extra root, using it as-is",
+            ea!(basis = basis_name, path = basis_path.to_string_lossy()),
+        );
+        return Ok(config);
+    }
+    let original_mode = lock.mode();
+    if original_mode == LockMode::Shared {
+        let log = log.fork(ea!(basis = basis_name));
+        match policy {
+            UpdatePolicy::Defer => {
+                let dependents = mounted_dependents(basis_name)?;
+                if !dependents.is_empty() || !lock.try_set_mode(LockMode::Exclusive)? {
+                    log.log_with(
+                        StandardFlag::Warning,
+                        "Basis needs an update but is in use by other systems, deferring the update",
+                        ea!(systems = dependents.join(", ")),
+                    );
+                    return Ok(config);
+                }
+            },
+            UpdatePolicy::Wait => {
+                let dependents = mounted_dependents(basis_name)?;
+                if !dependents.is_empty() {
+                    log.log_with(
+                        StandardFlag::Info,
+                        "Basis needs an update, waiting for other systems using it to unmount",
+                        ea!(systems = dependents.join(", ")),
+                    );
+                }
+                lock.wait_set_mode(LockMode::Exclusive)?;
+            },
+        }
+    } else {
+        lock.set_mode(LockMode::Exclusive)?;
+    }
+
+    // Another process may have updated it while the lock was released for upgrading
+    if basis_needs_update(&basis_path)? {
+        update_prefix(&basis_path, &config)?;
+    }
+    lock.set_mode(original_mode)?;
+    return Ok(config);
+}
+
+/// Names of all systems in the root, sorted.
+fn system_names() -> Result<Vec<String>, loga::Error> {
+    let systems_dir = root_dir()?.join("system");
+    let mut names = vec![];
+    if systems_dir.exists() {
+        for entry in fs::read_dir(
+            &systems_dir,
+        ).context_with("Error listing systems", ea!(path = systems_dir.to_string_lossy()))? {
+            let entry = entry.context("Error reading systems dir entry")?;
+            names.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    names.sort();
+    return Ok(names);
+}
+
+fn system_path(name: &str) -> Result<PathBuf, loga::Error> {
+    return Ok(root_dir()?.join("system").join(name));
+}
+
+fn system_shader_cache_path(system_path: &Path) -> PathBuf {
+    return system_path.join("shader_cache");
+}
+
+fn system_config_path(system_path: &Path) -> PathBuf {
+    return system_path.join("config.json");
+}
+
+fn system_prefix_path(system_path: &Path) -> PathBuf {
+    return system_path.join("prefix");
+}
+
+fn system_overlay_work_path(system_path: &Path) -> PathBuf {
+    return system_path.join("overlay_work");
+}
+
+fn system_mount_path(system_path: &Path) -> PathBuf {
+    return system_path.join("mount");
+}
+
+fn create_system(system_name: &str, basis_name: &str, wait: bool) -> Result<(), loga::Error> {
+    let _lock = lock_system(system_name, LockMode::Exclusive, wait)?;
+    let system_path = system_path(system_name)?;
+    create_dir_all(
+        &system_path,
+    ).context_with("Error creating system path", ea!(path = system_path.to_string_lossy()))?;
+    create_dir_all(system_prefix_path(&system_path)).context("Failed to ensure system prefix directory")?;
+    create_dir_all(
+        system_overlay_work_path(&system_path),
+    ).context("Failed to ensure system overlay work directory")?;
+    create_dir_all(system_mount_path(&system_path)).context("Failed to ensure system overlay mount directory")?;
+    write_system_config(&system_path, SystemLatestConfig::new(basis_name))?;
+    return Ok(());
+}
+
+fn write_system_config(system_path: &Path, config: SystemLatestConfig) -> Result<(), loga::Error> {
+    let config_path = system_config_path(system_path);
+    fs::write(
+        &config_path,
+        serde_json::to_vec_pretty(&SystemConfig::V1(config)).unwrap(),
+    ).context_with("Error writing config to system dir", ea!(path = config_path.to_string_lossy()))?;
+    return Ok(());
+}
+
+/// Expand `system_name_template` in the global config.
+fn expand_system_name(template: &str, basis_name: &str, date: &str, n: usize) -> Result<String, loga::Error> {
+    return expand_template(template, |key| {
+        match key {
+            "basis" => return Ok(Some(basis_name.to_string())),
+            "date" => return Ok(Some(date.to_string())),
+            "n" => return Ok(Some(n.to_string())),
+            _ => return Ok(None),
+        }
+    }).context("Error expanding system name template");
+}
+
+/// If `name` is a basis rather than a system, get the system to use instead:
+/// `name_override` (created if it doesn't exist) or a new system named using the
+/// template. Otherwise returns `name` unchanged. For dry runs, systems aren't
+/// created, the name they'd get is returned.
+fn resolve_system_name(
+    log: &StandardLog,
+    global_config: &GlobalLatestConfig,
+    name: &str,
+    name_override: &Option<String>,
+    wait: bool,
+    dry_run: bool,
+) -> Result<String, loga::Error> {
+    if name_override.is_none() && system_path(name)?.exists() {
+        return Ok(name.to_string());
+    }
+    if !basis_path(name)?.exists() {
+        if name_override.is_some() {
+            return Err(loga::err_with("No basis with this name to create the system from", ea!(basis = name)));
+        }
+
+        // Let the caller report the missing system
+        return Ok(name.to_string());
+    }
+    if let Some(system_name) = name_override {
+        if !system_path(system_name)?.exists() {
+            if dry_run {
+                println!("# System `{}` would be created from basis `{}`", system_name, name);
+                return Ok(system_name.clone());
+            }
+            create_system(system_name, name, wait)?;
+            log.log_with(StandardFlag::Info, "Created system", ea!(system = system_name, basis = name));
+        }
+        return Ok(system_name.clone());
+    }
+    let template = global_config.system_name_template.as_deref().unwrap_or("{basis}-{date}-{n}");
+    let date = &format_time(now_secs())[..10];
+    let system_dir = root_dir()?.join("system");
+    if !dry_run {
+        create_dir_all(
+            &system_dir,
+        ).context_with("Error creating systems dir", ea!(path = system_dir.to_string_lossy()))?;
+    }
+    for n in 1.. {
+        let system_name = expand_system_name(template, name, date, n)?;
+        if n > 1 && system_name == expand_system_name(template, name, date, n - 1)? {
+            return Err(
+                loga::err_with(
+                    "A system with the templated name already exists, add `{n}` to `system_name_template` or use `--name`",
+                    ea!(system = system_name),
+                ),
+            );
+        }
+
+        // Claim the name, in case another winebasin is creating a system at the same time
+        let system_path = system_path(&system_name)?;
+        if dry_run {
+            if system_path.exists() {
+                continue;
+            }
+            println!("# System `{}` would be created from basis `{}`", system_name, name);
+            return Ok(system_name);
+        }
+        match fs::create_dir(&system_path) {
+            Ok(_) => { },
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => {
+                return Err(e.context_with("Error creating system path", ea!(path = system_path.to_string_lossy())));
+            },
+        }
+        create_system(&system_name, name, wait)?;
+        log.log_with(StandardFlag::Info, "Created system", ea!(system = system_name, basis = name));
+        return Ok(system_name);
+    }
+    unreachable!();
+}
+
+/// Get the system for `system run` or `system shell` (see `resolve_system_name`),
+/// lock it, and load its config with `--allow-menus` applied. Dry runs don't lock,
+/// and a system that would be created gets the config it'd be created with.
+fn load_run_system(
+    log: &StandardLog,
+    global_config: &GlobalLatestConfig,
+    name: &str,
+    name_override: &Option<String>,
+    allow_menus: &Option<()>,
+    wait: bool,
+    dry_run: bool,
+) -> Result<(String, Option<Lock>, SystemLatestConfig), loga::Error> {
+    let system_name = resolve_system_name(log, global_config, name, name_override, wait, dry_run)?;
+    let lock = if dry_run {
+        None
+    } else {
+        Some(lock_system(&system_name, LockMode::Shared, wait)?)
+    };
+    let system_path = system_path(&system_name)?;
+    let mut system_config = if dry_run && !system_path.exists() && basis_path(name)?.exists() {
+        SystemLatestConfig::new(name)
+    } else {
+        check_system(&system_path)?
+    };
+    if allow_menus.is_some() {
+        system_config.allow_menus = true;
+    }
+    return Ok((system_name, lock, system_config));
+}
+
+fn check_system(system_path: &Path) -> Result<SystemLatestConfig, loga::Error> {
+    let config_path = basis_config_path(system_path);
+    let config =
+        match serde_json::from_slice::<SystemConfig>(
+            &fs::read(
+                &config_path,
+            ).context_with("Error reading system config", ea!(path = config_path.to_string_lossy()))?,
+        ).context_with("Error parsing system config as json", ea!(path = config_path.to_string_lossy()))? {
+            SystemConfig::V1(config) => config,
+        };
+    return Ok(config);
+}
+
+/// Programs installed in the system or its basis, sorted by name, without mounting
+/// it.
+fn system_installed_programs(system_name: &str) -> Result<Vec<InstalledProgram>, loga::Error> {
+    let system_path = system_path(system_name)?;
+    let system_config = check_system(&system_path)?;
+    let view = PrefixView {
+        upper: system_prefix_path(&system_path),
+        lower: basis_prefix_path(&basis_path(&system_config.basis_name)?),
+    };
+    let mut programs = vec![];
+    for file in ["system.reg", "user.reg"] {
+        programs.extend(installed_programs(&read_registry_tree(view.resolve(Path::new(file)).as_deref())?));
+    }
+    programs.sort_by_key(|p| p.name.to_lowercase());
+    return Ok(programs);
+}
+
+/// The command that uninstalls an installed program, found by its Uninstall key
+/// name or (case-insensitively) its name or part of its name.
+fn uninstall_command(system_name: &str, query: &str) -> Result<Vec<String>, loga::Error> {
+    let programs = system_installed_programs(system_name)?;
+    let query_lower = query.to_lowercase();
+    let mut matches =
+        programs.iter().filter(|p| p.id == query || p.name.to_lowercase() == query_lower).collect::<Vec<_>>();
+    if matches.is_empty() {
+        matches = programs.iter().filter(|p| p.name.to_lowercase().contains(&query_lower)).collect();
+    }
+    let program = match matches.as_slice() {
+        [program] => *program,
+        [] => return Err(
+            loga::err_with(
+                "No installed program matches, see `winebasin system apps`",
+                ea!(system = system_name, program = query),
+            ),
+        ),
+        _ => return Err(
+            loga::err_with(
+                "Multiple installed programs match, use the full name or ID from `winebasin system apps`",
+                ea!(matches = matches.iter().map(|p| p.name.as_str()).collect::<Vec<_>>().join(", ")),
+            ),
+        ),
+    };
+    let uninstall =
+        program
+            .uninstall_string
+            .as_ref()
+            .context_with("The program has no uninstaller registered", ea!(program = program.name))?;
+    let mut command = split_windows_command_line(uninstall);
+    command[0] = windows_program_path(&command[0])?;
+    return Ok(command);
+}
+
+/// The command line for a program registered with `system app add`.
+fn app_command(system_name: &str, config: &SystemLatestConfig, alias: &str) -> Result<Vec<String>, loga::Error> {
+    let app =
+        config
+            .apps
+            .get(alias)
+            .context_with(
+                "No app with this name, see `winebasin system app list`",
+                ea!(system = system_name, alias = alias),
+            )?;
+    let mut command = vec![app.exe.clone()];
+    command.extend(app.args.iter().cloned());
+    return Ok(command);
+}
+
+/// Systems with an allowlist don't allow changing the environment, which could be
+/// used to run other code (ex: with `WINEDLLOVERRIDES`).
+fn check_env_allowed(
+    system_name: &str,
+    config: &SystemLatestConfig,
+    env: &Option<Vec<EnvArg>>,
+) -> Result<(), loga::Error> {
+    if config.allowed_commands.is_some() && env.is_some() {
+        return Err(
+            loga::err_with(
+                "This system has a list of allowed commands, `--env` is disabled",
+                ea!(system = system_name),
+            ),
+        );
+    }
+    return Ok(());
+}
+
+/// Check the command against the system's allowlist, if it has one.
+fn check_command_allowed(system_name: &str, config: &SystemLatestConfig, command: &str) -> Result<(), loga::Error> {
+    let Some(allowed) = &config.allowed_commands else {
+        return Ok(());
+    };
+    let command = Path::new(command);
+    if !allowed.iter().any(|a| a.components().eq(command.components())) {
+        return Err(
+            loga::err_with(
+                "This command isn't in the system's list of allowed commands",
+                ea!(
+                    system = system_name,
+                    command = command.to_string_lossy(),
+                    allowed = allowed.iter().map(|a| a.to_string_lossy()).collect::<Vec<_>>().join(", ")
+                ),
+            ),
+        );
+    }
+    return Ok(());
+}
+
+/// Exit code used when winebasin itself fails, to distinguish from the exit code
+/// of a program run with `system run`.
+const ERROR_EXIT_CODE: i32 = 125;
+
+/// Time spent in each step of starting a program in a system.
+struct StartupProfile {
+    last: Instant,
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl StartupProfile {
+    fn new(start: Instant) -> StartupProfile {
+        return StartupProfile {
+            last: start,
+            phases: vec![],
+        };
+    }
+
+    /// Record the time since the previous phase ended as `phase`.
+    fn mark(&mut self, phase: &'static str) {
+        let now = Instant::now();
+        self.phases.push((phase, now - self.last));
+        self.last = now;
+    }
+
+    fn print(&self) {
+        eprintln!("Startup profile:");
+        for (phase, duration) in &self.phases {
+            eprintln!("  {:<20} {:>9.1}ms", phase, duration.as_secs_f64() * 1000.);
+        }
+        eprintln!(
+            "  {:<20} {:>9.1}ms",
+            "total",
+            self.phases.iter().map(|(_, d)| *d).sum::<Duration>().as_secs_f64() * 1000.
+        );
+    }
+}
+
+/// Global flags that take a value, skipped when looking for the command name.
+const GLOBAL_VALUE_FLAGS: &[&str] = &["--root", "--backend", "--update-policy"];
+
+/// Set for the background process started by `system run --detach`, which gets the
+/// parent's arguments with aliases already expanded and runs in the foreground.
+const DETACHED_ENV: &str = "WINEBASIN_DETACHED";
+
+/// How long `system stop` waits for background runs to exit before killing them.
+const STOP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// If the command (the first argument after any global flags) is an alias from the
+/// global config, replace it with the alias' arguments. The alias is split like a
+/// shell command line. `%1`, `%2`, ... are replaced with the arguments following
+/// the alias, a `%*` argument with all of them, and `%%` is a literal `%`.
+/// Arguments that aren't used by a placeholder are appended.
+fn expand_alias(aliases: &BTreeMap<String, String>, args: Vec<String>) -> Result<Vec<String>, loga::Error> {
+    let mut i = 0;
+    while i < args.len() && args[i].starts_with("--") {
+        if GLOBAL_VALUE_FLAGS.contains(&args[i].as_str()) {
+            i += 1;
+        }
+        i += 1;
+    }
+    let Some(alias) = args.get(i).and_then(|name| aliases.get(name)) else {
+        return Ok(args);
+    };
+    let name = &args[i];
+    let alias_args = &args[i + 1..];
+    let mut used = vec![
+        false;
+        alias_args.len()
+    ];
+    let mut out = args[..i].to_vec();
+    for word in shlex::split(
+        alias,
+    ).context_with("Alias in global config isn't valid shell syntax", ea!(alias = name))? {
+        if word == "%*" {
+            out.extend(alias_args.iter().cloned());
+            used.fill(true);
+            continue;
+        }
+        let mut arg = String::new();
+        let mut rest = word.as_str();
+        while let Some(j) = rest.find('%') {
+            arg.push_str(&rest[..j]);
+            rest = &rest[j + 1..];
+            if let Some(after) = rest.strip_prefix('%') {
+                arg.push('%');
+                rest = after;
+            } else {
+                let end = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+                let index =
+                    rest[..end]
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|x| *x > 0)
+                        .context_with(
+                            "Invalid placeholder in alias, expected `%1`, `%%`, or `%*` (as a whole argument)",
+                            ea!(alias = name),
+                        )?;
+                arg.push_str(
+                    alias_args
+                        .get(index - 1)
+                        .context_with(
+                            "Missing argument for alias",
+                            ea!(alias = name, placeholder = format!("%{}", index)),
+                        )?,
+                );
+                used[index - 1] = true;
+                rest = &rest[end..];
+            }
+        }
+        arg.push_str(rest);
+        out.push(arg);
+    }
+    for (arg, used) in alias_args.iter().zip(used) {
+        if !used {
+            out.push(arg.clone());
+        }
+    }
+    return Ok(out);
+}
+
+/// Error out if winebasin was asked to terminate while cleanup was deferred.
+fn check_signal() -> Result<(), loga::Error> {
+    if let Some(sig) = signals::received() {
+        return Err(loga::err_with("Terminated by signal", ea!(signal = sig)));
+    }
+    return Ok(());
+}
+
+fn main() {
+    let start = Instant::now();
+    match (|| {
+        signals::install()?;
+        let global_config = global_config()?;
+        let mut raw_args = env::args();
+        let program = raw_args.next();
+
+        // Don't pass this on to the programs run
+        let detached = env::var_os(DETACHED_ENV).is_some();
+        env::remove_var(DETACHED_ENV);
+        let expanded_args = if detached {
+            // Already expanded by the parent
+            raw_args.collect()
+        } else {
+            expand_alias(&global_config.aliases, raw_args.collect())?
+        };
+        let args = match vark_explicit::<Args>(program, expanded_args.clone()) {
+            Ok(args) => args,
+            Err(e) => {
+                eprintln!("{:?}", e);
+                exit(ERROR_EXIT_CODE);
+            },
+        };
+        let backend = args.backend;
+        let wait = args.wait.is_some();
+        let json = args.json.is_some();
+        let dry_run = args.dry_run.is_some();
+        let update_policy = args.update_policy.or(global_config.update_policy).unwrap_or(UpdatePolicy::Defer);
+        let root = if let Some(root) = &args.root {
+            Some(std::path::absolute(root).context("Error making `--root` absolute")?)
+        } else if let Some(root) = env::var_os("WINEBASIN_ROOT") {
+            Some(expand_path(&root.to_string_lossy())?)
+        } else if let Some(root) = &global_config.root {
+            Some(expand_path(root)?)
+        } else {
+            None
+        };
+        if let Some(root) = root {
+            ROOT_OVERRIDE.set(root).unwrap();
+        }
+        #[cfg(feature = "test-mode")]
+        if args.test_mode.is_some() {
+            // Don't put stubs in the real data dir (including a root from the global config)
+            if args.root.is_none() && env::var_os("WINEBASIN_ROOT").is_none() {
+                return Err(
+                    loga::err("`--test-mode` requires the root to be set with `--root` or `WINEBASIN_ROOT`"),
+                );
+            }
+            test_mode::enter(&root_dir()?)?;
+        }
+        _ = WINETRICKS_PIN.set(global_config.winetricks_pin.clone());
+        if let Some(cache) = &global_config.winetricks_cache {
+            _ = WINETRICKS_CACHE.set(expand_path(cache)?);
+        }
+        LOCALIZE_EXTRA_BASES.store(global_config.localize_extra_bases, Ordering::Relaxed);
+        EXTRA_ROOTS
+            .set(global_config.extra_roots.iter().map(|r| expand_path(r)).collect::<Result<Vec<_>, _>>()?)
+            .unwrap();
+        let log = if args.debug.is_some() {
+            ECHO_COMMANDS.store(true, Ordering::Relaxed);
+            StandardLog
+            ::new().with_flags(
+                &[StandardFlag::Error, StandardFlag::Warning, StandardFlag::Info, StandardFlag::Debug],
+            )
+        } else if args.verbose.is_some() {
+            StandardLog
+            ::new().with_flags(
+                &[StandardFlag::Error, StandardFlag::Warning, StandardFlag::Info, StandardFlag::Debug],
+            )
+        } else if args.quiet.is_some() {
+            StandardLog::new().with_flags(&[StandardFlag::Error])
+        } else {
+            StandardLog::new().with_flags(&[StandardFlag::Error, StandardFlag::Warning, StandardFlag::Info])
+        };
+        detect_enclosing(&log)?;
+        for (capability, res) in capabilities().all() {
+            if let Err(missing) = res {
+                log.log_with(
+                    StandardFlag::Debug,
+                    "Capability unavailable, features that need it are disabled",
+                    ea!(capability = capability.name(), missing = missing),
+                );
+            }
+        }
+        let (data_dir, config_dir, runtime_dir) = (root_dir()?, config_dir()?, runtime_dir()?);
+        log.log_with(
+            StandardFlag::Debug,
+            "Using directories",
+            ea!(
+                data = data_dir.to_string_lossy(),
+                config = config_dir.to_string_lossy(),
+                runtime = runtime_dir.to_string_lossy()
+            ),
+        );
+        if !matches!(args.command, ArgsCommand::Cleanup { .. }) {
+            let stale_mounts =
+                stale_systems()?
+                    .into_iter()
+                    .filter(|(_, staleness)| matches!(staleness, Staleness::Mount(_)))
+                    .map(|(name, _)| name)
+                    .collect::<Vec<_>>();
+            if !stale_mounts.is_empty() {
+                log.log_with(
+                    StandardFlag::Warning,
+                    "Found leftover mounts from winebasin processes that didn't exit cleanly, run `winebasin cleanup` to remove them",
+                    ea!(systems = stale_mounts.join(", ")),
+                );
+            }
+        }
+        let command = match args.command {
+            ArgsCommand::Run(args) => {
+                let manifest = read_manifest(&args.manifest)?;
+                let system = manifest.system.as_ref().context("The manifest has no `system` to run")?;
+                if !dry_run {
+                    apply_manifest(&log, &manifest, wait, update_policy)?;
+                }
+                let mut command = system.command.clone();
+                if command.is_empty() {
+                    return Err(loga::err("The manifest's `system` has no `command` to run"));
+                }
+                command.extend(args.args);
+                ArgsCommand::System(SystemArgs::Run(SystemRunArgs {
+                    working_dir: system.working_dir.clone(),
+                    detach: args.detach,
+                    ..SystemRunArgs::new(system.name.clone(), command)
+                }))
+            },
+            ArgsCommand::System(SystemArgs::App(SystemAppArgs::Run(args))) => {
+                let system_config = check_system(&system_path(&args.system_name)?)?;
+                let mut command = app_command(&args.system_name, &system_config, &args.alias)?;
+                command.extend(args.args);
+                ArgsCommand::System(SystemArgs::Run(SystemRunArgs {
+                    detach: args.detach,
+                    ..SystemRunArgs::new(args.system_name, command)
+                }))
+            },
+            ArgsCommand::System(SystemArgs::Uninstall { system_name, program }) => {
+                let command = uninstall_command(&system_name, &program)?;
+                ArgsCommand::System(SystemArgs::Run(SystemRunArgs::new(system_name, command)))
+            },
+            ArgsCommand::Open { file, system } => {
+                let file = std::path::absolute(&file).context("Error making program path absolute")?;
+                let system_name = match system {
+                    Some(system) => system,
+                    None => match choose_system(&file, &system_names()?)? {
+                        Some(system) => system,
+                        None => return Ok(()),
+                    },
+                };
+                ArgsCommand::System(
+                    SystemArgs::Run(SystemRunArgs::new(system_name, vec![file.to_string_lossy().to_string()])),
+                )
+            },
+            ArgsCommand::HandleUrl { url } => {
+                match parse_url(&url)? {
+                    WinebasinUrl::Run { system_name, app } => {
+                        // Links can come from anywhere, so don't create systems from bases
+                        if !system_names()?.contains(&system_name) {
+                            return Err(loga::err_with("No system with this name", ea!(system = system_name)));
+                        }
+                        let system_config = check_system(&system_path(&system_name)?)?;
+                        let command = app_command(&system_name, &system_config, &app)?;
+                        ArgsCommand::System(SystemArgs::Run(SystemRunArgs::new(system_name, command)))
+                    },
+                }
+            },
+            command => command,
+        };
+        match command {
+            ArgsCommand::Run(_) => unreachable!(),
+            ArgsCommand::Open { .. } => unreachable!(),
+            ArgsCommand::HandleUrl { .. } => unreachable!(),
+            ArgsCommand::Integrate { remove } => {
+                let entries = [(open_entry_path()?, open_entry()?), (url_entry_path()?, url_entry()?)];
+                let dir = entries[0].0.parent().unwrap();
+                if remove.is_some() {
+                    for (path, _) in &entries {
+                        if path.exists() {
+                            fs::remove_file(
+                                path,
+                            ).context_with("Error removing desktop entry", ea!(path = path.to_string_lossy()))?;
+                        }
+                    }
+                } else {
+                    create_dir_all(
+                        dir,
+                    ).context_with("Error creating applications dir", ea!(path = dir.to_string_lossy()))?;
+                    for (path, entry) in &entries {
+                        fs::write(
+                            path,
+                            entry,
+                        ).context_with("Error writing desktop entry", ea!(path = path.to_string_lossy()))?;
+                    }
+                }
+                update_desktop_database(dir);
+                if remove.is_none() {
+                    set_url_handler_default();
+                }
+            },
+            ArgsCommand::Wine(args) => match args {
+                WineArgs::Add { name, path } => {
+                    check_wine_name(&name)?;
+                    let path = std::path::absolute(&path).context("Error making wine path absolute")?;
+                    let wine = detect_wine_install(&path)?;
+                    let mut wines = read_wines()?;
+                    if wines.wines.contains_key(&name) {
+                        return Err(loga::err_with("A wine with this name is already registered", ea!(wine = name)));
+                    }
+                    wines.wines.insert(name, wine);
+                    write_wines(wines)?;
+                },
+                WineArgs::AddFlatpak { name, app } => {
+                    add_flatpak_wine(&name, app.as_deref().unwrap_or("org.winehq.Wine"))?;
+                },
+                WineArgs::Install { name, url, sha256 } => {
+                    install_wine(&log, &name, url.as_deref(), sha256.as_deref())?;
+                },
+                WineArgs::List => {
+                    let mut json_wines = vec![];
+                    for (name, wine) in read_wines()?.wines {
+                        let version =
+                            wine_version(
+                                &HashMap::from(
+                                    [
+                                        (OsString::from("WINE"), wine.wine_bin().into_os_string()),
+                                        (OsString::from("WINESERVER"), wine.wineserver_bin().into_os_string()),
+                                    ],
+                                ),
+                            );
+                        if json {
+                            json_wines.push(json!({
+                                "name": name,
+                                "path": wine.path,
+                                "version": version,
+                                "proton": wine.proton,
+                                "flatpak": wine.flatpak
+                            }));
+                        } else {
+                            let mut notes = vec![version.unwrap_or_else(|| "not working".to_string())];
+                            if wine.proton {
+                                notes.push("proton".to_string());
+                            }
+                            if let Some(app) = &wine.flatpak {
+                                notes.push(format!("flatpak {}", app));
+                            }
+                            println!("{}: {} ({})", name, wine.path.to_string_lossy(), notes.join(", "));
+                        }
+                    }
+                    if json {
+                        print_json(json!(json_wines));
+                    }
+                },
+                WineArgs::Remove { name } => {
+                    let mut wines = read_wines()?;
+                    if !wines.wines.contains_key(&name) {
+                        return Err(loga::err_with("Wine isn't registered", ea!(wine = name)));
+                    }
+                    let bases_dir = root_dir()?.join("basis");
+                    let mut users = vec![];
+                    for basis_name in dir_names(&bases_dir)? {
+                        if let Ok(config) = read_basis_config(&bases_dir.join(&basis_name)) {
+                            if config.wine.as_ref() == Some(&name) {
+                                users.push(basis_name);
+                            }
+                        }
+                    }
+                    if !users.is_empty() {
+                        return Err(
+                            loga::err_with(
+                                "Bases use this wine, change their `wine` first",
+                                ea!(wine = name, bases = users.join(", ")),
+                            ),
+                        );
+                    }
+                    let wine = wines.wines.remove(&name).unwrap();
+                    write_wines(wines)?;
+                    if wine.path.starts_with(installed_wines_dir()?) {
+                        fs::remove_dir_all(
+                            &wine.path,
+                        ).context_with("Error deleting installed wine", ea!(path = wine.path.to_string_lossy()))?;
+                    }
+                },
+            },
+            ArgsCommand::Cache(args) => match args {
+                CacheArgs::Stats => {
+                    let cache_dir = winetricks_cache_dir()?;
+                    let mut verbs = vec![];
+                    let mut total = 0;
+                    if cache_dir.exists() {
+                        for entry in fs::read_dir(
+                            &cache_dir,
+                        ).context_with("Error listing winetricks cache", ea!(path = cache_dir.to_string_lossy()))? {
+                            let entry = entry.context("Error reading winetricks cache entry")?;
+                            let size = disk_usage(&entry.path())?;
+                            total += size;
+                            verbs.push((entry.file_name().to_string_lossy().to_string(), size));
+                        }
+                    }
+                    verbs.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+                    if json {
+                        let json_verbs =
+                            verbs
+                                .iter()
+                                .map(|(name, size)| (name.clone(), json!(size)))
+                                .collect::<serde_json::Map<_, _>>();
+                        print_json(json!({
+                            "path": cache_dir,
+                            "total": total,
+                            "verbs": json_verbs
+                        }));
+                    } else {
+                        println!("{} ({})", cache_dir.to_string_lossy(), format_size(total));
+                        for (name, size) in verbs {
+                            println!("  {}: {}", name, format_size(size));
+                        }
+                    }
+                },
+                CacheArgs::Clear { verbs } => {
+                    let cache_dir = winetricks_cache_dir()?;
+                    if verbs.is_empty() {
+                        if cache_dir.exists() {
+                            fs::remove_dir_all(
+                                &cache_dir,
+                            ).context_with(
+                                "Error deleting winetricks cache",
+                                ea!(path = cache_dir.to_string_lossy()),
+                            )?;
+                        }
+                    } else {
+                        for verb in verbs {
+                            let path = cache_dir.join(&verb.0);
+                            if !path.exists() {
+                                log.log_with(StandardFlag::Info, "Nothing cached for verb", ea!(verb = verb.0));
+                                continue;
+                            }
+                            fs::remove_dir_all(
+                                &path,
+                            ).context_with(
+                                "Error deleting cached downloads for verb",
+                                ea!(path = path.to_string_lossy()),
+                            )?;
+                        }
+                    }
+                },
+                CacheArgs::Prefetch { arch, verbs } => {
+                    if verbs.is_empty() {
+                        return Err(loga::err("No winetricks verbs to prefetch"));
+                    }
+                    let cache_dir = winetricks_cache_dir()?;
+                    let scratch_path = root_dir()?.join("cache").join(format!(".prefetch-{}", std::process::id()));
+                    fs::create_dir_all(
+                        &scratch_path,
+                    ).context_with("Error creating scratch dir", ea!(path = scratch_path.to_string_lossy()))?;
+                    log.log_with(
+                        StandardFlag::Info,
+                        "Downloading winetricks verbs to the cache",
+                        ea!(
+                            cache = cache_dir.to_string_lossy(),
+                            verbs = verbs.iter().map(|v| v.0.as_str()).collect::<Vec<_>>().join(" ")
+                        ),
+                    );
+                    let res =
+                        prefetch_winetricks(
+                            &log,
+                            arch.unwrap_or(Arch::Win64),
+                            &scratch_path,
+                            &verbs.into_iter().map(|v| v.0).collect::<Vec<_>>(),
+                        );
+                    if let Err(e) = fs::remove_dir_all(&scratch_path) {
+                        log.log_with(
+                            StandardFlag::Warning,
+                            "Error deleting scratch dir",
+                            ea!(path = scratch_path.to_string_lossy(), err = e),
+                        );
+                    }
+                    res?;
+                },
+            },
+            ArgsCommand::Apply { manifest } => {
+                apply_manifest(&log, &read_manifest(&manifest)?, wait, update_policy)?;
+            },
+            ArgsCommand::Basis(args) => match args {
+                BasisArgs::Create(args) => {
+                    let _lock = lock_basis(&args.basis_name, LockMode::Exclusive, wait)?;
+                    let basis_path = basis_path(&args.basis_name)?;
+                    let log = log.fork(ea!(path = basis_path.to_string_lossy()));
+                    if basis_path.exists() {
+                        return Err(
+                            log.err("Basis already exists. Delete the directory first if you want to re-create it"),
+                        );
+                    }
+                    if let Some(wine) = &args.wine {
+                        registered_wine(wine)?;
+                    }
+                    let arch = args.arch.unwrap_or(Arch::Win64);
+                    let preset = match &args.preset {
+                        Some(preset_name) => {
+                            let preset = load_preset(preset_name)?;
+                            let preset_arch = preset.for_arch(arch);
+                            eprintln!(
+                                "Preset `{}`: {}\nVerbs: {}\nEstimated download {}MB, disk usage {}MB",
+                                preset_name,
+                                preset.description,
+                                preset_arch.verbs.join(" "),
+                                preset_arch.download_mb,
+                                preset_arch.disk_mb
+                            );
+                            if args.yes.is_none() && !confirm("Continue?")? {
+                                return Err(log.err("Cancelled"));
+                            }
+                            Some(preset)
+                        },
+                        None => None,
+                    };
+                    let mut verbs = vec![];
+                    if let Some(preset) = &preset {
+                        verbs.extend(preset.for_arch(arch).verbs.iter().cloned());
+                    }
+                    verbs.extend(args.winetricks.into_iter().flatten().map(|v| v.0));
+                    let mut registry = vec![];
+                    if let Some(registry_path) = &args.registry {
+                        registry.push(read_registry_file(registry_path)?);
+                    }
+                    let script = match &args.winetricks_script {
+                        Some(script_path) => Some(
+                            fs::read_to_string(
+                                script_path,
+                            ).context_with(
+                                "Error reading winetricks script",
+                                ea!(path = script_path.to_string_lossy()),
+                            )?,
+                        ),
+                        None => None,
+                    };
+                    create_basis(&log, &basis_path, &BasisLatestConfig {
+                        winetricks: verbs,
+                        registry: registry,
+                        script: script,
+                        wine: args.wine,
+                        audio: args.audio.unwrap_or_default(),
+                        ..BasisLatestConfig::new(arch)
+                    })?;
+                },
+                BasisArgs::Check { basis_name } => {
+                    let basis_path = basis_path(&basis_name)?;
+                    let needs_update = basis_needs_update(&basis_path)?;
+                    if json {
+                        let env = wine_envs(&read_basis_config(&basis_path)?, &basis_prefix_path(&basis_path))?;
+                        print_json(json!({
+                            "needs_update": needs_update,
+                            "wine_version": read_basis_wine(&basis_path)?.and_then(|w| w.wine_version),
+                            "current_wine_version": wine_version(&env)
+                        }));
+                    } else {
+                        print!("{}", needs_update);
+                    }
+                },
+                BasisArgs::Update { basis_name } => {
+                    let mut lock = lock_basis(&basis_name, LockMode::Exclusive, wait)?;
+                    check_basis_writable(&basis_name, &basis_path(&basis_name)?)?;
+                    update_basis(&log, &basis_name, &mut lock, update_policy)?;
+                },
+                BasisArgs::Winetricks { basis_name, verbs } => {
+                    if verbs.is_empty() {
+                        return Err(loga::err("No winetricks verbs specified"));
+                    }
+                    let mut lock = lock_basis(&basis_name, LockMode::Exclusive, wait)?;
+                    install_basis_verbs(
+                        &log,
+                        &basis_name,
+                        &mut lock,
+                        update_policy,
+                        verbs.into_iter().map(|v| v.0).collect(),
+                    )?;
+                },
+                BasisArgs::Recreate { basis_name } => {
+                    let _lock = lock_basis(&basis_name, LockMode::Exclusive, wait)?;
+                    let basis_path = basis_path(&basis_name)?;
+                    check_basis_writable(&basis_name, &basis_path)?;
+                    let config = read_basis_config(&basis_path)?;
+                    let mounted = mounted_dependents(&basis_name)?;
+                    if !mounted.is_empty() {
+                        return Err(
+                            loga::err_with(
+                                "Systems using the basis are running, stop them first",
+                                ea!(basis = basis_name, systems = mounted.join(", ")),
+                            ),
+                        );
+                    }
+                    let prefix_path = basis_prefix_path(&basis_path);
+                    let old_path = basis_path.join("prefix.old");
+                    if old_path.exists() {
+                        return Err(
+                            loga::err_with(
+                                "A previous recreate didn't finish, restore or delete the old prefix first",
+                                ea!(path = old_path.to_string_lossy()),
+                            ),
+                        );
+                    }
+                    if prefix_path.exists() {
+                        fs::rename(
+                            &prefix_path,
+                            &old_path,
+                        ).context_with("Error moving old prefix aside", ea!(path = prefix_path.to_string_lossy()))?;
+                    }
+                    match build_basis_prefix(&log, &basis_path, &config) {
+                        Ok(_) => {
+                            if old_path.exists() {
+                                fs::remove_dir_all(
+                                    &old_path,
+                                ).context_with("Error deleting old prefix", ea!(path = old_path.to_string_lossy()))?;
+                            }
+                        },
+                        Err(e) => {
+                            if old_path.exists() {
+                                let env = wine_envs(&config, &prefix_path)?;
+                                _ = Command::new(wineserver_bin(&env)).envs(&env).arg("-k").status();
+                                _ = fs::remove_dir_all(&prefix_path);
+                                fs::rename(
+                                    &old_path,
+                                    &prefix_path,
+                                ).context_with(
+                                    "Rebuilding the prefix failed and the old prefix couldn't be restored",
+                                    ea!(path = old_path.to_string_lossy(), error = e),
+                                )?;
+                            }
+                            return Err(e);
+                        },
+                    }
+                    let shadowed = registry_shadowed_dependents(&basis_name)?;
+                    if !shadowed.is_empty() {
+                        log.log_with(
+                            StandardFlag::Warning,
+                            "These systems have their own registry, so they still have the old prefix's registry",
+                            ea!(systems = shadowed.join(", ")),
+                        );
+                    }
+                },
+                BasisArgs::Dxvk(args) => {
+                    let basis_name = match &args {
+                        BasisDxvkArgs::Install { basis_name, .. } => basis_name.clone(),
+                        BasisDxvkArgs::Remove { basis_name } => basis_name.clone(),
+                    };
+                    let mut lock = lock_basis(&basis_name, LockMode::Exclusive, wait)?;
+                    let (basis_path, mut config) =
+                        prepare_basis_change(&log, &basis_name, &mut lock, update_policy)?;
+                    let prefix_path = basis_prefix_path(&basis_path);
+                    let env = wine_envs(&config, &prefix_path)?;
+                    match args {
+                        BasisDxvkArgs::Install { basis_name: _, version, sha256 } => {
+                            let version = version.unwrap_or_else(|| DEFAULT_DXVK_VERSION.to_string());
+                            let sha256 = fetch_dxvk(&log, &version, sha256.as_deref())?;
+                            let dxvk = BasisDxvk {
+                                version: version,
+                                sha256: sha256,
+                            };
+                            if config.dxvk.is_some() {
+                                remove_dxvk(&env, &prefix_path, config.arch)?;
+                            }
+                            install_dxvk(&env, &prefix_path, config.arch, &dxvk)?;
+                            config.dxvk = Some(dxvk);
+                        },
+                        BasisDxvkArgs::Remove { basis_name: _ } => {
+                            if config.dxvk.is_none() {
+                                return Err(
+                                    loga::err_with("DXVK isn't installed in the basis", ea!(basis = basis_name)),
+                                );
+                            }
+                            remove_dxvk(&env, &prefix_path, config.arch)?;
+
+                            // Restores wine's DLLs
+                            update_prefix(&basis_path, &config)?;
+                            config.dxvk = None;
+                        },
+                    }
+                    write_basis_config(&basis_path, &config)?;
+                    let shadowed = registry_shadowed_dependents(&basis_name)?;
+                    if !shadowed.is_empty() {
+                        log.log_with(
+                            StandardFlag::Warning,
+                            "These systems have their own registry, so they won't see the DLL override changes",
+                            ea!(systems = shadowed.join(", ")),
+                        );
+                    }
+                },
+                BasisArgs::SetAudio { basis_name, driver } => {
+                    let mut lock = lock_basis(&basis_name, LockMode::Exclusive, wait)?;
+                    let (basis_path, mut config) =
+                        prepare_basis_change(&log, &basis_name, &mut lock, update_policy)?;
+                    let prefix_path = basis_prefix_path(&basis_path);
+                    set_audio_driver(&wine_envs(&config, &prefix_path)?, &prefix_path, driver)?;
+                    config.audio = driver;
+                    write_basis_config(&basis_path, &config)?;
+                    let shadowed = registry_shadowed_dependents(&basis_name)?;
+                    if !shadowed.is_empty() {
+                        log.log_with(
+                            StandardFlag::Warning,
+                            "These systems have their own registry, so they won't see the audio driver change",
+                            ea!(systems = shadowed.join(", ")),
+                        );
+                    }
+                },
+                BasisArgs::Shell(args) => {
+                    let mut lock = lock_basis(&args.basis_name, LockMode::Exclusive, wait)?;
+                    let basis_path = basis_path(&args.basis_name)?;
+                    check_basis_writable(&args.basis_name, &basis_path)?;
+                    let basis_config = update_basis(&log, &args.basis_name, &mut lock, update_policy)?;
+                    let log = log.fork(ea!(path = basis_path.to_string_lossy()));
+                    if !basis_path.exists() {
+                        return Err(log.err("Basis doesn't exist"));
+                    }
+                    let prefix_path = basis_prefix_path(&basis_path);
+                    let mut env = wine_envs(&basis_config, &prefix_path)?;
+                    add_arg_envs(&mut env, &args.env);
+                    run_shell(shell_commandline(&env, &prefix_path), args.command)?;
+                },
+                BasisArgs::Localize { basis_name } => {
+                    let _lock = lock_basis(&basis_name, LockMode::Shared, wait)?;
+                    if !basis_path(&basis_name)?.exists() {
+                        return Err(loga::err_with("Basis doesn't exist", ea!(basis = basis_name)));
+                    }
+                    localize_basis(&log, &basis_name)?;
+                },
+                BasisArgs::List => {
+                    let mut roots = vec![(root_dir()?, false)];
+                    roots.extend(extra_roots().iter().map(|r| (r.clone(), true)));
+                    let mut seen = HashSet::new();
+                    let mut json_bases = vec![];
+                    for (root, read_only) in roots {
+                        let bases_dir = root.join("basis");
+                        if !bases_dir.exists() {
+                            continue;
+                        }
+                        let mut names = vec![];
+                        for entry in fs::read_dir(
+                            &bases_dir,
+                        ).context_with("Error listing bases", ea!(path = bases_dir.to_string_lossy()))? {
+                            let entry = entry.context("Error reading bases dir entry")?;
+                            names.push(entry.file_name().to_string_lossy().to_string());
+                        }
+                        names.sort();
+                        for name in names {
+                            let shadowed = !seen.insert(name.clone());
+                            let dxvk =
+                                read_basis_config(&bases_dir.join(&name))
+                                    .ok()
+                                    .and_then(|c| c.dxvk)
+                                    .map(|d| d.version);
+                            if json {
+                                json_bases.push(json!({
+                                    "name": name,
+                                    "root": root,
+                                    "read_only": read_only,
+                                    "shadowed": shadowed,
+                                    "dxvk": dxvk
+                                }));
+                            } else {
+                                let mut notes = vec![root.to_string_lossy().to_string()];
+                                if read_only {
+                                    notes.push("read-only".to_string());
+                                }
+                                if shadowed {
+                                    notes.push("shadowed".to_string());
+                                }
+                                if let Some(dxvk) = dxvk {
+                                    notes.push(format!("dxvk {}", dxvk));
+                                }
+                                println!("{} ({})", name, notes.join(", "));
+                            }
+                        }
+                    }
+                    if json {
+                        print_json(json!(json_bases));
+                    }
+                },
+                BasisArgs::Path { basis_name } => {
+                    let basis_path = basis_path(&basis_name)?;
+                    if json {
+                        print_json(json!({
+                            "path": basis_path
+                        }));
+                    } else {
+                        print!("{}", basis_path.to_string_lossy());
+                    }
+                },
+            },
+            ArgsCommand::System(args) => match args {
+                SystemArgs::Create { basis_name, system_name } => {
+                    create_system(&system_name, &basis_name, wait)?;
+                },
+                SystemArgs::Shell(mut args) => {
+                    let (system_name, _system_lock, system_config) =
+                        load_run_system(
+                            &log,
+                            &global_config,
+                            &args.system_name,
+                            &args.name,
+                            &args.allow_menus,
+                            wait,
+                            dry_run,
+                        )?;
+                    args.system_name = system_name;
+                    let system_path = system_path(&args.system_name)?;
+                    if system_config.allowed_commands.is_some() {
+                        return Err(
+                            loga::err_with(
+                                "This system has a list of allowed commands, shells are disabled",
+                                ea!(system = args.system_name),
+                            ),
+                        );
+                    }
+                    let basis_path = basis_path(&system_config.basis_name)?;
+                    let (basis_config, mount) = if dry_run {
+                        (read_basis_config(&basis_path)?, None)
+                    } else {
+                        let mut basis_lock = lock_basis(&system_config.basis_name, LockMode::Shared, wait)?;
+                        let basis_config =
+                            update_basis(&log, &system_config.basis_name, &mut basis_lock, update_policy)?;
+
+                        // Let the shell handle ctrl+c, and make sure we unmount if terminated
+                        signals::set_handler(Handler::Record);
+                        let mount =
+                            mount_system(
+                                &log,
+                                &select_backend(backend),
+                                &basis_path,
+                                &args.system_name,
+                                &system_path,
+                                &system_config,
+                            )?;
+                        (basis_config, Some((basis_lock, mount)))
+                    };
+                    let mount_path = system_mount_path(&system_path);
+                    let mut env =
+                        system_wine_envs(&basis_config, &system_config, &system_path, &mount_path, if dry_run {
+                            SecretEnv::Mask
+                        } else {
+                            SecretEnv::Resolve
+                        })?;
+                    if args.no_fsync.is_some() {
+                        SyncConfig::disabled().apply(&mut env, &BTreeMap::new());
+                    }
+                    if let Some(resolution) = virtual_desktop(&args.virtual_desktop, &system_config)? {
+                        env.insert("WINEBASIN_VIRTUAL_DESKTOP".into(), virtual_desktop_name(resolution).into());
+                    }
+                    add_arg_envs(&mut env, &args.env);
+                    let mut commandline =
+                        wrap_command(
+                            &priority_wrapper(&system_config, &args.cpus, args.nice, args.ionice)?,
+                            shell_commandline(&env, &mount_path),
+                        );
+                    if use_wayland(&log, &system_config, &args.wayland, &args.no_wayland) {
+                        commandline.env_remove("DISPLAY");
+                    }
+                    if dry_run {
+                        if !args.command.is_empty() {
+                            // Actually sent on stdin
+                            commandline.arg("-c").arg(OsStr::from_bytes(&quote_subcommand(args.command.iter().map(|x| x.as_bytes()))?));
+                        }
+                        print_dry_run(
+                            backend,
+                            &args.system_name,
+                            &system_path,
+                            &system_config.basis_name,
+                            &[commandline],
+                            &[],
+                        )?;
+                    } else {
+                        let res = run_shell(commandline, args.command);
+                        drop(mount);
+                        check_signal()?;
+                        res?;
+                    }
+                },
+                SystemArgs::SetAudio { system_name, driver } => {
+                    let _system_lock = lock_system(&system_name, LockMode::Shared, wait)?;
+                    let system_path = system_path(&system_name)?;
+                    let mut system_config = check_system(&system_path)?;
+                    let mut basis_lock = lock_basis(&system_config.basis_name, LockMode::Shared, wait)?;
+                    let basis_config = update_basis(&log, &system_config.basis_name, &mut basis_lock, update_policy)?;
+                    let basis_path = basis_path(&system_config.basis_name)?;
+
+                    // Make sure we unmount if terminated
+                    signals::set_handler(Handler::Record);
+                    let (mount, mount_path) =
+                        mount_system(
+                            &log,
+                            &select_backend(backend),
+                            &basis_path,
+                            &system_name,
+                            &system_path,
+                            &system_config,
+                        )?;
+                    let res =
+                        set_audio_driver(
+                            &system_wine_envs(
+                                &basis_config,
+                                &system_config,
+                                &system_path,
+                                &mount_path,
+                                SecretEnv::Omit,
+                            )?,
+                            &mount_path,
+                            driver,
+                        );
+                    drop(mount);
+                    check_signal()?;
+                    res?;
+                    system_config.audio = driver;
+                    write_system_config(&system_path, system_config)?;
+                },
+                SystemArgs::Winetricks { system_name, verbs } => {
+                    if verbs.is_empty() {
+                        return Err(loga::err("No winetricks verbs specified"));
+                    }
+                    let _system_lock = lock_system(&system_name, LockMode::Shared, wait)?;
+                    let system_path = system_path(&system_name)?;
                     let system_config = check_system(&system_path)?;
+                    if system_config.allowed_commands.is_some() {
+                        return Err(
+                            loga::err_with(
+                                "This system has a list of allowed commands, winetricks is disabled",
+                                ea!(system = system_name),
+                            ),
+                        );
+                    }
+                    let mut basis_lock = lock_basis(&system_config.basis_name, LockMode::Shared, wait)?;
+                    let basis_config = update_basis(&log, &system_config.basis_name, &mut basis_lock, update_policy)?;
                     let basis_path = basis_path(&system_config.basis_name)?;
-                    let basis_config = update_basis(&basis_path)?;
-                    let (_mount, mount_path) = mount_prefix(&log, &basis_path, &system_path)?;
-                    run_shell(&basis_config, &mount_path, args.command)?;
+
+                    // Make sure we unmount if terminated
+                    signals::set_handler(Handler::Record);
+                    let (mount, mount_path) =
+                        mount_system(
+                            &log,
+                            &select_backend(backend),
+                            &basis_path,
+                            &system_name,
+                            &system_path,
+                            &system_config,
+                        )?;
+                    let verbs = verbs.into_iter().map(|v| v.0).collect::<Vec<_>>();
+                    let res =
+                        run_winetricks(
+                            &log,
+                            &system_wine_envs(
+                                &basis_config,
+                                &system_config,
+                                &system_path,
+                                &mount_path,
+                                SecretEnv::Resolve,
+                            )?,
+                            &verbs,
+                        );
+                    drop(mount);
+                    check_signal()?;
+                    res?;
+                },
+                SystemArgs::Run(mut args) if dry_run => {
+                    let (system_name, _, system_config) =
+                        load_run_system(
+                            &log,
+                            &global_config,
+                            &args.system_name,
+                            &args.name,
+                            &args.allow_menus,
+                            wait,
+                            true,
+                        )?;
+                    args.system_name = system_name;
+                    let system_path = system_path(&args.system_name)?;
+                    check_command_allowed(
+                        &args.system_name,
+                        &system_config,
+                        args.command.first().context("Command line to run in system is empty")?,
+                    )?;
+                    check_env_allowed(&args.system_name, &system_config, &args.env)?;
+                    let wrappers = RunWrappers::new(&args, &system_config)?;
+                    let basis_config = read_basis_config(&basis_path(&system_config.basis_name)?)?;
+                    let mount_path = system_mount_path(&system_path);
+                    let env =
+                        run_envs(
+                            &global_config,
+                            &args,
+                            &basis_config,
+                            &system_config,
+                            &system_path,
+                            &mount_path,
+                            SecretEnv::Mask,
+                        )?;
+                    let mut commands = vec![];
+                    let (mut wine_command, _) =
+                        wrappers.wrap(
+                            &env,
+                            wine_run_command(
+                                &env,
+                                &mount_path.join("drive_c"),
+                                args.working_dir.as_deref(),
+                                virtual_desktop(&args.virtual_desktop, &system_config)?,
+                                args.command.split_off(0),
+                            )?,
+                            false,
+                        )?;
+                    if let Some(resolution) = &args.nested_x {
+                        commands.push(
+                            nested_x_command(
+                                args.nested_x_server.unwrap_or_else(NestedXServer::default_for_session),
+                                resolution,
+                            )?,
+                        );
+                        wine_command.env("DISPLAY", ":<nested X display>").env_remove("WAYLAND_DISPLAY");
+                    } else if use_wayland(&log, &system_config, &args.wayland, &args.no_wayland) {
+                        wine_command.env_remove("DISPLAY");
+                    }
+                    commands.push(wine_command);
+                    let mut wineserver_wait = Command::new(wineserver_bin(&env));
+                    wineserver_wait.envs(&env).arg("-w");
+                    commands.push(wineserver_wait);
+                    let mut notes = vec![];
+                    if let Err(e) = wrappers.check() {
+                        notes.push(format!("This would fail before mounting: {}", e));
+                    }
+                    if wrappers.landlock.is_some() {
+                        notes.push("The program would be confined with Landlock when it starts".to_string());
+                    }
+                    print_dry_run(
+                        backend,
+                        &args.system_name,
+                        &system_path,
+                        &system_config.basis_name,
+                        &commands,
+                        &notes,
+                    )?;
+                },
+                SystemArgs::Run(mut args) if args.detach.is_some() && !detached => {
+                    let system_name =
+                        resolve_system_name(&log, &global_config, &args.system_name, &args.name, wait, false)?;
+                    let created_name = if args.name.is_none() && system_name != args.system_name {
+                        Some(system_name.clone())
+                    } else {
+                        None
+                    };
+                    args.system_name = system_name;
+                    let system_config = check_system(&system_path(&args.system_name)?)?;
+                    check_command_allowed(
+                        &args.system_name,
+                        &system_config,
+                        args.command.first().context("Command line to run in system is empty")?,
+                    )?;
+                    check_env_allowed(&args.system_name, &system_config, &args.env)?;
+                    if select_backend(backend).backend.uses_sudo() {
+                        // The background process can't prompt for a password, cache credentials now
+                        Command::new("sudo").arg("--validate").run()?;
+                    }
+                    let runs_dir = run_states_dir()?;
+                    create_dir_all(
+                        &runs_dir,
+                    ).context_with("Error creating runtime state dir", ea!(path = runs_dir.to_string_lossy()))?;
+                    let log_path =
+                        runs_dir.join(format!("{}-{}-{}.log", args.system_name, now_secs(), std::process::id()));
+                    let log_file =
+                        File::create(
+                            &log_path,
+                        ).context_with("Error creating detached run log", ea!(path = log_path.to_string_lossy()))?;
+
+                    // Re-run the same (alias expanded) command line in the background. If a system
+                    // was created, pass its name in place of `--detach` so the background process
+                    // uses it rather than creating another.
+                    let mut removed_detach = false;
+                    let mut child =
+                        Command::new(current_exe().context("Error determining path to winebasin executable")?);
+                    child
+                        .args(expanded_args.iter().flat_map(|a| {
+                            if !removed_detach && a == "--detach" {
+                                removed_detach = true;
+                                return created_name
+                                    .iter()
+                                    .flat_map(|n| [OsString::from("--name"), OsString::from(n)])
+                                    .collect::<Vec<_>>();
+                            }
+                            return vec![OsString::from(a)];
+                        }))
+                        .env(DETACHED_ENV, "1")
+                        .process_group(0)
+                        .stdin(Stdio::null())
+                        .stdout(log_file.try_clone().context("Error duplicating detached run log handle")?)
+                        .stderr(log_file);
+                    echo_command(&child);
+                    let child = child.spawn().context("Error starting background winebasin process")?;
+                    write_run_state(&RunState {
+                        system_name: args.system_name.clone(),
+                        pid: child.id(),
+                        command: args.command.split_off(0),
+                        log_path: log_path.clone(),
+                        started: now_secs(),
+                    })?;
+                    log.log_with(
+                        StandardFlag::Info,
+                        "Started in background, use `winebasin system stop` to stop",
+                        ea!(system = args.system_name, pid = child.id(), log = log_path.to_string_lossy()),
+                    );
                 },
                 SystemArgs::Run(mut args) => {
+                    let mut profile = StartupProfile::new(start);
+                    let (system_name, _system_lock, system_config) =
+                        load_run_system(
+                            &log,
+                            &global_config,
+                            &args.system_name,
+                            &args.name,
+                            &args.allow_menus,
+                            wait,
+                            false,
+                        )?;
+                    args.system_name = system_name;
                     let system_path = system_path(&args.system_name)?;
+                    check_command_allowed(
+                        &args.system_name,
+                        &system_config,
+                        args.command.first().context("Command line to run in system is empty")?,
+                    )?;
+                    check_env_allowed(&args.system_name, &system_config, &args.env)?;
+                    let wrappers = RunWrappers::new(&args, &system_config)?;
+                    wrappers.check()?;
+                    profile.mark("config load");
+                    let mut basis_lock = lock_basis(&system_config.basis_name, LockMode::Shared, wait)?;
+                    profile.mark("basis lock");
+                    let basis_config = update_basis(&log, &system_config.basis_name, &mut basis_lock, update_policy)?;
+                    let basis_path = basis_path(&system_config.basis_name)?;
+                    check_basis_wine(
+                        &log,
+                        &system_config.basis_name,
+                        &basis_path,
+                        &wine_envs(&basis_config, &basis_prefix_path(&basis_path))?,
+                        args.allow_wine_mismatch.is_some(),
+                    )?;
+                    profile.mark("update check");
+                    let backend = select_backend(backend);
+                    if args.profile_startup.is_some() && backend.backend.uses_sudo() {
+                        // Separate authentication from mounting
+                        Command::new("sudo").arg("--validate").run()?;
+                        profile.mark("sudo auth");
+                    }
+                    signals::set_handler(Handler::Record);
+                    let (mount, mount_path) =
+                        mount_system(&log, &backend, &basis_path, &args.system_name, &system_path, &system_config)?;
+                    profile.mark("mount");
+                    let env =
+                        run_envs(
+                            &global_config,
+                            &args,
+                            &basis_config,
+                            &system_config,
+                            &system_path,
+                            &mount_path,
+                            SecretEnv::Resolve,
+                        )?;
+                    let history_command = args.command.clone();
+                    let started = now_secs();
+                    let res = (|| -> Result<ExitStatus, loga::Error> {
+                        // Stopped after everything in the prefix has exited
+                        let nested_x = match &args.nested_x {
+                            Some(resolution) => Some(
+                                NestedX::start(
+                                    &log,
+                                    args.nested_x_server.unwrap_or_else(NestedXServer::default_for_session),
+                                    resolution,
+                                )?,
+                            ),
+                            None => None,
+                        };
+                        if nested_x.is_none() {
+                            if let Err(missing) = capabilities().check(Capability::Display) {
+                                log.log_with(
+                                    StandardFlag::Info,
+                                    "No display, programs that open windows will fail",
+                                    ea!(missing = missing),
+                                );
+                            }
+                        }
+                        let _window_tagger = if !system_config.tag_windows {
+                            None
+                        } else if let Err(missing) = capabilities().check(Capability::Xdotool) {
+                            log.log_with(
+                                StandardFlag::Warning,
+                                "The system config has `tag_windows` set but window tagging is unavailable, windows won't be tagged",
+                                ea!(missing = missing),
+                            );
+                            None
+                        } else {
+                            Some(
+                                WindowTagger::start(
+                                    &log,
+                                    &args.system_name,
+                                    &mount_path,
+                                    nested_x.as_ref().map(|n| n.display.clone()),
+                                ),
+                            )
+                        };
+                        if let Some(AssumeAddons::Yes) = args.assume_addons {
+                            install_cached_addons(&log, &env, &mount_path, basis_config.arch)?;
+                        }
+                        if args.profile_startup.is_some() {
+                            // Returns once the server is ready (or immediately if it's already running)
+                            Command::new(wineserver_bin(&env)).envs(&env).run()?;
+                            profile.mark("wineserver start");
+                        }
+                        let (mut command, _landlock) =
+                            wrappers.wrap(
+                                &env,
+                                wine_run_command(
+                                    &env,
+                                    &mount_path.join("drive_c"),
+                                    args.working_dir.as_deref(),
+                                    virtual_desktop(&args.virtual_desktop, &system_config)?,
+                                    args.command.split_off(0),
+                                )?,
+                                true,
+                            )?;
+                        if let Some(nested_x) = &nested_x {
+                            command.env("DISPLAY", &nested_x.display).env_remove("WAYLAND_DISPLAY");
+                        } else if use_wayland(&log, &system_config, &args.wayland, &args.no_wayland) {
+                            command.env_remove("DISPLAY");
+                        }
+                        let mut log_file = None;
+                        if args.log.is_some() {
+                            let logs_dir = system_path.join("run_logs");
+                            create_dir_all(
+                                &logs_dir,
+                            ).context_with("Error creating run log dir", ea!(path = logs_dir.to_string_lossy()))?;
+                            let log_path = logs_dir.join(format!("{}-{}.log", now_secs(), std::process::id()));
+                            log_file =
+                                Some(
+                                    File::create(
+                                        &log_path,
+                                    ).context_with("Error creating run log", ea!(path = log_path.to_string_lossy()))?,
+                                );
+                            log.log_with(
+                                StandardFlag::Info,
+                                "Writing wine output to log",
+                                ea!(path = log_path.to_string_lossy()),
+                            );
+                            command.stderr(Stdio::piped());
+                        }
+                        let command_log = log.fork(ea!(command = mask_secrets(command.dbg_str())));
+                        echo_command(&command);
+                        let mut child = command.spawn().stack_context(&command_log, "Failed to spawn command")?;
+                        if let Some(log_file) = log_file {
+                            // Not joined, programs started by the command may keep writing until the prefix
+                            // is stopped
+                            let stderr = child.stderr.take().unwrap();
+                            let log = command_log.clone();
+                            thread::spawn(move || tee_to_log(&log, stderr, log_file));
+                        }
+                        profile.mark("process exec");
+                        if args.profile_startup.is_some() {
+                            profile.print();
+                        }
+
+                        // Forward termination to wine and stop everything else running in the prefix
+                        signals::set_handler(Handler::Call(Box::new({
+                            let pid = child.id();
+                            let wineserver = wineserver_bin(&env);
+                            let env =
+                                env
+                                    .iter()
+                                    .map(|(k, v)| (k.to_os_string(), v.clone()))
+                                    .collect::<Vec<(OsString, OsString)>>();
+                            move |sig| {
+                                signals::kill(pid, sig);
+                                _ = Command::new(&wineserver).envs(env.clone()).arg("-k").status();
+                            }
+                        })));
+                        let status = child.wait().stack_context(&command_log, "Error running command");
+                        signals::set_handler(Handler::Record);
+                        let status = status?;
+                        if signals::received().is_none() {
+                            wait_wineserver(
+                                &log,
+                                &env,
+                                args
+                                    .wineserver_timeout
+                                    .or(global_config.wineserver_timeout_secs)
+                                    .map(Duration::from_secs),
+                            )?;
+                        }
+                        return Ok(status);
+                    })();
+                    if let Ok(status) = &res {
+                        if let Err(e) = append_history(&system_path, HistoryLatestEntry {
+                            command: history_command,
+                            started: started,
+                            ended: now_secs(),
+                            exit_code: status.code(),
+                            signal: status.signal(),
+                            wine_version: wine_version(&env),
+                        }) {
+                            log.log_err(StandardFlag::Warning, e);
+                        }
+                    }
+                    drop(mount);
+                    check_signal()?;
+                    let status = res?;
+                    if !status.success() {
+                        exit(status.code().or(status.signal().map(|s| 128 + s)).unwrap_or(ERROR_EXIT_CODE));
+                    }
+                },
+                SystemArgs::Stop { system_name } => {
+                    let runs =
+                        list_run_states()?
+                            .into_iter()
+                            .filter(|r| r.system_name == system_name)
+                            .collect::<Vec<_>>();
+                    if runs.is_empty() {
+                        return Err(loga::err_with("System has no detached runs", ea!(system = system_name)));
+                    }
+
+                    // The background process kills the wine session and unmounts when terminated
+                    for run in &runs {
+                        if pid_alive(run.pid) {
+                            signals::kill(run.pid, libc::SIGTERM);
+                        }
+                    }
+                    let deadline = Instant::now() + STOP_TIMEOUT;
+                    for run in runs {
+                        while pid_alive(run.pid) && Instant::now() < deadline {
+                            sleep(Duration::from_millis(200));
+                        }
+                        if pid_alive(run.pid) {
+                            // Started in its own process group, this also gets the programs it ran
+                            signals::kill_group(run.pid, libc::SIGKILL);
+                            log.log_with(
+                                StandardFlag::Warning,
+                                "Background run didn't stop in time and was killed, run `winebasin cleanup` if the system is still mounted",
+                                ea!(system = run.system_name, pid = run.pid, command = run.command.join(" ")),
+                            );
+                            while pid_alive(run.pid) {
+                                sleep(Duration::from_millis(200));
+                            }
+                        }
+                        remove_run_state(&run)?;
+                    }
+                },
+                SystemArgs::Server { system_name } => {
+                    let _system_lock = lock_system(&system_name, LockMode::Shared, wait)?;
+                    let system_path = system_path(&system_name)?;
                     let system_config = check_system(&system_path)?;
+                    let server_config =
+                        system_config
+                            .server
+                            .as_ref()
+                            .context_with("System config has no server settings", ea!(system = system_name))?;
+                    let mut basis_lock = lock_basis(&system_config.basis_name, LockMode::Shared, wait)?;
+                    let basis_config = update_basis(&log, &system_config.basis_name, &mut basis_lock, update_policy)?;
                     let basis_path = basis_path(&system_config.basis_name)?;
-                    let basis_config = update_basis(&basis_path)?;
-                    let (_mount, mount_path) = mount_prefix(&log, &basis_path, &system_path)?;
-                    let drive_c_path = mount_path.join("drive_c");
-                    let command_args = args.command.split_off(1);
-                    let command_command =
-                        drive_c_path.join(args.command.pop().context("Command line to run in system is empty")?);
-                    let env = wine_envs(&basis_config, &mount_path);
-                    Command::new(wine_bin())
-                        .envs(&env)
-                        .current_dir(
-                            &args
-                                .working_dir
-                                .as_ref()
-                                .map(|x| x.as_path())
-                                .or(command_command.parent())
-                                .unwrap_or(drive_c_path.as_path()),
-                        )
-                        .arg(command_command)
-                        .args(command_args)
-                        .run()?;
-                    Command::new(wineserver_bin()).envs(&env).arg("-w").run()?;
+                    signals::set_handler(Handler::Record);
+                    let (mount, mount_path) =
+                        mount_system(
+                            &log,
+                            &select_backend(backend),
+                            &basis_path,
+                            &system_name,
+                            &system_path,
+                            &system_config,
+                        )?;
+                    let res =
+                        run_server(
+                            &log,
+                            &system_name,
+                            server_config,
+                            &system_wine_envs(
+                                &basis_config,
+                                &system_config,
+                                &system_path,
+                                &mount_path,
+                                SecretEnv::Resolve,
+                            )?,
+                            &mount_path,
+                            &system_path.join("server_logs"),
+                        );
+                    drop(mount);
+                    check_signal()?;
+                    res?;
+                },
+                SystemArgs::App(args) => match args {
+                    SystemAppArgs::Add(args) => {
+                        let system_path = system_path(&args.system_name)?;
+                        let mut system_config = check_system(&system_path)?;
+                        check_command_allowed(&args.system_name, &system_config, &args.exe)?;
+                        system_config.apps.insert(args.alias, AppConfig {
+                            exe: args.exe,
+                            args: args.args.unwrap_or_default(),
+                        });
+                        write_system_config(&system_path, system_config)?;
+                    },
+                    SystemAppArgs::Remove { system_name, alias } => {
+                        let system_path = system_path(&system_name)?;
+                        let mut system_config = check_system(&system_path)?;
+                        if system_config.apps.remove(&alias).is_none() {
+                            return Err(
+                                loga::err_with("No app with this name", ea!(system = system_name, alias = alias)),
+                            );
+                        }
+                        write_system_config(&system_path, system_config)?;
+                    },
+                    SystemAppArgs::List { system_name } => {
+                        let system_config = check_system(&system_path(&system_name)?)?;
+                        if json {
+                            print_json(json!(system_config.apps.iter().map(|(alias, app)| json!({
+                                "alias": alias,
+                                "exe": app.exe,
+                                "args": app.args,
+                            })).collect::<Vec<_>>()));
+                        } else {
+                            for (alias, app) in &system_config.apps {
+                                let mut command = vec![app.exe.as_bytes()];
+                                command.extend(app.args.iter().map(|a| a.as_bytes()));
+                                println!(
+                                    "{}: {}",
+                                    alias,
+                                    String::from_utf8_lossy(&quote_subcommand(command.into_iter())?)
+                                );
+                            }
+                        }
+                    },
+                    SystemAppArgs::Run(_) => unreachable!(),
+                },
+                SystemArgs::List => {
+                    let mut json_systems = vec![];
+                    for name in system_names()? {
+                        match check_system(&system_path(&name)?) {
+                            Ok(config) => {
+                                if json {
+                                    json_systems.push(json!({
+                                        "name": name,
+                                        "basis": config.basis_name
+                                    }));
+                                } else {
+                                    println!("{} (basis {})", name, config.basis_name);
+                                }
+                            },
+                            Err(e) => {
+                                if json {
+                                    json_systems.push(json!({
+                                        "name": name,
+                                        "broken": e.to_string()
+                                    }));
+                                } else {
+                                    println!(
+                                        "{} - broken, fix with `winebasin system repair {} BASIS` or ignore it: {}",
+                                        name,
+                                        name,
+                                        e
+                                    );
+                                }
+                            },
+                        }
+                    }
+                    if json {
+                        print_json(json!(json_systems));
+                    }
+                },
+                SystemArgs::Repair { system_name, basis_name } => {
+                    let _lock = lock_system(&system_name, LockMode::Exclusive, wait)?;
+                    let system_path = system_path(&system_name)?;
+                    if !system_path.exists() {
+                        return Err(loga::err_with("System doesn't exist", ea!(system = system_name)));
+                    }
+                    if check_system(&system_path).is_ok() {
+                        return Err(loga::err_with("System config isn't broken", ea!(system = system_name)));
+                    }
+                    let config_path = system_config_path(&system_path);
+                    if config_path.exists() {
+                        let broken_path = system_path.join("config.json.broken");
+                        fs::rename(
+                            &config_path,
+                            &broken_path,
+                        ).context_with(
+                            "Error moving broken system config aside",
+                            ea!(path = config_path.to_string_lossy()),
+                        )?;
+                        log.log_with(
+                            StandardFlag::Info,
+                            "Moved broken config aside",
+                            ea!(path = broken_path.to_string_lossy()),
+                        );
+                    }
+                    write_system_config(&system_path, SystemLatestConfig::new(&basis_name))?;
+                },
+                SystemArgs::Ps { system_name } => {
+                    let system_path = system_path(&system_name)?;
+                    check_system(&system_path)?;
+                    let processes = prefix_processes(&system_mount_path(&system_path))?;
+                    if json {
+                        print_json(json!(processes));
+                    } else {
+                        for process in processes {
+                            println!("{} {}", process.pid, process.cmdline.join(" "));
+                        }
+                    }
+                },
+                SystemArgs::Kill { system_name, hard } => {
+                    let system_path = system_path(&system_name)?;
+                    check_system(&system_path)?;
+                    let mount_path = system_mount_path(&system_path);
+                    if !is_mounted(&mount_path)? {
+                        return Err(loga::err_with("System isn't running", ea!(system = system_name)));
+                    }
+                    let env = mounted_system_envs(&system_path)?;
+                    Command::new(wineserver_bin(&env)).envs(&env).arg(if hard.is_some() {
+                        "-k9"
+                    } else {
+                        "-k"
+                    }).run()?;
+                },
+                SystemArgs::Health { system_name, fix } => {
+                    let _lock = if fix.is_some() {
+                        Some(lock_system(&system_name, LockMode::Exclusive, wait)?)
+                    } else {
+                        None
+                    };
+                    let system_path = system_path(&system_name)?;
+                    let system_config = check_system(&system_path)?;
+                    let basis_path = basis_path(&system_config.basis_name)?;
+                    let mut issues =
+                        check_health(&system_name, &system_path, &system_config.basis_name, &basis_path)?;
+                    if fix.is_some() && issues.iter().any(|i| i.fix.is_some()) {
+                        if is_mounted(&system_mount_path(&system_path))? {
+                            return Err(
+                                loga::err_with(
+                                    "Can't repair a mounted system, stop it first",
+                                    ea!(system = system_name),
+                                ),
+                            );
+                        }
+                        for issue in &issues {
+                            if let Some(f) = &issue.fix {
+                                apply_fix(f)?;
+                                log.log_with(StandardFlag::Info, "Repaired", ea!(problem = issue.problem));
+                            }
+                        }
+                        issues = check_health(&system_name, &system_path, &system_config.basis_name, &basis_path)?;
+                    }
+                    let score = health_score(&issues);
+                    if json {
+                        print_json(json!({
+                            "score": score,
+                            "issues": issues
+                        }));
+                        return Ok(());
+                    }
+                    println!("Health: {}/100", score);
+                    if issues.is_empty() {
+                        println!("No problems found");
+                    }
+                    for issue in &issues {
+                        let severity = match issue.severity {
+                            Severity::Info => "note",
+                            Severity::Warning => "warning",
+                            Severity::Error => "error",
+                        };
+                        println!("- {}: {}", severity, issue.problem);
+                        if issue.fix.is_some() {
+                            println!("  {} (or run with `--fix`)", issue.suggestion);
+                        } else {
+                            println!("  {}", issue.suggestion);
+                        }
+                    }
+                },
+                SystemArgs::Grep { pattern, system, ignore_case } => {
+                    let matcher = Matcher {
+                        pattern: pattern,
+                        ignore_case: ignore_case.is_some(),
+                    };
+                    let names_given = system.is_some();
+                    let names = match system {
+                        Some(name) => vec![name],
+                        None => dir_names(&root_dir()?.join("system"))?,
+                    };
+                    let mut json_matches = vec![];
+                    for name in names {
+                        let system_path = system_path(&name)?;
+                        let res = check_system(&system_path).and_then(|config| {
+                            return grep_system(&log, &matcher, &system_path, &basis_path(&config.basis_name)?);
+                        });
+                        let matches = match res {
+                            Ok(m) => m,
+                            Err(e) => {
+                                if names_given {
+                                    return Err(e);
+                                }
+                                log.log_with(
+                                    StandardFlag::Warning,
+                                    "Can't search system, skipping",
+                                    ea!(system = name, err = e),
+                                );
+                                continue;
+                            },
+                        };
+                        for m in matches {
+                            if json {
+                                json_matches.push(json!({
+                                    "system": name,
+                                    "path": m.path,
+                                    "line": m.line,
+                                    "text": m.text
+                                }));
+                            } else {
+                                match (m.line, m.text) {
+                                    (Some(line), Some(text)) => println!(
+                                        "{}:{}:{}: {}",
+                                        name,
+                                        m.path.to_string_lossy(),
+                                        line,
+                                        text
+                                    ),
+                                    _ => println!("{}:{}: binary file matches", name, m.path.to_string_lossy()),
+                                }
+                            }
+                        }
+                    }
+                    if json {
+                        print_json(json!(json_matches));
+                    }
+                },
+                SystemArgs::Apps { system_name } => {
+                    let programs = system_installed_programs(&system_name)?;
+                    if json {
+                        print_json(json!(programs));
+                    } else {
+                        for program in programs {
+                            match &program.version {
+                                Some(version) => println!("{} {} ({})", program.name, version, program.id),
+                                None => println!("{} ({})", program.name, program.id),
+                            }
+                            if let Some(location) = &program.install_location {
+                                println!("  Location: {}", location);
+                            }
+                            if let Some(uninstall) = &program.uninstall_string {
+                                println!("  Uninstall: {}", uninstall);
+                            }
+                        }
+                    }
+                },
+                SystemArgs::Uninstall { .. } => unreachable!(),
+                SystemArgs::Regdiff { system_name, against } => {
+                    let other_view = match against.unwrap_or(RegdiffAgainst::Basis) {
+                        RegdiffAgainst::Basis => None,
+                        RegdiffAgainst::System(other_name) => {
+                            let other_path = system_path(&other_name)?;
+                            let other_config = check_system(&other_path)?;
+                            Some(PrefixView {
+                                upper: system_prefix_path(&other_path),
+                                lower: basis_prefix_path(&basis_path(&other_config.basis_name)?),
+                            })
+                        },
+                    };
+                    let system_path = system_path(&system_name)?;
+                    let system_config = check_system(&system_path)?;
+                    let view = PrefixView {
+                        upper: system_prefix_path(&system_path),
+                        lower: basis_prefix_path(&basis_path(&system_config.basis_name)?),
+                    };
+
+                    // The basis alone, as a view with nothing on top
+                    let against_view = other_view.unwrap_or_else(|| PrefixView {
+                        upper: view.lower.clone(),
+                        lower: view.lower.clone(),
+                    });
+                    let mut json_changes = serde_json::Map::new();
+                    for file in REGISTRY_FILES {
+                        let old = read_registry_tree(against_view.resolve(Path::new(file)).as_deref())?;
+                        let new = read_registry_tree(view.resolve(Path::new(file)).as_deref())?;
+                        let changes = diff_registry(&old, &new);
+                        if changes.is_empty() {
+                            continue;
+                        }
+                        if json {
+                            json_changes.insert(file.to_string(), json!(changes));
+                            continue;
+                        }
+                        println!("{}", file);
+                        let mut last_key = None;
+                        for change in changes {
+                            let symbol = match change.kind {
+                                RegistryChangeKind::Added => "+",
+                                RegistryChangeKind::Removed => "-",
+                                RegistryChangeKind::Changed => "~",
+                            };
+                            let Some(name) = &change.name else {
+                                println!("{} [{}]", symbol, change.key);
+                                last_key = Some(change.key);
+                                continue;
+                            };
+                            if last_key.as_ref() != Some(&change.key) {
+                                println!("  [{}]", change.key);
+                                last_key = Some(change.key.clone());
+                            }
+                            match (&change.old, &change.new) {
+                                (Some(old), Some(new)) => println!("{}   {} = {} -> {}", symbol, name, old, new),
+                                (Some(value), None) | (None, Some(value)) => println!(
+                                    "{}   {} = {}",
+                                    symbol,
+                                    name,
+                                    value
+                                ),
+                                (None, None) => { },
+                            }
+                        }
+                    }
+                    if json {
+                        print_json(serde_json::Value::Object(json_changes));
+                    }
+                },
+                SystemArgs::History { system_name, last } => {
+                    let system_path = system_path(&system_name)?;
+                    check_system(&system_path)?;
+                    let mut history = read_history(&system_path)?;
+                    if let Some(last) = last {
+                        history = history.split_off(history.len().saturating_sub(last));
+                    }
+                    if json {
+                        print_json(json!(history));
+                        return Ok(());
+                    }
+                    if history.is_empty() {
+                        println!("No runs recorded");
+                    }
+                    for entry in history {
+                        let exit = match (entry.exit_code, entry.signal) {
+                            (Some(code), _) => format!("exit {}", code),
+                            (None, Some(signal)) => format!("signal {}", signal),
+                            (None, None) => "unknown exit".to_string(),
+                        };
+                        println!(
+                            "{} UTC ({}, {}, {}) {}",
+                            format_time(entry.started),
+                            format_duration(entry.ended.saturating_sub(entry.started)),
+                            exit,
+                            entry.wine_version.as_deref().unwrap_or("unknown wine version"),
+                            String::from_utf8_lossy(&quote_subcommand(entry.command.iter().map(|x| x.as_bytes()))?)
+                        );
+                    }
+                },
+                #[cfg(feature = "systemd")]
+                SystemArgs::Service(args) => match args {
+                    ServiceArgs::Install(args) => {
+                        capabilities::require(Capability::Systemd, "`system service install`")?;
+                        let system_config = check_system(&system_path(&args.system_name)?)?;
+                        let mut winebasin_args = vec!["system"];
+                        match args.command.first() {
+                            Some(command) => {
+                                check_command_allowed(&args.system_name, &system_config, command)?;
+                                winebasin_args.extend(["run", &args.system_name]);
+                                winebasin_args.extend(args.command.iter().map(|x| x.as_str()));
+                            },
+                            None => {
+                                if system_config.server.is_none() {
+                                    return Err(
+                                        loga::err_with(
+                                            "No command specified and the system config has no server settings",
+                                            ea!(system = args.system_name),
+                                        ),
+                                    );
+                                }
+                                winebasin_args.extend(["server", &args.system_name]);
+                            },
+                        }
+                        let backend = select_backend(backend).backend;
+                        if backend.uses_sudo() {
+                            log.log(
+                                StandardFlag::Warning,
+                                "The service will mount with sudo, which can't prompt for a password in a service - configure passwordless sudo or install fuse-overlayfs and re-install the service",
+                            );
+                        }
+                        let path = service_path(&args.system_name)?;
+                        let dir = path.parent().unwrap();
+                        create_dir_all(
+                            dir,
+                        ).context_with("Error creating systemd user unit dir", ea!(path = dir.to_string_lossy()))?;
+                        fs::write(
+                            &path,
+                            service_unit(&args.system_name, backend, &winebasin_args)?,
+                        ).context_with("Error writing systemd unit", ea!(path = path.to_string_lossy()))?;
+                        Command::new("systemctl").arg("--user").arg("daemon-reload").run()?;
+                        Command::new("systemctl")
+                            .arg("--user")
+                            .arg("enable")
+                            .arg("--now")
+                            .arg(service_name(&args.system_name))
+                            .run()?;
+                    },
+                    ServiceArgs::Status { system_name } => {
+                        capabilities::require(Capability::Systemd, "`system service status`")?;
+
+                        // Exits with an error if the service isn't running, which isn't a winebasin error
+                        _ =
+                            Command::new("systemctl")
+                                .arg("--user")
+                                .arg("status")
+                                .arg(service_name(&system_name))
+                                .status()
+                                .context("Error running systemctl")?;
+                    },
+                    ServiceArgs::Remove { system_name } => {
+                        let path = service_path(&system_name)?;
+                        if !path.exists() {
+                            return Err(
+                                loga::err_with(
+                                    "System has no service",
+                                    ea!(system = system_name, path = path.to_string_lossy()),
+                                ),
+                            );
+                        }
+                        Command::new("systemctl")
+                            .arg("--user")
+                            .arg("disable")
+                            .arg("--now")
+                            .arg(service_name(&system_name))
+                            .run()?;
+                        fs::remove_file(
+                            &path,
+                        ).context_with("Error removing systemd unit", ea!(path = path.to_string_lossy()))?;
+                        Command::new("systemctl").arg("--user").arg("daemon-reload").run()?;
+                    },
+                },
+                SystemArgs::DesktopEntry(args) => {
+                    let exe = args.exe.context("Missing `--exe`, the program to start")?;
+                    let system_path = system_path(&args.system_name)?;
+                    let system_config = check_system(&system_path)?;
+                    check_command_allowed(&args.system_name, &system_config, &exe)?;
+                    let icon = match args.icon {
+                        Some(icon) => Some(icon),
+                        None => install_program_icon(&log, &PrefixView {
+                            upper: system_prefix_path(&system_path),
+                            lower: basis_prefix_path(&basis_path(&system_config.basis_name)?),
+                        }, &exe, &desktop_entry_id(&args.system_name, &exe))?,
+                    };
+                    let title =
+                        args
+                            .title
+                            .unwrap_or_else(
+                                || Path::new(&exe).file_stem().unwrap_or_default().to_string_lossy().to_string(),
+                            );
+                    let path = desktop_entry_path(&args.system_name, &exe)?;
+                    let dir = path.parent().unwrap();
+                    create_dir_all(
+                        dir,
+                    ).context_with("Error creating applications dir", ea!(path = dir.to_string_lossy()))?;
+                    fs::write(
+                        &path,
+                        desktop_entry(&args.system_name, &exe, &title, icon.as_deref())?,
+                    ).context_with("Error writing desktop entry", ea!(path = path.to_string_lossy()))?;
+                    if json {
+                        print_json(json!({
+                            "path": path
+                        }));
+                    } else {
+                        println!("{}", path.to_string_lossy());
+                    }
+                },
+                SystemArgs::SteamShortcut(args) => {
+                    let exe = args.exe.context("Missing `--exe`, the program to start")?;
+                    let system_config = check_system(&system_path(&args.system_name)?)?;
+                    check_command_allowed(&args.system_name, &system_config, &exe)?;
+                    if steam_running() {
+                        return Err(
+                            log.err(
+                                "Steam is running and would overwrite the new shortcut when it exits, close it first",
+                            ),
+                        );
+                    }
+                    let path = shortcuts_path(args.steam_user.as_deref())?;
+                    let command_line = system_run_command_line(&args.system_name, &exe)?;
+                    let start_dir =
+                        Path::new(&command_line[0])
+                            .parent()
+                            .unwrap_or(Path::new("/"))
+                            .to_string_lossy()
+                            .to_string();
+                    let app_id = add_shortcut(&path, &Shortcut {
+                        name: args
+                            .title
+                            .unwrap_or_else(
+                                || Path::new(&exe).file_stem().unwrap_or_default().to_string_lossy().to_string(),
+                            ),
+                        exe: command_line[0].clone(),
+                        start_dir: start_dir,
+                        launch_options: String::from_utf8(
+                            quote_subcommand(command_line[1..].iter().map(|a| a.as_bytes()))?,
+                        ).unwrap(),
+                        icon: args.icon.map(|i| i.to_string_lossy().to_string()),
+                    })?;
+                    if json {
+                        print_json(json!({
+                            "path": path,
+                            "app_id": app_id
+                        }));
+                    } else {
+                        println!("{}", path.to_string_lossy());
+                    }
+                },
+                SystemArgs::ExportLutris(args) => {
+                    let exe = args.exe.context("Missing `--exe`, the program to start")?;
+                    let system_config = check_system(&system_path(&args.system_name)?)?;
+                    check_command_allowed(&args.system_name, &system_config, &exe)?;
+                    let title =
+                        args
+                            .title
+                            .unwrap_or_else(
+                                || Path::new(&exe).file_stem().unwrap_or_default().to_string_lossy().to_string(),
+                            );
+                    print!("{}", lutris_installer(&args.system_name, &exe, &title)?);
+                },
+                SystemArgs::ExportLauncher(args) => {
+                    let exe = args.exe.context("Missing `--exe`, the program to start")?;
+                    let system_config = check_system(&system_path(&args.system_name)?)?;
+                    check_command_allowed(&args.system_name, &system_config, &exe)?;
+                    let basis_config = read_basis_config(&basis_path(&system_config.basis_name)?)?;
+                    let title =
+                        args
+                            .title
+                            .unwrap_or_else(
+                                || Path::new(&exe).file_stem().unwrap_or_default().to_string_lossy().to_string(),
+                            );
+                    let mut env = basis_config.env.into_iter().collect::<BTreeMap<_, _>>();
+                    env.extend(system_config.env);
+                    print!(
+                        "{}",
+                        launcher_entry(
+                            &args.system_name,
+                            &exe,
+                            &title,
+                            env,
+                        )?.format(args.format.unwrap_or_default())?
+                    );
                 },
                 SystemArgs::Path { system_name } => {
                     let system_path = system_path(&system_name)?;
-                    print!("{}", system_path.to_string_lossy());
+                    if json {
+                        print_json(json!({
+                            "path": system_path
+                        }));
+                    } else {
+                        print!("{}", system_path.to_string_lossy());
+                    }
+                },
+                SystemArgs::Mount(args) => {
+                    if args.ro.is_none() {
+                        return Err(
+                            log.err(
+                                "Only read-only mounts (`--ro`) are supported, use `system shell` or `system run` to modify the system",
+                            ),
+                        );
+                    }
+                    let at = args.at.context("Missing `--at`, the location to mount the system")?;
+                    let at = std::path::absolute(&at).context("Error making mount location absolute")?;
+                    let _system_lock = lock_system(&args.system_name, LockMode::Shared, wait)?;
+                    let system_path = system_path(&args.system_name)?;
+                    let system_config = check_system(&system_path)?;
+                    let basis_path = basis_path(&system_config.basis_name)?;
+                    create_dir_all(
+                        &at,
+                    ).context_with("Error creating mount location", ea!(path = at.to_string_lossy()))?;
+                    let backend = select_backend(backend).backend;
+                    mount_readonly(backend, &basis_path, &system_path, &at)?;
+                    write_export_state(&ExportState {
+                        system_name: args.system_name,
+                        mount_path: at,
+                        backend: backend,
+                        started: now_secs(),
+                    })?;
+                },
+                SystemArgs::Unmount(args) => {
+                    let at = match args.at {
+                        Some(at) => Some(
+                            std::path::absolute(&at).context("Error making mount location absolute")?,
+                        ),
+                        None => None,
+                    };
+                    let mut found = false;
+                    for export in list_export_states()? {
+                        if export.system_name != args.system_name {
+                            continue;
+                        }
+                        if at.as_ref().is_some_and(|at| *at != export.mount_path) {
+                            continue;
+                        }
+                        found = true;
+                        if is_mounted(&export.mount_path)? {
+                            unmount_command(export.backend, &export.mount_path)?.run()?;
+                        }
+                        remove_export_state(&export.mount_path)?;
+                    }
+                    if !found {
+                        return Err(log.err_with("No matching read-only mounts", ea!(system = args.system_name)));
+                    }
                 },
             },
+            ArgsCommand::Status => {
+                let exports = list_export_states()?;
+                let mounted = mounted_systems()?;
+                let mut names = mounted.iter().map(|(name, _)| name.clone()).collect::<Vec<_>>();
+                names.extend(list_mount_states()?);
+                names.sort();
+                names.dedup();
+                let runs = list_run_states()?;
+                let broken = broken_state_files()?;
+                if json {
+                    let mut systems = vec![];
+                    for name in &names {
+                        let mount_path = system_mount_path(&system_path(name)?);
+                        let backend = mounted.iter().find(|(n, _)| n == name).map(|(_, b)| b.name());
+                        let state = read_mount_state(name).ok().flatten();
+                        let processes = prefix_processes(&mount_path)?;
+                        systems.push(json!({
+                            "name": name,
+                            "mount_path": mount_path,
+                            "mounted": backend.is_some(),
+                            "backend": backend,
+                            "state": state,
+                            "processes": processes
+                        }));
+                    }
+                    let mut detached_runs = vec![];
+                    for run in &runs {
+                        detached_runs.push(json!({
+                            "run": run,
+                            "running": pid_alive(run.pid)
+                        }));
+                    }
+                    let mut read_only_mounts = vec![];
+                    for export in &exports {
+                        let mounted = is_mounted(&export.mount_path)?;
+                        read_only_mounts.push(json!({
+                            "mount": export,
+                            "mounted": mounted
+                        }));
+                    }
+                    print_json(json!({
+                        "systems": systems,
+                        "detached_runs": detached_runs,
+                        "read_only_mounts": read_only_mounts,
+                        "broken_state_files": broken.iter().map(|(path, _)| path).collect::<Vec<_>>()
+                    }));
+                    return Ok(());
+                }
+                if names.is_empty() && exports.is_empty() && runs.is_empty() && broken.is_empty() {
+                    println!("No systems mounted");
+                }
+                for name in names {
+                    let mount_path = system_mount_path(&system_path(&name)?);
+                    let state = read_mount_state(&name).ok().flatten();
+                    println!("{}", name);
+                    if let Some((_, backend)) = mounted.iter().find(|(n, _)| *n == name) {
+                        println!("  Mountpoint: {}", mount_path.to_string_lossy());
+                        println!("  Backend: {}", backend.name());
+                    } else {
+                        println!("  Not mounted (stale runtime state)");
+                    }
+                    match &state {
+                        Some(state) => {
+                            println!(
+                                "  Up: {} (mounted by winebasin pid {}, in use by {})",
+                                format_duration(now_secs().saturating_sub(state.started)),
+                                state.pid,
+                                state.users.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(", ")
+                            );
+                            println!("  Backend selected because: {}", state.backend_reason);
+                        },
+                        None => {
+                            println!("  Up: unknown (no runtime state)");
+                        },
+                    }
+                    let processes = prefix_processes(&mount_path)?;
+                    if processes.is_empty() {
+                        println!("  No wine processes");
+                    } else {
+                        println!("  Wine processes:");
+                        for process in processes {
+                            println!("    {} {}", process.pid, process.cmdline.join(" "));
+                        }
+                    }
+                }
+                if !runs.is_empty() {
+                    println!("Detached runs:");
+                    for run in runs {
+                        println!(
+                            "  {} pid {}: {} (up {}, log {}){}",
+                            run.system_name,
+                            run.pid,
+                            run.command.join(" "),
+                            format_duration(now_secs().saturating_sub(run.started)),
+                            run.log_path.to_string_lossy(),
+                            if pid_alive(run.pid) {
+                                ""
+                            } else {
+                                ", exited"
+                            }
+                        );
+                    }
+                }
+                if !exports.is_empty() {
+                    println!("Read-only mounts:");
+                    for export in exports {
+                        println!(
+                            "  {} at {} (up {}, {}){}",
+                            export.system_name,
+                            export.mount_path.to_string_lossy(),
+                            format_duration(now_secs().saturating_sub(export.started)),
+                            export.backend.name(),
+                            if is_mounted(&export.mount_path)? {
+                                ""
+                            } else {
+                                ", not mounted (stale runtime state)"
+                            }
+                        );
+                    }
+                }
+                if !broken.is_empty() {
+                    println!("Broken runtime state (ignored, run `winebasin cleanup` to remove):");
+                    for (path, e) in broken {
+                        println!("  {}: {}", path.to_string_lossy(), e);
+                    }
+                }
+            },
+            ArgsCommand::Cleanup { exports } => {
+                for (path, e) in broken_state_files()? {
+                    log.log_err(
+                        StandardFlag::Info,
+                        e.context_with("Removing broken runtime state", ea!(path = path.to_string_lossy())),
+                    );
+                    fs::remove_file(
+                        &path,
+                    ).context_with("Error removing broken runtime state", ea!(path = path.to_string_lossy()))?;
+                }
+                for export in list_export_states()? {
+                    if !is_mounted(&export.mount_path)? {
+                        log.log_with(
+                            StandardFlag::Info,
+                            "Removing runtime state for read-only mount that's no longer mounted",
+                            ea!(system = export.system_name, path = export.mount_path.to_string_lossy()),
+                        );
+                        remove_export_state(&export.mount_path)?;
+                    } else if exports.is_some() {
+                        log.log_with(
+                            StandardFlag::Info,
+                            "Unmounting read-only mount",
+                            ea!(system = export.system_name, path = export.mount_path.to_string_lossy()),
+                        );
+                        unmount_command(export.backend, &export.mount_path)?.run()?;
+                        remove_export_state(&export.mount_path)?;
+                    }
+                }
+                for run in list_run_states()? {
+                    if !pid_alive(run.pid) {
+                        log.log_with(
+                            StandardFlag::Info,
+                            "Removing runtime state for detached run that has exited",
+                            ea!(system = run.system_name, pid = run.pid),
+                        );
+                        remove_run_state(&run)?;
+                    }
+                }
+                for (name, staleness) in stale_systems()? {
+                    match staleness {
+                        Staleness::Mount(backend) => {
+                            let system_path = system_path(&name)?;
+                            let mount_path = system_mount_path(&system_path);
+                            let processes = prefix_processes(&mount_path)?;
+                            if !processes.is_empty() {
+                                log.log_with(
+                                    StandardFlag::Warning,
+                                    "Leftover mount still has wine processes, skipping. Stop them (ex: `wineserver -k`) and re-run cleanup.",
+                                    ea!(
+                                        system = name,
+                                        pids =
+                                            processes
+                                                .iter()
+                                                .map(|p| p.pid.to_string())
+                                                .collect::<Vec<_>>()
+                                                .join(", ")
+                                    ),
+                                );
+                                continue;
+                            }
+                            log.log_with(
+                                StandardFlag::Info,
+                                "Unmounting leftover mount",
+                                ea!(system = name, backend = backend.name()),
+                            );
+                            unmount_command(backend, &mount_path)?.run()?;
+                            if backend.uses_sudo() {
+                                // Overlayfs may leave root-owned files in the work dir if not unmounted cleanly
+                                Command::new("sudo")
+                                    .arg("rm")
+                                    .arg("-rf")
+                                    .arg("--one-file-system")
+                                    .arg(system_overlay_work_path(&system_path).join("work"))
+                                    .run()?;
+                            }
+                            remove_mount_state(&name)?;
+                        },
+                        Staleness::State => {
+                            log.log_with(StandardFlag::Info, "Removing leftover runtime state", ea!(system = name));
+                            remove_mount_state(&name)?;
+                        },
+                    }
+                }
+            },
+            ArgsCommand::Doctor => {
+                // Registered wines are listed by `wine list`
+                let default_env = HashMap::new();
+                let default_bins =
+                    [
+                        ("wine", wine_bin(&default_env).to_string_lossy().to_string()),
+                        ("wineserver", wineserver_bin(&default_env).to_string_lossy().to_string()),
+                        ("winetricks", winetricks_bin()),
+                    ];
+                if json {
+                    let mut bins = serde_json::Map::new();
+                    for (name, bin) in &default_bins {
+                        bins.insert(name.to_string(), json!(find_bin(bin)));
+                    }
+                    let mut backends = serde_json::Map::new();
+                    for backend in MountBackend::ALL {
+                        let available = backend.check_available();
+                        backends.insert(backend.name().to_string(), json!({
+                            "available": available.is_ok(),
+                            "reason": available.err()
+                        }));
+                    }
+                    let mut caps = serde_json::Map::new();
+                    for (capability, res) in capabilities().all() {
+                        caps.insert(capability.name().to_string(), json!({
+                            "available": res.is_ok(),
+                            "reason": res.as_ref().err()
+                        }));
+                    }
+                    let inf_path = wine_inf_path(&default_env);
+                    let inf_exists = inf_path.exists();
+                    let choice = select_backend(backend);
+                    print_json(json!({
+                        "bins": bins,
+                        "wine_inf": {
+                            "path": inf_path,
+                            "exists": inf_exists
+                        },
+                        "backends": backends,
+                        "capabilities": caps,
+                        "selected_backend": {
+                            "backend": choice.backend.name(),
+                            "reason": choice.reason
+                        }
+                    }));
+                    return Ok(());
+                }
+                for (name, bin) in &default_bins {
+                    match find_bin(bin) {
+                        Some(path) => println!("{}: {}", name, path.to_string_lossy()),
+                        None => println!("{}: `{}` not found", name, bin),
+                    }
+                }
+                let inf_path = wine_inf_path(&default_env);
+                if inf_path.exists() {
+                    println!("wine.inf: {}", inf_path.to_string_lossy());
+                } else {
+                    println!(
+                        "wine.inf: {} not found, set WINE_INF_DIR to the path to wine.inf",
+                        inf_path.to_string_lossy()
+                    );
+                }
+                println!("Mount backends:");
+                for backend in MountBackend::ALL {
+                    match backend.check_available() {
+                        Ok(_) => println!("  {}: available", backend.name()),
+                        Err(e) => println!("  {}: unavailable, {}", backend.name(), e),
+                    }
+                }
+                let choice = select_backend(backend);
+                println!("Selected backend: {} ({})", choice.backend.name(), choice.reason);
+                println!("Capabilities:");
+                for (capability, res) in capabilities().all() {
+                    match res {
+                        Ok(_) => println!("  {}: available", capability.name()),
+                        Err(e) => println!("  {}: unavailable, {}", capability.name(), e),
+                    }
+                }
+            },
+            ArgsCommand::Escape { system } => {
+                if !mounted_systems()?.iter().any(|(name, _)| *name == system) {
+                    return Err(loga::err_with("System isn't running", ea!(system = system)));
+                }
+                let system_path = system_path(&system)?;
+                log.log_with(StandardFlag::Info, "Killing programs in system", ea!(system = system));
+
+                // Keep going, the point is to get control back
+                let env = match mounted_system_envs(&system_path) {
+                    Ok(env) => env,
+                    Err(e) => {
+                        log.log_err(StandardFlag::Warning, e);
+                        HashMap::from(
+                            [(OsString::from("WINEPREFIX"), system_mount_path(&system_path).into_os_string())],
+                        )
+                    },
+                };
+                Command::new(wineserver_bin(&env)).envs(&env).arg("-k9").run()?;
+            },
+            ArgsCommand::EscapeSupervisor { release_hotkey, kill_hotkey } => {
+                let release = release_hotkey.unwrap_or_else(|| Hotkey::from_str("ctrl+alt+end").unwrap());
+                let kill = kill_hotkey.unwrap_or_else(|| Hotkey::from_str("ctrl+alt+shift+end").unwrap());
+                if release == kill {
+                    return Err(loga::err("The release and kill hotkeys must be different"));
+                }
+                escape::supervise(&log, &release, &kill)?;
+            },
+            ArgsCommand::Graph { dot, tree } => {
+                if dot.is_some() && tree.is_some() {
+                    return Err(loga::err("`--dot` and `--tree` can't be used together"));
+                }
+                let graph = build_graph()?;
+                if json {
+                    print_json(graph_json(&graph));
+                } else if dot.is_some() {
+                    print_dot(&graph);
+                } else {
+                    print_tree(&graph);
+                }
+            },
         }
         return Ok(()) as Result<_, loga::Error>;
     })() {
         Ok(_) => { },
         Err(e) => {
-            fatal(e);
+            StandardLog::new().with_flags(&[StandardFlag::Error]).log_err(StandardFlag::Error, e);
+            exit(ERROR_EXIT_CODE);
         },
     }
 }
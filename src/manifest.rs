@@ -0,0 +1,187 @@
+use {
+    crate::{
+        audio::AudioDriver,
+        basis_path,
+        check_basis_writable,
+        check_system,
+        create_basis,
+        create_system,
+        install_basis_verbs,
+        lock::{
+            lock_basis,
+            lock_system,
+            LockMode,
+        },
+        read_basis_config,
+        system_path,
+        winetricks::load_preset,
+        write_basis_config,
+        write_system_config,
+        Arch,
+        BasisLatestConfig,
+        SyncConfig,
+        UpdatePolicy,
+    },
+    loga::{
+        ea,
+        ResultContext,
+        StandardFlag,
+        StandardLog,
+    },
+    serde::Deserialize,
+    std::{
+        collections::BTreeMap,
+        fs,
+        path::{
+            Path,
+            PathBuf,
+        },
+    },
+};
+
+pub const MANIFEST_NAME: &str = "winebasin.toml";
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestBasis {
+    pub name: String,
+    /// Defaults to `Win64`.
+    #[serde(default)]
+    pub arch: Option<Arch>,
+    /// Winetricks preset, installed before `winetricks`.
+    #[serde(default)]
+    pub preset: Option<String>,
+    #[serde(default)]
+    pub winetricks: Vec<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestSystem {
+    pub name: String,
+    /// Command and arguments for `winebasin run`, relative to `drive_c`.
+    #[serde(default)]
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub working_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub dll_overrides: BTreeMap<String, String>,
+}
+
+/// A project's basis and system, from `winebasin.toml`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub basis: ManifestBasis,
+    #[serde(default)]
+    pub system: Option<ManifestSystem>,
+}
+
+pub fn read_manifest(path: &Option<PathBuf>) -> Result<Manifest, loga::Error> {
+    let path = path.as_deref().unwrap_or(Path::new(MANIFEST_NAME));
+    let data = fs::read_to_string(path).context_with("Error reading manifest", ea!(path = path.to_string_lossy()))?;
+    return toml::from_str(&data).context_with("Error parsing manifest", ea!(path = path.to_string_lossy()));
+}
+
+fn apply_basis(
+    log: &StandardLog,
+    basis: &ManifestBasis,
+    wait: bool,
+    update_policy: UpdatePolicy,
+) -> Result<(), loga::Error> {
+    let arch = basis.arch.unwrap_or(Arch::Win64);
+    let mut verbs = vec![];
+    if let Some(preset) = &basis.preset {
+        verbs.extend(load_preset(preset)?.for_arch(arch).verbs.iter().cloned());
+    }
+    verbs.extend(basis.winetricks.iter().cloned());
+    let mut lock = lock_basis(&basis.name, LockMode::Exclusive, wait)?;
+    let basis_path = basis_path(&basis.name)?;
+    if !basis_path.exists() {
+        log.log_with(StandardFlag::Info, "Creating basis", ea!(basis = basis.name));
+        create_basis(log, &basis_path, &BasisLatestConfig {
+            arch: arch,
+            env: basis.env.clone(),
+            winetricks: verbs,
+            registry: vec![],
+            script: None,
+            update_command: None,
+            wine: None,
+            dxvk: None,
+            shared_shader_cache: false,
+            sync: SyncConfig::default(),
+            audio: AudioDriver::Auto,
+        })?;
+        return Ok(());
+    }
+    let mut config = read_basis_config(&basis_path)?;
+    if config.arch != arch {
+        return Err(
+            loga::err_with(
+                "The existing basis has a different architecture than the manifest, delete it or rename the basis in the manifest",
+                ea!(basis = basis.name),
+            ),
+        );
+    }
+    if config.env != basis.env {
+        check_basis_writable(&basis.name, &basis_path)?;
+        log.log_with(StandardFlag::Info, "Updating basis env", ea!(basis = basis.name));
+        config.env = basis.env.clone();
+        write_basis_config(&basis_path, &config)?;
+    }
+    let missing = verbs.into_iter().filter(|v| !config.winetricks.contains(v)).collect::<Vec<_>>();
+    if !missing.is_empty() {
+        log.log_with(
+            StandardFlag::Info,
+            "Installing winetricks verbs in basis",
+            ea!(basis = basis.name, verbs = missing.join(" ")),
+        );
+        install_basis_verbs(log, &basis.name, &mut lock, update_policy, missing)?;
+    }
+    return Ok(());
+}
+
+fn apply_system(log: &StandardLog, basis_name: &str, system: &ManifestSystem, wait: bool) -> Result<(), loga::Error> {
+    let system_path = system_path(&system.name)?;
+    if !system_path.exists() {
+        log.log_with(StandardFlag::Info, "Creating system", ea!(system = system.name));
+        create_system(&system.name, basis_name, wait)?;
+    }
+    let _lock = lock_system(&system.name, LockMode::Exclusive, wait)?;
+    let mut config = check_system(&system_path)?;
+    if config.basis_name != basis_name {
+        return Err(
+            loga::err_with(
+                "The existing system uses a different basis than the manifest",
+                ea!(system = system.name, basis = config.basis_name, manifest_basis = basis_name),
+            ),
+        );
+    }
+    if config.env != system.env || config.dll_overrides != system.dll_overrides {
+        log.log_with(StandardFlag::Info, "Updating system config", ea!(system = system.name));
+        config.env = system.env.clone();
+        config.dll_overrides = system.dll_overrides.clone();
+        write_system_config(&system_path, config)?;
+    }
+    return Ok(());
+}
+
+/// Create or update the basis and system described by the manifest so they match
+/// it. Verbs are only ever added, removing one from the manifest doesn't uninstall
+/// it.
+pub fn apply_manifest(
+    log: &StandardLog,
+    manifest: &Manifest,
+    wait: bool,
+    update_policy: UpdatePolicy,
+) -> Result<(), loga::Error> {
+    apply_basis(log, &manifest.basis, wait, update_policy)?;
+    if let Some(system) = &manifest.system {
+        apply_system(log, &manifest.basis.name, system, wait)?;
+    }
+    return Ok(());
+}
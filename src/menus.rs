@@ -0,0 +1,74 @@
+use {
+    directories::BaseDirs,
+    loga::{
+        ea,
+        ResultContext,
+        StandardFlag,
+        StandardLog,
+    },
+    std::{
+        collections::HashMap,
+        ffi::OsString,
+        fs,
+        path::{
+            Path,
+            PathBuf,
+        },
+    },
+};
+
+/// Stop `winemenubuilder` from adding menu entries and file associations to the
+/// host. Added before any existing overrides, so an explicit override wins.
+pub fn disable_menus(env: &mut HashMap<OsString, OsString>) {
+    let mut overrides = vec!["winemenubuilder.exe=d".to_string()];
+    if let Some(existing) = env.get(&OsString::from("WINEDLLOVERRIDES")) {
+        overrides.push(existing.to_string_lossy().to_string());
+    }
+    env.insert(OsString::from("WINEDLLOVERRIDES"), OsString::from(overrides.join(";")));
+}
+
+/// Desktop files `winemenubuilder` created under `dir` for the prefix.
+fn find_menu_entries(dir: &Path, prefix_marker: &str, out: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_dir() {
+            find_menu_entries(&path, prefix_marker, out);
+        } else if path.extension().is_some_and(|e| e == "desktop") &&
+            fs::read_to_string(&path).is_ok_and(|c| c.contains(prefix_marker)) {
+            out.push(path);
+        }
+    }
+}
+
+/// Remove menu entries and file associations `winemenubuilder` added to the host
+/// for the (mounted) prefix, ex: from before menus were disabled.
+pub fn remove_menu_entries(log: &StandardLog, prefix: &Path) -> Result<(), loga::Error> {
+    let base = BaseDirs::new().context("Unable to determine user data directory")?;
+    let applications = base.data_dir().join("applications");
+    let mut found = vec![];
+    let prefix_marker = format!("WINEPREFIX=\"{}\"", prefix.to_string_lossy());
+
+    // Menu entries are in `wine/`, file associations are `wine-extension-*.desktop`
+    find_menu_entries(&applications.join("wine"), &prefix_marker, &mut found);
+    if let Ok(entries) = fs::read_dir(&applications) {
+        for entry in entries.flatten() {
+            if entry.file_name().to_string_lossy().starts_with("wine-extension-") {
+                let path = entry.path();
+                if fs::read_to_string(&path).is_ok_and(|c| c.contains(&prefix_marker)) {
+                    found.push(path);
+                }
+            }
+        }
+    }
+    for path in found {
+        log.log_with(StandardFlag::Info, "Removing menu entry added by wine", ea!(path = path.to_string_lossy()));
+        fs::remove_file(&path).context_with("Error removing menu entry", ea!(path = path.to_string_lossy()))?;
+    }
+    return Ok(());
+}
@@ -0,0 +1,618 @@
+use {
+    crate::{
+        basis_prefix_path,
+        find_bin,
+        lock::lock_system_mount,
+        runtime::{
+            mounted_systems,
+            now_secs,
+            pid_alive,
+            read_mount_state,
+            remove_mount_state,
+            write_mount_state,
+            MountState,
+        },
+        system_mount_path,
+        system_overlay_work_path,
+        system_prefix_path,
+        CommandRun,
+    },
+    aargvark::Aargvark,
+    defer::defer,
+    format_bytes::format_bytes,
+    loga::{
+        ea,
+        ResultContext,
+        StandardFlag,
+        StandardLog,
+    },
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+    std::{
+        fs,
+        io,
+        os::unix::{
+            ffi::OsStrExt,
+            fs::{
+                symlink,
+                FileTypeExt,
+                MetadataExt,
+            },
+        },
+        path::{
+            Path,
+            PathBuf,
+        },
+        process::Command,
+    },
+};
+#[cfg(any(feature = "sudo-overlayfs", feature = "fuse-overlayfs"))]
+use {
+    crate::capabilities::{
+        capabilities,
+        Capability,
+    },
+    std::ffi::OsStr,
+};
+#[cfg(feature = "sudo-overlayfs")]
+use {
+    crate::{
+        echo_commands,
+        quote_subcommand,
+    },
+    command_fds::{
+        CommandFdExt,
+        FdMapping,
+    },
+    loga::DebugDisplay,
+    os_pipe::pipe,
+    std::{
+        io::{
+            BufRead,
+            BufReader,
+            Write,
+        },
+        process::Stdio,
+    },
+};
+
+#[derive(Aargvark, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MountBackend {
+    /// Kernel overlayfs, mounted and unmounted by a background `sudo` process.
+    #[cfg(feature = "sudo-overlayfs")]
+    SudoOverlayfs,
+    /// `fuse-overlayfs`, doesn't require root.
+    #[cfg(feature = "fuse-overlayfs")]
+    FuseOverlayfs,
+    /// No overlay: the first time the system is mounted the basis prefix is copied
+    /// into the system's, which is then used directly, so the system no longer gets
+    /// basis changes. For hosts without overlayfs or FUSE. Only picked automatically
+    /// when built without the overlay backends.
+    Copy,
+}
+
+impl MountBackend {
+    pub const ALL: &'static [MountBackend] = &[
+        #[cfg(feature = "sudo-overlayfs")]
+        MountBackend::SudoOverlayfs,
+        #[cfg(feature = "fuse-overlayfs")]
+        MountBackend::FuseOverlayfs,
+        MountBackend::Copy,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "sudo-overlayfs")]
+            MountBackend::SudoOverlayfs => "sudo-overlayfs",
+            #[cfg(feature = "fuse-overlayfs")]
+            MountBackend::FuseOverlayfs => "fuse-overlayfs",
+            MountBackend::Copy => "copy",
+        }
+    }
+
+    /// Mounting needs root, through `sudo`.
+    pub fn uses_sudo(&self) -> bool {
+        match self {
+            #[cfg(feature = "sudo-overlayfs")]
+            MountBackend::SudoOverlayfs => true,
+            #[cfg(feature = "fuse-overlayfs")]
+            MountBackend::FuseOverlayfs => false,
+            MountBackend::Copy => false,
+        }
+    }
+
+    /// Identify the backend from the filesystem type in `/proc/mounts`.
+    pub fn from_fs_type(fs_type: &str) -> Option<MountBackend> {
+        match fs_type {
+            #[cfg(feature = "sudo-overlayfs")]
+            "overlay" => Some(MountBackend::SudoOverlayfs),
+            #[cfg(feature = "fuse-overlayfs")]
+            "fuse.fuse-overlayfs" => Some(MountBackend::FuseOverlayfs),
+            _ => None,
+        }
+    }
+
+    /// Check whether the backend can be used on this host, returning a description of
+    /// what's missing if not.
+    pub fn check_available(&self) -> Result<(), &'static str> {
+        match self {
+            #[cfg(feature = "sudo-overlayfs")]
+            MountBackend::SudoOverlayfs => {
+                capabilities().check(Capability::Sudo)?;
+                capabilities().check(Capability::Overlayfs)?;
+            },
+            #[cfg(feature = "fuse-overlayfs")]
+            MountBackend::FuseOverlayfs => {
+                capabilities().check(Capability::Fuse)?;
+            },
+            MountBackend::Copy => { },
+        }
+        return Ok(());
+    }
+}
+
+pub struct BackendChoice {
+    pub backend: MountBackend,
+    /// Why this backend was selected, for diagnostics.
+    pub reason: String,
+}
+
+/// Pick the mount backend to use. `forced` comes from the `--backend` flag.
+pub fn select_backend(forced: Option<MountBackend>) -> BackendChoice {
+    if let Some(backend) = forced {
+        return BackendChoice {
+            backend: backend,
+            reason: "Forced with `--backend`".to_string(),
+        };
+    }
+    let mut unavailable = vec![];
+
+    // Copying makes the system independent of the basis, so it's only done when asked
+    // for if there are overlay backends
+    for backend in MountBackend::ALL.iter().filter(|b| **b != MountBackend::Copy || MountBackend::ALL.len() == 1) {
+        match backend.check_available() {
+            Ok(_) => {
+                let mut reason = "First available backend".to_string();
+                if !unavailable.is_empty() {
+                    reason = format!("{} ({})", reason, unavailable.join("; "));
+                }
+                return BackendChoice {
+                    backend: *backend,
+                    reason: reason,
+                };
+            },
+            Err(e) => {
+                unavailable.push(format!("{} unavailable: {}", backend.name(), e));
+            },
+        }
+    }
+    return BackendChoice {
+        backend: MountBackend::ALL[0],
+        reason: format!("No backend available, falling back to default ({})", unavailable.join("; ")),
+    };
+}
+
+pub fn fusermount_bin() -> Option<PathBuf> {
+    return find_bin("fusermount3").or_else(|| find_bin("fusermount"));
+}
+
+/// Unmount a system mount left behind by another process (for cleanup) or a
+/// read-only export mount.
+pub fn unmount_command(backend: MountBackend, mount_path: &Path) -> Result<Command, loga::Error> {
+    match backend {
+        #[cfg(feature = "sudo-overlayfs")]
+        MountBackend::SudoOverlayfs => {
+            let mut command = Command::new("sudo");
+            command.arg("umount").arg(mount_path);
+            return Ok(command);
+        },
+        #[cfg(feature = "fuse-overlayfs")]
+        MountBackend::FuseOverlayfs => {
+            let mut command =
+                Command::new(fusermount_bin().context("Couldn't find `fusermount3` or `fusermount` in PATH")?);
+            command.arg("-u").arg(mount_path);
+            return Ok(command);
+        },
+        MountBackend::Copy => {
+            let mut command = Command::new("rm");
+            command.arg(mount_path);
+            return Ok(command);
+        },
+    }
+}
+
+/// The command that mounts a system's writable merged prefix. For sudo, the
+/// arguments after `sudo` are run in a persistent root shell rather than this
+/// command being run directly. For copy, this links the system's prefix at the
+/// mount path, after copying the basis prefix into it (see `copy_missing`).
+#[cfg_attr(not(any(feature = "sudo-overlayfs", feature = "fuse-overlayfs")), allow(unused_variables))]
+pub fn mount_command(backend: MountBackend, basis_path: &Path, system_path: &Path, mount_path: &Path) -> Command {
+    let lower = basis_prefix_path(basis_path);
+    let upper = system_prefix_path(system_path);
+    let work = system_overlay_work_path(system_path);
+    match backend {
+        #[cfg(feature = "sudo-overlayfs")]
+        MountBackend::SudoOverlayfs => {
+            let mut command = Command::new("sudo");
+            command
+                .arg("mount")
+                .arg("--types")
+                .arg("overlay")
+                .arg("overlay")
+                .arg("--options")
+                .arg(
+                    OsStr::from_bytes(
+                        &format_bytes!(
+                            b"lowerdir={},upperdir={},workdir={},metacopy=off,index=off",
+                            lower.as_os_str().as_bytes(),
+                            upper.as_os_str().as_bytes(),
+                            work.as_os_str().as_bytes()
+                        ),
+                    ),
+                )
+                .arg(mount_path);
+            return command;
+        },
+        #[cfg(feature = "fuse-overlayfs")]
+        MountBackend::FuseOverlayfs => {
+            let mut command = Command::new("fuse-overlayfs");
+            command
+                .arg("-o")
+                .arg(
+                    OsStr::from_bytes(
+                        &format_bytes!(
+                            b"lowerdir={},upperdir={},workdir={}",
+                            lower.as_os_str().as_bytes(),
+                            upper.as_os_str().as_bytes(),
+                            work.as_os_str().as_bytes()
+                        ),
+                    ),
+                )
+                .arg(mount_path);
+            return command;
+        },
+        MountBackend::Copy => {
+            let mut command = Command::new("ln");
+            command.arg("-s").arg(upper).arg(mount_path);
+            return command;
+        },
+    }
+}
+
+/// Mount a read-only merged view of the system at `at`. The mount persists until
+/// unmounted with `unmount_command`.
+#[cfg_attr(not(any(feature = "sudo-overlayfs", feature = "fuse-overlayfs")), allow(unused_variables))]
+pub fn mount_readonly(
+    backend: MountBackend,
+    basis_path: &Path,
+    system_path: &Path,
+    at: &Path,
+) -> Result<(), loga::Error> {
+    // Without an upperdir overlays are read-only
+    let options =
+        format_bytes!(
+            b"lowerdir={}:{}",
+            system_prefix_path(system_path).as_os_str().as_bytes(),
+            basis_prefix_path(basis_path).as_os_str().as_bytes()
+        );
+    match backend {
+        #[cfg(feature = "sudo-overlayfs")]
+        MountBackend::SudoOverlayfs => {
+            Command::new("sudo")
+                .arg("mount")
+                .arg("--types")
+                .arg("overlay")
+                .arg("overlay")
+                .arg("--options")
+                .arg(OsStr::from_bytes(&options))
+                .arg(at)
+                .run()?;
+            return Ok(());
+        },
+        #[cfg(feature = "fuse-overlayfs")]
+        MountBackend::FuseOverlayfs => {
+            Command::new("fuse-overlayfs").arg("-o").arg(OsStr::from_bytes(&options)).arg(at).run()?;
+            return Ok(());
+        },
+        MountBackend::Copy => {
+            return Err(loga::err("The copy backend can't make read-only views, pick another with `--backend`"));
+        },
+    }
+}
+
+/// Called with `true` to unmount and clean up, or `false` to only clean up
+/// (another process is still using the mount).
+type Unmount = Box<dyn FnOnce(bool) -> Result<(), loga::Error>>;
+
+#[cfg_attr(not(feature = "sudo-overlayfs"), allow(unused_variables))]
+fn mount_new(
+    log: &StandardLog,
+    backend: MountBackend,
+    basis_path: &Path,
+    system_path: &Path,
+    mount_path: &Path,
+) -> Result<Unmount, loga::Error> {
+    match backend {
+        #[cfg(feature = "sudo-overlayfs")]
+        MountBackend::SudoOverlayfs => {
+            // Launch background sudo process (keep it open so don't need reauth at exit)
+            let (sudo_read, sudo_read_child) = pipe().context("Error creating sudo read pipe pair")?;
+            let mut sudo_read = BufReader::new(sudo_read).lines();
+            let mut sudo =
+                Command::new("sudo")
+                    .arg("--close-from")
+                    .arg("4")
+                    .arg("bash")
+                    .arg("-eu")
+                    .stdin(Stdio::piped())
+                    .fd_mappings(vec![FdMapping {
+                        parent_fd: sudo_read_child.into(),
+                        child_fd: 3,
+                    }])
+                    .context("Error attaching pipes to sudo child")?
+                    .spawn()
+                    .context("Error starting cleanup bash process")?;
+            let mut sudo_write = sudo.stdin.take().unwrap();
+            let mut sudo_exec = {
+                let mut i = 0;
+                let log = log.clone();
+                move |line: &[u8]| {
+                    if echo_commands() {
+                        log.log_with(
+                            StandardFlag::Debug,
+                            "Running command as root",
+                            ea!(command = String::from_utf8_lossy(line)),
+                        );
+                    }
+                    sudo_write.write_all(line)?;
+                    sudo_write.write_all(b";\n")?;
+                    let want_i = i.to_string();
+                    i += 1;
+                    sudo_write.write_all(&format_bytes!(b"echo {} >&3;\n", want_i.as_bytes()))?;
+                    sudo_write.flush()?;
+                    for line in sudo_read.by_ref() {
+                        let line = line.context("Error reading ipc line")?;
+                        let line = line.trim();
+                        if line == want_i {
+                            break;
+                        }
+                    }
+                    return Ok(()) as Result<_, loga::Error>;
+                }
+            };
+
+            // Ctrl+c goes to the whole foreground process group - ignore it so this survives
+            // to unmount
+            sudo_exec(b"trap '' INT HUP")?;
+
+            // Mount
+            sudo_exec(
+                &quote_subcommand(
+                    mount_command(backend, basis_path, system_path, mount_path).get_args().map(|a| a.as_bytes()),
+                )?,
+            )?;
+            return Ok(Box::new({
+                let log = log.clone();
+                let mount_path = mount_path.to_path_buf();
+                move |unmount| {
+                    if unmount {
+                        sudo_exec(&quote_subcommand([b"umount", mount_path.as_os_str().as_bytes()]).unwrap())?;
+                    }
+                    drop(sudo_exec);
+                    let res = sudo.wait_with_output()?;
+                    if !res.status.success() {
+                        log.log_with(
+                            StandardFlag::Warning,
+                            "Cleanup sudo process exited with error",
+                            ea!(output = res.dbg_str()),
+                        );
+                    }
+                    return Ok(());
+                }
+            }));
+        },
+        #[cfg(feature = "fuse-overlayfs")]
+        MountBackend::FuseOverlayfs => {
+            let fusermount = fusermount_bin().context("Couldn't find `fusermount3` or `fusermount` in PATH")?;
+            mount_command(backend, basis_path, system_path, mount_path).run()?;
+            return Ok(Box::new({
+                let mount_path = mount_path.to_path_buf();
+                move |unmount| {
+                    if unmount {
+                        Command::new(fusermount).arg("-u").arg(&mount_path).run()?;
+                    }
+                    return Ok(());
+                }
+            }));
+        },
+        MountBackend::Copy => {
+            let upper = system_prefix_path(system_path);
+            let copied = system_path.join(COPIED_MARKER);
+            if !copied.exists() {
+                log.log(StandardFlag::Info, "Copying the basis prefix into the system, this may take a while");
+                copy_missing(&basis_prefix_path(basis_path), &upper)?;
+                fs::write(
+                    &copied,
+                    b"",
+                ).context_with("Error marking system as copied", ea!(path = copied.to_string_lossy()))?;
+            }
+
+            // Replace the empty mount dir
+            if mount_path.is_dir() && !mount_path.is_symlink() {
+                fs::remove_dir(
+                    mount_path,
+                ).context_with("Error removing mount dir", ea!(path = mount_path.to_string_lossy()))?;
+            }
+            mount_command(backend, basis_path, system_path, mount_path).run()?;
+            return Ok(Box::new({
+                let mount_path = mount_path.to_path_buf();
+                move |unmount| {
+                    if unmount {
+                        fs::remove_file(
+                            &mount_path,
+                        ).context_with("Error removing mount link", ea!(path = mount_path.to_string_lossy()))?;
+                    }
+                    return Ok(());
+                }
+            }));
+        },
+    }
+}
+
+/// Marks systems the copy backend has copied the basis prefix into, in the system
+/// dir.
+const COPIED_MARKER: &str = "copied";
+
+/// Copy files from `from` into `to` that aren't already there. Overlayfs whiteouts
+/// in `to` (files the system deleted) are removed instead of being replaced.
+fn copy_missing(from: &Path, to: &Path) -> Result<(), loga::Error> {
+    for entry in fs::read_dir(from).context_with("Error listing dir", ea!(path = from.to_string_lossy()))? {
+        let entry = entry.context_with("Error reading dir entry", ea!(path = from.to_string_lossy()))?;
+        let src = entry.path();
+        let dest = to.join(entry.file_name());
+        let meta =
+            src.symlink_metadata().context_with("Error reading file metadata", ea!(path = src.to_string_lossy()))?;
+        match dest.symlink_metadata() {
+            Ok(dest_meta) => {
+                if dest_meta.file_type().is_char_device() && dest_meta.rdev() == 0 {
+                    fs::remove_file(
+                        &dest,
+                    ).context_with("Error removing whiteout", ea!(path = dest.to_string_lossy()))?;
+                } else if meta.is_dir() && dest_meta.is_dir() {
+                    copy_missing(&src, &dest)?;
+                }
+                continue;
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => { },
+            Err(e) => return Err(
+                loga::err_with("Error reading file metadata", ea!(path = dest.to_string_lossy(), err = e)),
+            ),
+        }
+        if meta.is_symlink() {
+            let target = fs::read_link(&src).context_with("Error reading link", ea!(path = src.to_string_lossy()))?;
+            symlink(target, &dest).context_with("Error creating link", ea!(path = dest.to_string_lossy()))?;
+        } else if meta.is_dir() {
+            fs::create_dir(&dest).context_with("Error creating dir", ea!(path = dest.to_string_lossy()))?;
+            fs::set_permissions(
+                &dest,
+                meta.permissions(),
+            ).context_with("Error setting dir permissions", ea!(path = dest.to_string_lossy()))?;
+            copy_missing(&src, &dest)?;
+        } else if meta.is_file() {
+            fs::copy(
+                &src,
+                &dest,
+            ).context_with("Error copying file", ea!(from = src.to_string_lossy(), to = dest.to_string_lossy()))?;
+        }
+    }
+    return Ok(());
+}
+
+/// Mount the system, or if it's already mounted by another winebasin process reuse
+/// that mount. The mount is unmounted when the last process using it drops the
+/// returned value.
+#[allow(dyn_drop)]
+pub fn mount_prefix(
+    log: &StandardLog,
+    backend: &BackendChoice,
+    basis_path: &Path,
+    system_name: &str,
+    system_path: &Path,
+) -> Result<(Box<dyn Drop>, PathBuf), loga::Error> {
+    let mount_path = system_mount_path(system_path);
+    let pid = std::process::id();
+
+    // Serialize mount/unmount decisions between processes using this system
+    let mount_lock = lock_system_mount(system_name)?;
+    let existing =
+        mounted_systems()?.into_iter().find(|(name, _)| name == system_name).map(|(_, backend)| backend);
+    let (log, unmount) = match existing {
+        Some(existing_backend) => {
+            let log = log.fork(ea!(backend = existing_backend.name()));
+            log.log(StandardFlag::Info, "System is already mounted, sharing the existing mount");
+            let mut state = match read_mount_state(system_name)? {
+                Some(state) => state,
+                None => MountState {
+                    system_name: system_name.to_string(),
+                    mount_path: mount_path.clone(),
+                    pid: pid,
+                    users: vec![],
+                    started: now_secs(),
+                    backend: existing_backend,
+                    backend_reason: "Mounted by another process".to_string(),
+                },
+            };
+            state.users.retain(|p| pid_alive(*p));
+            state.users.push(pid);
+            write_mount_state(&state)?;
+            (log, None)
+        },
+        None => {
+            let log = log.fork(ea!(backend = backend.backend.name()));
+            log.log_with(
+                StandardFlag::Debug,
+                "Mounting system",
+                ea!(mount_path = mount_path.to_string_lossy(), backend_reason = backend.reason),
+            );
+
+            // The copy backend replaces it with a link
+            if backend.backend != MountBackend::Copy {
+                fs::create_dir_all(
+                    &mount_path,
+                ).context_with("Error creating mount dir", ea!(path = mount_path.to_string_lossy()))?;
+            }
+            let unmount = mount_new(&log, backend.backend, basis_path, system_path, &mount_path)?;
+            write_mount_state(&MountState {
+                system_name: system_name.to_string(),
+                mount_path: mount_path.clone(),
+                pid: pid,
+                users: vec![pid],
+                started: now_secs(),
+                backend: backend.backend,
+                backend_reason: backend.reason.clone(),
+            }).log(&log, StandardFlag::Warning, "Error recording mount state");
+            (log, Some(unmount))
+        },
+    };
+    drop(mount_lock);
+    return Ok((
+        // Unmount when dropped, if the last user
+        Box::new(defer({
+            let system_name = system_name.to_string();
+            let mount_path = mount_path.clone();
+            move || {
+                (|| {
+                    let _mount_lock = lock_system_mount(&system_name)?;
+                    let state = read_mount_state(&system_name)?.map(|mut state| {
+                        state.users.retain(|p| *p != pid && pid_alive(*p));
+                        state
+                    });
+                    let last = state.as_ref().map(|s| s.users.is_empty()).unwrap_or(true);
+                    match unmount {
+                        Some(unmount) => unmount(last)?,
+                        None => if last {
+                            if let Some(state) = &state {
+                                unmount_command(state.backend, &mount_path)?.run()?;
+                            }
+                        },
+                    }
+                    match state {
+                        Some(state) if !last => {
+                            write_mount_state(&state)?;
+                        },
+                        _ => {
+                            remove_mount_state(
+                                &system_name,
+                            ).log(&log, StandardFlag::Warning, "Error removing mount state");
+                        },
+                    }
+                    return Ok(()) as Result<_, loga::Error>;
+                })().log(&log, StandardFlag::Warning, "Error completing cleanup");
+            }
+        })),
+        // Useful return
+        mount_path,
+    ));
+}
@@ -0,0 +1,146 @@
+use {
+    crate::{
+        capabilities::{
+            require,
+            Capability,
+        },
+        echo_command,
+        find_bin,
+        signals,
+    },
+    aargvark::Aargvark,
+    command_fds::{
+        CommandFdExt,
+        FdMapping,
+    },
+    loga::{
+        ea,
+        DebugDisplay,
+        ResultContext,
+        StandardFlag,
+        StandardLog,
+    },
+    os_pipe::pipe,
+    std::{
+        env,
+        io::{
+            BufRead,
+            BufReader,
+        },
+        process::{
+            Child,
+            Command,
+            Stdio,
+        },
+    },
+};
+
+#[derive(Aargvark, Clone, Copy, PartialEq, Eq)]
+pub enum NestedXServer {
+    /// Runs as a window on an X11 (or Xwayland) desktop
+    Xephyr,
+    /// Rootful Xwayland, runs as a window on a Wayland desktop
+    Xwayland,
+}
+
+impl NestedXServer {
+    /// Xwayland if running under Wayland, otherwise Xephyr.
+    pub fn default_for_session() -> NestedXServer {
+        if env::var_os("WAYLAND_DISPLAY").is_some() {
+            return NestedXServer::Xwayland;
+        } else {
+            return NestedXServer::Xephyr;
+        }
+    }
+}
+
+/// Check the resolution looks like `WIDTHxHEIGHT`.
+pub fn check_resolution(resolution: &str) -> Result<(), loga::Error> {
+    let valid = match resolution.split_once('x') {
+        Some((w, h)) => w.parse::<u32>().is_ok_and(|w| w > 0) && h.parse::<u32>().is_ok_and(|h| h > 0),
+        None => false,
+    };
+    if !valid {
+        return Err(loga::err_with("Invalid resolution, expected `WIDTHxHEIGHT`", ea!(resolution = resolution)));
+    }
+    return Ok(());
+}
+
+/// The command to start the server. The server picks a free display number and
+/// writes it to fd 3.
+pub fn nested_x_command(server: NestedXServer, resolution: &str) -> Result<Command, loga::Error> {
+    check_resolution(resolution)?;
+    let mut command;
+    match server {
+        NestedXServer::Xephyr => {
+            command = Command::new("Xephyr");
+            command.arg("-screen").arg(resolution);
+        },
+        NestedXServer::Xwayland => {
+            command = Command::new("Xwayland");
+            command.arg("-geometry").arg(resolution);
+        },
+    }
+    command.arg("-displayfd").arg("3").arg("-nolisten").arg("tcp");
+    return Ok(command);
+}
+
+/// A nested X server for isolating fullscreen mode changes and input grabs from
+/// the desktop. Stopped when dropped.
+pub struct NestedX {
+    child: Child,
+    /// Value for `DISPLAY`, ex: `:10`
+    pub display: String,
+}
+
+impl NestedX {
+    pub fn start(log: &StandardLog, server: NestedXServer, resolution: &str) -> Result<NestedX, loga::Error> {
+        require(Capability::Display, "`--nested-x`")?;
+        let bin = match server {
+            NestedXServer::Xephyr => "Xephyr",
+            NestedXServer::Xwayland => "Xwayland",
+        };
+        if find_bin(bin).is_none() {
+            return Err(
+                loga::err_with(
+                    "`--nested-x` needs the nested X server, but it wasn't found in PATH",
+                    ea!(server = bin),
+                ),
+            );
+        }
+        let (display_read, display_write) = pipe().context("Error creating pipe for nested X display number")?;
+        let mut child = {
+            let mut command = nested_x_command(server, resolution)?;
+            command.stdin(Stdio::null()).fd_mappings(vec![FdMapping {
+                parent_fd: display_write.into(),
+                child_fd: 3,
+            }]).context("Error attaching display pipe to nested X server")?;
+            echo_command(&command);
+            let log = log.fork(ea!(command = command.dbg_str()));
+            log.log(StandardFlag::Debug, "Starting nested X server");
+
+            // Dropping the command closes our copy of the pipe, so reading stops if the
+            // server exits without writing the display
+            command.spawn().stack_context(&log, "Failed to start nested X server")?
+        };
+        let mut display = String::new();
+        let read = BufReader::new(display_read).read_line(&mut display);
+        let display = display.trim();
+        if read.is_err() || display.is_empty() {
+            signals::kill(child.id(), libc::SIGTERM);
+            _ = child.wait();
+            return Err(loga::err("Nested X server exited without starting"));
+        }
+        return Ok(NestedX {
+            display: format!(":{}", display),
+            child: child,
+        });
+    }
+}
+
+impl Drop for NestedX {
+    fn drop(&mut self) {
+        signals::kill(self.child.id(), libc::SIGTERM);
+        _ = self.child.wait();
+    }
+}
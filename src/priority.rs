@@ -0,0 +1,110 @@
+use {
+    aargvark::{
+        AargvarkFromStr,
+        HelpPattern,
+        HelpPatternElement,
+        HelpState,
+    },
+    loga::ea,
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+    std::ffi::OsString,
+};
+
+/// IO scheduling class and level, for `ionice`.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum IoPriority {
+    /// Only gets disk time when nothing else needs it
+    Idle,
+    /// Best-effort level, 0 (highest) to 7
+    BestEffort(u8),
+}
+
+impl TryFrom<String> for IoPriority {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if value == "idle" {
+            return Ok(IoPriority::Idle);
+        }
+        match value.parse::<u8>() {
+            Ok(level) if level <= 7 => return Ok(IoPriority::BestEffort(level)),
+            _ => return Err(format!("Expected an IO priority (`idle` or a level from 0 to 7), got `{}`", value)),
+        }
+    }
+}
+
+impl From<IoPriority> for String {
+    fn from(value: IoPriority) -> Self {
+        match value {
+            IoPriority::Idle => return "idle".to_string(),
+            IoPriority::BestEffort(level) => return level.to_string(),
+        }
+    }
+}
+
+impl AargvarkFromStr for IoPriority {
+    fn from_str(s: &str) -> Result<Self, String> {
+        return IoPriority::try_from(s.to_string());
+    }
+
+    fn build_help_pattern(_state: &mut HelpState) -> HelpPattern {
+        return HelpPattern(vec![HelpPatternElement::Type("idle|LEVEL".to_string())]);
+    }
+}
+
+/// Which CPUs programs in a system may use and their scheduling priority. Applied
+/// to the process started, so everything it starts inherits it.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ProcessPriority {
+    /// CPUs to pin to, as for `taskset --cpu-list`, ex: `0-3` or `0,2`.
+    #[serde(default)]
+    pub cpus: Option<String>,
+    /// Niceness, from -20 (highest priority) to 19 (lowest). Raising the priority
+    /// needs privileges.
+    #[serde(default)]
+    pub nice: Option<i32>,
+    /// IO priority, `idle` or a best-effort level from 0 (highest) to 7.
+    #[serde(default)]
+    pub ionice: Option<IoPriority>,
+}
+
+impl ProcessPriority {
+    /// These settings, replaced by the ones set in `other`.
+    pub fn merge(&self, other: &ProcessPriority) -> ProcessPriority {
+        return ProcessPriority {
+            cpus: other.cpus.clone().or_else(|| self.cpus.clone()),
+            nice: other.nice.or(self.nice),
+            ionice: other.ionice.or(self.ionice),
+        };
+    }
+
+    /// The command line to run a program through (`taskset`, `nice`, `ionice`) to
+    /// apply the settings, empty if none are set.
+    pub fn wrapper(&self) -> Result<Vec<OsString>, loga::Error> {
+        let mut out: Vec<OsString> = vec![];
+        if let Some(cpus) = &self.cpus {
+            if cpus.is_empty() || !cpus.chars().all(|c| c.is_ascii_digit() || c == ',' || c == '-') {
+                return Err(loga::err_with("Invalid CPU list, expected ex: `0-3` or `0,2`", ea!(cpus = cpus)));
+            }
+            out.extend(["taskset".into(), "--cpu-list".into(), cpus.into()]);
+        }
+        if let Some(nice) = self.nice {
+            if !(-20 ..= 19).contains(&nice) {
+                return Err(loga::err_with("Niceness must be from -20 to 19", ea!(nice = nice)));
+            }
+            out.extend(["nice".into(), "-n".into(), nice.to_string().into()]);
+        }
+        match self.ionice {
+            Some(IoPriority::Idle) => out.extend(["ionice".into(), "-c".into(), "3".into()]),
+            Some(IoPriority::BestEffort(level)) => out.extend(
+                ["ionice".into(), "-c".into(), "2".into(), "-n".into(), level.to_string().into()],
+            ),
+            None => { },
+        }
+        return Ok(out);
+    }
+}
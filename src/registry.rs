@@ -0,0 +1,452 @@
+use {
+    loga::{
+        ea,
+        ResultContext,
+    },
+    serde::Serialize,
+    std::{
+        collections::{
+            BTreeMap,
+            BTreeSet,
+        },
+        fs,
+        path::Path,
+    },
+};
+
+/// Read a `.reg` file, converting from UTF-16 (what `regedit` exports) if needed.
+pub fn read_registry_file(path: &Path) -> Result<String, loga::Error> {
+    let data = fs::read(path).context_with("Error reading registry file", ea!(path = path.to_string_lossy()))?;
+    if let Some(utf16) = data.strip_prefix(&[0xff, 0xfe]) {
+        let units = utf16.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect::<Vec<_>>();
+        return String::from_utf16(
+            &units,
+        ).context_with("Registry file isn't valid UTF-16", ea!(path = path.to_string_lossy()));
+    }
+    return String::from_utf8(
+        data,
+    ).context_with("Registry file isn't valid UTF-8", ea!(path = path.to_string_lossy()));
+}
+
+pub fn decode_utf16(data: &[u8]) -> String {
+    let units = data.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect::<Vec<_>>();
+    return String::from_utf16_lossy(&units);
+}
+
+/// Join the strings in a multi-string, dropping the terminating nulls.
+fn join_nul_separated(text: &str) -> String {
+    return text.split('\0').filter(|s| !s.is_empty()).collect::<Vec<_>>().join(", ");
+}
+
+/// Parse a registry string starting after the opening quote (or the key name after
+/// `[`), up to `end`. Returns the unescaped string and the rest of the line after
+/// `end`.
+fn unescape_until(s: &str, end: char) -> Option<(String, &str)> {
+    let mut out = String::new();
+    let mut chars = s.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if c == end {
+            return Some((out, &s[i + c.len_utf8()..]));
+        }
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        let (_, escaped) = chars.next()?;
+        match escaped {
+            'n' => out.push('\n'),
+            'r' => out.push('\r'),
+            't' => out.push('\t'),
+            '0' => out.push('\0'),
+            'x' => {
+                // Wine writes up to 4 hex digits
+                let mut code = 0u32;
+                let mut digits = 0;
+                while digits < 4 {
+                    let Some(d) = chars.peek().and_then(|(_, c)| c.to_digit(16)) else {
+                        break;
+                    };
+                    code = code * 16 + d;
+                    digits += 1;
+                    chars.next();
+                }
+                out.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+            },
+            c => out.push(c),
+        }
+    }
+    return None;
+}
+
+fn parse_hex(data: &str) -> Vec<u8> {
+    return data
+        .split(',')
+        .filter_map(|b| u8::from_str_radix(b.trim().trim_end_matches('\\').trim(), 16).ok())
+        .collect();
+}
+
+/// Binary data that's really text (ex: a product key), as text. Only printable
+/// ASCII (as UTF-16 or single bytes, ignoring trailing nulls) counts, since most
+/// binary data would otherwise decode to some UTF-16 text.
+fn hex_as_text(data: &[u8]) -> Option<String> {
+    let printable = |c: u16| (0x20 ..= 0x7e).contains(&c);
+    if data.len().is_multiple_of(2) {
+        let mut units = data.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect::<Vec<_>>();
+        while units.last() == Some(&0) {
+            units.pop();
+        }
+        if !units.is_empty() && units.iter().all(|u| printable(*u)) {
+            return Some(units.into_iter().map(|u| u as u8 as char).collect());
+        }
+    }
+    let mut bytes = data;
+    while let Some(rest) = bytes.strip_suffix(&[0]) {
+        bytes = rest;
+    }
+    if !bytes.is_empty() && bytes.iter().all(|b| printable(*b as u16)) {
+        return Some(bytes.iter().map(|b| *b as char).collect());
+    }
+    return None;
+}
+
+/// Make a value from a `.reg` file readable: strings unescaped, expandable and
+/// multi-strings stored as hex decoded. Other values are left as-is unless the
+/// data is text.
+pub fn decode_registry_value(value: &str) -> String {
+    if let Some(quoted) = value.strip_prefix('"') {
+        if let Some((text, _)) = unescape_until(quoted, '"') {
+            return text;
+        }
+    }
+    if let Some(rest) = value.strip_prefix("str(") {
+        if let Some((_, quoted)) = rest.split_once("):\"") {
+            if let Some((text, _)) = unescape_until(quoted, '"') {
+                return join_nul_separated(&text);
+            }
+        }
+    }
+    if let Some(rest) = value.strip_prefix("hex(2):").or_else(|| value.strip_prefix("hex(7):")) {
+        return join_nul_separated(&decode_utf16(&parse_hex(rest)));
+    }
+    if let Some((kind, rest)) = value.split_once(':') {
+        if kind.starts_with("hex") {
+            if let Some(text) = hex_as_text(&parse_hex(rest)) {
+                return text;
+            }
+        }
+    }
+    return value.to_string();
+}
+
+pub enum RegistryLine {
+    /// `[KEY]`, unescaped
+    Key(String),
+    /// `"NAME"=VALUE` with the name unescaped (`@` for the default value) and the
+    /// value as written
+    Value {
+        name: String,
+        value: String,
+    },
+}
+
+/// Parse the keys and values in a `.reg` file (wine's `system.reg`/`user.reg` or a
+/// `regedit` export), with the line number (starting at 1) each starts on.
+/// Comments, headers and wine's timestamps are skipped.
+pub fn parse_registry(text: &str) -> Vec<(usize, RegistryLine)> {
+    let mut out = vec![];
+    let mut lines = text.lines().enumerate();
+    while let Some((i, line)) = lines.next() {
+        // Long hex values continue on the next lines
+        let mut line = line.trim().to_string();
+        while line.ends_with('\\') {
+            let Some((_, next)) = lines.next() else {
+                break;
+            };
+            line.pop();
+            line.push_str(next.trim());
+        }
+        if let Some(rest) = line.strip_prefix('[') {
+            if let Some((key, _)) = unescape_until(rest, ']') {
+                out.push((i + 1, RegistryLine::Key(key)));
+            }
+            continue;
+        }
+        let (name, value) = if let Some(rest) = line.strip_prefix("@=") {
+            ("@".to_string(), rest)
+        } else if let Some(rest) = line.strip_prefix('"') {
+            let Some((name, rest)) = unescape_until(rest, '"') else {
+                continue;
+            };
+            let Some(value) = rest.strip_prefix('=') else {
+                continue;
+            };
+            (name, value)
+        } else {
+            continue;
+        };
+        out.push((i + 1, RegistryLine::Value {
+            name: name,
+            value: value.to_string(),
+        }));
+    }
+    return out;
+}
+
+/// Values (as written) by name, by key.
+pub type RegistryTree = BTreeMap<String, BTreeMap<String, String>>;
+
+pub fn registry_tree(text: &str) -> RegistryTree {
+    let mut out = RegistryTree::new();
+    let mut key = String::new();
+    for (_, line) in parse_registry(text) {
+        match line {
+            RegistryLine::Key(k) => {
+                out.entry(k.clone()).or_default();
+                key = k;
+            },
+            RegistryLine::Value { name, value } => {
+                out.entry(key.clone()).or_default().insert(name, value);
+            },
+        }
+    }
+    return out;
+}
+
+/// The registry files in a prefix.
+pub const REGISTRY_FILES: &[&str] = &["system.reg", "user.reg", "userdef.reg"];
+
+/// Read a registry file, empty if it doesn't exist.
+pub fn read_registry_tree(path: Option<&Path>) -> Result<RegistryTree, loga::Error> {
+    let Some(path) = path else {
+        return Ok(RegistryTree::new());
+    };
+    return Ok(registry_tree(&read_registry_file(path)?));
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistryChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+#[derive(Serialize)]
+pub struct RegistryChange {
+    pub key: String,
+    /// None if the whole key was added or removed
+    pub name: Option<String>,
+    pub kind: RegistryChangeKind,
+    /// Decoded, see `decode_registry_value`
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// Keys and values added, removed, or changed going from `old` to `new`, sorted by
+/// key. Values in added keys are listed individually, values in removed keys
+/// aren't.
+pub fn diff_registry(old: &RegistryTree, new: &RegistryTree) -> Vec<RegistryChange> {
+    let mut out = vec![];
+    let keys = old.keys().chain(new.keys()).collect::<BTreeSet<_>>();
+    for key in keys {
+        let old_values = old.get(key);
+        let new_values = new.get(key);
+        match (old_values, new_values) {
+            (Some(_), None) => {
+                out.push(RegistryChange {
+                    key: key.clone(),
+                    name: None,
+                    kind: RegistryChangeKind::Removed,
+                    old: None,
+                    new: None,
+                });
+                continue;
+            },
+            (None, Some(_)) => {
+                out.push(RegistryChange {
+                    key: key.clone(),
+                    name: None,
+                    kind: RegistryChangeKind::Added,
+                    old: None,
+                    new: None,
+                });
+            },
+            _ => { },
+        }
+        let empty = BTreeMap::new();
+        let old_values = old_values.unwrap_or(&empty);
+        let new_values = new_values.unwrap_or(&empty);
+        let names = old_values.keys().chain(new_values.keys()).collect::<BTreeSet<_>>();
+        for name in names {
+            let old_value = old_values.get(name);
+            let new_value = new_values.get(name);
+            let kind = match (old_value, new_value) {
+                (Some(o), Some(n)) if o == n => continue,
+                (Some(_), Some(_)) => RegistryChangeKind::Changed,
+                (Some(_), None) => RegistryChangeKind::Removed,
+                (None, Some(_)) => RegistryChangeKind::Added,
+                (None, None) => unreachable!(),
+            };
+            out.push(RegistryChange {
+                key: key.clone(),
+                name: Some(name.clone()),
+                kind: kind,
+                old: old_value.map(|v| decode_registry_value(v)),
+                new: new_value.map(|v| decode_registry_value(v)),
+            });
+        }
+    }
+    return out;
+}
+
+/// A program listed in the registry's Uninstall keys (Control Panel's "Add/Remove
+/// Programs").
+#[derive(Serialize)]
+pub struct InstalledProgram {
+    /// The Uninstall subkey name, often a GUID.
+    pub id: String,
+    pub name: String,
+    pub version: Option<String>,
+    pub publisher: Option<String>,
+    pub install_location: Option<String>,
+    pub uninstall_string: Option<String>,
+}
+
+/// Programs in the Uninstall keys of a registry file (`system.reg` for all users,
+/// `user.reg` for the current user), including 32-bit programs in a 64-bit prefix.
+/// Entries without a name and hidden system components are skipped, like Windows
+/// does.
+pub fn installed_programs(tree: &RegistryTree) -> Vec<InstalledProgram> {
+    let mut out = vec![];
+    for (key, values) in tree {
+        // ASCII lowercasing keeps byte offsets
+        const UNINSTALL: &str = "\\microsoft\\windows\\currentversion\\uninstall\\";
+        let Some(start) = key.to_ascii_lowercase().find(UNINSTALL) else {
+            continue;
+        };
+        let id = &key[start + UNINSTALL.len()..];
+        if id.is_empty() || id.contains('\\') {
+            continue;
+        }
+        let get = |name: &str| {
+            values
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, v)| decode_registry_value(v))
+                .filter(|v| !v.is_empty())
+        };
+        let Some(name) = get("DisplayName") else {
+            continue;
+        };
+        if get("SystemComponent").as_deref() == Some("dword:00000001") {
+            continue;
+        }
+        out.push(InstalledProgram {
+            id: id.to_string(),
+            name: name,
+            version: get("DisplayVersion"),
+            publisher: get("Publisher"),
+            install_location: get("InstallLocation"),
+            uninstall_string: get("UninstallString"),
+        });
+    }
+    return out;
+}
+
+/// Split a Windows command line (ex: an `UninstallString`) into the program and
+/// arguments. Like Windows, an unquoted program path may contain spaces if it ends
+/// in `.exe`.
+pub fn split_windows_command_line(line: &str) -> Vec<String> {
+    let line = line.trim();
+    let mut out = vec![];
+    let rest;
+    if let Some(quoted) = line.strip_prefix('"') {
+        let (program, r) = quoted.split_once('"').unwrap_or((quoted, ""));
+        out.push(program.to_string());
+        rest = r;
+    } else {
+        let lower = line.to_ascii_lowercase();
+        let end = match lower.find(".exe ") {
+            Some(i) => i + ".exe".len(),
+            None if lower.ends_with(".exe") => line.len(),
+            None => line.find(char::is_whitespace).unwrap_or(line.len()),
+        };
+        out.push(line[..end].to_string());
+        rest = &line[end..];
+    }
+    out.extend(split_windows_args(rest));
+    return out;
+}
+
+/// Split Windows command line arguments, ex: from a shortcut.
+pub fn split_windows_args(args: &str) -> Vec<String> {
+    let mut out = vec![];
+    let mut arg = None::<String>;
+    let mut quoted = false;
+    for c in args.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                arg.get_or_insert_default();
+            },
+            c if c.is_whitespace() && !quoted => {
+                out.extend(arg.take());
+            },
+            c => arg.get_or_insert_default().push(c),
+        }
+    }
+    out.extend(arg);
+    return out;
+}
+
+/// Convert a Windows program path from the registry to a path relative to
+/// `drive_c`, like `system run` takes. Programs without a directory (ex:
+/// `MsiExec.exe`) are looked up in `system32`.
+pub fn windows_program_path(program: &str) -> Result<String, loga::Error> {
+    let program = program.replace('\\', "/");
+    if let Some(rest) = program.strip_prefix("C:/").or_else(|| program.strip_prefix("c:/")) {
+        return Ok(rest.to_string());
+    }
+    if program.contains('/') || program.contains(':') {
+        return Err(loga::err_with("Program isn't on the C: drive", ea!(program = program)));
+    }
+    let mut program = program.to_ascii_lowercase();
+    if !program.contains('.') {
+        program.push_str(".exe");
+    }
+    return Ok(format!("windows/system32/{}", program));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode_registry_value;
+
+    #[test]
+    fn decode_strings() {
+        assert_eq!(decode_registry_value("\"C:\\\\Games\\\\a.exe\""), "C:\\Games\\a.exe");
+        assert_eq!(decode_registry_value("str(2):\"%SystemRoot%\\\\x\""), "%SystemRoot%\\x");
+        assert_eq!(decode_registry_value("hex(7):61,00,00,00,62,00,00,00,00,00"), "a, b");
+        assert_eq!(decode_registry_value("dword:00000001"), "dword:00000001");
+    }
+
+    #[test]
+    fn decode_text_in_binary() {
+        // UTF-16 and single byte text, with terminating nulls
+        assert_eq!(decode_registry_value("hex:41,00,42,00,43,00,00,00"), "ABC");
+        assert_eq!(decode_registry_value("hex:41,42,43,00"), "ABC");
+    }
+
+    #[test]
+    fn decode_binary_stays_hex() {
+        for value in [
+            "hex:de,ad,be,ef",
+            "hex:00,00",
+            "hex:",
+            "hex:01,00,02,00",
+            "hex:41,0a,42,00",
+            "hex(3):e9,00,e8,00",
+        ] {
+            assert_eq!(decode_registry_value(value), value);
+        }
+    }
+}
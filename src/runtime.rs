@@ -0,0 +1,494 @@
+use {
+    crate::{
+        mount::MountBackend,
+        project_dirs,
+        root_dir,
+        system_mount_path,
+        system_path,
+    },
+    loga::{
+        ea,
+        ResultContext,
+    },
+    serde::{
+        de::DeserializeOwned,
+        Deserialize,
+        Serialize,
+    },
+    std::{
+        ffi::OsStr,
+        fs::{
+            self,
+            create_dir_all,
+        },
+        hash::{
+            DefaultHasher,
+            Hash,
+            Hasher,
+        },
+        os::unix::ffi::OsStrExt,
+        path::{
+            Path,
+            PathBuf,
+        },
+        time::{
+            Duration,
+            SystemTime,
+            UNIX_EPOCH,
+        },
+    },
+};
+
+/// Information about an active system mount, written when the mount is created and
+/// removed when it's unmounted.
+#[derive(Serialize, Deserialize)]
+pub struct MountState {
+    pub system_name: String,
+    pub mount_path: PathBuf,
+    /// Pid of the winebasin process that mounted it.
+    pub pid: u32,
+    /// Pids of winebasin processes currently using the mount. The last one to exit
+    /// unmounts it.
+    pub users: Vec<u32>,
+    /// Unix timestamp, seconds.
+    pub started: u64,
+    pub backend: MountBackend,
+    /// Why the backend was selected.
+    pub backend_reason: String,
+}
+
+/// Directory for state that only lives as long as the current boot/login - falls
+/// back to a subdirectory of the data dir if the environment has no runtime dir.
+pub fn runtime_dir() -> Result<PathBuf, loga::Error> {
+    // Keep test state away from the user's real runtime state
+    #[cfg(feature = "test-mode")]
+    if let Some(dir) = crate::test_mode::test_dir() {
+        return Ok(dir.join("run"));
+    }
+    if let Some(p) = project_dirs()?.runtime_dir() {
+        return Ok(p.to_path_buf());
+    }
+    return Ok(root_dir()?.join("run"));
+}
+
+fn mount_states_dir() -> Result<PathBuf, loga::Error> {
+    return Ok(runtime_dir()?.join("mounts"));
+}
+
+pub fn mount_state_path(system_name: &str) -> Result<PathBuf, loga::Error> {
+    return Ok(mount_states_dir()?.join(format!("{}.json", system_name)));
+}
+
+pub fn now_secs() -> u64 {
+    return SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO).as_secs();
+}
+
+pub fn write_mount_state(state: &MountState) -> Result<(), loga::Error> {
+    let dir = mount_states_dir()?;
+    create_dir_all(&dir).context_with("Error creating runtime state dir", ea!(path = dir.to_string_lossy()))?;
+    let path = mount_state_path(&state.system_name)?;
+    fs::write(
+        &path,
+        serde_json::to_vec_pretty(state).unwrap(),
+    ).context_with("Error writing mount state", ea!(path = path.to_string_lossy()))?;
+    return Ok(());
+}
+
+fn read_state<T: DeserializeOwned>(path: &Path) -> Result<T, loga::Error> {
+    return serde_json::from_slice(
+        &fs::read(path).context_with("Error reading runtime state", ea!(path = path.to_string_lossy()))?,
+    ).context_with("Error parsing runtime state", ea!(path = path.to_string_lossy()));
+}
+
+pub fn read_mount_state(system_name: &str) -> Result<Option<MountState>, loga::Error> {
+    let path = mount_state_path(system_name)?;
+    if !path.exists() {
+        return Ok(None);
+    }
+    return Ok(Some(read_state(&path)?));
+}
+
+/// Names of all systems with a mount state file.
+pub fn list_mount_states() -> Result<Vec<String>, loga::Error> {
+    let dir = mount_states_dir()?;
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut out = vec![];
+    for entry in fs::read_dir(&dir).context_with("Error listing mount states", ea!(path = dir.to_string_lossy()))? {
+        let entry = entry.context("Error reading mount state dir entry")?;
+        let path = entry.path();
+        if path.extension() != Some(OsStr::new("json")) {
+            continue;
+        }
+        let Some(name) = path.file_stem() else {
+            continue;
+        };
+        out.push(name.to_string_lossy().to_string());
+    }
+    out.sort();
+    return Ok(out);
+}
+
+pub fn remove_mount_state(system_name: &str) -> Result<(), loga::Error> {
+    let path = mount_state_path(system_name)?;
+    if !path.exists() {
+        return Ok(());
+    }
+    fs::remove_file(&path).context_with("Error removing mount state", ea!(path = path.to_string_lossy()))?;
+    return Ok(());
+}
+
+/// A read-only view of a system mounted at a user-chosen location, which outlives
+/// the winebasin process that created it.
+#[derive(Serialize, Deserialize)]
+pub struct ExportState {
+    pub system_name: String,
+    pub mount_path: PathBuf,
+    pub backend: MountBackend,
+    /// Unix timestamp, seconds.
+    pub started: u64,
+}
+
+fn export_states_dir() -> Result<PathBuf, loga::Error> {
+    return Ok(runtime_dir()?.join("exports"));
+}
+
+fn export_state_path(mount_path: &Path) -> Result<PathBuf, loga::Error> {
+    let mut hasher = DefaultHasher::new();
+    mount_path.hash(&mut hasher);
+    return Ok(export_states_dir()?.join(format!("{:016x}.json", hasher.finish())));
+}
+
+pub fn write_export_state(state: &ExportState) -> Result<(), loga::Error> {
+    let dir = export_states_dir()?;
+    create_dir_all(&dir).context_with("Error creating runtime state dir", ea!(path = dir.to_string_lossy()))?;
+    let path = export_state_path(&state.mount_path)?;
+    fs::write(
+        &path,
+        serde_json::to_vec_pretty(state).unwrap(),
+    ).context_with("Error writing export mount state", ea!(path = path.to_string_lossy()))?;
+    return Ok(());
+}
+
+pub fn list_export_states() -> Result<Vec<ExportState>, loga::Error> {
+    let dir = export_states_dir()?;
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut out = vec![];
+    for entry in fs::read_dir(&dir).context_with("Error listing export states", ea!(path = dir.to_string_lossy()))? {
+        let path = entry.context("Error reading export state dir entry")?.path();
+
+        // Broken files are reported by `broken_state_files`
+        let Ok(state) = read_state::<ExportState>(&path) else {
+            continue;
+        };
+        out.push(state);
+    }
+    out.sort_by(|a, b| (&a.system_name, &a.mount_path).cmp(&(&b.system_name, &b.mount_path)));
+    return Ok(out);
+}
+
+pub fn remove_export_state(mount_path: &Path) -> Result<(), loga::Error> {
+    let path = export_state_path(mount_path)?;
+    if !path.exists() {
+        return Ok(());
+    }
+    fs::remove_file(&path).context_with("Error removing export state", ea!(path = path.to_string_lossy()))?;
+    return Ok(());
+}
+
+/// A `system run --detach` in progress, identified by the pid of the background
+/// winebasin process.
+#[derive(Serialize, Deserialize)]
+pub struct RunState {
+    pub system_name: String,
+    pub pid: u32,
+    pub command: Vec<String>,
+    /// Where the background process' output is written.
+    pub log_path: PathBuf,
+    /// Unix timestamp, seconds.
+    pub started: u64,
+}
+
+pub fn run_states_dir() -> Result<PathBuf, loga::Error> {
+    return Ok(runtime_dir()?.join("runs"));
+}
+
+fn run_state_path(pid: u32) -> Result<PathBuf, loga::Error> {
+    return Ok(run_states_dir()?.join(format!("{}.json", pid)));
+}
+
+pub fn write_run_state(state: &RunState) -> Result<(), loga::Error> {
+    let dir = run_states_dir()?;
+    create_dir_all(&dir).context_with("Error creating runtime state dir", ea!(path = dir.to_string_lossy()))?;
+    let path = run_state_path(state.pid)?;
+    fs::write(
+        &path,
+        serde_json::to_vec_pretty(state).unwrap(),
+    ).context_with("Error writing run state", ea!(path = path.to_string_lossy()))?;
+    return Ok(());
+}
+
+pub fn list_run_states() -> Result<Vec<RunState>, loga::Error> {
+    let dir = run_states_dir()?;
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut out = vec![];
+    for entry in fs::read_dir(&dir).context_with("Error listing run states", ea!(path = dir.to_string_lossy()))? {
+        let path = entry.context("Error reading run state dir entry")?.path();
+        if path.extension() != Some(OsStr::new("json")) {
+            continue;
+        }
+
+        // Broken files are reported by `broken_state_files`
+        let Ok(state) = read_state::<RunState>(&path) else {
+            continue;
+        };
+        out.push(state);
+    }
+    out.sort_by(|a, b| (&a.system_name, a.started).cmp(&(&b.system_name, b.started)));
+    return Ok(out);
+}
+
+/// Remove the run state and its log.
+pub fn remove_run_state(state: &RunState) -> Result<(), loga::Error> {
+    let path = run_state_path(state.pid)?;
+    if path.exists() {
+        fs::remove_file(&path).context_with("Error removing run state", ea!(path = path.to_string_lossy()))?;
+    }
+    if state.log_path.exists() {
+        fs::remove_file(
+            &state.log_path,
+        ).context_with("Error removing detached run log", ea!(path = state.log_path.to_string_lossy()))?;
+    }
+    return Ok(());
+}
+
+/// Runtime state files that can't be read or parsed (ex: truncated by a crash),
+/// which are otherwise skipped.
+pub fn broken_state_files() -> Result<Vec<(PathBuf, loga::Error)>, loga::Error> {
+    let mut out = vec![];
+    for (
+        dir,
+        parse,
+    ) in [
+        (
+            mount_states_dir()?,
+            (|p| read_state::<MountState>(p).map(|_| ())) as fn(&Path) -> Result<(), loga::Error>,
+        ),
+        (export_states_dir()?, |p| read_state::<ExportState>(p).map(|_| ())),
+        (run_states_dir()?, |p| read_state::<RunState>(p).map(|_| ())),
+    ] {
+        if !dir.exists() {
+            continue;
+        }
+        for entry in fs::read_dir(
+            &dir,
+        ).context_with("Error listing runtime state", ea!(path = dir.to_string_lossy()))? {
+            let path = entry.context("Error reading runtime state dir entry")?.path();
+            if path.extension() != Some(OsStr::new("json")) {
+                continue;
+            }
+            if let Err(e) = parse(&path) {
+                out.push((path, e));
+            }
+        }
+    }
+    return Ok(out);
+}
+
+pub struct ProcMount {
+    pub fs_type: String,
+    pub mount_point: PathBuf,
+}
+
+/// Undo the octal escaping (ex: `\040` for space) used in `/proc/mounts`.
+fn unescape_proc_mounts(s: &str) -> Vec<u8> {
+    let s = s.as_bytes();
+    let mut out = vec![];
+    let mut i = 0;
+    while i < s.len() {
+        if s[i] == b'\\' && i + 3 < s.len() && s[i + 1 ..= i + 3].iter().all(|c| (b'0' ..= b'7').contains(c)) {
+            out.push((s[i + 1] - b'0') * 64 + (s[i + 2] - b'0') * 8 + (s[i + 3] - b'0'));
+            i += 4;
+        } else {
+            out.push(s[i]);
+            i += 1;
+        }
+    }
+    return out;
+}
+
+pub fn proc_mounts() -> Result<Vec<ProcMount>, loga::Error> {
+    let path = PathBuf::from("/proc/mounts");
+    #[cfg(feature = "test-mode")]
+    let path = crate::test_mode::test_dir().map(crate::test_mode::mounts_path).unwrap_or(path);
+    let text =
+        fs::read_to_string(&path).context_with("Error reading mount table", ea!(path = path.to_string_lossy()))?;
+    let mut out = vec![];
+    for line in text.lines() {
+        let mut parts = line.split(' ');
+
+        // Skip source
+        parts.next();
+        let Some(mount_point) = parts.next() else {
+            continue;
+        };
+        let Some(fs_type) = parts.next() else {
+            continue;
+        };
+        out.push(ProcMount {
+            fs_type: fs_type.to_string(),
+            mount_point: PathBuf::from(OsStr::from_bytes(&unescape_proc_mounts(mount_point))),
+        });
+    }
+    return Ok(out);
+}
+
+/// Systems with an overlay currently mounted at their mount path, and the backend
+/// that mounted it.
+pub fn mounted_systems() -> Result<Vec<(String, MountBackend)>, loga::Error> {
+    let systems_dir = root_dir()?.join("system");
+    let mut out = vec![];
+    for mount in proc_mounts()? {
+        let Some(backend) = MountBackend::from_fs_type(&mount.fs_type) else {
+            continue;
+        };
+        let Ok(rel) = mount.mount_point.strip_prefix(&systems_dir) else {
+            continue;
+        };
+        let Some(name) = rel.components().next() else {
+            continue;
+        };
+        let name = name.as_os_str().to_string_lossy().to_string();
+        if system_mount_path(&system_path(&name)?) != mount.mount_point {
+            continue;
+        }
+        out.push((name, backend));
+    }
+
+    // The copy backend links the system's prefix instead of mounting
+    if systems_dir.exists() {
+        for entry in fs::read_dir(
+            &systems_dir,
+        ).context_with("Error listing systems", ea!(path = systems_dir.to_string_lossy()))? {
+            let entry = entry.context("Error reading systems dir entry")?;
+            if system_mount_path(&entry.path()).is_symlink() {
+                out.push((entry.file_name().to_string_lossy().to_string(), MountBackend::Copy));
+            }
+        }
+    }
+    return Ok(out);
+}
+
+pub fn is_mounted(mount_path: &Path) -> Result<bool, loga::Error> {
+    return Ok(mount_path.is_symlink() || proc_mounts()?.iter().any(|m| m.mount_point == mount_path));
+}
+
+pub fn pid_alive(pid: u32) -> bool {
+    let Ok(stat) = fs::read_to_string(Path::new("/proc").join(pid.to_string()).join("stat")) else {
+        return false;
+    };
+
+    // Zombies (exited but not yet reaped) aren't alive. The state follows the
+    // parenthesized command name, which may itself contain spaces or parentheses.
+    return !stat.rsplit_once(')').is_some_and(|(_, rest)| rest.trim_start().starts_with('Z'));
+}
+
+pub enum Staleness {
+    /// Mounted, but the owning winebasin process is gone.
+    Mount(MountBackend),
+    /// Runtime state remains but nothing is mounted.
+    State,
+}
+
+/// Find systems with leftover mounts or runtime state from winebasin processes
+/// that exited without cleaning up (ex: killed).
+pub fn stale_systems() -> Result<Vec<(String, Staleness)>, loga::Error> {
+    let mounted = mounted_systems()?;
+    let mut out = vec![];
+    for (name, backend) in &mounted {
+        // Unreadable state is treated as missing
+        match read_mount_state(name).ok().flatten() {
+            Some(state) if state.users.iter().any(|p| pid_alive(*p)) => { },
+            _ => out.push((name.clone(), Staleness::Mount(*backend))),
+        }
+    }
+    for name in list_mount_states()? {
+        if !mounted.iter().any(|(n, _)| *n == name) {
+            out.push((name, Staleness::State));
+        }
+    }
+    return Ok(out);
+}
+
+#[derive(Serialize)]
+pub struct PrefixProcess {
+    pub pid: u32,
+    pub cmdline: Vec<String>,
+}
+
+/// Find processes (visible to the current user) whose `WINEPREFIX` is `prefix`.
+pub fn prefix_processes(prefix: &Path) -> Result<Vec<PrefixProcess>, loga::Error> {
+    let mut want = b"WINEPREFIX=".to_vec();
+    want.extend(prefix.as_os_str().as_bytes());
+    let mut out = vec![];
+    for entry in fs::read_dir("/proc").context("Error listing /proc")? {
+        let Ok(entry) = entry else {
+            continue;
+        };
+        let Some(pid) = entry.file_name().to_str().and_then(|x| x.parse::<u32>().ok()) else {
+            continue;
+        };
+
+        // Processes may exit or belong to other users, ignore read failures
+        let Ok(environ) = fs::read(entry.path().join("environ")) else {
+            continue;
+        };
+        if !environ.split(|c| *c == 0).any(|e| e == want) {
+            continue;
+        }
+        let cmdline = fs::read(entry.path().join("cmdline")).unwrap_or_default();
+        out.push(PrefixProcess {
+            pid: pid,
+            cmdline: cmdline
+                .split(|c| *c == 0)
+                .filter(|x| !x.is_empty())
+                .map(|x| String::from_utf8_lossy(x).to_string())
+                .collect(),
+        });
+    }
+    out.sort_by_key(|p| p.pid);
+    return Ok(out);
+}
+
+pub fn format_duration(secs: u64) -> String {
+    let (d, h, m, s) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    if d > 0 {
+        return format!("{}d {}h {}m", d, h, m);
+    }
+    if h > 0 {
+        return format!("{}h {}m {}s", h, m, s);
+    }
+    if m > 0 {
+        return format!("{}m {}s", m, s);
+    }
+    return format!("{}s", s);
+}
+
+pub fn format_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024. && unit + 1 < units.len() {
+        size /= 1024.;
+        unit += 1;
+    }
+    if unit == 0 {
+        return format!("{}B", bytes);
+    }
+    return format!("{:.1}{}", size, units[unit]);
+}
@@ -0,0 +1,115 @@
+use {
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+    std::{
+        collections::HashMap,
+        env,
+        ffi::{
+            OsStr,
+            OsString,
+        },
+        path::{
+            Path,
+            PathBuf,
+        },
+        process::Command,
+    },
+};
+
+/// Host directories programs need (libraries, config, fonts), exposed read-only.
+pub const SYSTEM_DIRS: &[&str] =
+    &["/usr", "/etc", "/opt", "/bin", "/sbin", "/lib", "/lib32", "/lib64", "/nix", "/run/systemd/resolve"];
+
+/// Environment variables with paths the wine process tree writes to (the prefix,
+/// Proton's compat data and shader caches).
+pub const WRITABLE_PATH_VARS: &[&str] =
+    &[
+        "WINEPREFIX",
+        "STEAM_COMPAT_DATA_PATH",
+        "DXVK_STATE_CACHE_PATH",
+        "VKD3D_SHADER_CACHE_PATH",
+        "MESA_SHADER_CACHE_DIR",
+        "__GL_SHADER_DISK_CACHE_PATH",
+    ];
+
+/// Environment variables with paths the wine process tree only reads.
+pub const READ_ONLY_PATH_VARS: &[&str] = &["STEAM_COMPAT_CLIENT_INSTALL_PATH", "PROTONPATH", "VKBASALT_CONFIG_FILE"];
+
+/// Host paths a sandboxed (or Landlock confined) system can use in addition to its
+/// prefix.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct SandboxConfig {
+    /// Paths the programs can read and write (ex: a directory of mods).
+    #[serde(default)]
+    pub allow: Vec<PathBuf>,
+    /// Paths the programs can only read (ex: an ISO collection).
+    #[serde(default)]
+    pub allow_read_only: Vec<PathBuf>,
+}
+
+/// The installation a program in `bin/` belongs to (ex: `/opt/wine-staging`), or
+/// the program's directory.
+pub fn install_dir(program: &Path) -> Option<&Path> {
+    let dir = program.parent()?;
+    if dir.file_name() == Some(OsStr::new("bin")) {
+        return dir.parent();
+    }
+    return Some(dir);
+}
+
+/// The `bwrap` command line to run `command` (the wine command) in a sandbox that
+/// only has the system's prefix, the host's system directories, devices, display
+/// and audio sockets, the wine installation, and the allowed paths. The home
+/// directory is replaced with an empty one.
+pub fn bwrap_wrapper(config: &SandboxConfig, env: &HashMap<OsString, OsString>, command: &Command) -> Vec<OsString> {
+    let mut out: Vec<OsString> = vec!["bwrap".into(), "--die-with-parent".into(), "--new-session".into()];
+    let bind = |out: &mut Vec<OsString>, flag: &str, path: &Path| {
+        out.extend([flag.into(), path.as_os_str().to_os_string(), path.as_os_str().to_os_string()]);
+    };
+    for dir in SYSTEM_DIRS {
+        bind(&mut out, "--ro-bind-try", Path::new(dir));
+    }
+    bind(&mut out, "--dev-bind", Path::new("/dev"));
+    bind(&mut out, "--ro-bind", Path::new("/sys"));
+
+    // X11 sockets and the wineserver socket, shared with the wineserver started
+    // outside the sandbox
+    bind(&mut out, "--bind", Path::new("/tmp"));
+    if let Some(runtime_dir) = env::var_os("XDG_RUNTIME_DIR") {
+        // Wayland, PulseAudio/PipeWire and the session bus
+        bind(&mut out, "--bind-try", Path::new(&runtime_dir));
+    }
+    out.extend(["--proc".into(), "/proc".into()]);
+    if let Some(home) = env::var_os("HOME") {
+        out.extend(["--tmpfs".into(), home]);
+    }
+
+    // After the home dir, since these are often inside it
+    for program in [command.get_program(), env.get(OsStr::new("WINE")).map(|p| p.as_os_str()).unwrap_or_default()] {
+        let program = Path::new(program);
+        if program.is_absolute() {
+            if let Some(dir) = install_dir(program) {
+                bind(&mut out, "--ro-bind-try", dir);
+            }
+        }
+    }
+    for key in READ_ONLY_PATH_VARS {
+        if let Some(path) = env.get(OsStr::new(key)) {
+            bind(&mut out, "--ro-bind-try", Path::new(path));
+        }
+    }
+    for key in WRITABLE_PATH_VARS {
+        if let Some(path) = env.get(OsStr::new(key)) {
+            bind(&mut out, "--bind-try", Path::new(path));
+        }
+    }
+    for path in &config.allow_read_only {
+        bind(&mut out, "--ro-bind-try", path);
+    }
+    for path in &config.allow {
+        bind(&mut out, "--bind-try", path);
+    }
+    return out;
+}
@@ -0,0 +1,127 @@
+use {
+    loga::{
+        ea,
+        ResultContext,
+    },
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+    std::{
+        collections::BTreeMap,
+        env,
+        process::{
+            Command,
+            Stdio,
+        },
+        sync::Mutex,
+    },
+};
+
+/// Where to get a value that shouldn't be written in the config (ex: a proxy
+/// password), looked up when a program starts.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum SecretRef {
+    /// A host environment variable
+    Env(String),
+    /// The first line of a `pass` entry (ex: `work/proxy`)
+    Pass(String),
+    /// An item in the desktop keyring (GNOME Keyring, KWallet) with these attributes,
+    /// looked up with `secret-tool`
+    Keyring(BTreeMap<String, String>),
+}
+
+/// What to do with a system's `secret_env` when building its environment.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SecretEnv {
+    /// Look up the values, for running programs
+    Resolve,
+    /// Use where the values come from instead, for `--dry-run`
+    Mask,
+    /// Leave them out, for maintenance commands that don't run the user's programs
+    Omit,
+}
+
+/// Values that have been looked up (and their escaped forms), hidden in logged
+/// command lines.
+static RESOLVED: Mutex<Vec<String>> = Mutex::new(vec![]);
+
+impl SecretRef {
+    /// Where the value comes from, shown in place of it.
+    pub fn describe(&self) -> String {
+        match self {
+            SecretRef::Env(name) => return format!("<secret from env {}>", name),
+            SecretRef::Pass(name) => return format!("<secret from pass {}>", name),
+            SecretRef::Keyring(attributes) => return format!(
+                "<secret from keyring {}>",
+                attributes.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>().join(" ")
+            ),
+        }
+    }
+
+    pub fn resolve(&self) -> Result<String, loga::Error> {
+        let value = match self {
+            SecretRef::Env(name) => env::var(
+                name,
+            ).context_with("Environment variable for secret isn't set", ea!(name = name))?,
+            SecretRef::Pass(name) => {
+                let output =
+                    Command::new("pass")
+                        .arg("show")
+                        .arg(name)
+                        .stdin(Stdio::inherit())
+                        .stderr(Stdio::inherit())
+                        .output()
+                        .context_with("Error running `pass`, is it installed?", ea!(name = name))?;
+                if !output.status.success() {
+                    return Err(loga::err_with("Error getting secret from `pass`", ea!(name = name)));
+                }
+                String::from_utf8_lossy(&output.stdout).lines().next().unwrap_or_default().to_string()
+            },
+            SecretRef::Keyring(attributes) => {
+                let output =
+                    Command::new("secret-tool")
+                        .arg("lookup")
+                        .args(attributes.iter().flat_map(|(k, v)| [k, v]))
+                        .stderr(Stdio::inherit())
+                        .output()
+                        .context("Error running `secret-tool`, is it installed?")?;
+                if !output.status.success() {
+                    return Err(
+                        loga::err_with(
+                            "No secret in the keyring with these attributes",
+                            ea!(secret = self.describe()),
+                        ),
+                    );
+                }
+                String::from_utf8_lossy(&output.stdout).trim_end_matches('\n').to_string()
+            },
+        };
+        if !value.is_empty() {
+            let mut resolved = RESOLVED.lock().unwrap();
+
+            // As it appears in `Command` debug output, where quotes and backslashes (and
+            // control characters) are escaped
+            let escaped = value.escape_debug().to_string();
+            if escaped != value {
+                resolved.push(escaped);
+            }
+            resolved.push(value.clone());
+
+            // Longest first, so a value isn't partly replaced as part of a longer one
+            resolved.sort_by_key(|v| std::cmp::Reverse(v.len()));
+        }
+        return Ok(value);
+    }
+}
+
+/// Replace looked up secret values in text (ex: a command line with its
+/// environment) before it's shown.
+pub fn mask_secrets(text: String) -> String {
+    let mut out = text;
+    for value in RESOLVED.lock().unwrap().iter() {
+        out = out.replace(value.as_str(), "<secret>");
+    }
+    return out;
+}
@@ -0,0 +1,301 @@
+use {
+    crate::{
+        echo_command,
+        signals::{
+            self,
+            Handler,
+        },
+        secrets::mask_secrets,
+        template::expand_template,
+        wine_program_command,
+        wineserver_bin,
+    },
+    loga::{
+        ea,
+        DebugDisplay,
+        ErrContext,
+        ResultContext,
+        StandardFlag,
+        StandardLog,
+    },
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+    std::{
+        collections::{
+            BTreeMap,
+            HashMap,
+        },
+        env,
+        ffi::OsString,
+        fs::{
+            self,
+            create_dir_all,
+            File,
+            OpenOptions,
+        },
+        io::{
+            Read,
+            Write,
+        },
+        path::{
+            Path,
+            PathBuf,
+        },
+        process::{
+            Command,
+            Stdio,
+        },
+        sync::{
+            Arc,
+            Mutex,
+        },
+        thread::{
+            self,
+            sleep,
+        },
+        time::{
+            Duration,
+            Instant,
+        },
+    },
+};
+
+fn default_restart_delay_secs() -> u64 {
+    return 5;
+}
+
+fn default_log_max_mb() -> u64 {
+    return 10;
+}
+
+fn default_log_keep() -> usize {
+    return 5;
+}
+
+/// Settings for running a system unattended with `system server`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ServerConfig {
+    /// Command and arguments, relative to `drive_c`. Templated (see `template`).
+    pub command: Vec<String>,
+    /// Extra environment variables for the server. Values are templated.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Keep `DISPLAY`/`WAYLAND_DISPLAY` so the server can open windows. By default
+    /// servers run headless.
+    #[serde(default)]
+    pub gui: bool,
+    /// How long to wait before restarting the server after it exits with an error.
+    #[serde(default = "default_restart_delay_secs")]
+    pub restart_delay_secs: u64,
+    /// Start a new log file when the current one exceeds this size.
+    #[serde(default = "default_log_max_mb")]
+    pub log_max_mb: u64,
+    /// How many old log files to keep.
+    #[serde(default = "default_log_keep")]
+    pub log_keep: usize,
+}
+
+/// Expand `{...}` placeholders: `{system}` (system name), `{drive_c}` (path to the
+/// mounted `drive_c`), and `{env:NAME}` (host environment variable `NAME`, ex: for
+/// tokens that shouldn't be written in the config). `{{` and `}}` are literal
+/// braces.
+pub fn template(text: &str, system_name: &str, drive_c: &Path) -> Result<String, loga::Error> {
+    return expand_template(text, |key| {
+        if key == "system" {
+            return Ok(Some(system_name.to_string()));
+        } else if key == "drive_c" {
+            return Ok(Some(drive_c.to_string_lossy().to_string()));
+        } else if let Some(name) = key.strip_prefix("env:") {
+            return Ok(
+                Some(
+                    env::var(
+                        name,
+                    ).context_with("Environment variable in server config template isn't set", ea!(name = name))?,
+                ),
+            );
+        } else {
+            return Ok(None);
+        }
+    }).context("Error expanding server config template");
+}
+
+/// Server output log, moved aside (`server.log.1`, `server.log.2`, ...) when it
+/// gets too big.
+struct RotatingLog {
+    dir: PathBuf,
+    file: File,
+    size: u64,
+    max: u64,
+    keep: usize,
+}
+
+impl RotatingLog {
+    fn path(dir: &Path, i: usize) -> PathBuf {
+        if i == 0 {
+            return dir.join("server.log");
+        } else {
+            return dir.join(format!("server.log.{}", i));
+        }
+    }
+
+    fn open(dir: &Path, max: u64, keep: usize) -> Result<RotatingLog, loga::Error> {
+        create_dir_all(dir).context_with("Error creating server log dir", ea!(path = dir.to_string_lossy()))?;
+        let path = Self::path(dir, 0);
+        let file =
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .context_with("Error opening server log", ea!(path = path.to_string_lossy()))?;
+        let size = file.metadata().context("Error reading server log metadata")?.len();
+        return Ok(RotatingLog {
+            dir: dir.to_path_buf(),
+            file: file,
+            size: size,
+            max: max,
+            keep: keep,
+        });
+    }
+
+    fn rotate(&mut self) -> Result<(), loga::Error> {
+        for i in (1 ..= self.keep).rev() {
+            let from = Self::path(&self.dir, i - 1);
+            if from.exists() {
+                fs::rename(
+                    &from,
+                    Self::path(&self.dir, i),
+                ).context_with("Error rotating server log", ea!(path = from.to_string_lossy()))?;
+            }
+        }
+        let path = Self::path(&self.dir, 0);
+        self.file =
+            File::create(&path).context_with("Error creating server log", ea!(path = path.to_string_lossy()))?;
+        self.size = 0;
+        return Ok(());
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), loga::Error> {
+        if self.size > 0 && self.size + data.len() as u64 > self.max {
+            self.rotate()?;
+        }
+        self.file.write_all(data).context("Error writing server log")?;
+        self.size += data.len() as u64;
+        return Ok(());
+    }
+}
+
+fn copy_to_log(log: &StandardLog, mut source: impl Read, dest: Arc<Mutex<RotatingLog>>) {
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = match source.read(&mut buf) {
+            Ok(0) => return,
+            Ok(n) => n,
+            Err(e) => {
+                log.log_err(StandardFlag::Warning, e.context("Error reading server output"));
+                return;
+            },
+        };
+        if let Err(e) = dest.lock().unwrap().write(&buf[..n]) {
+            log.log_err(StandardFlag::Warning, e);
+        }
+    }
+}
+
+/// Run the server in the mounted system, restarting it if it exits with an error,
+/// until it exits successfully or winebasin is asked to terminate.
+pub fn run_server(
+    log: &StandardLog,
+    system_name: &str,
+    config: &ServerConfig,
+    wine_env: &HashMap<OsString, OsString>,
+    mount_path: &Path,
+    log_dir: &Path,
+) -> Result<(), loga::Error> {
+    let drive_c_path = mount_path.join("drive_c");
+    let mut command = vec![];
+    for arg in &config.command {
+        command.push(template(arg, system_name, &drive_c_path)?);
+    }
+    if command.is_empty() {
+        return Err(loga::err("Server command in system config is empty"));
+    }
+    let command_command = drive_c_path.join(command.remove(0));
+    let mut env = vec![];
+    for (k, v) in &config.env {
+        env.push((k.clone(), template(v, system_name, &drive_c_path)?));
+    }
+    let output =
+        Arc::new(Mutex::new(RotatingLog::open(log_dir, config.log_max_mb * 1024 * 1024, config.log_keep)?));
+    let wine_env_owned =
+        wine_env.iter().map(|(k, v)| (k.to_os_string(), v.clone())).collect::<Vec<(OsString, OsString)>>();
+    let wineserver = wineserver_bin(wine_env);
+    loop {
+        let mut child_command = wine_program_command(wine_env)?;
+        child_command
+            .envs(wine_env)
+            .envs(env.iter().cloned())
+            .current_dir(command_command.parent().unwrap_or(drive_c_path.as_path()))
+            .arg(&command_command)
+            .args(&command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if !config.gui {
+            child_command.env_remove("DISPLAY").env_remove("WAYLAND_DISPLAY");
+        }
+        let command_log = log.fork(ea!(command = mask_secrets(child_command.dbg_str())));
+        command_log.log(StandardFlag::Info, "Starting server");
+        echo_command(&child_command);
+        let mut child = child_command.spawn().stack_context(&command_log, "Failed to spawn server")?;
+        let copiers = [thread::spawn({
+            let log = command_log.clone();
+            let stdout = child.stdout.take().unwrap();
+            let output = output.clone();
+            move || copy_to_log(&log, stdout, output)
+        }), thread::spawn({
+            let log = command_log.clone();
+            let stderr = child.stderr.take().unwrap();
+            let output = output.clone();
+            move || copy_to_log(&log, stderr, output)
+        })];
+        signals::set_handler(Handler::Call(Box::new({
+            let pid = child.id();
+            let wine_env = wine_env_owned.clone();
+            let wineserver = wineserver.clone();
+            move |sig| {
+                signals::kill(pid, sig);
+                _ = Command::new(&wineserver).envs(wine_env.clone()).arg("-k").status();
+            }
+        })));
+        let status = child.wait().stack_context(&command_log, "Error waiting for server");
+        signals::set_handler(Handler::Record);
+        let status = status?;
+
+        // Stop anything the server left behind
+        _ = Command::new(&wineserver).envs(wine_env).arg("-k").status();
+        for copier in copiers {
+            _ = copier.join();
+        }
+        if signals::received().is_some() {
+            return Ok(());
+        }
+        if status.success() {
+            command_log.log(StandardFlag::Info, "Server exited");
+            return Ok(());
+        }
+        command_log.log_with(
+            StandardFlag::Warning,
+            "Server exited with error, restarting",
+            ea!(status = status, delay_secs = config.restart_delay_secs),
+        );
+        let restart_at = Instant::now() + Duration::from_secs(config.restart_delay_secs);
+        while Instant::now() < restart_at {
+            if signals::received().is_some() {
+                return Ok(());
+            }
+            sleep(Duration::from_millis(200));
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use {
+    crate::{
+        mount::MountBackend,
+        quote_subcommand,
+        root_dir,
+    },
+    directories::BaseDirs,
+    loga::{
+        ea,
+        ResultContext,
+    },
+    std::{
+        env::current_exe,
+        path::PathBuf,
+    },
+};
+
+pub fn service_name(system_name: &str) -> String {
+    return format!("winebasin-{}.service", system_name);
+}
+
+/// Where systemd looks for user units, ex: `~/.config/systemd/user/`.
+pub fn service_path(system_name: &str) -> Result<PathBuf, loga::Error> {
+    let base = BaseDirs::new().context("Unable to determine user config directory")?;
+    return Ok(base.config_dir().join("systemd").join("user").join(service_name(system_name)));
+}
+
+/// Build a systemd user unit that runs winebasin with the arguments (ex:
+/// `system run NAME COMMAND...`) in the foreground. Systemd stops it with SIGTERM
+/// to winebasin only (`KillMode=mixed`) so winebasin can stop wine and unmount
+/// before anything else is killed.
+pub fn service_unit(
+    system_name: &str,
+    backend: MountBackend,
+    winebasin_args: &[&str],
+) -> Result<String, loga::Error> {
+    let exe = current_exe().context("Error determining path to winebasin executable")?;
+    let root = root_dir()?;
+    let mut args =
+        vec![
+            exe.to_str().context_with("Winebasin executable path isn't utf-8", ea!(path = exe.to_string_lossy()))?,
+            "--root",
+            root.to_str().context_with("Winebasin data root path isn't utf-8", ea!(path = root.to_string_lossy()))?,
+            "--backend",
+            backend.name()
+        ];
+    args.extend(winebasin_args);
+    let exec_start = String::from_utf8(quote_subcommand(args.iter().map(|x| x.as_bytes()))?).unwrap();
+
+    // Escape systemd specifiers and env var expansion
+    let exec_start = exec_start.replace('%', "%%").replace('$', "$$");
+    return Ok(
+        format!(
+            "[Unit]\nDescription=winebasin system {}\n\n[Service]\nExecStart={}\nKillMode=mixed\n\n[Install]\nWantedBy=default.target\n",
+            system_name,
+            exec_start
+        ),
+    );
+}
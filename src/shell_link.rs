@@ -0,0 +1,263 @@
+use {
+    crate::{
+        drives::unix_path,
+        registry::split_windows_args,
+    },
+    loga::{
+        ea,
+        ResultContext,
+    },
+    std::{
+        fs,
+        path::{
+            Path,
+            PathBuf,
+        },
+    },
+};
+
+// See [MS-SHLLINK]
+const HEADER_SIZE: usize = 0x4c;
+const HAS_LINK_TARGET_ID_LIST: u32 = 0x1;
+const HAS_LINK_INFO: u32 = 0x2;
+const HAS_NAME: u32 = 0x4;
+const HAS_RELATIVE_PATH: u32 = 0x8;
+const HAS_WORKING_DIR: u32 = 0x10;
+const HAS_ARGUMENTS: u32 = 0x20;
+const IS_UNICODE: u32 = 0x80;
+const VOLUME_ID_AND_LOCAL_BASE_PATH: u32 = 0x1;
+
+/// What a Windows shortcut (`.lnk`) starts. Paths are Windows paths.
+pub struct ShellLink {
+    /// Absolute path of the program, if the shortcut has one
+    pub target: Option<String>,
+    /// Path of the program relative to the shortcut
+    pub relative_path: Option<String>,
+    pub working_dir: Option<String>,
+    /// The arguments as a command line
+    pub arguments: Option<String>,
+}
+
+/// `base + add`, for offsets read from the file.
+fn add_offset(base: usize, add: usize) -> Result<usize, String> {
+    return base.checked_add(add).ok_or_else(|| "Shortcut offset out of range".to_string());
+}
+
+/// `count` bytes at `at`.
+fn read_bytes(data: &[u8], at: usize, count: usize) -> Result<&[u8], String> {
+    return data
+        .get(at .. add_offset(at, count)?)
+        .ok_or_else(|| format!("Truncated shortcut (reading {} bytes at offset {})", count, at));
+}
+
+fn read_u16(data: &[u8], at: usize) -> Result<u16, String> {
+    return read_bytes(data, at, 2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+}
+
+fn read_u32(data: &[u8], at: usize) -> Result<u32, String> {
+    return read_bytes(data, at, 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+}
+
+/// A null-terminated string, ANSI (read as Latin-1) or UTF-16.
+fn read_cstr(data: &[u8], at: usize, unicode: bool) -> Result<String, String> {
+    let rest = data.get(at..).ok_or_else(|| format!("Truncated shortcut (reading offset {})", at))?;
+    if unicode {
+        let units =
+            rest
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .take_while(|u| *u != 0)
+                .collect::<Vec<_>>();
+        return Ok(String::from_utf16_lossy(&units));
+    }
+    return Ok(rest.iter().take_while(|b| **b != 0).map(|b| *b as char).collect());
+}
+
+/// The target path from the link info structure.
+fn read_link_info(data: &[u8], at: usize) -> Result<Option<String>, String> {
+    let header_size = read_u32(data, add_offset(at, 4)?)? as usize;
+    let flags = read_u32(data, add_offset(at, 8)?)?;
+    if flags & VOLUME_ID_AND_LOCAL_BASE_PATH == 0 {
+        // Network path
+        return Ok(None);
+    }
+    let (base, suffix) = if header_size >= 0x24 {
+        (
+            read_cstr(data, add_offset(at, read_u32(data, add_offset(at, 0x1c)?)? as usize)?, true)?,
+            read_cstr(data, add_offset(at, read_u32(data, add_offset(at, 0x20)?)? as usize)?, true)?,
+        )
+    } else {
+        (
+            read_cstr(data, add_offset(at, read_u32(data, add_offset(at, 0x10)?)? as usize)?, false)?,
+            read_cstr(data, add_offset(at, read_u32(data, add_offset(at, 0x18)?)? as usize)?, false)?,
+        )
+    };
+    return Ok(Some(format!("{}{}", base, suffix)));
+}
+
+pub fn parse_shell_link(data: &[u8]) -> Result<ShellLink, String> {
+    if read_u32(data, 0)? as usize != HEADER_SIZE {
+        return Err("Not a Windows shortcut (bad header size)".to_string());
+    }
+    let flags = read_u32(data, 0x14)?;
+    let unicode = flags & IS_UNICODE != 0;
+    let mut at = HEADER_SIZE;
+    if flags & HAS_LINK_TARGET_ID_LIST != 0 {
+        at = add_offset(at, 2 + read_u16(data, at)? as usize)?;
+    }
+    let mut target = None;
+    if flags & HAS_LINK_INFO != 0 {
+        target = read_link_info(data, at)?;
+        at = add_offset(at, read_u32(data, at)? as usize)?;
+    }
+
+    // Sized strings, in this order if present
+    let mut strings = vec![];
+    for flag in [HAS_NAME, HAS_RELATIVE_PATH, HAS_WORKING_DIR, HAS_ARGUMENTS] {
+        if flags & flag == 0 {
+            strings.push(None);
+            continue;
+        }
+        let count = read_u16(data, at)? as usize;
+        at = add_offset(at, 2)?;
+        let string = if unicode {
+            let bytes = read_bytes(data, at, count * 2)?;
+            at = add_offset(at, count * 2)?;
+            String::from_utf16_lossy(
+                &bytes.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect::<Vec<_>>(),
+            )
+        } else {
+            let bytes = read_bytes(data, at, count)?;
+            at = add_offset(at, count)?;
+            bytes.iter().map(|b| *b as char).collect()
+        };
+        strings.push(Some(string).filter(|s| !s.is_empty()));
+    }
+    let mut strings = strings.into_iter().skip(1);
+    return Ok(ShellLink {
+        target: target.filter(|t| !t.is_empty()),
+        relative_path: strings.next().unwrap(),
+        working_dir: strings.next().unwrap(),
+        arguments: strings.next().unwrap(),
+    });
+}
+
+/// The program, arguments and working directory a shortcut in the prefix starts,
+/// as host paths.
+pub struct ShellLinkCommand {
+    pub program: PathBuf,
+    pub args: Vec<String>,
+    pub working_dir: Option<PathBuf>,
+}
+
+pub fn shell_link_command(prefix: &Path, path: &Path) -> Result<ShellLinkCommand, loga::Error> {
+    let data = fs::read(path).context_with("Error reading shortcut", ea!(path = path.to_string_lossy()))?;
+    let link =
+        parse_shell_link(
+            &data,
+        ).map_err(|e| loga::err_with("Error parsing shortcut", ea!(path = path.to_string_lossy(), err = e)))?;
+    let program = match (&link.target, &link.relative_path) {
+        (Some(target), _) => unix_path(prefix, target)?,
+        (None, Some(relative)) => path.parent().unwrap().join(relative.replace('\\', "/")),
+        (None, None) => return Err(
+            loga::err_with("Shortcut doesn't point to a program on a drive", ea!(path = path.to_string_lossy())),
+        ),
+    };
+    return Ok(ShellLinkCommand {
+        program: program,
+        args: link.arguments.as_deref().map(split_windows_args).unwrap_or_default(),
+        // Sometimes uses environment variables, ex: `%USERPROFILE%`
+        working_dir: link.working_dir.as_deref().and_then(|d| unix_path(prefix, d).ok()),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(flags: u32, body: &[u8]) -> Vec<u8> {
+        let mut out = vec![
+            0u8;
+            HEADER_SIZE
+        ];
+        out[0 .. 4].copy_from_slice(&(HEADER_SIZE as u32).to_le_bytes());
+        out[0x14 .. 0x18].copy_from_slice(&flags.to_le_bytes());
+        out.extend_from_slice(body);
+        return out;
+    }
+
+    fn string(text: &str) -> Vec<u8> {
+        let units = text.encode_utf16().collect::<Vec<_>>();
+        let mut out = (units.len() as u16).to_le_bytes().to_vec();
+        out.extend(units.into_iter().flat_map(|u| u.to_le_bytes()));
+        return out;
+    }
+
+    /// Link info with an ANSI local base path.
+    fn link_info(size: u32, base_offset: u32, path: &str) -> Vec<u8> {
+        let mut out = vec![
+            0u8;
+            0x1c
+        ];
+        out[0 .. 4].copy_from_slice(&size.to_le_bytes());
+        out[4 .. 8].copy_from_slice(&0x1cu32.to_le_bytes());
+        out[8 .. 12].copy_from_slice(&VOLUME_ID_AND_LOCAL_BASE_PATH.to_le_bytes());
+        out[0x10 .. 0x14].copy_from_slice(&base_offset.to_le_bytes());
+        out[0x18 .. 0x1c].copy_from_slice(&(0x1c + path.len() as u32).to_le_bytes());
+        out.extend(path.bytes());
+        out.extend([0, 0]);
+        return out;
+    }
+
+    #[test]
+    fn strings() {
+        let mut body = string("..\\game.exe");
+        body.extend(string("C:\\Games"));
+        body.extend(string("-windowed -x"));
+        let parsed =
+            parse_shell_link(
+                &link(HAS_RELATIVE_PATH | HAS_WORKING_DIR | HAS_ARGUMENTS | IS_UNICODE, &body),
+            ).unwrap();
+        assert!(parsed.target.is_none());
+        assert_eq!(parsed.relative_path.as_deref(), Some("..\\game.exe"));
+        assert_eq!(parsed.working_dir.as_deref(), Some("C:\\Games"));
+        assert_eq!(parsed.arguments.as_deref(), Some("-windowed -x"));
+    }
+
+    #[test]
+    fn target() {
+        let path = "C:\\Games\\game.exe";
+        let info = link_info(0x1c + path.len() as u32 + 2, 0x1c, path);
+        let parsed = parse_shell_link(&link(HAS_LINK_INFO, &info)).unwrap();
+        assert_eq!(parsed.target.as_deref(), Some(path));
+    }
+
+    #[test]
+    fn truncated() {
+        assert!(parse_shell_link(b"").is_err());
+        assert!(parse_shell_link(&link(0, &[])[..0x10]).is_err());
+        assert!(parse_shell_link(&[0x4c, 0, 0, 0]).is_err());
+
+        // String longer than the file
+        let mut body = string("-windowed");
+        body.truncate(6);
+        assert!(parse_shell_link(&link(HAS_ARGUMENTS | IS_UNICODE, &body)).is_err());
+
+        // Link info cut off
+        assert!(parse_shell_link(&link(HAS_LINK_INFO, &[0x1c, 0, 0, 0, 0x1c])).is_err());
+    }
+
+    #[test]
+    fn offsets_out_of_range() {
+        // Path offset past the end of the file
+        assert!(parse_shell_link(&link(HAS_LINK_INFO, &link_info(0x30, 0xffff_ffff, "C:\\a.exe"))).is_err());
+
+        // Sizes that skip past the end
+        let mut body = link_info(0xffff_ffff, 0x1c, "C:\\a.exe");
+        body.extend(string("-x"));
+        assert!(parse_shell_link(&link(HAS_LINK_INFO | HAS_ARGUMENTS | IS_UNICODE, &body)).is_err());
+        let mut body = 0xffffu16.to_le_bytes().to_vec();
+        body.extend(string("-x"));
+        assert!(parse_shell_link(&link(HAS_LINK_TARGET_ID_LIST | HAS_ARGUMENTS | IS_UNICODE, &body)).is_err());
+    }
+}
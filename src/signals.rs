@@ -0,0 +1,96 @@
+use {
+    loga::ResultContext,
+    std::{
+        mem::MaybeUninit,
+        process::exit,
+        ptr,
+        sync::{
+            atomic::{
+                AtomicI32,
+                Ordering,
+            },
+            Mutex,
+        },
+        thread,
+    },
+};
+
+/// What to do when winebasin receives SIGINT, SIGTERM, or SIGHUP.
+pub enum Handler {
+    /// Exit immediately (the default, as if there were no handler).
+    Exit,
+    /// Record the signal (see `received`) and continue, ex: while cleaning up.
+    Record,
+    /// Record the signal and call the function, ex: to forward termination to a child.
+    Call(Box<dyn Fn(i32) + Send>),
+}
+
+static HANDLER: Mutex<Handler> = Mutex::new(Handler::Exit);
+static RECEIVED: AtomicI32 = AtomicI32::new(0);
+
+fn signal_set() -> libc::sigset_t {
+    unsafe {
+        let mut set = MaybeUninit::<libc::sigset_t>::uninit();
+        libc::sigemptyset(set.as_mut_ptr());
+        libc::sigaddset(set.as_mut_ptr(), libc::SIGINT);
+        libc::sigaddset(set.as_mut_ptr(), libc::SIGTERM);
+        libc::sigaddset(set.as_mut_ptr(), libc::SIGHUP);
+        return set.assume_init();
+    }
+}
+
+/// Block termination signals and handle them on a background thread instead.  Must
+/// be called before any other threads are started so they inherit the mask. (Child
+/// processes started with `Command` have their signal mask reset.)
+pub fn install() -> Result<(), loga::Error> {
+    let set = signal_set();
+    let res = unsafe {
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut())
+    };
+    if res != 0 {
+        return Err(std::io::Error::from_raw_os_error(res)).context("Error blocking signals");
+    }
+    thread::spawn(move || {
+        loop {
+            let mut sig = 0;
+            if unsafe {
+                libc::sigwait(&set, &mut sig)
+            } != 0 {
+                continue;
+            }
+            RECEIVED.store(sig, Ordering::SeqCst);
+            match &*HANDLER.lock().unwrap() {
+                Handler::Exit => exit(128 + sig),
+                Handler::Record => { },
+                Handler::Call(f) => f(sig),
+            }
+        }
+    });
+    return Ok(());
+}
+
+pub fn set_handler(handler: Handler) {
+    *HANDLER.lock().unwrap() = handler;
+}
+
+/// The last termination signal received, if any.
+pub fn received() -> Option<i32> {
+    match RECEIVED.load(Ordering::SeqCst) {
+        0 => return None,
+        sig => return Some(sig),
+    }
+}
+
+/// Send a signal to a process.
+pub fn kill(pid: u32, sig: i32) {
+    unsafe {
+        libc::kill(pid as i32, sig);
+    }
+}
+
+/// Send a signal to every process in a process group.
+pub fn kill_group(pgid: u32, sig: i32) {
+    unsafe {
+        libc::killpg(pgid as i32, sig);
+    }
+}
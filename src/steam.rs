@@ -0,0 +1,275 @@
+use {
+    crate::exe_icon::crc32,
+    directories::BaseDirs,
+    loga::{
+        ea,
+        ResultContext,
+    },
+    std::{
+        fs,
+        path::{
+            Path,
+            PathBuf,
+        },
+    },
+};
+
+// Binary VDF value types
+const VDF_MAP: u8 = 0;
+const VDF_STRING: u8 = 1;
+const VDF_INT: u8 = 2;
+const VDF_FLOAT: u8 = 3;
+const VDF_UINT64: u8 = 7;
+const VDF_END: u8 = 8;
+
+/// A value in Steam's binary VDF format (`shortcuts.vdf`). Keys and strings are
+/// kept as bytes so entries written by Steam are preserved exactly.
+#[derive(Clone, PartialEq)]
+pub enum VdfValue {
+    Map(Vec<(Vec<u8>, VdfValue)>),
+    String(Vec<u8>),
+    Int(u32),
+    Float(f32),
+    UInt64(u64),
+}
+
+impl VdfValue {
+    pub fn get(&self, key: &str) -> Option<&VdfValue> {
+        let VdfValue::Map(entries) = self else {
+            return None;
+        };
+        return entries.iter().find(|(k, _)| k.eq_ignore_ascii_case(key.as_bytes())).map(|(_, v)| v);
+    }
+}
+
+fn read_cstr<'a>(data: &'a [u8], at: &mut usize) -> Result<&'a [u8], String> {
+    let rest = data.get(*at..).ok_or_else(|| "Truncated VDF".to_string())?;
+    let len = rest.iter().position(|b| *b == 0).ok_or_else(|| "Unterminated VDF string".to_string())?;
+    *at += len + 1;
+    return Ok(&rest[..len]);
+}
+
+fn read_bytes<const N: usize>(data: &[u8], at: &mut usize) -> Result<[u8; N], String> {
+    let out = data.get(*at .. *at + N).ok_or_else(|| "Truncated VDF".to_string())?.try_into().unwrap();
+    *at += N;
+    return Ok(out);
+}
+
+/// Read map entries until the map's end marker.
+fn read_map(data: &[u8], at: &mut usize) -> Result<Vec<(Vec<u8>, VdfValue)>, String> {
+    let mut out = vec![];
+    loop {
+        let type_ = *data.get(*at).ok_or_else(|| "Truncated VDF".to_string())?;
+        *at += 1;
+        if type_ == VDF_END {
+            return Ok(out);
+        }
+        let key = read_cstr(data, at)?.to_vec();
+        let value = match type_ {
+            VDF_MAP => VdfValue::Map(read_map(data, at)?),
+            VDF_STRING => VdfValue::String(read_cstr(data, at)?.to_vec()),
+            VDF_INT => VdfValue::Int(u32::from_le_bytes(read_bytes(data, at)?)),
+            VDF_FLOAT => VdfValue::Float(f32::from_le_bytes(read_bytes(data, at)?)),
+            VDF_UINT64 => VdfValue::UInt64(u64::from_le_bytes(read_bytes(data, at)?)),
+            t => return Err(format!("Unsupported VDF value type {}", t)),
+        };
+        out.push((key, value));
+    }
+}
+
+/// Parse a binary VDF file, a map without a leading type.
+pub fn read_vdf(data: &[u8]) -> Result<VdfValue, String> {
+    let mut at = 0;
+    let out = read_map(data, &mut at)?;
+    if at != data.len() {
+        return Err("Unexpected data after the end of the VDF".to_string());
+    }
+    return Ok(VdfValue::Map(out));
+}
+
+fn write_map(out: &mut Vec<u8>, entries: &[(Vec<u8>, VdfValue)]) {
+    for (key, value) in entries {
+        let type_ = match value {
+            VdfValue::Map(_) => VDF_MAP,
+            VdfValue::String(_) => VDF_STRING,
+            VdfValue::Int(_) => VDF_INT,
+            VdfValue::Float(_) => VDF_FLOAT,
+            VdfValue::UInt64(_) => VDF_UINT64,
+        };
+        out.push(type_);
+        out.extend(key);
+        out.push(0);
+        match value {
+            VdfValue::Map(entries) => write_map(out, entries),
+            VdfValue::String(s) => {
+                out.extend(s);
+                out.push(0);
+            },
+            VdfValue::Int(i) => out.extend(i.to_le_bytes()),
+            VdfValue::Float(f) => out.extend(f.to_le_bytes()),
+            VdfValue::UInt64(i) => out.extend(i.to_le_bytes()),
+        }
+    }
+    out.push(VDF_END);
+}
+
+pub fn write_vdf(root: &VdfValue) -> Vec<u8> {
+    let mut out = vec![];
+    if let VdfValue::Map(entries) = root {
+        write_map(&mut out, entries);
+    }
+    return out;
+}
+
+/// Steam's installation directory, native or Flatpak.
+fn steam_dir() -> Result<PathBuf, loga::Error> {
+    let base = BaseDirs::new().context("Unable to determine home directory")?;
+    let candidates =
+        [
+            base.home_dir().join(".steam/steam"),
+            base.data_dir().join("Steam"),
+            base.home_dir().join(".var/app/com.valvesoftware.Steam/.steam/steam"),
+        ];
+    return candidates
+        .iter()
+        .find(|d| d.join("userdata").is_dir())
+        .cloned()
+        .context_with(
+            "Couldn't find Steam's user data, start Steam and log in first",
+            ea!(searched = candidates.iter().map(|c| c.to_string_lossy()).collect::<Vec<_>>().join(", ")),
+        );
+}
+
+/// The `shortcuts.vdf` of the Steam account (the only one if `user` isn't given).
+pub fn shortcuts_path(user: Option<&str>) -> Result<PathBuf, loga::Error> {
+    let userdata = steam_dir()?.join("userdata");
+    if let Some(user) = user {
+        let dir = userdata.join(user);
+        if !dir.is_dir() {
+            return Err(loga::err_with("No Steam account with this ID", ea!(user = user)));
+        }
+        return Ok(dir.join("config/shortcuts.vdf"));
+    }
+    let mut users = vec![];
+    for entry in fs::read_dir(
+        &userdata,
+    ).context_with("Error listing Steam user data", ea!(path = userdata.to_string_lossy()))? {
+        let entry = entry.context("Error reading Steam user data entry")?;
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        // `0` is used when no one is logged in
+        if name != "0" && name.chars().all(|c| c.is_ascii_digit()) {
+            users.push(name);
+        }
+    }
+    match users.as_slice() {
+        [user] => return Ok(userdata.join(user).join("config/shortcuts.vdf")),
+        [] => return Err(loga::err("No Steam accounts found, start Steam and log in first")),
+        _ => return Err(
+            loga::err_with(
+                "Multiple Steam accounts found, pick one with `--steam-user`",
+                ea!(users = users.join(", ")),
+            ),
+        ),
+    }
+}
+
+/// Whether Steam is running (it overwrites `shortcuts.vdf` when it exits).
+pub fn steam_running() -> bool {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return false;
+    };
+    return entries
+        .flatten()
+        .any(|e| fs::read_to_string(e.path().join("comm")).is_ok_and(|c| c.trim_end() == "steam"));
+}
+
+/// A non-Steam game shortcut.
+pub struct Shortcut {
+    pub name: String,
+    pub exe: String,
+    pub start_dir: String,
+    pub launch_options: String,
+    pub icon: Option<String>,
+}
+
+/// Add the shortcut to `shortcuts.vdf`, replacing a shortcut with the same name
+/// and launch options. Returns the shortcut's app ID.
+pub fn add_shortcut(path: &Path, shortcut: &Shortcut) -> Result<u32, loga::Error> {
+    let mut root = match fs::read(path) {
+        Ok(data) => read_vdf(
+            &data,
+        ).map_err(
+            |e| loga::err_with("Error parsing Steam shortcuts", ea!(path = path.to_string_lossy(), err = e)),
+        )?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => VdfValue::Map(vec![]),
+        Err(e) => return Err(
+            loga::err_with("Error reading Steam shortcuts", ea!(path = path.to_string_lossy(), err = e)),
+        ),
+    };
+    let VdfValue::Map(root_entries) = &mut root else {
+        unreachable!();
+    };
+    if !root_entries.iter().any(|(k, _)| k.eq_ignore_ascii_case(b"shortcuts")) {
+        root_entries.push((b"shortcuts".to_vec(), VdfValue::Map(vec![])));
+    }
+    let Some(
+        (_, VdfValue::Map(shortcuts))
+    ) = root_entries.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(b"shortcuts")) else {
+        return Err(
+            loga::err_with("Steam shortcuts file has an unexpected layout", ea!(path = path.to_string_lossy())),
+        );
+    };
+
+    // Steam's ID for non-Steam games, used for artwork and `steam://rungameid/`
+    let exe = format!("\"{}\"", shortcut.exe);
+    let app_id = crc32(format!("{}{}", exe, shortcut.name).as_bytes()) | 0x8000_0000;
+    let string = |s: &str| VdfValue::String(s.as_bytes().to_vec());
+    let entry =
+        VdfValue::Map(
+            vec![
+                (b"appid".to_vec(), VdfValue::Int(app_id)),
+                (b"AppName".to_vec(), string(&shortcut.name)),
+                (b"Exe".to_vec(), string(&exe)),
+                (b"StartDir".to_vec(), string(&format!("\"{}\"", shortcut.start_dir))),
+                (b"icon".to_vec(), string(shortcut.icon.as_deref().unwrap_or(""))),
+                (b"ShortcutPath".to_vec(), string("")),
+                (b"LaunchOptions".to_vec(), string(&shortcut.launch_options)),
+                (b"IsHidden".to_vec(), VdfValue::Int(0)),
+                (b"AllowDesktopConfig".to_vec(), VdfValue::Int(1)),
+                (b"AllowOverlay".to_vec(), VdfValue::Int(1)),
+                (b"OpenVR".to_vec(), VdfValue::Int(0)),
+                (b"Devkit".to_vec(), VdfValue::Int(0)),
+                (b"DevkitGameID".to_vec(), string("")),
+                (b"DevkitOverrideAppID".to_vec(), VdfValue::Int(0)),
+                (b"LastPlayTime".to_vec(), VdfValue::Int(0)),
+                (b"FlatpakAppID".to_vec(), string("")),
+                (b"tags".to_vec(), VdfValue::Map(vec![]))
+            ],
+        );
+    let existing = shortcuts.iter().position(|(_, v)| {
+        v.get("AppName") == Some(&string(&shortcut.name)) &&
+            v.get("LaunchOptions") == Some(&string(&shortcut.launch_options))
+    });
+    match existing {
+        Some(i) => shortcuts[i].1 = entry,
+        None => {
+            // Keys are indexes
+            let next =
+                shortcuts
+                    .iter()
+                    .filter_map(|(k, _)| std::str::from_utf8(k).ok()?.parse::<usize>().ok())
+                    .max()
+                    .map(|i| i + 1)
+                    .unwrap_or(0);
+            shortcuts.push((next.to_string().into_bytes(), entry));
+        },
+    }
+    let dir = path.parent().unwrap();
+    fs::create_dir_all(dir).context_with("Error creating Steam config dir", ea!(path = dir.to_string_lossy()))?;
+    fs::write(
+        path,
+        write_vdf(&root),
+    ).context_with("Error writing Steam shortcuts", ea!(path = path.to_string_lossy()))?;
+    return Ok(app_id);
+}
@@ -0,0 +1,42 @@
+use loga::{
+    ea,
+    ResultContext,
+};
+
+/// Expand `{KEY}` placeholders in `text`, with `lookup` giving the value for each
+/// key (`None` if the key isn't known). `{{` and `}}` are literal braces.
+pub fn expand_template(
+    text: &str,
+    mut lookup: impl FnMut(&str) -> Result<Option<String>, loga::Error>,
+) -> Result<String, loga::Error> {
+    let mut out = String::new();
+    let mut rest = text;
+    loop {
+        let Some(i) = rest.find(['{', '}']) else {
+            out.push_str(rest);
+            return Ok(out);
+        };
+        out.push_str(&rest[..i]);
+        let brace = &rest[i .. i + 1];
+        rest = &rest[i + 1..];
+        if rest.starts_with(brace) {
+            out.push_str(brace);
+            rest = &rest[1..];
+            continue;
+        }
+        if brace == "}" {
+            return Err(loga::err_with("Unmatched `}` in template", ea!(template = text)));
+        }
+        let end = rest.find('}').context_with("Unclosed `{` in template", ea!(template = text))?;
+        let key = &rest[..end];
+        rest = &rest[end + 1..];
+        match lookup(key)? {
+            Some(value) => out.push_str(&value),
+            None => {
+                return Err(
+                    loga::err_with("Unknown placeholder in template", ea!(template = text, placeholder = key)),
+                );
+            },
+        }
+    }
+}
@@ -0,0 +1,298 @@
+use {
+    crate::quote_subcommand,
+    loga::{
+        ea,
+        ResultContext,
+    },
+    std::{
+        env,
+        fs::{
+            self,
+            create_dir_all,
+            Permissions,
+        },
+        os::unix::{
+            ffi::OsStrExt,
+            fs::PermissionsExt,
+        },
+        path::{
+            Path,
+            PathBuf,
+        },
+        sync::OnceLock,
+    },
+};
+
+/// Wine version reported by the stub `wine` until a test changes it.
+const DEFAULT_WINE_VERSION: &str = "wine-9.0 (test)";
+
+/// Shared by the mount stubs. The mount table has the same format as
+/// `/proc/mounts`.
+const OVERLAY_PRELUDE: &str =
+    r#"#!/bin/sh
+set -eu
+test_dir=@TEST_DIR@
+mounts="$test_dir/mounts"
+
+# Sets `lower` (`:` separated, top first) and `upper` from overlay options
+parse_options() {
+    lower=
+    upper=
+    old_ifs=$IFS
+    IFS=,
+    for option in $1; do
+        case "$option" in
+            lowerdir=*) lower=${option#lowerdir=} ;;
+            upperdir=*) upper=${option#upperdir=} ;;
+        esac
+    done
+    IFS=$old_ifs
+}
+"#;
+
+/// `mount` and `fuse-overlayfs`: copy the layers into the target instead of
+/// mounting an overlay.
+const MOUNT_STUB: &str =
+    r#"
+options=
+target=
+while [ $# -gt 0 ]; do
+    case "$1" in
+        --types|-t) shift 2 ;;
+        --options|-o) options=$2; shift 2 ;;
+        *) target=$1; shift ;;
+    esac
+done
+parse_options "$options"
+layers=$(printf '%s\n' "$lower" | tr ':' '\n' | sed '1!G;h;$!d')
+old_ifs=$IFS
+IFS='
+'
+for layer in $layers $upper; do
+    cp -a "$layer/." "$target/"
+done
+IFS=$old_ifs
+printf '%s %s %s %s 0 0\n' @FS_TYPE@ "$target" @FS_TYPE@ "$options" >> "$mounts"
+"#;
+
+/// `umount` and `fusermount3`: copy files that differ from the lower layers to the
+/// upper layer, then empty the target. Deletions aren't copied back.
+const UNMOUNT_STUB: &str =
+    r#"
+target=
+for arg in "$@"; do
+    target=$arg
+done
+line=$(awk -v t="$target" '$2 == t' "$mounts" | head -n 1)
+if [ -z "$line" ]; then
+    echo "umount: $target: not mounted" >&2
+    exit 32
+fi
+parse_options "$(printf '%s' "$line" | cut -d ' ' -f 4)"
+if [ -n "$upper" ]; then
+    cd "$target"
+    find . ! -type d | while IFS= read -r path; do
+        base=
+        old_ifs=$IFS
+        IFS=:
+        for layer in $lower; do
+            if [ -e "$layer/$path" ] || [ -L "$layer/$path" ]; then
+                base="$layer/$path"
+                break
+            fi
+        done
+        IFS=$old_ifs
+        if [ -L "$path" ]; then
+            if [ -n "$base" ] && [ -L "$base" ] && [ "$(readlink "$path")" = "$(readlink "$base")" ]; then
+                continue
+            fi
+        elif [ -n "$base" ] && cmp -s "$path" "$base"; then
+            continue
+        fi
+        mkdir -p "$upper/$(dirname "$path")"
+        cp -a "$path" "$upper/$path"
+    done
+    cd /
+fi
+find "$target" -mindepth 1 -delete
+awk -v t="$target" '$2 != t' "$mounts" > "$mounts.new"
+mv "$mounts.new" "$mounts"
+"#;
+
+/// Runs the command as the current user.
+const SUDO_STUB: &str =
+    r#"#!/bin/sh
+set -eu
+while [ $# -gt 0 ]; do
+    case "$1" in
+        --validate) exit 0 ;;
+        --close-from) shift 2 ;;
+        -*) shift ;;
+        *) break ;;
+    esac
+done
+exec "$@"
+"#;
+
+/// `wineboot` creates a skeleton prefix, `regedit` imports are recorded rather
+/// than applied, and programs are run as shell scripts. Programs run in a virtual
+/// desktop get its `/desktop=` value in `TEST_DESKTOP`.
+const WINE_STUB: &str =
+    r#"#!/bin/sh
+set -eu
+test_dir=@TEST_DIR@
+case "${1:-}" in
+    --version)
+        cat "$test_dir/wine-version"
+        ;;
+    wineboot)
+        mkdir -p "$WINEPREFIX/drive_c/windows/system32"
+        for name in system.reg user.reg userdef.reg; do
+            if [ ! -e "$WINEPREFIX/$name" ]; then
+                printf 'WINE REGISTRY Version 2\n\n#arch=%s\n' "${WINEARCH:-win64}" > "$WINEPREFIX/$name"
+            fi
+        done
+        date +%s > "$WINEPREFIX/.update-timestamp"
+        ;;
+    regedit)
+        shift
+        if [ "${1:-}" = /S ]; then
+            shift
+        fi
+        cat "$1" >> "$WINEPREFIX/regedit.log"
+        ;;
+    explorer)
+        export TEST_DESKTOP="${2#/desktop=}"
+        shift 2
+        exec sh "$@"
+        ;;
+    msiexec)
+        export TEST_MSIEXEC="$2"
+        shift 2
+        exec sh "$@"
+        ;;
+    *)
+        exec sh "$@"
+        ;;
+esac
+"#;
+
+/// Programs are run directly by the stub `wine`, so there's nothing to wait for.
+const WINESERVER_STUB: &str = r#"#!/bin/sh
+exit 0
+"#;
+
+/// Records the verbs in `winetricks.log` in the prefix instead of installing them.
+/// Loaded as a library, has a `corefonts` verb and a `vcrun2019` verb depending on
+/// it, which download a file and run it.
+const WINETRICKS_STUB: &str =
+    r#"#!/bin/sh
+if [ -n "${WINETRICKS_LIB:-}" ]; then
+    w_download_to() {
+        mkdir -p "$1"
+        printf 'downloaded from %s\n' "$2" > "$1/${2##*/}"
+    }
+    w_download() {
+        w_download_to "$W_CACHE/$W_PACKAGE" "$1"
+    }
+    w_try() {
+        touch "$W_CACHE/installed"
+        "$@"
+    }
+    w_call() {
+        echo "Dependencies aren't supported when loaded as a library" >&2
+        exit 1
+    }
+    load_corefonts() {
+        w_download https://example.com/arial32.exe
+        w_try "$WINE" "$W_CACHE/corefonts/arial32.exe"
+    }
+    load_vcrun2019() {
+        w_call corefonts
+        w_download https://example.com/vc_redist.x64.exe
+        w_try "$WINE" "$W_CACHE/vcrun2019/vc_redist.x64.exe"
+        touch "$W_SYSTEM32_DLLS/msvcp140.dll"
+    }
+    return 0
+fi
+set -eu
+for arg in "$@"; do
+    case "$arg" in
+        -*) ;;
+        *) printf '%s\n' "$arg" >> "$WINEPREFIX/winetricks.log" ;;
+    esac
+done
+"#;
+static TEST_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// The directory with the stubs and their state, if in test mode.
+pub fn test_dir() -> Option<&'static Path> {
+    return TEST_DIR.get().map(|d| d.as_path());
+}
+
+/// Replaces `/proc/mounts` in test mode.
+pub fn mounts_path(test_dir: &Path) -> PathBuf {
+    return test_dir.join("mounts");
+}
+
+/// Enter test mode (`--test-mode`): write stub `wine`, `wineserver`, `winetricks`,
+/// `sudo` and mount commands to `.test` in the root and use them instead of the
+/// real ones, so everything runs as the current user without wine installed.
+/// Mounts are simulated by copying, see the stubs. Tests can change the wine
+/// version by writing `.test/wine-version`. Call once at startup, before starting
+/// threads that use the environment.
+pub fn enter(root: &Path) -> Result<(), loga::Error> {
+    let dir = root.join(".test");
+    let bin_dir = dir.join("bin");
+    create_dir_all(&bin_dir).context_with("Error creating test mode dir", ea!(path = bin_dir.to_string_lossy()))?;
+    let quoted_dir = String::from_utf8_lossy(&quote_subcommand([dir.as_os_str().as_bytes()])?).to_string();
+    let overlay_stub = |body: &str, fs_type: &str| {
+        return format!("{}{}", OVERLAY_PRELUDE, body).replace("@FS_TYPE@", fs_type);
+    };
+    for (
+        name,
+        script,
+    ) in [
+        ("wine", WINE_STUB.to_string()),
+        ("wineserver", WINESERVER_STUB.to_string()),
+        ("winetricks", WINETRICKS_STUB.to_string()),
+        ("sudo", SUDO_STUB.to_string()),
+        ("mount", overlay_stub(MOUNT_STUB, "overlay")),
+        ("umount", overlay_stub(UNMOUNT_STUB, "overlay")),
+        ("fuse-overlayfs", overlay_stub(MOUNT_STUB, "fuse.fuse-overlayfs")),
+        ("fusermount3", overlay_stub(UNMOUNT_STUB, "fuse.fuse-overlayfs")),
+    ] {
+        let path = bin_dir.join(name);
+        fs::write(
+            &path,
+            script.replace("@TEST_DIR@", &quoted_dir),
+        ).context_with("Error writing test stub", ea!(path = path.to_string_lossy()))?;
+        fs::set_permissions(
+            &path,
+            Permissions::from_mode(0o755),
+        ).context_with("Error making test stub executable", ea!(path = path.to_string_lossy()))?;
+    }
+    for (
+        name,
+        default,
+    ) in [("wine-version", DEFAULT_WINE_VERSION), ("wine.inf", "; Test mode wine.inf\n"), ("mounts", "")] {
+        let path = dir.join(name);
+        if !path.exists() {
+            fs::write(
+                &path,
+                default,
+            ).context_with("Error writing test mode file", ea!(path = path.to_string_lossy()))?;
+        }
+    }
+    let mut paths = vec![bin_dir.clone()];
+    if let Some(path) = env::var_os("PATH") {
+        paths.extend(env::split_paths(&path));
+    }
+    env::set_var("PATH", env::join_paths(paths).context("Error adding test stubs to PATH")?);
+    env::set_var("WINE", bin_dir.join("wine"));
+    env::set_var("WINESERVER", bin_dir.join("wineserver"));
+    env::set_var("WINETRICKS", bin_dir.join("winetricks"));
+    env::set_var("WINE_INF_DIR", dir.join("wine.inf"));
+    TEST_DIR.set(dir).unwrap();
+    return Ok(());
+}
@@ -0,0 +1,122 @@
+use {
+    crate::runtime::prefix_processes,
+    loga::{
+        ea,
+        StandardFlag,
+        StandardLog,
+    },
+    std::{
+        collections::HashSet,
+        path::{
+            Path,
+            PathBuf,
+        },
+        process::{
+            Command,
+            Stdio,
+        },
+        sync::{
+            atomic::{
+                AtomicBool,
+                Ordering,
+            },
+            Arc,
+        },
+        thread::{
+            self,
+            sleep,
+            JoinHandle,
+        },
+        time::Duration,
+    },
+};
+
+pub fn window_class(system_name: &str) -> String {
+    return format!("winebasin-{}", system_name);
+}
+
+fn windows_for_pid(display: &Option<String>, pid: u32) -> Vec<String> {
+    let mut command = Command::new("xdotool");
+    command.arg("search").arg("--pid").arg(pid.to_string()).stderr(Stdio::null());
+    if let Some(display) = display {
+        command.env("DISPLAY", display);
+    }
+    let Ok(output) = command.output() else {
+        return vec![];
+    };
+    return String::from_utf8_lossy(&output.stdout).split_whitespace().map(|x| x.to_string()).collect();
+}
+
+fn tag_windows(
+    log: &StandardLog,
+    class: &str,
+    mount_path: &Path,
+    display: &Option<String>,
+    tagged: &mut HashSet<String>,
+) {
+    let processes = match prefix_processes(mount_path) {
+        Ok(p) => p,
+        Err(e) => {
+            log.log_err(StandardFlag::Debug, e);
+            return;
+        },
+    };
+    for process in processes {
+        for window in windows_for_pid(display, process.pid) {
+            if tagged.contains(&window) {
+                continue;
+            }
+            let mut command = Command::new("xdotool");
+            command.arg("set_window").arg("--class").arg(class).arg(&window).stderr(Stdio::null());
+            if let Some(display) = display {
+                command.env("DISPLAY", display);
+            }
+            if command.status().is_ok_and(|s| s.success()) {
+                log.log_with(StandardFlag::Debug, "Tagged window", ea!(window = window, pid = process.pid));
+            }
+
+            // Don't retry windows that can't be tagged (ex: already destroyed)
+            tagged.insert(window);
+        }
+    }
+}
+
+/// Sets the WM_CLASS class of windows opened by programs in the system to
+/// `winebasin-SYSTEM` (using `xdotool`) so window manager rules can match them.
+/// The instance name (usually the executable name) is kept. Stopped when dropped.
+pub struct WindowTagger {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl WindowTagger {
+    pub fn start(log: &StandardLog, system_name: &str, mount_path: &Path, display: Option<String>) -> WindowTagger {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = thread::spawn({
+            let log = log.fork(ea!(system = system_name));
+            let class = window_class(system_name);
+            let mount_path = PathBuf::from(mount_path);
+            let stop = stop.clone();
+            move || {
+                let mut tagged = HashSet::new();
+                while !stop.load(Ordering::Relaxed) {
+                    tag_windows(&log, &class, &mount_path, &display, &mut tagged);
+                    sleep(Duration::from_millis(500));
+                }
+            }
+        });
+        return WindowTagger {
+            stop: stop,
+            thread: Some(thread),
+        };
+    }
+}
+
+impl Drop for WindowTagger {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,365 @@
+use {
+    crate::{
+        quote_subcommand,
+        root_dir,
+        winetricks::{
+            checksum_file,
+            download,
+        },
+        CommandRun,
+    },
+    loga::{
+        ea,
+        ResultContext,
+        StandardFlag,
+        StandardLog,
+    },
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+    std::{
+        collections::BTreeMap,
+        fs,
+        os::unix::fs::PermissionsExt,
+        path::{
+            Path,
+            PathBuf,
+        },
+        process::Command,
+    },
+};
+
+/// Where a family of prebuilt wine builds is published, for `wine install`.
+/// Installed names are `PREFIX-VERSION`, and `{version}` in the URLs is replaced
+/// with the version.
+struct BuildSource {
+    prefix: &'static str,
+    url: &'static str,
+    /// A `sha512sum` style checksum file published alongside the archive, if any.
+    sha512_url: Option<&'static str>,
+}
+
+const BUILD_SOURCES: &[BuildSource] = &[BuildSource {
+    prefix: "wine-ge",
+    url: "https://github.com/GloriousEggroll/wine-ge-custom/releases/download/GE-Proton{version}/wine-lutris-GE-Proton{version}-x86_64.tar.xz",
+    sha512_url: Some(
+        "https://github.com/GloriousEggroll/wine-ge-custom/releases/download/GE-Proton{version}/wine-lutris-GE-Proton{version}-x86_64.sha512sum",
+    ),
+}, BuildSource {
+    prefix: "lutris",
+    url: "https://github.com/lutris/wine/releases/download/lutris-wine-{version}/wine-lutris-{version}-x86_64.tar.xz",
+    sha512_url: None,
+}, BuildSource {
+    prefix: "tkg",
+    url: "https://github.com/Kron4ek/Wine-Builds/releases/download/{version}/wine-{version}-staging-tkg-amd64.tar.xz",
+    sha512_url: None,
+}];
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RegisteredWine {
+    /// The installation directory, containing `bin/wine`, or for Proton the `proton`
+    /// launcher and `files/bin/wine`.
+    pub path: PathBuf,
+    /// A Proton install. Programs are run with the `proton` launcher, which sets up
+    /// the prefix its own way.
+    #[serde(default)]
+    pub proton: bool,
+    /// The Flatpak app id, if this is wine from a Flatpak (added with
+    /// `wine add-flatpak`). `path` then has wrapper scripts that run the app's wine.
+    #[serde(default)]
+    pub flatpak: Option<String>,
+}
+
+impl RegisteredWine {
+    pub fn bin_dir(&self) -> PathBuf {
+        if self.proton {
+            // Older Proton versions used `dist`
+            let dist = self.path.join("dist").join("bin");
+            if dist.exists() {
+                return dist;
+            }
+            return self.path.join("files").join("bin");
+        }
+        return self.path.join("bin");
+    }
+
+    pub fn proton_bin(&self) -> PathBuf {
+        return self.path.join("proton");
+    }
+
+    pub fn wine_bin(&self) -> PathBuf {
+        return self.bin_dir().join("wine");
+    }
+
+    pub fn wineserver_bin(&self) -> PathBuf {
+        return self.bin_dir().join("wineserver");
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct WinesV1 {
+    pub wines: BTreeMap<String, RegisteredWine>,
+}
+
+pub type WinesLatest = WinesV1;
+
+#[derive(Serialize, Deserialize)]
+enum Wines {
+    V1(WinesV1),
+}
+
+fn wines_path() -> Result<PathBuf, loga::Error> {
+    return Ok(root_dir()?.join("wines.json"));
+}
+
+/// Wine installations added with `wine add`, by name. Empty if none have been
+/// added.
+pub fn read_wines() -> Result<WinesLatest, loga::Error> {
+    let path = wines_path()?;
+    if !path.exists() {
+        return Ok(WinesLatest::default());
+    }
+    match serde_json::from_slice::<Wines>(
+        &fs::read(&path).context_with("Error reading wine registry", ea!(path = path.to_string_lossy()))?,
+    ).context_with("Error parsing wine registry", ea!(path = path.to_string_lossy()))? {
+        Wines::V1(wines) => return Ok(wines),
+    }
+}
+
+pub fn write_wines(wines: WinesLatest) -> Result<(), loga::Error> {
+    let path = wines_path()?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).context_with("Error creating root dir", ea!(path = parent.to_string_lossy()))?;
+    }
+    fs::write(
+        &path,
+        serde_json::to_vec_pretty(&Wines::V1(wines)).unwrap(),
+    ).context_with("Error writing wine registry", ea!(path = path.to_string_lossy()))?;
+    return Ok(());
+}
+
+/// Look up a wine by the name a basis refers to it with.
+pub fn registered_wine(name: &str) -> Result<RegisteredWine, loga::Error> {
+    return read_wines()?
+        .wines
+        .remove(name)
+        .context_with("Wine isn't registered, add it with `wine add` or change the basis' `wine`", ea!(wine = name));
+}
+
+/// Check a directory looks like a wine or Proton installation before registering
+/// it.
+pub fn detect_wine_install(path: &Path) -> Result<RegisteredWine, loga::Error> {
+    let wine = RegisteredWine {
+        path: path.to_path_buf(),
+        proton: path.join("proton").is_file(),
+        flatpak: None,
+    };
+    for bin in [wine.wine_bin(), wine.wineserver_bin()] {
+        if !bin.is_file() {
+            return Err(
+                loga::err_with(
+                    "Not a wine installation, expected the directory containing `bin/wine` and `bin/wineserver` (or `proton` and `files/bin/wine` for Proton)",
+                    ea!(path = path.to_string_lossy(), missing = bin.to_string_lossy()),
+                ),
+            );
+        }
+    }
+    return Ok(wine);
+}
+
+/// Names are used in paths, so can't contain `/` or start with `.`.
+pub fn check_wine_name(name: &str) -> Result<(), loga::Error> {
+    if name.is_empty() || name.contains('/') || name.starts_with('.') {
+        return Err(loga::err_with("Invalid wine name", ea!(name = name)));
+    }
+    return Ok(());
+}
+
+/// Where `wine install` unpacks builds. Registered wines in here are deleted when
+/// removed.
+pub fn installed_wines_dir() -> Result<PathBuf, loga::Error> {
+    return Ok(root_dir()?.join("wine"));
+}
+
+/// Download a prebuilt wine build, check it against `sha256` (or the checksum
+/// published with it), unpack it into the root and register it. Without `url` the
+/// build is found from the name's prefix in `BUILD_SOURCES`.
+pub fn install_wine(
+    log: &StandardLog,
+    name: &str,
+    url: Option<&str>,
+    sha256: Option<&str>,
+) -> Result<(), loga::Error> {
+    check_wine_name(name)?;
+    let log = log.fork(ea!(wine = name));
+    let mut wines = read_wines()?;
+    if wines.wines.contains_key(name) {
+        return Err(log.err("A wine with this name is already registered"));
+    }
+    let dest = installed_wines_dir()?.join(name);
+    if dest.exists() {
+        return Err(
+            log.err_with(
+                "The install directory for this name already exists, remove it first",
+                ea!(path = dest.to_string_lossy()),
+            ),
+        );
+    }
+    let source = BUILD_SOURCES.iter().find(|s| {
+        return name.strip_prefix(s.prefix).is_some_and(|v| v.starts_with('-'));
+    });
+    let (url, sha512_url) = match (url, source) {
+        (Some(url), _) => (url.to_string(), None),
+        (None, Some(source)) => {
+            let version = &name[source.prefix.len() + 1..];
+            (source.url.replace("{version}", version), source.sha512_url.map(|u| u.replace("{version}", version)))
+        },
+        (None, None) => {
+            return Err(
+                log.err_with(
+                    "Unknown wine build; use a known name or specify `--url`",
+                    ea!(
+                        known =
+                            BUILD_SOURCES
+                                .iter()
+                                .map(|s| format!("{}-VERSION", s.prefix))
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                    ),
+                ),
+            );
+        },
+    };
+    let parent = installed_wines_dir()?;
+    fs::create_dir_all(
+        &parent,
+    ).context_with("Error creating wine install dir", ea!(path = parent.to_string_lossy()))?;
+    let staging =
+        tempfile::Builder::new()
+            .prefix(".install-")
+            .tempdir_in(&parent)
+            .context("Error creating temporary dir for wine download")?;
+    let archive = staging.path().join("archive");
+    log.log_with(StandardFlag::Info, "Downloading wine build", ea!(url = url));
+    download(&url, &archive)?;
+
+    // Check the download
+    let (program, want) = match (sha256, sha512_url) {
+        (Some(sha256), _) => ("sha256sum", sha256.to_string()),
+        (None, Some(sha512_url)) => {
+            let sums_path = staging.path().join("sha512sum");
+            download(&sha512_url, &sums_path)?;
+            let sums =
+                fs::read_to_string(
+                    &sums_path,
+                ).context_with("Error reading published checksum", ea!(path = sums_path.to_string_lossy()))?;
+            ("sha512sum", sums.split_whitespace().next().unwrap_or_default().to_string())
+        },
+        _ => {
+            return Err(
+                log.err(
+                    "No published checksum for this build; check the download some other way and pass its checksum with `--sha256`",
+                ),
+            );
+        },
+    };
+    let have = checksum_file(program, &archive)?;
+    if !have.eq_ignore_ascii_case(&want) {
+        return Err(
+            log.err_with(
+                "Downloaded wine build doesn't match its checksum",
+                ea!(program = program, want = want, have = have),
+            ),
+        );
+    }
+
+    // Unpack. Builds are usually archived in a single top level directory.
+    let unpacked = staging.path().join("unpacked");
+    fs::create_dir(&unpacked).context("Error creating dir to unpack wine build in")?;
+    Command::new("tar").arg("--extract").arg("--file").arg(&archive).arg("--directory").arg(&unpacked).run()?;
+    let mut install = unpacked.clone();
+    if !install.join("bin").exists() {
+        let entries =
+            fs::read_dir(&unpacked)
+                .context("Error listing unpacked wine build")?
+                .collect::<Result<Vec<_>, _>>()
+                .context("Error reading unpacked wine build entry")?;
+        if let [entry] = entries.as_slice() {
+            install = entry.path();
+        }
+    }
+    let proton = detect_wine_install(&install)?.proton;
+    fs::rename(
+        &install,
+        &dest,
+    ).context_with("Error moving unpacked wine build into place", ea!(path = dest.to_string_lossy()))?;
+    wines.wines.insert(name.to_string(), RegisteredWine {
+        path: dest,
+        proton: proton,
+        flatpak: None,
+    });
+    write_wines(wines)?;
+    return Ok(());
+}
+
+/// Runs a command from the Flatpak with access to the prefix and the winetricks
+/// cache. Flatpak doesn't pass all environment variables into the sandbox so wine
+/// settings are passed explicitly. `/tmp` is shared so separate invocations (ex:
+/// `wineserver -w`) find the same wineserver.
+const FLATPAK_WRAPPER: &str =
+    r#"#!/bin/sh
+set -eu
+prefix=${WINEPREFIX:-$HOME/.wine}
+set -- --command=@COMMAND@ @APP@ "$@"
+for var in $(env | sed -n -E 's/^((WINE|DXVK_|VKD3D_)[A-Za-z0-9_]*)=.*/\1/p'); do
+    eval "value=\${$var}"
+    set -- --env="$var=$value" "$@"
+done
+if [ -n "${W_CACHE:-}" ]; then
+    set -- --filesystem="$W_CACHE" "$@"
+fi
+set -- --filesystem="$prefix" --filesystem=/tmp "$@"
+exec flatpak run "$@"
+"#;
+
+/// Register wine from a Flatpak app (ex: `org.winehq.Wine`), writing wrapper
+/// scripts for `wine` and `wineserver` to `wine/NAME` in the root.
+pub fn add_flatpak_wine(name: &str, app: &str) -> Result<(), loga::Error> {
+    check_wine_name(name)?;
+    let mut wines = read_wines()?;
+    if wines.wines.contains_key(name) {
+        return Err(loga::err_with("A wine with this name is already registered", ea!(wine = name)));
+    }
+    let info = Command::new("flatpak").arg("info").arg(app).output().context("Error running `flatpak info`")?;
+    if !info.status.success() {
+        return Err(
+            loga::err_with(
+                "Flatpak app isn't installed, install it first (ex: `flatpak install flathub org.winehq.Wine`)",
+                ea!(app = app),
+            ),
+        );
+    }
+    let wine = RegisteredWine {
+        path: installed_wines_dir()?.join(name),
+        proton: false,
+        flatpak: Some(app.to_string()),
+    };
+    let bin_dir = wine.bin_dir();
+    fs::create_dir_all(&bin_dir).context_with("Error creating wrapper dir", ea!(path = bin_dir.to_string_lossy()))?;
+    let quoted_app = String::from_utf8_lossy(&quote_subcommand([app.as_bytes()])?).to_string();
+    for command in ["wine", "wineserver"] {
+        let path = bin_dir.join(command);
+        fs::write(
+            &path,
+            FLATPAK_WRAPPER.replace("@COMMAND@", command).replace("@APP@", &quoted_app),
+        ).context_with("Error writing Flatpak wrapper", ea!(path = path.to_string_lossy()))?;
+        fs::set_permissions(
+            &path,
+            fs::Permissions::from_mode(0o755),
+        ).context_with("Error making Flatpak wrapper executable", ea!(path = path.to_string_lossy()))?;
+    }
+    wines.wines.insert(name.to_string(), wine);
+    write_wines(wines)?;
+    return Ok(());
+}
@@ -0,0 +1,329 @@
+use {
+    crate::{
+        config_dir,
+        root_dir,
+        Arch,
+        CommandRun,
+    },
+    loga::{
+        ea,
+        ResultContext,
+        StandardFlag,
+        StandardLog,
+    },
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+    std::{
+        collections::HashMap,
+        env,
+        ffi::OsString,
+        fs,
+        os::unix::fs::PermissionsExt,
+        path::{
+            Path,
+            PathBuf,
+        },
+        process::Command,
+        sync::OnceLock,
+    },
+};
+
+#[derive(Deserialize)]
+pub struct PresetArch {
+    pub verbs: Vec<String>,
+    /// Rough estimate of the download size
+    pub download_mb: u64,
+    /// Rough estimate of the space used in the prefix after installation
+    pub disk_mb: u64,
+}
+
+#[derive(Deserialize)]
+pub struct Preset {
+    pub description: String,
+    pub win32: PresetArch,
+    pub win64: PresetArch,
+}
+
+impl Preset {
+    pub fn for_arch(&self, arch: Arch) -> &PresetArch {
+        match arch {
+            Arch::Win32 => &self.win32,
+            Arch::Win64 | Arch::Wow64 => &self.win64,
+        }
+    }
+}
+
+pub const BUILTIN_PRESETS: &[(&str, &[u8])] =
+    &[
+        ("recommended", include_bytes!("../presets/recommended.json")),
+        ("gaming", include_bytes!("../presets/gaming.json")),
+        ("office", include_bytes!("../presets/office.json")),
+        ("minimal", include_bytes!("../presets/minimal.json")),
+    ];
+
+/// Load a preset by name, preferring a user-provided preset in the config
+/// directory to the built-in ones.
+pub fn load_preset(name: &str) -> Result<Preset, loga::Error> {
+    let override_path = config_dir()?.join("presets").join(format!("{}.json", name));
+    let data = if override_path.exists() {
+        fs::read(
+            &override_path,
+        ).context_with("Error reading user winetricks preset", ea!(path = override_path.to_string_lossy()))?
+    } else if let Some((_, data)) = BUILTIN_PRESETS.iter().find(|(k, _)| *k == name) {
+        data.to_vec()
+    } else {
+        return Err(
+            loga::err_with(
+                "No winetricks preset with this name",
+                ea!(
+                    preset = name,
+                    builtin = BUILTIN_PRESETS.iter().map(|(k, _)| *k).collect::<Vec<_>>().join(", "),
+                    user_path = override_path.to_string_lossy()
+                ),
+            ),
+        );
+    };
+    return serde_json::from_slice(&data).context_with("Error parsing winetricks preset as json", ea!(preset = name));
+}
+
+/// A specific winetricks release to download and use instead of the one in `PATH`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WinetricksPin {
+    /// A winetricks release tag, ex: `20240105`.
+    pub version: String,
+    /// SHA-256 of the release's `winetricks` script, hex.
+    pub sha256: Option<String>,
+}
+
+pub static WINETRICKS_PIN: OnceLock<Option<WinetricksPin>> = OnceLock::new();
+pub static WINETRICKS_CACHE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Where winetricks keeps downloaded installers, shared by all bases and systems.
+pub fn winetricks_cache_dir() -> Result<PathBuf, loga::Error> {
+    if let Some(dir) = WINETRICKS_CACHE.get() {
+        return Ok(dir.clone());
+    }
+    return Ok(root_dir()?.join("cache").join("winetricks"));
+}
+
+fn winetricks_pin() -> Option<&'static WinetricksPin> {
+    return WINETRICKS_PIN.get().and_then(|p| p.as_ref());
+}
+
+fn pinned_winetricks_path(version: &str) -> Result<PathBuf, loga::Error> {
+    return Ok(root_dir()?.join("winetricks").join(version).join("winetricks"));
+}
+
+/// `WINETRICKS` if set, otherwise the pinned winetricks (which may not be
+/// downloaded yet, see `ensure_winetricks`), otherwise `winetricks` from `PATH`.
+pub fn winetricks_bin() -> String {
+    if let Ok(bin) = env::var("WINETRICKS") {
+        return bin;
+    }
+    if let Some(pin) = winetricks_pin() {
+        if let Ok(path) = pinned_winetricks_path(&pin.version) {
+            return path.to_string_lossy().to_string();
+        }
+    }
+    return "winetricks".to_string();
+}
+
+pub fn sha256_file(path: &Path) -> Result<String, loga::Error> {
+    return checksum_file("sha256sum", path);
+}
+
+/// Hash a file with a coreutils checksum program (ex: `sha512sum`), returning the
+/// hex digest.
+pub fn checksum_file(program: &str, path: &Path) -> Result<String, loga::Error> {
+    let output =
+        Command::new(program)
+            .arg(path)
+            .output()
+            .context_with("Error running checksum program", ea!(program = program, path = path.to_string_lossy()))?;
+    if !output.status.success() {
+        return Err(
+            loga::err_with("Checksum program failed", ea!(program = program, path = path.to_string_lossy())),
+        );
+    }
+    return Ok(String::from_utf8_lossy(&output.stdout).split_whitespace().next().unwrap_or_default().to_string());
+}
+
+/// Download a URL to a file with `curl`.
+pub fn download(url: &str, path: &Path) -> Result<(), loga::Error> {
+    Command::new("curl")
+        .arg("--fail")
+        .arg("--location")
+        .arg("--silent")
+        .arg("--show-error")
+        .arg("--output")
+        .arg(path)
+        .arg(url)
+        .run()?;
+    return Ok(());
+}
+
+/// Download the pinned winetricks release if it isn't already, checking it matches
+/// the pinned checksum. Does nothing if winetricks isn't pinned or `WINETRICKS` is
+/// set.
+pub fn ensure_winetricks(log: &StandardLog) -> Result<(), loga::Error> {
+    if env::var_os("WINETRICKS").is_some() {
+        return Ok(());
+    }
+    let Some(pin) = winetricks_pin() else {
+        return Ok(());
+    };
+    let path = pinned_winetricks_path(&pin.version)?;
+    let log = log.fork(ea!(version = pin.version, path = path.to_string_lossy()));
+    let Some(want_sha256) = &pin.sha256 else {
+        return Err(
+            log.err(
+                "Pinned winetricks has no `sha256` in the global config; download the release, check it, and add its checksum",
+            ),
+        );
+    };
+    if path.exists() {
+        if sha256_file(&path)?.eq_ignore_ascii_case(want_sha256) {
+            return Ok(());
+        }
+        log.log(StandardFlag::Warning, "Pinned winetricks doesn't match its checksum, downloading it again");
+    }
+    let dir = path.parent().unwrap();
+    fs::create_dir_all(dir).context_with("Error creating winetricks dir", ea!(path = dir.to_string_lossy()))?;
+    let download_path = dir.join("winetricks.download");
+    let url = format!("https://raw.githubusercontent.com/Winetricks/winetricks/{}/src/winetricks", pin.version);
+    log.log_with(StandardFlag::Info, "Downloading pinned winetricks", ea!(url = url));
+    download(&url, &download_path)?;
+    let have_sha256 = sha256_file(&download_path)?;
+    if !have_sha256.eq_ignore_ascii_case(want_sha256) {
+        _ = fs::remove_file(&download_path);
+        return Err(
+            log.err_with(
+                "Downloaded winetricks doesn't match the pinned checksum",
+                ea!(want = want_sha256, have = have_sha256),
+            ),
+        );
+    }
+    fs::set_permissions(
+        &download_path,
+        fs::Permissions::from_mode(0o755),
+    ).context_with("Error making winetricks executable", ea!(path = download_path.to_string_lossy()))?;
+    fs::rename(
+        &download_path,
+        &path,
+    ).context_with("Error moving downloaded winetricks into place", ea!(path = path.to_string_lossy()))?;
+    return Ok(());
+}
+
+/// Loads winetricks as a library (`WINETRICKS_LIB`, which defines the verbs
+/// without running anything) and runs each verb's load function with everything
+/// but downloading turned off. Paths in the prefix point into a scratch directory
+/// (`WINEPREFIX`) in case a verb touches files directly. Arguments are the
+/// winetricks script and the verbs.
+const PREFETCH_SCRIPT: &str =
+    r#"
+winetricks=$1
+shift
+WINETRICKS_LIB=1
+. "$winetricks"
+if ! command -v w_download_to > /dev/null; then
+    echo "This winetricks can't be loaded as a library" >&2
+    exit 1
+fi
+
+WINE=true
+WINE64=true
+WINESERVER=true
+W_PLATFORM=wine
+W_OPT_UNATTENDED=1
+W_DRIVE_C="$WINEPREFIX/drive_c"
+W_WINDIR_UNIX="$W_DRIVE_C/windows"
+W_SYSTEM32_DLLS="$W_WINDIR_UNIX/system32"
+W_SYSTEM64_DLLS="$W_WINDIR_UNIX/system32"
+W_FONTSDIR_UNIX="$W_WINDIR_UNIX/Fonts"
+W_PROGRAMS_UNIX="$W_DRIVE_C/Program Files"
+W_PROGRAMS_X86_UNIX="$W_DRIVE_C/Program Files (x86)"
+W_TMP="$W_DRIVE_C/windows/temp"
+W_TMP_EARLY="$W_TMP"
+mkdir -p "$W_SYSTEM32_DLLS" "$W_FONTSDIR_UNIX" "$W_PROGRAMS_UNIX" "$W_PROGRAMS_X86_UNIX" "$W_TMP"
+
+# Running installers, extracting, and changing wine settings
+for name in \
+    w_try w_try_7z w_try_cabextract w_try_unrar w_try_unzip w_try_msiexec64 w_try_ms_installer \
+    w_try_regedit w_try_regedit32 w_try_regedit64 w_try_regsvr w_try_regsvr32 w_try_regsvr64 \
+    w_override_dlls w_override_no_dlls w_override_app_dlls w_set_winver w_set_app_winver \
+    w_register_font w_register_font_replacement w_append_path w_ahk_do w_wineserver; do
+    eval "$name() { return 0; }"
+done
+
+# Dependencies are downloaded in their own package dir
+w_call() {
+    (prefetch_verb "$1")
+}
+
+prefetch_verb() {
+    if ! command -v "load_$1" > /dev/null; then
+        echo "Unknown winetricks verb: $1" >&2
+        return 1
+    fi
+    W_PACKAGE=$1
+    "load_$1"
+}
+
+failed=
+for verb in "$@"; do
+    if ! (prefetch_verb "$verb"); then
+        failed="$failed $verb"
+    fi
+done
+if [ -n "$failed" ]; then
+    echo "Failed to download:$failed" >&2
+    exit 1
+fi
+"#;
+
+/// Download everything the verbs need into the winetricks cache without installing
+/// them or running wine. `scratch` is an empty directory for files verbs write
+/// outside the cache.
+pub fn prefetch_winetricks(
+    log: &StandardLog,
+    arch: Arch,
+    scratch: &Path,
+    verbs: &[String],
+) -> Result<(), loga::Error> {
+    ensure_winetricks(log)?;
+    let mut command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg(PREFETCH_SCRIPT)
+        .arg("sh")
+        .arg(winetricks_bin())
+        .args(verbs)
+        .env("WINEPREFIX", scratch)
+        .env("W_ARCH", match arch {
+            Arch::Win32 => "win32",
+            Arch::Win64 | Arch::Wow64 => "win64",
+        });
+    if env::var_os("W_CACHE").is_none() {
+        command.env("W_CACHE", winetricks_cache_dir()?);
+    }
+    command.run()?;
+    return Ok(());
+}
+
+/// Install verbs unattended in the prefix set by `WINEPREFIX` in `env`.
+pub fn run_winetricks(
+    log: &StandardLog,
+    env: &HashMap<OsString, OsString>,
+    verbs: &[String],
+) -> Result<(), loga::Error> {
+    ensure_winetricks(log)?;
+    let mut command = Command::new(winetricks_bin());
+    command.envs(env);
+    if env::var_os("W_CACHE").is_none() {
+        command.env("W_CACHE", winetricks_cache_dir()?);
+    }
+    command.arg("-q").args(verbs).run()?;
+    return Ok(());
+}
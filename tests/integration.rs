@@ -0,0 +1,1467 @@
+//! End-to-end tests using `--test-mode` (stub wine, winetricks, sudo and mounts).
+//! Run with `cargo test --features test-mode`.
+mod support;
+
+use {
+    std::{
+        fs,
+        os::unix::fs::symlink,
+    },
+    support::TestEnv,
+};
+
+#[test]
+fn basis_create_builds_prefix() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b", "--winetricks", "corefonts", "vcrun2019"]);
+    let prefix = env.basis_prefix("b");
+    assert!(prefix.join("drive_c").is_dir());
+    assert!(prefix.join("system.reg").is_file());
+    assert_eq!(fs::read_to_string(prefix.join("winetricks.log")).unwrap(), "corefonts\nvcrun2019\n");
+}
+
+#[test]
+fn basis_winetricks_adds_verbs() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.run(&["basis", "winetricks", "b", "corefonts"]);
+    assert_eq!(fs::read_to_string(env.basis_prefix("b").join("winetricks.log")).unwrap(), "corefonts\n");
+}
+
+#[test]
+fn cache_prefetch() {
+    let env = TestEnv::new();
+    env.run(&["cache", "prefetch", "vcrun2019"]);
+
+    // Downloads the verb and its dependencies without installing anything
+    let cache = env.root().join("cache").join("winetricks");
+    assert!(cache.join("corefonts/arial32.exe").exists());
+    assert!(cache.join("vcrun2019/vc_redist.x64.exe").exists());
+    assert!(!cache.join("installed").exists());
+    assert_eq!(fs::read_dir(env.root().join("cache")).unwrap().count(), 1);
+    env.run_err(&["cache", "prefetch", "unknownverb"]);
+}
+
+#[test]
+fn system_run_writes_to_system_layer() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.write_program(&env.basis_prefix("b"), "app/app.exe", "echo saved > \"$WINEPREFIX/drive_c/app/save.txt\"\n");
+    env.run(&["system", "create", "b", "s"]);
+    env.run(&["system", "run", "s", "app/app.exe"]);
+    assert_eq!(fs::read_to_string(env.system_prefix("s").join("drive_c/app/save.txt")).unwrap(), "saved\n");
+    assert!(!env.basis_prefix("b").join("drive_c/app/save.txt").exists());
+
+    // Unchanged basis files stay out of the system layer
+    assert!(!env.system_prefix("s").join("drive_c/app/app.exe").exists());
+
+    // Unmounted after the run
+    assert_eq!(env.mounts(), "");
+    assert_eq!(fs::read_dir(env.system_mount("s")).unwrap().count(), 0);
+}
+
+#[test]
+fn fuse_backend_run() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.write_program(&env.basis_prefix("b"), "app.exe", "echo saved > \"$WINEPREFIX/drive_c/save.txt\"\n");
+    env.run(&["system", "create", "b", "s"]);
+    env.run(&["--backend", "fuse-overlayfs", "system", "run", "s", "app.exe"]);
+    assert_eq!(fs::read_to_string(env.system_prefix("s").join("drive_c/save.txt")).unwrap(), "saved\n");
+    assert_eq!(env.mounts(), "");
+}
+
+#[test]
+fn copy_backend_run() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.write_program(&env.basis_prefix("b"), "app.exe", "echo saved > \"$WINEPREFIX/drive_c/save.txt\"\n");
+    env.run(&["system", "create", "b", "s"]);
+    env.run(&["--backend", "copy", "system", "run", "s", "app.exe"]);
+    assert_eq!(fs::read_to_string(env.system_prefix("s").join("drive_c/save.txt")).unwrap(), "saved\n");
+
+    // The basis is copied into the system, which is used directly
+    assert!(env.system_prefix("s").join("drive_c/app.exe").exists());
+    assert!(!env.basis_prefix("b").join("drive_c/save.txt").exists());
+    assert!(!env.system_mount("s").exists());
+    env.run(&["--backend", "copy", "system", "run", "s", "app.exe"]);
+    assert!(!env.system_mount("s").exists());
+    assert_eq!(env.mounts(), "");
+}
+
+#[test]
+fn system_run_sees_earlier_changes() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.write_program(&env.basis_prefix("b"), "count.exe", "echo x >> \"$WINEPREFIX/drive_c/count.txt\"\n");
+    env.run(&["system", "create", "b", "s"]);
+    env.run(&["system", "run", "s", "count.exe"]);
+    env.run(&["system", "run", "s", "count.exe"]);
+    assert_eq!(fs::read_to_string(env.system_prefix("s").join("drive_c/count.txt")).unwrap(), "x\nx\n");
+}
+
+#[test]
+fn systems_are_isolated() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.write_program(&env.basis_prefix("b"), "touch.exe", "touch \"$WINEPREFIX/drive_c/touched\"\n");
+    env.write_program(&env.basis_prefix("b"), "check.exe", "test ! -e \"$WINEPREFIX/drive_c/touched\"\n");
+    env.run(&["system", "create", "b", "s1"]);
+    env.run(&["system", "create", "b", "s2"]);
+    env.run(&["system", "run", "s1", "touch.exe"]);
+    env.run(&["system", "run", "s2", "check.exe"]);
+}
+
+#[test]
+fn system_run_exit_code() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.write_program(&env.basis_prefix("b"), "fail.exe", "exit 3\n");
+    env.run(&["system", "create", "b", "s"]);
+    let output = env.run_err(&["system", "run", "s", "fail.exe"]);
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(env.mounts(), "");
+
+    // Winebasin's own errors, including bad arguments, use a code programs don't
+    assert_eq!(env.run_err(&["system", "run", "missing", "fail.exe"]).status.code(), Some(125));
+    assert_eq!(env.run_err(&["system", "run", "--bogus"]).status.code(), Some(125));
+}
+
+#[test]
+fn wine_upgrade_needs_basis_update() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    assert_eq!(env.run(&["basis", "check", "b"]), "false");
+    env.set_wine_version("wine-10.0 (test)");
+    assert_eq!(env.run(&["basis", "check", "b"]), "true");
+    env.run(&["basis", "update", "b"]);
+    assert_eq!(env.run(&["basis", "check", "b"]), "false");
+}
+
+#[test]
+fn test_mode_requires_root() {
+    let env = TestEnv::new();
+    let output =
+        std::process::Command::new(env!("CARGO_BIN_EXE_winebasin"))
+            .env("XDG_CONFIG_HOME", env.root().join("config"))
+            .env_remove("WINEBASIN_ROOT")
+            .args(["--test-mode", "basis", "list"])
+            .output()
+            .unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn deferred_update_wine_mismatch() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.write_program(&env.basis_prefix("b"), "app.exe", "true\n");
+    env.run(&["system", "create", "b", "s1"]);
+    env.run(&["system", "create", "b", "s2"]);
+
+    // The update is deferred while another system using the basis is mounted
+    env.fake_mount("s2");
+    env.set_wine_version("wine-10.0 (test)");
+    let output = env.run_err(&["system", "run", "s1", "app.exe"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--allow-wine-mismatch"));
+    env.run(&["system", "run", "s1", "--allow-wine-mismatch", "app.exe"]);
+    assert_eq!(env.run(&["basis", "check", "b"]), "true");
+}
+
+#[test]
+fn registered_wine() {
+    let env = TestEnv::new();
+
+    // Writes the stubs the install wraps
+    env.run(&["wine", "list"]);
+    let install = env.root().join("custom-wine");
+    env.write_wine_install(&install, "wine-custom");
+    env.run(&["wine", "add", "custom", install.to_str().unwrap()]);
+    assert!(env.run(&["wine", "list"]).contains("custom"));
+    env.run(&["basis", "create", "b", "--wine", "custom"]);
+    let recorded = fs::read_to_string(env.root().join("basis/b/wine.json")).unwrap();
+    assert!(recorded.contains("wine-custom"));
+    assert_eq!(env.run(&["basis", "check", "b"]), "false");
+
+    // Upgrading the default wine doesn't affect it
+    env.set_wine_version("wine-10.0 (test)");
+    assert_eq!(env.run(&["basis", "check", "b"]), "false");
+
+    // In use
+    env.run_err(&["wine", "remove", "custom"]);
+    env.run_err(&["basis", "create", "b2", "--wine", "missing"]);
+}
+
+#[test]
+fn install_wine_build() {
+    let env = TestEnv::new();
+    env.run(&["wine", "list"]);
+    let build = env.root().join("build");
+    env.write_wine_install(&build.join("wine-custom-x86_64"), "wine-custom");
+    let archive = env.root().join("wine-custom.tar.gz");
+    let status =
+        std::process::Command::new("tar")
+            .arg("--create")
+            .arg("--gzip")
+            .arg("--file")
+            .arg(&archive)
+            .arg("--directory")
+            .arg(&build)
+            .arg("wine-custom-x86_64")
+            .status()
+            .unwrap();
+    assert!(status.success());
+    let output = std::process::Command::new("sha256sum").arg(&archive).output().unwrap();
+    let sha256 = String::from_utf8(output.stdout).unwrap().split_whitespace().next().unwrap().to_string();
+    let url = format!("file://{}", archive.display());
+
+    // Wrong checksum
+    env.run_err(&["wine", "install", "custom", "--url", &url, "--sha256", "00"]);
+    assert!(!env.root().join("wine/custom").exists());
+    env.run(&["wine", "install", "custom", "--url", &url, "--sha256", &sha256]);
+    assert!(env.root().join("wine/custom/bin/wine").is_file());
+    assert!(env.run(&["wine", "list"]).contains("wine-custom"));
+    env.run(&["wine", "remove", "custom"]);
+    assert!(!env.root().join("wine/custom").exists());
+}
+
+#[test]
+fn proton_runner() {
+    let env = TestEnv::new();
+    env.run(&["wine", "list"]);
+    let install = env.root().join("proton-9");
+    env.write_proton_install(&install, "proton-9.0");
+    env.run(&["wine", "add", "proton", install.to_str().unwrap()]);
+    assert!(env.run(&["wine", "list"]).contains("proton"));
+    env.run(&["basis", "create", "b", "--wine", "proton"]);
+
+    // Created through the launcher, in the linked prefix
+    assert_eq!(fs::read_link(env.root().join("basis/b/proton/pfx")).unwrap(), env.basis_prefix("b"));
+    assert!(env.root().join("basis/b/proton/version").is_file());
+    assert!(env.basis_prefix("b").join("system.reg").is_file());
+    env.write_program(&env.basis_prefix("b"), "app.exe", "echo saved > \"$WINEPREFIX/drive_c/save.txt\"\n");
+    env.run(&["system", "create", "b", "s"]);
+    env.run(&["system", "run", "s", "app.exe"]);
+    assert!(env.root().join("system/s/proton/version").is_file());
+    assert_eq!(fs::read_to_string(env.system_prefix("s").join("drive_c/save.txt")).unwrap(), "saved\n");
+}
+
+#[test]
+fn umu_system() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.write_program(&env.basis_prefix("b"), "app.exe", "true\n");
+    env.run(&["system", "create", "b", "s"]);
+    env.edit_system_config("s", |config| {
+        config["umu"] = serde_json::json!({
+            "game_id": "umu-test",
+            "proton_path": "GE-Proton"
+        });
+    });
+
+    // Not installed
+    env.run_err(&["system", "run", "s", "app.exe"]);
+    env.write_bin(
+        "umu-run",
+        "#!/bin/sh\necho \"$GAMEID $PROTONPATH\" > \"$WINEPREFIX/drive_c/umu.txt\"\nexec wine \"$@\"\n",
+    );
+    env.run(&["system", "run", "s", "app.exe"]);
+    assert_eq!(fs::read_to_string(env.system_prefix("s").join("drive_c/umu.txt")).unwrap(), "umu-test GE-Proton\n");
+}
+
+#[test]
+fn flatpak_wine() {
+    let env = TestEnv::new();
+    env.run(&["wine", "list"]);
+
+    // Runs the stub wine, logging the arguments
+    env.write_bin(
+        "flatpak",
+        &format!(
+            "#!/bin/sh\necho \"$@\" >> '{}'\n[ \"$1\" = run ] || exit 0\nshift\nfor arg in \"$@\"; do shift; case \"$arg\" in --command=*) command=${{arg#--command=}} ;; -*) ;; *) break ;; esac; done\nexec '{}'/\"$command\" \"$@\"\n",
+            env.root().join("flatpak.log").display(),
+            env.root().join(".test/bin").display()
+        ),
+    );
+    env.run(&["wine", "add-flatpak", "fp"]);
+    env.run(&["basis", "create", "b", "--wine", "fp"]);
+    let log = fs::read_to_string(env.root().join("flatpak.log")).unwrap();
+    assert!(log.contains(&format!("--filesystem={}", env.basis_prefix("b").display())));
+    assert!(log.contains("--command=wine org.winehq.Wine wineboot"));
+    assert!(env.basis_prefix("b").join("system.reg").is_file());
+}
+
+#[test]
+fn wow64_basis() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b", "--arch", "wow-64", "--preset", "recommended", "--yes"]);
+    let prefix = env.basis_prefix("b");
+    assert!(fs::read_to_string(prefix.join("system.reg")).unwrap().contains("#arch=win64"));
+    assert!(fs::read_to_string(env.root().join("basis/b/config.json")).unwrap().contains("Wow64"));
+    env.run(&["system", "create", "b", "s"]);
+    env.run(&["system", "health", "s"]);
+}
+
+#[test]
+fn basis_dxvk() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+
+    // A fake release, already in the cache
+    let release = env.root().join("release/dxvk-2.4");
+    for arch in ["x64", "x32"] {
+        fs::create_dir_all(release.join(arch)).unwrap();
+        fs::write(release.join(arch).join("d3d11.dll"), format!("dxvk {}", arch)).unwrap();
+    }
+    let archive = env.root().join("cache/dxvk/dxvk-2.4.tar.gz");
+    fs::create_dir_all(archive.parent().unwrap()).unwrap();
+    let status =
+        std::process::Command::new("tar")
+            .arg("--create")
+            .arg("--gzip")
+            .arg("--file")
+            .arg(&archive)
+            .arg("--directory")
+            .arg(release.parent().unwrap())
+            .arg("dxvk-2.4")
+            .status()
+            .unwrap();
+    assert!(status.success());
+    env.run(&["basis", "dxvk", "install", "b", "--version", "2.4"]);
+    let prefix = env.basis_prefix("b");
+    assert_eq!(fs::read_to_string(prefix.join("drive_c/windows/system32/d3d11.dll")).unwrap(), "dxvk x64");
+    assert_eq!(fs::read_to_string(prefix.join("drive_c/windows/syswow64/d3d11.dll")).unwrap(), "dxvk x32");
+    assert!(fs::read_to_string(prefix.join("regedit.log")).unwrap().contains("\"d3d11\"=\"native\""));
+    assert!(env.run(&["basis", "list"]).contains("dxvk 2.4"));
+
+    // Part of the recipe
+    env.run(&["basis", "recreate", "b"]);
+    assert_eq!(fs::read_to_string(prefix.join("drive_c/windows/system32/d3d11.dll")).unwrap(), "dxvk x64");
+    env.run(&["basis", "dxvk", "remove", "b"]);
+    assert!(!prefix.join("drive_c/windows/system32/d3d11.dll").exists());
+    assert!(fs::read_to_string(prefix.join("regedit.log")).unwrap().contains("\"d3d11\"=-"));
+    assert!(!env.run(&["basis", "list"]).contains("dxvk"));
+}
+
+#[test]
+fn shared_shader_cache() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.edit_basis_config("b", |config| {
+        config["shared_shader_cache"] = serde_json::json!(true);
+    });
+    env.write_program(
+        &env.basis_prefix("b"),
+        "game.exe",
+        "echo x >> \"$DXVK_STATE_CACHE_PATH/game.dxvk-cache\"\ntest \"$VKD3D_SHADER_CACHE_PATH\" = \"$DXVK_STATE_CACHE_PATH\"\n",
+    );
+    env.run(&["system", "create", "b", "s1"]);
+    env.run(&["system", "create", "b", "s2"]);
+    env.run(&["system", "run", "s1", "game.exe"]);
+    env.run(&["system", "run", "s2", "game.exe"]);
+    assert_eq!(fs::read_to_string(env.root().join("cache/shaders/b/game.dxvk-cache")).unwrap(), "x\nx\n");
+}
+
+#[test]
+fn isolated_shader_cache() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.write_program(
+        &env.basis_prefix("b"),
+        "game.exe",
+        "echo x > \"$MESA_SHADER_CACHE_DIR/index\"\ntest \"$__GL_SHADER_DISK_CACHE_PATH\" = \"$MESA_SHADER_CACHE_DIR\"\n",
+    );
+    env.run(&["system", "create", "b", "s"]);
+    env.edit_system_config("s", |config| {
+        config["isolate_shader_cache"] = serde_json::json!(true);
+    });
+    env.run(&["system", "run", "s", "game.exe"]);
+    assert!(env.root().join("system/s/shader_cache/index").is_file());
+}
+
+#[test]
+fn sync_settings() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.edit_basis_config("b", |config| {
+        config["sync"] = serde_json::json!({
+            "esync": true,
+            "fsync": true
+        });
+    });
+    env.write_program(
+        &env.basis_prefix("b"),
+        "sync.exe",
+        "echo \"$WINEESYNC $WINEFSYNC $PROTON_NO_FSYNC\" > \"$WINEPREFIX/drive_c/sync.txt\"\n",
+    );
+    env.run(&["system", "create", "b", "s"]);
+    env.edit_system_config("s", |config| {
+        config["sync"] = serde_json::json!({
+            "esync": false
+        });
+    });
+    let sync_path = env.system_prefix("s").join("drive_c/sync.txt");
+    env.run(&["system", "run", "s", "sync.exe"]);
+    assert_eq!(fs::read_to_string(&sync_path).unwrap(), "0 1 0\n");
+    env.run(&["system", "run", "s", "--no-fsync", "sync.exe"]);
+    assert_eq!(fs::read_to_string(&sync_path).unwrap(), "0 0 1\n");
+}
+
+#[test]
+fn wayland_driver() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.write_program(
+        &env.basis_prefix("b"),
+        "display.exe",
+        "echo \"${DISPLAY:-none}\" > \"$WINEPREFIX/drive_c/display.txt\"\n",
+    );
+    env.run(&["system", "create", "b", "s"]);
+    let display_path = env.system_prefix("s").join("drive_c/display.txt");
+    let run = |args: &[&str], wayland_display: Option<&str>| {
+        let mut command = env.command(args);
+        command.env("DISPLAY", ":7");
+        match wayland_display {
+            Some(d) => command.env("WAYLAND_DISPLAY", d),
+            None => command.env_remove("WAYLAND_DISPLAY"),
+        };
+        assert!(command.output().unwrap().status.success());
+        return fs::read_to_string(&display_path).unwrap();
+    };
+    assert_eq!(run(&["system", "run", "s", "display.exe"], Some("wayland-1")), ":7\n");
+    assert_eq!(run(&["system", "run", "s", "--wayland", "display.exe"], Some("wayland-1")), "none\n");
+
+    // Falls back to X11 without a Wayland session
+    assert_eq!(run(&["system", "run", "s", "--wayland", "display.exe"], None), ":7\n");
+    env.edit_system_config("s", |config| {
+        config["wayland"] = serde_json::json!(true);
+    });
+    assert_eq!(run(&["system", "run", "s", "display.exe"], Some("wayland-1")), "none\n");
+    assert_eq!(run(&["system", "run", "s", "--no-wayland", "display.exe"], Some("wayland-1")), ":7\n");
+}
+
+#[test]
+fn virtual_desktop() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.write_program(
+        &env.basis_prefix("b"),
+        "desktop.exe",
+        "echo \"${TEST_DESKTOP:-none}\" > \"$WINEPREFIX/drive_c/desktop.txt\"\n",
+    );
+    env.run(&["system", "create", "b", "s"]);
+    let desktop_path = env.system_prefix("s").join("drive_c/desktop.txt");
+    env.run(&["system", "run", "s", "desktop.exe"]);
+    assert_eq!(fs::read_to_string(&desktop_path).unwrap(), "none\n");
+    env.run(&["system", "run", "s", "--virtual-desktop", "800x600", "desktop.exe"]);
+    assert_eq!(fs::read_to_string(&desktop_path).unwrap(), "winebasin,800x600\n");
+    env.run_err(&["system", "run", "s", "--virtual-desktop", "big", "desktop.exe"]);
+    env.edit_system_config("s", |config| {
+        config["virtual_desktop"] = serde_json::json!("1024x768");
+    });
+    env.run(&["system", "run", "s", "desktop.exe"]);
+    assert_eq!(fs::read_to_string(&desktop_path).unwrap(), "winebasin,1024x768\n");
+    let out = env.run(&["system", "shell", "s", "printenv", "WINEBASIN_VIRTUAL_DESKTOP"]);
+    assert_eq!(out, "winebasin,1024x768\n");
+}
+
+#[test]
+fn audio_driver() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b", "--audio", "alsa"]);
+    let basis_log = env.basis_prefix("b").join("regedit.log");
+    assert!(
+        fs::read_to_string(&basis_log)
+            .unwrap()
+            .contains("[HKEY_CURRENT_USER\\Software\\Wine\\Drivers]\n\"Audio\"=\"alsa\"\n")
+    );
+    env.run(&["basis", "set-audio", "b", "disabled"]);
+    assert!(fs::read_to_string(&basis_log).unwrap().ends_with("\"Audio\"=\"\"\n"));
+    let config: serde_json::Value =
+        serde_json::from_slice(&fs::read(env.root().join("basis/b/config.json")).unwrap()).unwrap();
+    assert_eq!(config["V2"]["audio"], "Disabled");
+
+    // Recreating the basis keeps the setting
+    env.run(&["basis", "recreate", "b"]);
+    assert!(fs::read_to_string(&basis_log).unwrap().ends_with("\"Audio\"=\"\"\n"));
+    env.run(&["system", "create", "b", "s"]);
+    env.run(&["system", "set-audio", "s", "auto"]);
+    assert!(fs::read_to_string(env.system_prefix("s").join("regedit.log")).unwrap().ends_with("\"Audio\"=-\n"));
+    let config: serde_json::Value =
+        serde_json::from_slice(&fs::read(env.root().join("system/s/config.json")).unwrap()).unwrap();
+    assert_eq!(config["V1"]["audio"], "Auto");
+}
+
+#[test]
+fn gamemode() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.write_program(
+        &env.basis_prefix("b"),
+        "game.exe",
+        "echo \"${TEST_GAMEMODE:-off}\" > \"$WINEPREFIX/drive_c/gamemode.txt\"\n",
+    );
+    env.run(&["system", "create", "b", "s"]);
+
+    // Not installed
+    let output = env.run_err(&["system", "run", "s", "--gamemode", "game.exe"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("gamemoderun"));
+    env.write_bin("gamemoderun", "#!/bin/sh\nexport TEST_GAMEMODE=on\nexec \"$@\"\n");
+    let gamemode_path = env.system_prefix("s").join("drive_c/gamemode.txt");
+    env.run(&["system", "run", "s", "game.exe"]);
+    assert_eq!(fs::read_to_string(&gamemode_path).unwrap(), "off\n");
+    env.run(&["system", "run", "s", "--gamemode", "game.exe"]);
+    assert_eq!(fs::read_to_string(&gamemode_path).unwrap(), "on\n");
+    env.edit_system_config("s", |config| {
+        config["gamemode"] = serde_json::json!(true);
+    });
+    fs::remove_file(&gamemode_path).unwrap();
+    env.run(&["system", "run", "s", "game.exe"]);
+    assert_eq!(fs::read_to_string(&gamemode_path).unwrap(), "on\n");
+}
+
+#[test]
+fn mangohud() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.write_program(
+        &env.basis_prefix("b"),
+        "game.exe",
+        "echo \"${MANGOHUD:-unset}\" > \"$WINEPREFIX/drive_c/mangohud.txt\"\n",
+    );
+    env.run(&["system", "create", "b", "s"]);
+    let mangohud_path = env.system_prefix("s").join("drive_c/mangohud.txt");
+    env.run(&["system", "run", "s", "game.exe"]);
+    assert_eq!(fs::read_to_string(&mangohud_path).unwrap(), "unset\n");
+    env.run(&["system", "run", "s", "--mangohud", "game.exe"]);
+    assert_eq!(fs::read_to_string(&mangohud_path).unwrap(), "1\n");
+    env.edit_system_config("s", |config| {
+        config["mangohud"] = serde_json::json!(true);
+    });
+    env.run(&["system", "run", "s", "game.exe"]);
+    assert_eq!(fs::read_to_string(&mangohud_path).unwrap(), "1\n");
+
+    // The config's `env` wins
+    env.edit_system_config("s", |config| {
+        config["env"] = serde_json::json!({
+            "MANGOHUD": "0"
+        });
+    });
+    env.run(&["system", "run", "s", "game.exe"]);
+    assert_eq!(fs::read_to_string(&mangohud_path).unwrap(), "0\n");
+}
+
+#[test]
+fn vkbasalt() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.write_program(
+        &env.basis_prefix("b"),
+        "game.exe",
+        "echo \"${ENABLE_VKBASALT:-unset} ${VKBASALT_CONFIG_FILE:-unset}\" > \"$WINEPREFIX/drive_c/vkbasalt.txt\"\n",
+    );
+    env.run(&["system", "create", "b", "s"]);
+    let vkbasalt_path = env.system_prefix("s").join("drive_c/vkbasalt.txt");
+    env.run(&["system", "run", "s", "game.exe"]);
+    assert_eq!(fs::read_to_string(&vkbasalt_path).unwrap(), "unset unset\n");
+    env.run(&["system", "run", "s", "--vkbasalt", "game.exe"]);
+    assert_eq!(fs::read_to_string(&vkbasalt_path).unwrap(), "1 unset\n");
+    env.run(&["system", "run", "s", "--vkbasalt-config", "/etc/vkBasalt.conf", "game.exe"]);
+    assert_eq!(fs::read_to_string(&vkbasalt_path).unwrap(), "1 /etc/vkBasalt.conf\n");
+    env.edit_system_config("s", |config| {
+        config["vkbasalt"] = serde_json::json!({
+            "config_file": "vkBasalt.conf"
+        });
+    });
+    env.run(&["system", "run", "s", "game.exe"]);
+    assert_eq!(
+        fs::read_to_string(&vkbasalt_path).unwrap(),
+        format!("1 {}\n", env.root().join("system/s/vkBasalt.conf").display())
+    );
+}
+
+#[test]
+fn gpu_selection() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.write_program(
+        &env.basis_prefix("b"),
+        "game.exe",
+        "echo \"${DRI_PRIME:-unset}\" > \"$WINEPREFIX/drive_c/gpu.txt\"\n",
+    );
+    env.run(&["system", "create", "b", "s"]);
+    let gpu_path = env.system_prefix("s").join("drive_c/gpu.txt");
+    env.run(&["system", "run", "s", "game.exe"]);
+    assert_eq!(fs::read_to_string(&gpu_path).unwrap(), "unset\n");
+    env.run(&["system", "run", "s", "--gpu", "discrete", "game.exe"]);
+    assert_eq!(fs::read_to_string(&gpu_path).unwrap(), "1\n");
+    env.run(&["system", "run", "s", "--gpu", "2", "game.exe"]);
+    assert_eq!(fs::read_to_string(&gpu_path).unwrap(), "2\n");
+    env.run_err(&["system", "run", "s", "--gpu", "fastest", "game.exe"]);
+    env.edit_system_config("s", |config| {
+        config["gpu"] = serde_json::json!("integrated");
+    });
+    env.run(&["system", "run", "s", "game.exe"]);
+    assert_eq!(fs::read_to_string(&gpu_path).unwrap(), "0\n");
+    env.run(&["system", "run", "s", "--gpu", "discrete", "game.exe"]);
+    assert_eq!(fs::read_to_string(&gpu_path).unwrap(), "1\n");
+}
+
+#[test]
+fn process_priority() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.write_program(&env.basis_prefix("b"), "game.exe", "nice > \"$WINEPREFIX/drive_c/nice.txt\"\n");
+    env.run(&["system", "create", "b", "s"]);
+    let nice_path = env.system_prefix("s").join("drive_c/nice.txt");
+    env.run(&["system", "run", "s", "game.exe"]);
+    let base_nice = fs::read_to_string(&nice_path).unwrap().trim().parse::<i32>().unwrap();
+    env.run(&["system", "run", "s", "--nice", "5", "game.exe"]);
+    assert_eq!(fs::read_to_string(&nice_path).unwrap(), format!("{}\n", (base_nice + 5).min(19)));
+    env.run_err(&["system", "run", "s", "--nice", "40", "game.exe"]);
+    env.run_err(&["system", "run", "s", "--cpus", "all", "game.exe"]);
+
+    // The flags override the config
+    env.edit_system_config("s", |config| {
+        config["priority"] = serde_json::json!({
+            "cpus": "0",
+            "nice": 3,
+            "ionice": "idle"
+        });
+    });
+    let out = env.run(&["--dry-run", "system", "run", "s", "--nice", "7", "game.exe"]);
+    assert!(out.contains("taskset --cpu-list 0 nice -n 7 ionice -c 3 "), "{}", out);
+}
+
+#[test]
+fn systemd_scope() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.run(&["system", "create", "b", "s"]);
+    let out = env.run(&["--dry-run", "system", "run", "s", "--systemd-scope", "game.exe"]);
+    assert!(out.contains("systemd-run --user --scope --quiet -- "), "{}", out);
+    env.edit_system_config("s", |config| {
+        config["systemd_scope"] = serde_json::json!({
+            "memory_max": "8G",
+            "cpu_quota": "200%"
+        });
+    });
+    let out = env.run(&["--dry-run", "system", "run", "s", "game.exe"]);
+    assert!(
+        out.contains("systemd-run --user --scope --quiet --property 'MemoryMax=8G' --property 'CPUQuota=200%' -- "),
+        "{}",
+        out
+    );
+}
+
+#[test]
+fn dry_run_checks() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+
+    // Shows the system a basis run would create, without creating it
+    let out = env.run(&["--dry-run", "system", "run", "b", "game.exe"]);
+    assert!(out.contains("would be created from basis `b`"), "{}", out);
+    assert!(
+        !env.root().join("system").exists() || fs::read_dir(env.root().join("system")).unwrap().next().is_none()
+    );
+    env.run(&["system", "create", "b", "s"]);
+    env.edit_system_config("s", |config| {
+        config["allowed_commands"] = serde_json::json!(["game.exe"]);
+    });
+    let output = env.run_err(&["--dry-run", "system", "shell", "s"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("shells are disabled"));
+    env.run_err(&["--dry-run", "system", "run", "s", "other.exe"]);
+    env.run(&["--dry-run", "system", "run", "s", "game.exe"]);
+}
+
+#[test]
+fn secret_env() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.write_program(
+        &env.basis_prefix("b"),
+        "game.exe",
+        "echo \"$PROXY_PASSWORD $TOKEN\" > \"$WINEPREFIX/drive_c/secrets.txt\"\n",
+    );
+    env.run(&["system", "create", "b", "s"]);
+    let pass =
+        env.write_bin(
+            "pass",
+            "#!/bin/sh\n[ \"$1 $2\" = \"show work/proxy\" ] || exit 1\nprintf 'hunter2\\nuser: me\\n'\n",
+        );
+    env.edit_system_config("s", |config| {
+        config["secret_env"] = serde_json::json!({
+            "PROXY_PASSWORD": {
+                "pass": "work/proxy"
+            },
+            "TOKEN": {
+                "env": "MY_TOKEN"
+            }
+        });
+    });
+
+    // Looked up at launch, hidden in logged commands
+    let output =
+        env.command(&["--debug", "system", "run", "s", "game.exe"]).env("MY_TOKEN", "tok\"123").output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(
+        fs::read_to_string(env.system_prefix("s").join("drive_c/secrets.txt")).unwrap(),
+        "hunter2 tok\"123\n"
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("PROXY_PASSWORD"), "{}", stderr);
+    assert!(!stderr.contains("hunter2") && !stderr.contains("123"), "{}", stderr);
+
+    // Not looked up for dry runs
+    fs::remove_file(&pass).unwrap();
+    let out = env.run(&["--dry-run", "system", "run", "s", "game.exe"]);
+    assert!(out.contains("<secret from pass work/proxy>"), "{}", out);
+    env.run_err(&["system", "run", "s", "game.exe"]);
+}
+
+#[test]
+fn bwrap_sandbox() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.write_program(&env.basis_prefix("b"), "game.exe", "echo ran > \"$WINEPREFIX/drive_c/ran.txt\"\n");
+    env.run(&["system", "create", "b", "s"]);
+
+    // Records its arguments, then runs the command without sandboxing it
+    env.write_bin(
+        "bwrap",
+        &format!(
+            "#!/bin/sh\necho \"$*\" > '{}'\nwhile [ $# -gt 0 ]; do\n    case \"$1\" in\n        --*bind*) shift 3 ;;\n        --tmpfs|--proc) shift 2 ;;\n        --*) shift ;;\n        *) break ;;\n    esac\ndone\nexec \"$@\"\n",
+            env.root().join(".test/bwrap-args").display()
+        ),
+    );
+    env.edit_system_config("s", |config| {
+        config["sandbox"] = serde_json::json!({
+            "allow_read_only":["/srv/isos"]
+        });
+    });
+    let mut command = env.command(&["system", "run", "s", "game.exe"]);
+    let output = command.env("HOME", "/home/test-user").output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read_to_string(env.system_prefix("s").join("drive_c/ran.txt")).unwrap(), "ran\n");
+    let args = fs::read_to_string(env.root().join(".test/bwrap-args")).unwrap();
+    let mount = env.system_mount("s");
+    assert!(args.contains("--tmpfs /home/test-user "), "{}", args);
+    assert!(args.contains(&format!("--bind-try {0} {0} ", mount.display())), "{}", args);
+    assert!(args.contains("--ro-bind-try /srv/isos /srv/isos "), "{}", args);
+}
+
+#[test]
+fn landlock_confinement() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.write_program(
+        &env.basis_prefix("b"),
+        "game.exe",
+        &format!(
+            "echo ran > \"$WINEPREFIX/drive_c/ran.txt\"\nif echo escaped > '{0}/outside.txt'; then exit 1; fi\necho read > '{0}/allowed/read.txt'\n",
+            env.root().display()
+        ),
+    );
+    env.run(&["system", "create", "b", "s"]);
+    fs::create_dir_all(env.root().join("allowed")).unwrap();
+    env.edit_system_config("s", |config| {
+        config["landlock"] = serde_json::json!({
+            "allow":[env.root().join("allowed")]
+        });
+    });
+    let output = env.command(&["system", "run", "s", "game.exe"]).output().unwrap();
+    if String::from_utf8_lossy(&output.stderr).contains("needs landlock, but") {
+        // The kernel doesn't have Landlock
+        return;
+    }
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read_to_string(env.system_prefix("s").join("drive_c/ran.txt")).unwrap(), "ran\n");
+    assert!(!env.root().join("outside.txt").exists());
+    assert_eq!(fs::read_to_string(env.root().join("allowed/read.txt")).unwrap(), "read\n");
+}
+
+#[test]
+fn z_drive() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    fs::create_dir_all(env.basis_prefix("b").join("dosdevices")).unwrap();
+    symlink("/", env.basis_prefix("b").join("dosdevices/z:")).unwrap();
+    env.write_program(
+        &env.basis_prefix("b"),
+        "game.exe",
+        "readlink \"$WINEPREFIX/dosdevices/z:\" > \"$WINEPREFIX/drive_c/z.txt\" || echo none > \"$WINEPREFIX/drive_c/z.txt\"\n",
+    );
+    env.run(&["system", "create", "b", "s"]);
+    let set_z_drive = |value: serde_json::Value| {
+        env.edit_system_config("s", |config| {
+            config["z_drive"] = value;
+        });
+    };
+    let z_drive = || fs::read_to_string(env.system_prefix("s").join("drive_c/z.txt")).unwrap();
+    env.run(&["system", "run", "s", "game.exe"]);
+    assert_eq!(z_drive(), "/\n");
+    set_z_drive(serde_json::json!({
+        "path": "/srv/games"
+    }));
+    env.run(&["system", "run", "s", "game.exe"]);
+    assert_eq!(z_drive(), "/srv/games\n");
+    set_z_drive(serde_json::json!("remove"));
+    env.run(&["system", "run", "s", "game.exe"]);
+    assert_eq!(z_drive(), "none\n");
+    set_z_drive(serde_json::json!("host"));
+    env.run(&["system", "run", "s", "game.exe"]);
+    assert_eq!(z_drive(), "/\n");
+}
+
+#[test]
+fn drive_mappings() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.write_program(
+        &env.basis_prefix("b"),
+        "game.exe",
+        "readlink \"$WINEPREFIX/dosdevices/d:\" > \"$WINEPREFIX/drive_c/d.txt\"\n",
+    );
+    env.run(&["system", "create", "b", "s"]);
+    env.edit_system_config("s", |config| {
+        config["drives"] = serde_json::json!({
+            "D:": "/mnt/games/IsoCache"
+        });
+    });
+    env.run(&["system", "run", "s", "game.exe"]);
+    assert_eq!(fs::read_to_string(env.system_prefix("s").join("drive_c/d.txt")).unwrap(), "/mnt/games/IsoCache\n");
+    let link = env.system_prefix("s").join("dosdevices/d:");
+    assert!(link.is_symlink());
+
+    // Removed from the system's layer once unmounted
+    env.edit_system_config("s", |config| {
+        config["remove_drives_on_unmount"] = serde_json::json!(true);
+    });
+    fs::remove_file(env.system_prefix("s").join("drive_c/d.txt")).unwrap();
+    env.run(&["system", "run", "s", "game.exe"]);
+    assert_eq!(fs::read_to_string(env.system_prefix("s").join("drive_c/d.txt")).unwrap(), "/mnt/games/IsoCache\n");
+    assert!(!link.is_symlink());
+
+    // Only drive letters
+    env.edit_system_config("s", |config| {
+        config["drives"] = serde_json::json!({
+            "c:": "/mnt"
+        });
+    });
+    let output = env.run_err(&["system", "run", "s", "game.exe"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Invalid drive"));
+}
+
+#[test]
+fn shared_drive_locking() {
+    let env = TestEnv::new();
+    let tmp = env.root().parent().unwrap().to_path_buf();
+    let saves = tmp.join("saves");
+    fs::create_dir_all(&saves).unwrap();
+    env.run(&["basis", "create", "b"]);
+    env.write_program(
+        &env.basis_prefix("b"),
+        "wait.exe",
+        &format!(
+            "touch {}\nwhile [ ! -e {} ]; do sleep 0.1; done\n",
+            tmp.join("started").display(),
+            tmp.join("release").display()
+        ),
+    );
+    env.write_program(&env.basis_prefix("b"), "app.exe", "true\n");
+    for name in ["s1", "s2"] {
+        env.run(&["system", "create", "b", name]);
+        env.edit_system_config(name, |config| {
+            config["drives"] = serde_json::json!({
+                "s:": saves
+            });
+        });
+    }
+    let mut running = env.command(&["system", "run", "s1", "wait.exe"]).spawn().unwrap();
+    while !tmp.join("started").exists() {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+
+    // The same system can share it, other systems can't. Let the first run finish
+    // before checking so a failure doesn't leave it running.
+    let same = env.command(&["system", "run", "s1", "app.exe"]).output().unwrap();
+    let other = env.command(&["system", "run", "s2", "app.exe"]).output().unwrap();
+    fs::write(tmp.join("release"), "").unwrap();
+    assert!(running.wait().unwrap().success());
+    assert!(same.status.success(), "{}", String::from_utf8_lossy(&same.stderr));
+    assert!(!other.status.success());
+    assert!(String::from_utf8_lossy(&other.stderr).contains("`s1`"), "{}", String::from_utf8_lossy(&other.stderr));
+    env.run(&["system", "run", "s2", "app.exe"]);
+}
+
+#[test]
+fn detached_alias() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.write_program(&env.basis_prefix("b"), "game.exe", "echo ran > \"$WINEPREFIX/drive_c/ran.txt\"\n");
+    env.run(&["system", "create", "b", "s"]);
+    fs::write(env.dir().join("config/winebasin/config.json"), serde_json::to_vec(&serde_json::json!({
+        "V1": {
+            "aliases": {
+                "bg": "system run s --detach game.exe"
+            }
+        }
+    })).unwrap()).unwrap();
+
+    // The background process runs the program instead of detaching again
+    env.run(&["bg"]);
+    let ran = env.system_prefix("s").join("drive_c/ran.txt");
+    for _ in 0 .. 200 {
+        if ran.exists() {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    let runs =
+        fs::read_dir(env.root().join(".test/run/runs"))
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().path().extension().unwrap() == "json")
+            .count();
+    env.run(&["system", "stop", "s"]);
+    assert!(ran.exists());
+    assert_eq!(runs, 1);
+}
+
+#[test]
+fn escape_supervisor() {
+    let env = TestEnv::new();
+    let tmp = env.dir().to_path_buf();
+    env.run(&["basis", "create", "b"]);
+    env.write_program(
+        &env.basis_prefix("b"),
+        "game.exe",
+        &format!("echo $$ > {}\nexec sleep 60\n", tmp.join("game.pid").display()),
+    );
+    env.run(&["system", "create", "b", "s"]);
+    env.write_bin(
+        "xdotool",
+        &format!(
+            "#!/bin/sh\ncase \"$1\" in\n    key) echo \"$2\" >> {0}/xdotool.log ;;\n    getactivewindow) cat {0}/game.pid ;;\nesac\n",
+            tmp.display()
+        ),
+    );
+    env.write_bin("setxkbmap", &format!("#!/bin/sh\necho \"$*\" > {}/setxkbmap.log\n", tmp.display()));
+    let mut running = env.command(&["system", "run", "s", "game.exe"]).spawn().unwrap();
+    while !tmp.join("game.pid").exists() {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+
+    // Key events are read from a fifo standing in for a keyboard
+    let input = env.root().join(".test/input");
+    fs::create_dir_all(&input).unwrap();
+    assert!(std::process::Command::new("mkfifo").arg(input.join("event0")).status().unwrap().success());
+    let mut supervisor = env.command(&["escape-supervisor"]).spawn().unwrap();
+    let mut keyboard = fs::OpenOptions::new().write(true).open(input.join("event0")).unwrap();
+    for (code, value) in [(29u16, 1i32), (56, 1), (107, 1), (107, 0), (42, 1), (107, 1)] {
+        let mut event = vec![
+            0u8;
+            16
+        ];
+        event.extend(1u16.to_ne_bytes());
+        event.extend(code.to_ne_bytes());
+        event.extend(value.to_ne_bytes());
+        std::io::Write::write_all(&mut keyboard, &event).unwrap();
+    }
+
+    // Ctrl+alt+end released the grabs, adding shift also killed the focused game
+    let status = running.wait().unwrap();
+    supervisor.kill().unwrap();
+    supervisor.wait().unwrap();
+    assert!(!status.success());
+    assert_eq!(fs::read_to_string(tmp.join("setxkbmap.log")).unwrap(), "-option grab:break_actions\n");
+    assert_eq!(fs::read_to_string(tmp.join("xdotool.log")).unwrap(), "XF86Ungrab\nXF86Ungrab\n");
+}
+
+#[test]
+fn menus_disabled() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.write_program(
+        &env.basis_prefix("b"),
+        "setup.exe",
+        "printenv WINEDLLOVERRIDES > \"$WINEPREFIX/drive_c/overrides.txt\" || true\n",
+    );
+    env.run(&["system", "create", "b", "s"]);
+
+    // Left by an earlier install, and by another prefix
+    let applications = env.root().parent().unwrap().join("data/applications");
+    fs::create_dir_all(applications.join("wine/Programs")).unwrap();
+    let stray = applications.join("wine/Programs/Game.desktop");
+    fs::write(
+        &stray,
+        format!("[Desktop Entry]\nExec=env WINEPREFIX=\"{}\" wine game.exe\n", env.system_mount("s").display()),
+    ).unwrap();
+    let association = applications.join("wine-extension-sav.desktop");
+    fs::write(
+        &association,
+        format!("[Desktop Entry]\nExec=env WINEPREFIX=\"{}\" wine start %f\n", env.system_mount("s").display()),
+    ).unwrap();
+    let other = applications.join("wine/Programs/Other.desktop");
+    fs::write(&other, "[Desktop Entry]\nExec=env WINEPREFIX=\"/home/me/.wine\" wine other.exe\n").unwrap();
+    let overrides = || fs::read_to_string(env.system_prefix("s").join("drive_c/overrides.txt")).unwrap();
+    env.run(&["system", "run", "s", "setup.exe"]);
+    assert_eq!(overrides(), "winemenubuilder.exe=d\n");
+    assert!(!stray.exists());
+    assert!(!association.exists());
+    assert!(other.exists());
+    env.run(&["system", "run", "s", "--allow-menus", "setup.exe"]);
+    assert_eq!(overrides(), "");
+}
+
+#[test]
+fn desktop_entry() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.run(&["system", "create", "b", "s"]);
+    let path =
+        env.run(
+            &["system", "desktop-entry", "s", "--exe", "Program Files/Game/game.exe", "--icon", "applications-games"],
+        );
+    let path = path.trim();
+    assert_eq!(
+        path,
+        env.root().parent().unwrap().join("data/applications/winebasin-s-game.desktop").to_string_lossy()
+    );
+    let entry = fs::read_to_string(path).unwrap();
+    assert!(entry.starts_with("[Desktop Entry]\nType=Application\nName=game\n"), "{}", entry);
+    assert!(
+        entry.contains(&format!(" --root {} system run s \"Program Files/Game/game.exe\"\n", env.root().display())),
+        "{}",
+        entry
+    );
+    assert!(entry.contains("Icon=applications-games\n"), "{}", entry);
+    let output = env.run_err(&["system", "desktop-entry", "s"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--exe"));
+}
+
+/// A minimal PE executable whose only resource is a 32x32 icon, solid red with a
+/// transparent top row.
+fn exe_with_icon() -> Vec<u8> {
+    fn u16s(out: &mut Vec<u8>, values: &[u16]) {
+        for v in values {
+            out.extend(v.to_le_bytes());
+        }
+    }
+
+    fn u32s(out: &mut Vec<u8>, values: &[u32]) {
+        for v in values {
+            out.extend(v.to_le_bytes());
+        }
+    }
+
+    // Icon bitmap, rows bottom to top, then the mask
+    let mut icon = vec![];
+    u32s(&mut icon, &[40, 32, 64]);
+    u16s(&mut icon, &[1, 32]);
+    u32s(&mut icon, &[0, 0, 0, 0, 0, 0]);
+    for y in 0 .. 32 {
+        for _ in 0 .. 32 {
+            icon.extend(if y == 31 {
+                [0, 0, 0, 0]
+            } else {
+                [0, 0, 255, 255]
+            });
+        }
+    }
+    icon.extend([0; 32 * 4]);
+    let mut group = vec![];
+    u16s(&mut group, &[0, 1, 1]);
+    group.extend([32, 32, 0, 0]);
+    u16s(&mut group, &[1, 32]);
+    u32s(&mut group, &[icon.len() as u32]);
+    u16s(&mut group, &[1]);
+
+    // Resource tree: type -> ID -> language -> data, for the icon (3) and the group
+    // (14)
+    let base = 0x1000u32;
+    let mut rsrc = vec![];
+    let dir = |rsrc: &mut Vec<u8>, entries: &[(u32, u32)]| {
+        u32s(rsrc, &[0, 0]);
+        u16s(rsrc, &[0, 0, 0, entries.len() as u16]);
+        for (id, offset) in entries {
+            u32s(rsrc, &[*id, *offset]);
+        }
+    };
+    dir(&mut rsrc, &[(3, 0x8000_0000 | 0x20), (14, 0x8000_0000 | 0x38)]);
+    dir(&mut rsrc, &[(1, 0x8000_0000 | 0x50)]);
+    dir(&mut rsrc, &[(1, 0x8000_0000 | 0x68)]);
+    dir(&mut rsrc, &[(0x409, 0x80)]);
+    dir(&mut rsrc, &[(0x409, 0x90)]);
+    let icon_at = 0xa0u32;
+    let group_at = icon_at + icon.len() as u32;
+    u32s(&mut rsrc, &[base + icon_at, icon.len() as u32, 0, 0]);
+    u32s(&mut rsrc, &[base + group_at, group.len() as u32, 0, 0]);
+    assert_eq!(rsrc.len(), icon_at as usize);
+    rsrc.extend(&icon);
+    rsrc.extend(&group);
+
+    // Headers, with the resources at file offset 0x200
+    let mut out = vec![
+        0u8;
+        0x40
+    ];
+    out[0 .. 2].copy_from_slice(b"MZ");
+    out[0x3c .. 0x40].copy_from_slice(&0x40u32.to_le_bytes());
+    out.extend(b"PE\0\0");
+    u16s(&mut out, &[0x14c, 1]);
+    u32s(&mut out, &[0, 0, 0]);
+    u16s(&mut out, &[224, 0x102]);
+    let optional = out.len();
+    out.resize(optional + 224, 0);
+    out[optional .. optional + 2].copy_from_slice(&0x10bu16.to_le_bytes());
+    out[optional + 92 .. optional + 96].copy_from_slice(&16u32.to_le_bytes());
+    out[optional + 112 .. optional + 116].copy_from_slice(&base.to_le_bytes());
+    out[optional + 116 .. optional + 120].copy_from_slice(&(rsrc.len() as u32).to_le_bytes());
+    out.extend(b".rsrc\0\0\0");
+    u32s(&mut out, &[rsrc.len() as u32, base, rsrc.len() as u32, 0x200, 0, 0, 0]);
+    out.resize(0x200, 0);
+    out.extend(rsrc);
+    return out;
+}
+
+#[test]
+fn desktop_entry_icon() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.run(&["system", "create", "b", "s"]);
+    fs::create_dir_all(env.system_prefix("s").join("drive_c")).unwrap();
+    fs::write(env.system_prefix("s").join("drive_c/game.exe"), exe_with_icon()).unwrap();
+    let entry = fs::read_to_string(env.run(&["system", "desktop-entry", "s", "--exe", "game.exe"]).trim()).unwrap();
+    assert!(entry.contains("Icon=winebasin-s-game\n"), "{}", entry);
+    let png =
+        fs::read(env.root().parent().unwrap().join("data/icons/hicolor/32x32/apps/winebasin-s-game.png")).unwrap();
+    assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+
+    // Width and height
+    assert_eq!(&png[16 .. 24], &[0, 0, 0, 32, 0, 0, 0, 32]);
+}
+
+#[test]
+fn steam_shortcut() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.run(&["system", "create", "b", "s"]);
+    let home = env.root().parent().unwrap().join("home");
+    let config_dir = home.join(".steam/steam/userdata/1234/config");
+    fs::create_dir_all(&config_dir).unwrap();
+
+    // An existing shortcut, which should be kept
+    let mut existing = b"\x00shortcuts\x00\x000\x00\x01AppName\x00Other\x00\x02IsHidden\x00".to_vec();
+    existing.extend([0, 0, 0, 0]);
+    existing.extend(b"\x08\x08\x08");
+    fs::write(config_dir.join("shortcuts.vdf"), &existing).unwrap();
+    for _ in 0 .. 2 {
+        let output =
+            env
+                .command(&["system", "steam-shortcut", "s", "--exe", "Program Files/Game/game.exe"])
+                .env("HOME", &home)
+                .output()
+                .unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    }
+    let shortcuts = fs::read(config_dir.join("shortcuts.vdf")).unwrap();
+    assert!(shortcuts.starts_with(&existing[..existing.len() - 2]));
+    assert!(shortcuts.ends_with(b"\x08\x08\x08\x08"));
+    let shortcuts = String::from_utf8_lossy(&shortcuts);
+
+    // Added once, after the existing one
+    assert_eq!(shortcuts.matches("\x08\x001\x00").count(), 1, "{:?}", shortcuts);
+    assert!(!shortcuts.contains("\x08\x002\x00"), "{:?}", shortcuts);
+    assert!(shortcuts.contains("\x01AppName\x00game\x00"), "{:?}", shortcuts);
+    assert!(
+        shortcuts.contains(
+            &format!(
+                "\x01LaunchOptions\x00--root {} system run s 'Program Files/Game/game.exe'\x00",
+                env.root().display()
+            ),
+        ),
+        "{:?}",
+        shortcuts
+    );
+}
+
+#[test]
+fn integrate() {
+    let env = TestEnv::new();
+    env.run(&["integrate"]);
+    let entry_path = env.root().parent().unwrap().join("data/applications/winebasin-open.desktop");
+    let entry = fs::read_to_string(&entry_path).unwrap();
+    assert!(entry.contains("MimeType=application/x-ms-dos-executable;"), "{}", entry);
+    assert!(entry.contains(&format!(" --root {} open %f\n", env.root().display())), "{}", entry);
+    env.run(&["integrate", "--remove"]);
+    assert!(!entry_path.exists());
+}
+
+#[test]
+fn open() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.run(&["system", "create", "b", "s"]);
+    env.run(&["system", "create", "b", "t"]);
+    let downloads = env.root().parent().unwrap().join("downloads");
+    fs::create_dir_all(&downloads).unwrap();
+    let setup = downloads.join("setup.exe");
+    fs::write(
+        &setup,
+        "mkdir -p \"$WINEPREFIX/drive_c\"\necho \"$(pwd)\" > \"$WINEPREFIX/drive_c/opened.txt\"\n",
+    ).unwrap();
+    env.run(&["open", "--system", "s", &setup.to_string_lossy()]);
+    assert_eq!(
+        fs::read_to_string(env.system_prefix("s").join("drive_c/opened.txt")).unwrap(),
+        format!("{}\n", downloads.display())
+    );
+
+    // Asks which system
+    env.write_bin("zenity", "#!/bin/sh\necho t\n");
+    env.run(&["open", &setup.to_string_lossy()]);
+    assert!(env.system_prefix("t").join("drive_c/opened.txt").exists());
+}
+
+#[test]
+fn handle_url() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.write_program(
+        &env.basis_prefix("b"),
+        "Program Files/My Game/game.exe",
+        "echo ran > \"$WINEPREFIX/drive_c/ran.txt\"\n",
+    );
+    env.run(&["system", "create", "b", "s"]);
+    env.run(&["integrate"]);
+    let entry =
+        fs::read_to_string(env.root().parent().unwrap().join("data/applications/winebasin-url.desktop")).unwrap();
+    assert!(entry.contains("MimeType=x-scheme-handler/winebasin;"), "{}", entry);
+    assert!(entry.contains(" handle-url %u\n"), "{}", entry);
+    env.run(&["system", "app", "add", "s", "my game", "Program Files/My Game/game.exe"]);
+    env.run(&["handle-url", "winebasin://run/s/my%20game"]);
+    assert_eq!(fs::read_to_string(env.system_prefix("s").join("drive_c/ran.txt")).unwrap(), "ran\n");
+
+    // Only registered apps in existing systems
+    let output = env.run_err(&["handle-url", "winebasin://run/s/Program%20Files/My%20Game/game.exe"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Invalid winebasin:// URL"));
+    let output = env.run_err(&["handle-url", "winebasin://run/s/game.exe"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No app with this name"));
+    let output = env.run_err(&["handle-url", "winebasin://run/b/my%20game"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No system"));
+}
+
+#[test]
+fn system_apps() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.write_program(
+        &env.basis_prefix("b"),
+        "Program Files/My Game/game.exe",
+        "echo \"$@\" > \"$WINEPREFIX/drive_c/args.txt\"\n",
+    );
+    env.run(&["system", "create", "b", "s"]);
+    env.run(&["system", "app", "add", "s", "game", "Program Files/My Game/game.exe", "--args", "-windowed"]);
+    assert_eq!(env.run(&["system", "app", "list", "s"]), "game: 'Program Files/My Game/game.exe' -windowed\n");
+    let args = || fs::read_to_string(env.system_prefix("s").join("drive_c/args.txt")).unwrap();
+    env.run(&["system", "app", "run", "s", "game", "-nosound"]);
+    assert_eq!(args(), "-windowed -nosound\n");
+    env.run(&["handle-url", "winebasin://run/s/game"]);
+    assert_eq!(args(), "-windowed\n");
+    env.run(&["system", "app", "remove", "s", "game"]);
+    let output = env.run_err(&["system", "app", "run", "s", "game"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No app with this name"));
+}
+
+#[test]
+fn installed_apps() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    fs::write(
+        env.basis_prefix("b").join("system.reg"),
+        concat!(
+            "WINE REGISTRY Version 2\n",
+            "[Software\\\\Microsoft\\\\Windows\\\\CurrentVersion\\\\Uninstall\\\\Wine Mono Runtime] 1700000000\n",
+            "\"DisplayName\"=\"Wine Mono Runtime\"\n",
+            "\"SystemComponent\"=dword:00000001\n"
+        ),
+    ).unwrap();
+    env.run(&["system", "create", "b", "s"]);
+    fs::write(
+        env.system_prefix("s").join("system.reg"),
+        concat!(
+            "WINE REGISTRY Version 2\n",
+            "[Software\\\\Wow6432Node\\\\Microsoft\\\\Windows\\\\CurrentVersion\\\\Uninstall\\\\My Game_is1] 1700000000\n",
+            "\"DisplayName\"=\"My Game\"\n",
+            "\"DisplayVersion\"=\"1.2\"\n",
+            "\"InstallLocation\"=\"C:\\\\Games\\\\My Game\\\\\"\n",
+            "\"UninstallString\"=\"\\\"C:\\\\Games\\\\My Game\\\\unins000.exe\\\"\"\n"
+        ),
+    ).unwrap();
+    assert_eq!(
+        env.run(&["system", "apps", "s"]),
+        "My Game 1.2 (My Game_is1)\n  Location: C:\\Games\\My Game\\\n  Uninstall: \"C:\\Games\\My Game\\unins000.exe\"\n"
+    );
+}
+
+#[test]
+fn uninstall() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.write_program(
+        &env.basis_prefix("b"),
+        "Games/My Game/unins000.exe",
+        "echo \"$@\" > \"$WINEPREFIX/drive_c/uninstalled.txt\"\n",
+    );
+    env.run(&["system", "create", "b", "s"]);
+    fs::write(
+        env.system_prefix("s").join("system.reg"),
+        concat!(
+            "WINE REGISTRY Version 2\n",
+            "[Software\\\\Microsoft\\\\Windows\\\\CurrentVersion\\\\Uninstall\\\\My Game_is1] 1700000000\n",
+            "\"DisplayName\"=\"My Game\"\n",
+            "\"UninstallString\"=\"\\\"C:\\\\Games\\\\My Game\\\\unins000.exe\\\" /SILENT\"\n"
+        ),
+    ).unwrap();
+    env.run(&["system", "uninstall", "s", "my game"]);
+    assert_eq!(fs::read_to_string(env.system_prefix("s").join("drive_c/uninstalled.txt")).unwrap(), "/SILENT\n");
+    let output = env.run_err(&["system", "uninstall", "s", "Other"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No installed program matches"));
+}
+
+#[test]
+fn run_msi() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.write_program(
+        &env.basis_prefix("b"),
+        "setup.MSI",
+        "echo \"$TEST_MSIEXEC $@\" > \"$WINEPREFIX/drive_c/installed.txt\"\n",
+    );
+    env.run(&["system", "create", "b", "s"]);
+    env.run(&["system", "run", "s", "setup.MSI", "/quiet"]);
+    assert_eq!(fs::read_to_string(env.system_prefix("s").join("drive_c/installed.txt")).unwrap(), "/i /quiet\n");
+}
+
+/// A minimal Windows shortcut (unicode, with a local target path).
+fn shell_link(target: &str, working_dir: &str, arguments: &str) -> Vec<u8> {
+    let utf16 = |s: &str| s.encode_utf16().flat_map(|u| u.to_le_bytes()).collect::<Vec<_>>();
+    let mut out = vec![
+        0u8;
+        0x4c
+    ];
+    out[0] = 0x4c;
+    out[4 .. 20].copy_from_slice(&[1, 0x14, 2, 0, 0, 0, 0, 0, 0xc0, 0, 0, 0, 0, 0, 0, 0x46]);
+
+    // Has link info, working dir, arguments; is unicode
+    out[0x14] = 0xb2;
+    let mut strings = vec![];
+    let ansi_path = strings.len();
+    strings.extend(target.as_bytes());
+    strings.push(0);
+    let ansi_suffix = strings.len();
+    strings.push(0);
+    let unicode_path = strings.len();
+    strings.extend(utf16(target));
+    strings.extend([0, 0]);
+    let unicode_suffix = strings.len();
+    strings.extend([0, 0]);
+    let header = 0x24u32;
+    let mut info = vec![];
+    for value in [
+        header + strings.len() as u32,
+        header,
+        1,
+        header,
+        header + ansi_path as u32,
+        0,
+        header + ansi_suffix as u32,
+        header + unicode_path as u32,
+        header + unicode_suffix as u32,
+    ] {
+        info.extend(value.to_le_bytes());
+    }
+    out.extend(info);
+    out.extend(strings);
+    for s in [working_dir, arguments] {
+        out.extend((s.encode_utf16().count() as u16).to_le_bytes());
+        out.extend(utf16(s));
+    }
+    out.extend([0, 0, 0, 0]);
+    return out;
+}
+
+#[test]
+fn run_shortcut() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.write_program(
+        &env.basis_prefix("b"),
+        "Games/My Game/game.exe",
+        "echo \"$(pwd) $@\" > \"$WINEPREFIX/drive_c/ran.txt\"\n",
+    );
+    let start_menu = env.basis_prefix("b").join("drive_c/users/Public/Start Menu/Programs");
+    fs::create_dir_all(&start_menu).unwrap();
+    fs::write(
+        start_menu.join("My Game.lnk"),
+        shell_link("C:\\Games\\My Game\\game.exe", "C:\\Games", "-windowed \"a b\""),
+    ).unwrap();
+    env.run(&["system", "create", "b", "s"]);
+    env.run(&["system", "run", "s", "users/Public/Start Menu/Programs/My Game.lnk", "-nosound"]);
+    assert_eq!(
+        fs::read_to_string(env.system_prefix("s").join("drive_c/ran.txt")).unwrap(),
+        format!("{} -windowed a b -nosound\n", env.system_mount("s").join("drive_c/Games").display())
+    );
+}
+
+#[test]
+fn export_lutris() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.run(&["system", "create", "b", "s"]);
+    let installer =
+        env.run(&["system", "export-lutris", "s", "--exe", "Program Files/Game/game.exe", "--title", "My Game"]);
+    assert!(
+        installer.starts_with(
+            "name: \"My Game\"\ngame_slug: \"my-game\"\nversion: \"winebasin s\"\nslug: \"my-game-winebasin-s\"\nrunner: linux\n",
+        ),
+        "{}",
+        installer
+    );
+    assert!(
+        installer.contains(
+            &format!("    args: \"--root {} system run s 'Program Files/Game/game.exe'\"\n", env.root().display()),
+        ),
+        "{}",
+        installer
+    );
+}
+
+#[test]
+fn export_launcher() {
+    let env = TestEnv::new();
+    env.run(&["basis", "create", "b"]);
+    env.run(&["system", "create", "b", "s"]);
+    env.edit_system_config("s", |config| {
+        config["env"] = serde_json::json!({
+            "DXVK_HUD": "fps"
+        });
+    });
+    let entry: serde_json::Value =
+        serde_json::from_str(
+            &env.run(&["system", "export-launcher", "s", "--exe", "Program Files/Game/game.exe"]),
+        ).unwrap();
+    assert_eq!(entry["title"], "game");
+    assert_eq!(
+        entry["args"],
+        serde_json::json!(["--root", env.root(), "system", "run", "s", "Program Files/Game/game.exe"])
+    );
+    assert_eq!(
+        entry["launch_options"],
+        format!("--root {} system run s 'Program Files/Game/game.exe'", env.root().display())
+    );
+    assert_eq!(entry["env"]["DXVK_HUD"], "fps");
+    let toml = env.run(&["system", "export-launcher", "s", "--exe", "game.exe", "--format", "toml"]);
+    assert!(toml.contains("title = \"game\"\n"), "{}", toml);
+    assert!(toml.contains("DXVK_HUD = \"fps\"\n"), "{}", toml);
+}
@@ -0,0 +1,193 @@
+use {
+    std::{
+        fs,
+        os::unix::fs::PermissionsExt,
+        path::{
+            Path,
+            PathBuf,
+        },
+        process::{
+            Command,
+            Output,
+        },
+    },
+    tempfile::TempDir,
+};
+
+/// A temporary root and config dir for running `winebasin --test-mode`, isolated
+/// from the user's real bases, systems and config.
+pub struct TestEnv {
+    dir: TempDir,
+}
+
+impl TestEnv {
+    pub fn new() -> TestEnv {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir_all(dir.path().join("config").join("winebasin")).unwrap();
+        return TestEnv { dir: dir };
+    }
+
+    /// The directory containing the root and the XDG dirs.
+    pub fn dir(&self) -> &Path {
+        return self.dir.path();
+    }
+
+    pub fn root(&self) -> PathBuf {
+        return self.dir.path().join("root");
+    }
+
+    pub fn basis_prefix(&self, basis_name: &str) -> PathBuf {
+        return self.root().join("basis").join(basis_name).join("prefix");
+    }
+
+    pub fn system_prefix(&self, system_name: &str) -> PathBuf {
+        return self.root().join("system").join(system_name).join("prefix");
+    }
+
+    pub fn system_mount(&self, system_name: &str) -> PathBuf {
+        return self.root().join("system").join(system_name).join("mount");
+    }
+
+    /// The simulated mount table.
+    pub fn mounts(&self) -> String {
+        return fs::read_to_string(self.root().join(".test").join("mounts")).unwrap_or_default();
+    }
+
+    /// Make a system look mounted by another process.
+    pub fn fake_mount(&self, system_name: &str) {
+        let mut mounts = self.mounts();
+        mounts.push_str(
+            &format!("overlay {} overlay lowerdir=/nonexistent 0 0\n", self.system_mount(system_name).display()),
+        );
+        fs::write(self.root().join(".test").join("mounts"), mounts).unwrap();
+    }
+
+    /// Change the version the stub wine reports.
+    pub fn set_wine_version(&self, version: &str) {
+        fs::write(self.root().join(".test").join("wine-version"), version).unwrap();
+    }
+
+    pub fn command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_winebasin"));
+        command
+            .env("XDG_CONFIG_HOME", self.dir.path().join("config"))
+            .env("XDG_CACHE_HOME", self.dir.path().join("cache"))
+            .env("XDG_DATA_HOME", self.dir.path().join("data"))
+            .env_remove("WINEBASIN_ROOT")
+            .env_remove("WINEPREFIX")
+            .env_remove("WINEARCH")
+            .env_remove("W_CACHE")
+            .arg("--test-mode")
+            .arg("--root")
+            .arg(self.root())
+            .args(args);
+        return command;
+    }
+
+    /// Run winebasin, panicking with its output if it fails. Returns stdout.
+    pub fn run(&self, args: &[&str]) -> String {
+        let output = self.command(args).output().unwrap();
+        if !output.status.success() {
+            panic!("`winebasin {}` failed:\n{}", args.join(" "), describe(&output));
+        }
+        return String::from_utf8(output.stdout).unwrap();
+    }
+
+    /// Run winebasin, panicking with its output if it succeeds.
+    pub fn run_err(&self, args: &[&str]) -> Output {
+        let output = self.command(args).output().unwrap();
+        if output.status.success() {
+            panic!("`winebasin {}` succeeded but should have failed:\n{}", args.join(" "), describe(&output));
+        }
+        return output;
+    }
+
+    /// Create a wine installation at `path` that reports `version` and otherwise
+    /// behaves like the stub wine.
+    pub fn write_wine_install(&self, path: &Path, version: &str) {
+        let bin = path.join("bin");
+        fs::create_dir_all(&bin).unwrap();
+        let stubs = self.root().join(".test").join("bin");
+        for (
+            name,
+            script,
+        ) in [
+            (
+                "wine",
+                format!(
+                    "#!/bin/sh\nif [ \"$1\" = --version ]; then echo '{}'; exit; fi\nexec '{}' \"$@\"\n",
+                    version,
+                    stubs.join("wine").display()
+                ),
+            ),
+            ("wineserver", format!("#!/bin/sh\nexec '{}' \"$@\"\n", stubs.join("wineserver").display())),
+        ] {
+            let path = bin.join(name);
+            fs::write(&path, script).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+    }
+
+    /// Create a Proton install at `path` whose launcher runs the stub wine in `pfx` in
+    /// the compat data dir, recording that it ran in `version` there.
+    pub fn write_proton_install(&self, path: &Path, version: &str) {
+        self.write_wine_install(&path.join("files"), version);
+        let launcher = path.join("proton");
+        fs::write(
+            &launcher,
+            format!(
+                "#!/bin/sh
+set -eu
+[ \"$1\" = run ]
+shift
+echo '{}' > \"$STEAM_COMPAT_DATA_PATH/version\"
+WINEPREFIX=\"$STEAM_COMPAT_DATA_PATH/pfx\" exec '{}' \"$@\"
+",
+                version,
+                path.join("files/bin/wine").display()
+            ),
+        ).unwrap();
+        fs::set_permissions(&launcher, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    /// Write a program (a shell script, run by the stub wine) into a prefix.
+    pub fn write_program(&self, prefix: &Path, name: &str, script: &str) {
+        let path = prefix.join("drive_c").join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, script).unwrap();
+    }
+
+    /// Write an executable script into the stub commands directory, which is in `PATH`
+    /// in test mode, ex: to fake a host tool. Returns its path.
+    pub fn write_bin(&self, name: &str, script: &str) -> PathBuf {
+        let path = self.root().join(".test").join("bin").join(name);
+        fs::write(&path, script).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        return path;
+    }
+
+    /// Change a system's config. `edit` gets the fields of the latest config version.
+    pub fn edit_system_config(&self, system_name: &str, edit: impl FnOnce(&mut serde_json::Value)) {
+        edit_config(&self.root().join("system").join(system_name).join("config.json"), "V1", edit);
+    }
+
+    /// Change a basis' config. `edit` gets the fields of the latest config version.
+    pub fn edit_basis_config(&self, basis_name: &str, edit: impl FnOnce(&mut serde_json::Value)) {
+        edit_config(&self.root().join("basis").join(basis_name).join("config.json"), "V2", edit);
+    }
+}
+
+fn edit_config(path: &Path, version: &str, edit: impl FnOnce(&mut serde_json::Value)) {
+    let mut config: serde_json::Value = serde_json::from_slice(&fs::read(path).unwrap()).unwrap();
+    edit(&mut config[version]);
+    fs::write(path, serde_json::to_vec(&config).unwrap()).unwrap();
+}
+
+fn describe(output: &Output) -> String {
+    return format!(
+        "status: {}\nstdout:\n{}\nstderr:\n{}",
+        output.status,
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}